@@ -0,0 +1,571 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use thiserror::Error;
+
+// Amounts of money, as the ledger stores them. By default they are f32, as they always were; with
+// the minor-units feature they are integer numbers of minor units (see MinorUnits), so that no
+// floating point is involved in parsing, adding up and comparing amounts, and checks that the
+// books balance are exact.
+#[cfg(not(feature = "minor-units"))]
+pub type Amount = f32;
+#[cfg(feature = "minor-units")]
+pub type Amount = MinorUnits;
+
+// What the ledger does with amounts besides adding them up and comparing them, in both
+// representations.
+pub trait Money:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Sum
+{
+    const ZERO: Self;
+
+    // The sum of the amounts, None if it is out of range. The operators panic on overflow, like
+    // those of the integers; the ledger checks the balances a transaction moves with this first.
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    // The amount multiplied by a rate, rounded to the minor unit with minor units. None if it is
+    // out of range.
+    fn times(self, rate: f32) -> Option<Self>;
+
+    // The given percentage of the amount, rounded to the minor unit with minor units. None if it
+    // is out of range.
+    fn percent(self, percent: f32) -> Option<Self>;
+
+    // The amount as a fraction of another one.
+    fn ratio(self, other: Self) -> f32;
+
+    // The amount in major units, for the outputs.
+    fn to_f64(self) -> f64;
+}
+
+impl Money for f32 {
+    const ZERO: f32 = 0.0;
+
+    fn checked_add(self, other: f32) -> Option<f32> {
+        Some(self + other).filter(|sum| sum.is_finite())
+    }
+
+    fn checked_sub(self, other: f32) -> Option<f32> {
+        Some(self - other).filter(|difference| difference.is_finite())
+    }
+
+    fn times(self, rate: f32) -> Option<f32> {
+        Some(self * rate).filter(|product| product.is_finite())
+    }
+
+    fn percent(self, percent: f32) -> Option<f32> {
+        Some(self * percent / 100.0).filter(|part| part.is_finite())
+    }
+
+    fn ratio(self, other: f32) -> f32 {
+        self / other
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid amount {0:?}")]
+pub struct InvalidAmount(String);
+
+// Decimal places an amount can have at most, that i64 minor units can hold. Precisions and
+// currency exponents above it are rejected when the config and the arguments are read.
+pub const MAX_EXPONENT: u8 = 18;
+
+// 10^exponent, None above 10^38.
+fn power(exponent: u32) -> Option<i128> {
+    10i128.checked_pow(exponent)
+}
+
+// An amount as a whole number of minor units of its currency, 10^-exponent of the major unit
+// (1234 with exponent 2 for 12.34 USD, 1234 with exponent 0 for 1234 JPY). The ledger brings the
+// amounts of the transactions to the exponent of their currency (see Config::currency_exponents)
+// and rejects those with more decimal places; amounts of different exponents are still added and
+// compared exactly, as a number of the smaller unit. Multiplications by rates and percentages
+// round half away from zero to the unit of the amount.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MinorUnits {
+    units: i64,
+    exponent: u8,
+}
+
+// The units if they are within ±i64::MAX, the range of the units of amounts.
+fn in_range(units: i128) -> Option<i64> {
+    (units.abs() <= i128::from(i64::MAX)).then_some(units as i64)
+}
+
+// Units rounded half away from zero to the given divisor, a power of ten.
+fn divided(units: i128, divisor: i128) -> i128 {
+    let (quotient, remainder) = (units / divisor, units % divisor);
+    quotient + i128::from(remainder.abs() * 2 >= divisor) * units.signum()
+}
+
+// The decimal number which the rate is written as, e.g. 1.1 (11 units of exponent 1) rather than
+// the nearest binary fraction, with its decimal places, so that rates are applied in integers.
+fn decimal(rate: f32) -> Option<(i128, u32)> {
+    let s = format!("{}", rate);
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.as_str()),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let units: i128 = format!("{}{}", whole, fraction).parse().ok()?;
+    let places = u32::try_from(fraction.len()).ok()?;
+    Some((if negative { -units } else { units }, places))
+}
+
+impl MinorUnits {
+    pub const fn new(units: i64, exponent: u8) -> MinorUnits {
+        assert!(exponent <= MAX_EXPONENT, "exponent too large");
+        assert!(units != i64::MIN, "amount out of range");
+        MinorUnits { units, exponent }
+    }
+
+    pub fn units(self) -> i64 {
+        self.units
+    }
+
+    pub fn exponent(self) -> u8 {
+        self.exponent
+    }
+
+    // The amount in minor units of the given exponent, None if it has more decimal places than
+    // that, or doesn't fit (or the exponent is above MAX_EXPONENT).
+    pub fn at(self, exponent: u8) -> Option<MinorUnits> {
+        if exponent > MAX_EXPONENT {
+            return None;
+        }
+        let units = i128::from(self.units);
+        let units = if exponent >= self.exponent {
+            units.checked_mul(power(u32::from(exponent - self.exponent))?)?
+        } else {
+            let divisor = power(u32::from(self.exponent - exponent))?;
+            if units % divisor != 0 {
+                return None;
+            }
+            units / divisor
+        };
+        Some(MinorUnits {
+            units: in_range(units)?,
+            exponent,
+        })
+    }
+
+    // The amount rounded half away from zero to the given number of decimal places. An amount
+    // with fewer decimal places stays as it is.
+    pub fn rounded(self, exponent: u8) -> MinorUnits {
+        if exponent >= self.exponent {
+            return self;
+        }
+        let divisor = 10i128.pow(u32::from(self.exponent - exponent));
+        MinorUnits {
+            // Dividing doesn't take the units out of range.
+            units: divided(i128::from(self.units), divisor) as i64,
+            exponent,
+        }
+    }
+
+    pub fn abs(self) -> MinorUnits {
+        MinorUnits {
+            units: self.units.abs(),
+            ..self
+        }
+    }
+
+    // Both amounts in units of the smaller of the two. Exponents being at most MAX_EXPONENT,
+    // i64 units always fit in i128 units of any exponent.
+    fn aligned(self, other: MinorUnits) -> (i128, i128, u8) {
+        let exponent = self.exponent.max(other.exponent);
+        let at = |a: MinorUnits| i128::from(a.units) * 10i128.pow(u32::from(exponent - a.exponent));
+        (at(self), at(other), exponent)
+    }
+
+    // The amount multiplied by the decimal factor (units of the given decimal places), rounded
+    // half away from zero to units of the exponent. None if it doesn't fit.
+    fn scaled(self, (factor, places): (i128, u32), exponent: u8) -> Option<MinorUnits> {
+        let units = i128::from(self.units).checked_mul(factor)?;
+        let (from, to) = (u32::from(self.exponent) + places, u32::from(exponent));
+        let units = if to >= from {
+            units.checked_mul(power(to - from)?)?
+        } else {
+            // Below 10^-38 of a unit, which rounds to zero.
+            power(from - to).map_or(0, |divisor| divided(units, divisor))
+        };
+        Some(MinorUnits {
+            units: in_range(units)?,
+            exponent,
+        })
+    }
+
+    // The amount converted at the rate to a currency of the given exponent, see Config::convert.
+    // None if it doesn't fit.
+    pub fn converted(self, rate: f32, exponent: u8) -> Option<MinorUnits> {
+        self.scaled(decimal(rate)?, exponent.min(MAX_EXPONENT))
+    }
+}
+
+impl Money for MinorUnits {
+    const ZERO: MinorUnits = MinorUnits {
+        units: 0,
+        exponent: 0,
+    };
+
+    fn checked_add(self, other: MinorUnits) -> Option<MinorUnits> {
+        let (a, b, exponent) = self.aligned(other);
+        let mut sum = a + b; // Two i64 of at most 10^18 units of the other exponent
+        let mut exponent = exponent;
+        // A sum which doesn't fit in units of the smaller may in those of the larger exponent.
+        while in_range(sum).is_none() && exponent > self.exponent.min(other.exponent) {
+            if sum % 10 != 0 {
+                return None;
+            }
+            (sum, exponent) = (sum / 10, exponent - 1);
+        }
+        Some(MinorUnits {
+            units: in_range(sum)?,
+            exponent,
+        })
+    }
+
+    fn checked_sub(self, other: MinorUnits) -> Option<MinorUnits> {
+        self.checked_add(-other)
+    }
+
+    fn times(self, rate: f32) -> Option<MinorUnits> {
+        self.scaled(decimal(rate)?, self.exponent)
+    }
+
+    fn percent(self, percent: f32) -> Option<MinorUnits> {
+        let (units, places) = decimal(percent)?;
+        self.scaled((units, places + 2), self.exponent)
+    }
+
+    fn ratio(self, other: MinorUnits) -> f32 {
+        let (a, b, _) = self.aligned(other);
+        (a as f64 / b as f64) as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self.units as f64 / 10f64.powi(i32::from(self.exponent))
+    }
+}
+
+impl PartialEq for MinorUnits {
+    fn eq(&self, other: &MinorUnits) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MinorUnits {}
+
+impl PartialOrd for MinorUnits {
+    fn partial_cmp(&self, other: &MinorUnits) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MinorUnits {
+    fn cmp(&self, other: &MinorUnits) -> Ordering {
+        let (a, b, _) = self.aligned(*other);
+        a.cmp(&b)
+    }
+}
+
+impl Add for MinorUnits {
+    type Output = MinorUnits;
+
+    fn add(self, other: MinorUnits) -> MinorUnits {
+        self.checked_add(other).expect("amount out of range")
+    }
+}
+
+impl Sub for MinorUnits {
+    type Output = MinorUnits;
+
+    fn sub(self, other: MinorUnits) -> MinorUnits {
+        self + -other
+    }
+}
+
+impl Neg for MinorUnits {
+    type Output = MinorUnits;
+
+    // Units are within ±i64::MAX, so every amount has an opposite.
+    fn neg(self) -> MinorUnits {
+        MinorUnits {
+            units: -self.units,
+            ..self
+        }
+    }
+}
+
+impl AddAssign for MinorUnits {
+    fn add_assign(&mut self, other: MinorUnits) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for MinorUnits {
+    fn sub_assign(&mut self, other: MinorUnits) {
+        *self = *self - other;
+    }
+}
+
+impl Sum for MinorUnits {
+    fn sum<I: Iterator<Item = MinorUnits>>(iter: I) -> MinorUnits {
+        iter.fold(MinorUnits::ZERO, Add::add)
+    }
+}
+
+impl FromStr for MinorUnits {
+    type Err = InvalidAmount;
+
+    // Parses a decimal number, such as "12.34", "-5" or ".5", keeping the decimal places it is
+    // written with.
+    fn from_str(s: &str) -> Result<MinorUnits, InvalidAmount> {
+        let invalid = || InvalidAmount(s.to_string());
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |p: &str| p.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() && fraction.is_empty() || !all_digits(whole) || !all_digits(fraction) {
+            return Err(invalid());
+        }
+        let exponent = u8::try_from(fraction.len())
+            .ok()
+            .filter(|e| *e <= MAX_EXPONENT)
+            .ok_or_else(invalid)?;
+        let units = format!("{}{}", whole, fraction)
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+        Ok(MinorUnits {
+            units: if negative { -units } else { units },
+            exponent,
+        })
+    }
+}
+
+impl fmt::Display for MinorUnits {
+    // Writes the amount with its decimal places, or rounded (or padded) to the precision given.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let exponent = f
+            .precision()
+            .map_or(self.exponent, |p| p.min(usize::from(MAX_EXPONENT)) as u8);
+        let a = self.rounded(exponent.min(self.exponent)).at(exponent);
+        let a = a.unwrap_or(*self);
+        let divisor = 10u64.pow(u32::from(a.exponent));
+        let magnitude = a.units.unsigned_abs();
+        let sign = if a.units < 0 { "-" } else { "" };
+        if a.exponent == 0 {
+            return write!(f, "{}{}", sign, magnitude);
+        }
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            magnitude / divisor,
+            magnitude % divisor,
+            width = usize::from(a.exponent)
+        )
+    }
+}
+
+// Reads the optional amount of a CSV record from the text of its field. CSV fields which look like
+// numbers are otherwise deserialized as f64, which drops the digits of amounts beyond 15 or so.
+pub(crate) fn csv_field<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Amount>, D::Error> {
+    struct Text;
+
+    impl serde::de::Visitor<'_> for Text {
+        type Value = Amount;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an amount")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Amount, E> {
+            s.trim()
+                .parse()
+                .map_err(|_| E::custom(format!("invalid amount {:?}", s)))
+        }
+    }
+
+    struct Field;
+
+    impl<'de> serde::de::Visitor<'de> for Field {
+        type Value = Option<Amount>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an amount")
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Option<Amount>, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: serde::Deserializer<'de>>(self, d: D) -> Result<Option<Amount>, D::Error> {
+            d.deserialize_str(Text).map(Some)
+        }
+    }
+
+    d.deserialize_option(Field)
+}
+
+// Amounts are written as strings in JSON and TOML, so that the decimal places survive.
+impl serde::Serialize for MinorUnits {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Amounts are read from strings, and from numbers as written (10.5 being 10.5, not the nearest
+// binary fraction), so that config files can keep their numbers.
+impl<'de> serde::Deserialize<'de> for MinorUnits {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<MinorUnits, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = MinorUnits;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an amount")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<MinorUnits, E> {
+                s.trim().parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<MinorUnits, E> {
+                in_range(i128::from(v))
+                    .map(|v| MinorUnits::new(v, 0))
+                    .ok_or_else(|| E::custom("amount out of range"))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<MinorUnits, E> {
+                in_range(i128::from(v))
+                    .map(|v| MinorUnits::new(v, 0))
+                    .ok_or_else(|| E::custom("amount out of range"))
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<MinorUnits, E> {
+                // The shortest representation of the number, which is how it was written.
+                let s = format!("{}", v);
+                if s.contains(['e', 'i', 'N']) {
+                    return Err(E::custom(format!("invalid amount {}", v)));
+                }
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units(s: &str) -> MinorUnits {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_and_writes_decimal_places() {
+        for s in ["12.34", "-5", "0.5", "1.000"] {
+            assert_eq!(units(s).to_string(), s);
+        }
+        assert_eq!(units(".5").to_string(), "0.5");
+        assert_eq!(format!("{:.1}", units("2.25")), "2.3");
+        assert_eq!(format!("{:.3}", units("-2.25")), "-2.250");
+        for s in ["", ".", "1.2.3", "1e5", "--1", "0.1234567890123456789"] {
+            assert!(s.parse::<MinorUnits>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn brings_amounts_to_exponents_exactly() {
+        assert_eq!(units("1.50").at(1).map(|a| a.units), Some(15));
+        assert_eq!(units("1.5").at(4).map(|a| a.units), Some(15000));
+        assert!(units("1.55").at(1).is_none());
+        assert!(units("9000000000000").at(MAX_EXPONENT).is_none());
+        assert!(units("1").at(MAX_EXPONENT + 1).is_none());
+    }
+
+    #[test]
+    fn rounds_half_away_from_zero() {
+        assert_eq!(units("2.345").rounded(2), units("2.35"));
+        assert_eq!(units("-2.345").rounded(2), units("-2.35"));
+        assert_eq!(units("2.344").rounded(2), units("2.34"));
+        // Rounding to more decimal places than the amount has, even far more, leaves it be.
+        let large = units("9000000000000.5");
+        assert_eq!(large.rounded(MAX_EXPONENT).exponent(), 1);
+        assert_eq!(large.rounded(u8::MAX), large);
+    }
+
+    #[test]
+    fn compares_amounts_of_any_exponents() {
+        let large = units("9000000000000.0000");
+        let small = MinorUnits::new(1, MAX_EXPONENT);
+        assert!(large > small);
+        assert!(-large < small);
+        assert_eq!(units("1.5"), units("1.500"));
+        assert!((small.ratio(large) - 1.1e-31).abs() < 1e-32);
+    }
+
+    #[test]
+    fn checked_sums_stay_in_range() {
+        let max = MinorUnits::new(i64::MAX, 0);
+        assert_eq!(max.checked_add(MinorUnits::new(1, 0)), None);
+        assert_eq!(
+            max.checked_sub(MinorUnits::new(1, 0)).map(|a| a.units),
+            Some(i64::MAX - 1)
+        );
+        assert_eq!((-max).checked_sub(MinorUnits::new(1, 0)), None);
+        // The sum doesn't fit in units of the larger exponent, but is exact in the smaller one.
+        let tenths = MinorUnits::new(i64::MAX / 10 * 10, 1);
+        let sum = tenths.checked_add(MinorUnits::new(0, 2)).unwrap();
+        assert_eq!((sum.units, sum.exponent), (i64::MAX / 10 * 10, 1));
+        assert_eq!(tenths.checked_add(MinorUnits::new(1, 2)), None);
+        assert_eq!(units("0.1") + units("0.25") - units("0.05"), units("0.3"));
+    }
+
+    #[test]
+    fn applies_rates_as_written() {
+        // 1.00 at 2.5% is 0.025, rounded away from zero (a binary 0.025 is a little less).
+        assert_eq!(units("1.00").percent(2.5), Some(units("0.03")));
+        assert_eq!(units("-1.00").percent(2.5), Some(units("-0.03")));
+        assert_eq!(units("10.00").times(1.1), Some(units("11.00")));
+        assert_eq!(units("0.10").times(0.0000001), Some(units("0.00")));
+        assert_eq!(MinorUnits::new(i64::MAX, 0).times(2.0), None);
+        let yen = units("100");
+        assert_eq!(yen.converted(0.0067, 2), Some(units("0.67")));
+        assert_eq!(units("0.67").converted(149.25, 0), Some(units("100")));
+    }
+
+    #[test]
+    fn reads_amounts_as_written() {
+        let a: MinorUnits = serde_json::from_str("10.5").unwrap();
+        assert_eq!((a.units, a.exponent), (105, 1));
+        let a: MinorUnits = serde_json::from_str("\"0.10\"").unwrap();
+        assert_eq!((a.units, a.exponent), (10, 2));
+        assert!(serde_json::from_str::<MinorUnits>(&i64::MIN.to_string()).is_err());
+        assert!(serde_json::from_str::<MinorUnits>("1e300").is_err());
+        assert_eq!(serde_json::to_string(&units("-0.50")).unwrap(), "\"-0.50\"");
+    }
+}
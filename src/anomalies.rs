@@ -0,0 +1,175 @@
+use ledger::{Applied, Money, TransactionType};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+
+// Transactions of a type a client must have made before their amounts are checked for spikes.
+const MIN_HISTORY: u64 = 5;
+// Score from which an amount is a spike: its distance to the mean of the previous amounts of the
+// client, in standard deviations.
+const SPIKE_SCORE: f64 = 4.0;
+// Transactions of a client over which disputes are counted for bursts.
+const BURST_WINDOW: usize = 10;
+// Disputes within the window from which they are a burst, provided they are also at least
+// BURST_SCORE times as many as the history of the client makes expected.
+const BURST_DISPUTES: usize = 3;
+const BURST_SCORE: f64 = 3.0;
+
+// Running mean and variance of amounts (Welford's algorithm).
+#[derive(Debug, Default)]
+struct Stats {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Stats {
+    fn add(&mut self, v: f64) {
+        self.count += 1;
+        let delta = v - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (v - self.mean);
+    }
+
+    // Distance of the value to the mean in standard deviations. The deviation is taken as at
+    // least a tenth of the mean, so that a client always moving the same amount doesn't make any
+    // other amount infinitely anomalous.
+    fn score(&self, v: f64) -> f64 {
+        let deviation = (self.m2 / self.count as f64).sqrt();
+        (v - self.mean) / deviation.max(self.mean.abs() / 10.0).max(f64::EPSILON)
+    }
+}
+
+// History of a client.
+#[derive(Debug, Default)]
+struct History {
+    deposits: Stats,
+    withdrawals: Stats,
+    recent: VecDeque<(u32, bool)>, // Last transactions, and whether they were disputes
+    transactions: u64,             // Transactions before the window
+    disputes: u64,                 // Disputes before the window
+}
+
+// Row of the anomalies file.
+#[derive(Debug, serde::Serialize)]
+struct AnomalyRecord {
+    client: u16,
+    anomaly: &'static str,
+    score: String,
+    transactions: String, // Ids of the contributing transactions, separated by ';'
+    detail: String,
+}
+
+// Opt-in analysis pass (--anomalies) flagging clients whose transactions deviate strongly from
+// their own history: deposits or withdrawals far above the amounts they usually move, and bursts
+// of disputes compared to how often they disputed before. Every anomaly is written to a CSV file
+// with its score and the transactions it was found on; the transactions are applied all the same.
+pub struct Anomalies {
+    writer: csv::Writer<File>,
+    clients: HashMap<u16, History>,
+    flagged: u64,
+}
+
+impl Anomalies {
+    pub fn create(path: &str) -> Result<Anomalies, csv::Error> {
+        Ok(Anomalies {
+            writer: csv::Writer::from_path(path)?,
+            clients: HashMap::new(),
+            flagged: 0,
+        })
+    }
+
+    fn flag(&mut self, record: AnomalyRecord) -> Result<(), csv::Error> {
+        self.flagged += 1;
+        self.writer.serialize(record)
+    }
+
+    pub fn record(&mut self, applied: &Applied) -> Result<(), csv::Error> {
+        let history = self.clients.entry(applied.client_id).or_default();
+        let mut anomalies = Vec::new();
+        let stats = match applied.kind {
+            TransactionType::Deposit => Some(&mut history.deposits),
+            TransactionType::Withdrawal => Some(&mut history.withdrawals),
+            _ => None,
+        };
+        if let (Some(stats), Some(amount)) = (stats, applied.amount) {
+            let amount = amount.to_f64();
+            if stats.count >= MIN_HISTORY {
+                let score = stats.score(amount);
+                if score >= SPIKE_SCORE {
+                    anomalies.push(AnomalyRecord {
+                        client: applied.client_id,
+                        anomaly: "amount_spike",
+                        score: format!("{:.4}", score),
+                        transactions: applied.tx.to_string(),
+                        detail: format!(
+                            "{} of {:.4} against a mean of {:.4} over {} transactions",
+                            applied.kind.as_str(),
+                            amount,
+                            stats.mean,
+                            stats.count
+                        ),
+                    });
+                }
+            }
+            stats.add(amount);
+        }
+        let dispute = applied.kind == TransactionType::Dispute;
+        history.recent.push_back((applied.tx, dispute));
+        if history.recent.len() > BURST_WINDOW {
+            if let Some((_, was_dispute)) = history.recent.pop_front() {
+                history.transactions += 1;
+                history.disputes += u64::from(was_dispute);
+            }
+        }
+        let disputes: Vec<u32> = history
+            .recent
+            .iter()
+            .filter(|(_, d)| *d)
+            .map(|(tx, _)| *tx)
+            .collect();
+        if dispute && disputes.len() >= BURST_DISPUTES {
+            let rate = match history.transactions {
+                0 => 0.0,
+                n => history.disputes as f64 / n as f64,
+            };
+            // At least one dispute in the window is always expected, to avoid flagging new
+            // clients on their very first disputes.
+            let expected = (rate * history.recent.len() as f64).max(1.0);
+            let score = disputes.len() as f64 / expected;
+            if score >= BURST_SCORE {
+                anomalies.push(AnomalyRecord {
+                    client: applied.client_id,
+                    anomaly: "dispute_burst",
+                    score: format!("{:.4}", score),
+                    transactions: disputes
+                        .iter()
+                        .map(u32::to_string)
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                    detail: format!(
+                        "{} disputes in the last {} transactions, {:.4} expected",
+                        disputes.len(),
+                        history.recent.len(),
+                        expected
+                    ),
+                });
+                // The disputes of a burst are only reported once.
+                for (_, d) in history.recent.iter_mut() {
+                    *d = false;
+                }
+                history.disputes += disputes.len() as u64;
+            }
+        }
+        for anomaly in anomalies {
+            self.flag(anomaly)?;
+        }
+        Ok(())
+    }
+
+    // Flushes the file and returns the number of anomalies found.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.flagged)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::output::Decimals;
+use anyhow::{anyhow, Result};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use ledger::Ledger;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+// Balances file, as rows by key (the client, and the sub-account when there is a subaccount
+// column), each row being its cells by column name.
+struct Balances {
+    columns: Vec<String>,
+    rows: BTreeMap<(String, String), BTreeMap<String, String>>,
+}
+
+impl Balances {
+    fn parse(name: &str, data: &[u8]) -> Result<Balances> {
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .comment(Some(b'#')) // --csv-metadata lines
+            .from_reader(data);
+        let headers = reader.headers()?.clone();
+        if !headers.iter().any(|h| h == "client") {
+            return Err(anyhow! {"{} has no client column", name});
+        }
+        let columns: Vec<String> = headers.iter().map(String::from).collect();
+        let mut rows = BTreeMap::new();
+        for record in reader.records() {
+            let record: StringRecord = record?;
+            let row: BTreeMap<String, String> = columns
+                .iter()
+                .cloned()
+                .zip(record.iter().map(String::from))
+                .collect();
+            let key = (
+                row.get("client").cloned().unwrap_or_default(),
+                row.get("subaccount").cloned().unwrap_or_default(),
+            );
+            if rows.insert(key.clone(), row).is_some() {
+                return Err(anyhow! {"{} lists {} twice", name, described(&key)});
+            }
+        }
+        Ok(Balances { columns, rows })
+    }
+}
+
+fn described((client, subaccount): &(String, String)) -> String {
+    match subaccount.as_str() {
+        "" => format!("client {}", client),
+        name => format!("client {} ({})", client, name),
+    }
+}
+
+// Cells are equal if they are the same number (the expected file may be written with another
+// number of decimal places, "1.5" for "1.5000") or else the same text.
+fn same(expected: &str, actual: &str) -> bool {
+    match (expected.parse::<f64>(), actual.parse::<f64>()) {
+        (Ok(e), Ok(a)) => e == a,
+        _ => expected == actual,
+    }
+}
+
+fn cells(row: &BTreeMap<String, String>, columns: &[String]) -> String {
+    let cells: Vec<&str> = columns
+        .iter()
+        .map(|c| row.get(c).map_or("", String::as_str))
+        .collect();
+    cells.join(",")
+}
+
+// `ledger assert-state <expected.csv> <input>`: processes the input like the balances command,
+// then checks that the final balances are exactly those of the expected file, in the CSV output
+// format (in any row and column order). The differences are written to the output, one line per
+// differing cell, missing or unexpected account, and the run exits with status 1 so that it can
+// gate a deployment. Returns whether the balances match.
+pub fn write(expected: &str, l: &Ledger, out: &mut impl Write) -> Result<bool> {
+    let data = std::fs::read(expected)
+        .map_err(|e| anyhow! {"cannot read expected balances {}: {}", expected, e})?;
+    let expected_balances = Balances::parse(expected, &data)?;
+    let mut actual = Vec::new();
+    crate::output::write_csv(l, None, &Decimals::default(), 1, &mut actual)?;
+    let actual = Balances::parse("output", &actual)?;
+
+    let mut differences = 0;
+    let missing: Vec<&String> = (expected_balances.columns.iter())
+        .filter(|c| !actual.columns.contains(c))
+        .collect();
+    let unexpected: Vec<&String> = (actual.columns.iter())
+        .filter(|c| !expected_balances.columns.contains(c))
+        .collect();
+    for c in &missing {
+        writeln!(out, "- column {}", c)?;
+        differences += 1;
+    }
+    for c in &unexpected {
+        writeln!(out, "+ column {}", c)?;
+        differences += 1;
+    }
+    for (key, row) in &expected_balances.rows {
+        let Some(actual_row) = actual.rows.get(key) else {
+            let line = cells(row, &expected_balances.columns);
+            writeln!(out, "- {}: {}", described(key), line)?;
+            differences += 1;
+            continue;
+        };
+        for (column, e) in row {
+            match actual_row.get(column) {
+                Some(a) if !same(e, a) => {
+                    writeln!(out, "~ {}: {} {} != {}", described(key), column, e, a)?;
+                    differences += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    for (key, row) in &actual.rows {
+        if !expected_balances.rows.contains_key(key) {
+            writeln!(out, "+ {}: {}", described(key), cells(row, &actual.columns))?;
+            differences += 1;
+        }
+    }
+    if differences > 0 {
+        writeln!(
+            out,
+            "State differs from {}: {} differences",
+            expected, differences
+        )?;
+    } else {
+        writeln!(
+            out,
+            "State matches {} ({} rows)",
+            expected,
+            actual.rows.len()
+        )?;
+    }
+    Ok(differences == 0)
+}
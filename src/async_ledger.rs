@@ -0,0 +1,134 @@
+use crate::{
+    credit_overflow, process_transaction, sweep_target, Account, Applied, Config,
+    ExchangeRateProvider, LedgerError, Rates, TransactionEntry,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, PoisonError};
+
+// Storage of accounts for AsyncLedger. Implementations talk to whatever holds the accounts (a
+// database, a remote service, ...) without blocking the executor. Failures are reported as
+// LedgerError::Storage.
+pub trait AsyncLedgerStore {
+    // Returns the account of the given client, or None if the client has not been seen yet.
+    fn load(
+        &self,
+        client_id: u16,
+    ) -> impl Future<Output = Result<Option<Account>, LedgerError>> + Send;
+
+    // Runs the change on the account of the given client (a new one if the client has not been
+    // seen yet), and keeps the account as the change leaves it. Updates of the same account must
+    // not interleave (e.g. a row lock or a compare-and-set in a database), as many clients sweep
+    // into the same overflow account.
+    fn update<R: Send>(
+        &self,
+        client_id: u16,
+        change: impl FnOnce(&mut Account) -> R + Send,
+    ) -> impl Future<Output = Result<R, LedgerError>> + Send;
+}
+
+// Async counterpart of Ledger, applying transactions to accounts kept in an AsyncLedgerStore.
+// Applying a transaction runs it through the same state machine as Ledger in an update of the
+// account, and credits any excess swept to the overflow account in another. Callers await the
+// transactions of a client one after the other (e.g. one task per client or shard), to keep them
+// in input order.
+#[derive(Debug, Default)]
+pub struct AsyncLedger<S> {
+    store: S,
+    config: Config,
+    rates: Rates,
+}
+
+impl<S: AsyncLedgerStore> AsyncLedger<S> {
+    pub fn new(store: S) -> AsyncLedger<S> {
+        AsyncLedger::with_config(store, Config::default())
+    }
+
+    pub fn with_config(store: S, config: Config) -> AsyncLedger<S> {
+        AsyncLedger {
+            store,
+            config,
+            rates: Rates::default(),
+        }
+    }
+
+    pub fn set_rates(&mut self, rates: impl ExchangeRateProvider + 'static) {
+        self.rates = Rates(Box::new(rates));
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub async fn apply_transaction(&self, tx: TransactionEntry) -> Result<Applied, LedgerError> {
+        // Like Ledger, a client is created by its first transaction even when that transaction
+        // is rejected, which leaves the account untouched otherwise.
+        let (config, rates) = (&self.config, &*self.rates.0);
+        // The room of the overflow account for the swept part is that of before the update of
+        // the client: as the two accounts are updated one after the other, a concurrent sweep may
+        // take it in between, and the credit of the swept part then fails with the error.
+        let overflow = match sweep_target(&tx, config) {
+            Some(o) => self.store.load(o).await?.map(|a| a.rollup()),
+            None => None,
+        };
+        let result = self
+            .store
+            .update(tx.client_id, |account| {
+                process_transaction(tx, account, config, rates, None, overflow)
+            })
+            .await?;
+        if let Ok(Applied {
+            client_id,
+            tx,
+            swept: Some((overflow_account, excess)),
+            ..
+        }) = result
+        {
+            self.store
+                .update(overflow_account, |overflow| {
+                    credit_overflow(client_id, tx, excess, overflow)
+                })
+                .await??;
+        }
+        result
+    }
+
+    pub async fn account(&self, client_id: u16) -> Result<Option<Account>, LedgerError> {
+        self.store.load(client_id).await
+    }
+}
+
+// In-memory AsyncLedgerStore, updating the accounts in place. The lock is only held for the
+// duration of a map lookup or of an update, so it never blocks the executor for long.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    accounts: Mutex<HashMap<u16, Account>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn into_accounts(self) -> HashMap<u16, Account> {
+        self.accounts
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl AsyncLedgerStore for MemoryStore {
+    async fn load(&self, client_id: u16) -> Result<Option<Account>, LedgerError> {
+        let accounts = self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(accounts.get(&client_id).cloned())
+    }
+
+    async fn update<R: Send>(
+        &self,
+        client_id: u16,
+        change: impl FnOnce(&mut Account) -> R + Send,
+    ) -> Result<R, LedgerError> {
+        let mut accounts = self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(change(accounts.entry(client_id).or_default()))
+    }
+}
@@ -0,0 +1,399 @@
+use crate::encryption::{self, LogWriter};
+use anyhow::{anyhow, Result};
+use ledger::{Amount, Applied, LegalHold, Money, Timestamp};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// An applied transaction, as written to the audit log. Amount is the amount the transaction
+// actually moved and the balances are those of the account after the transaction. Note describes
+// the path taken when a policy decided how the transaction was applied. Expired marks the
+// reversals of expired bonuses and authorizations, which are no transactions of the input: their
+// timestamp is the time they expired at.
+#[derive(Debug, serde::Serialize)]
+struct AuditRecord<'a> {
+    line: u64,
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subaccount: Option<&'a str>,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<Amount>,
+    available: Amount,
+    held: Amount,
+    #[serde(skip_serializing_if = "is_zero")]
+    escrow: Amount,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    expired: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+}
+
+fn is_zero(v: &Amount) -> bool {
+    *v == Amount::ZERO
+}
+
+// Writes every applied transaction to a file, one JSON object per line (NDJSON), so that the
+// resulting balances can be traced back to the input. It is encrypted if there is an encryption
+// key, like snapshots (see encryption::LogWriter).
+pub struct AuditLog {
+    writer: LogWriter<BufWriter<File>>,
+}
+
+impl AuditLog {
+    pub fn create(path: &str) -> Result<AuditLog> {
+        Ok(AuditLog {
+            writer: LogWriter::create(path)?,
+        })
+    }
+
+    // Carries on the log of an interrupted run, see checkpoint::reopen.
+    pub fn resume(path: &str, file: File) -> Result<AuditLog> {
+        Ok(AuditLog {
+            writer: LogWriter::resume(path, file)?,
+        })
+    }
+
+    // Flushes the log and returns its length, for the checkpoints.
+    pub fn len(&mut self) -> io::Result<u64> {
+        self.writer.len()
+    }
+
+    pub fn write(&mut self, line: u64, applied: &Applied, expired: bool) -> io::Result<()> {
+        let record = AuditRecord {
+            line,
+            client: applied.client_id,
+            subaccount: applied.subaccount.as_deref(),
+            tx: applied.tx,
+            kind: applied.kind.as_str(),
+            amount: applied.amount,
+            available: applied.after.available,
+            held: applied.after.held,
+            escrow: applied.after.escrow,
+            locked: applied.after.locked,
+            timestamp: applied.timestamp,
+            expired,
+            note: applied.note.as_deref(),
+            tags: &applied.tags,
+            memo: applied.memo.as_deref(),
+            reason: applied.reason.as_deref(),
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// What compaction needs of a record of the log: where it comes from and the balances it left.
+#[derive(Debug, serde::Deserialize)]
+struct LoggedRecord {
+    line: u64,
+    client: u16,
+    tx: Option<u32>, // None for a ForgottenRecord
+    subaccount: Option<String>,
+    available: Amount,
+    held: Amount,
+    #[serde(default)]
+    escrow: Amount,
+    locked: bool,
+    forgotten: Option<u64>, // Of a ForgottenRecord
+}
+
+// Balances of an account (or sub-account) at a checkpoint.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct CheckpointAccount {
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subaccount: Option<String>,
+    available: Amount,
+    held: Amount,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    escrow: Amount,
+    locked: bool,
+}
+
+// First line of a compacted log, standing for the records folded into it: how many there were,
+// the input line of the last one, the SHA-256 digest of their lines (each followed by a line
+// break) and the balances they left, for every account they touched. The folded lines include any
+// earlier checkpoint, so the digests of successive compactions chain up.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Checkpoint {
+    records: u64,
+    through_line: u64,
+    sha256: String,
+    accounts: Vec<CheckpointAccount>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct CheckpointRecord {
+    checkpoint: Checkpoint,
+}
+
+// `ledger audit compact <log> [--before-line N] [--config c.toml] [--snapshot s.json]`: folds the
+// records of an audit log from input lines before N (all of them by default) into a checkpoint
+// record, and rewrites the log as that checkpoint followed by the records which were kept. The
+// records under the legal holds of the config file or snapshot (see legal_hold::holds) are kept,
+// and compaction stops before the first of them, as only the start of the log is folded.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    if it.next().map(String::as_str) != Some("compact") {
+        return Err(anyhow! {"audit requires a command: compact"});
+    }
+    let mut path = None;
+    let (mut before_line, mut config, mut snapshot) = (None, None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--before-line" => before_line = Some(value()?.parse()?),
+            "--config" => config = Some(value()?.clone()),
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let path = path.ok_or_else(|| anyhow! {"audit compact requires the audit log to compact"})?;
+    let holds = crate::legal_hold::holds(config.as_deref(), snapshot.as_deref())?;
+    compact(&path, before_line.unwrap_or(u64::MAX), &holds)
+}
+
+// Whether the record is under one of the holds. The tombstone of a forgotten client stands for
+// all its transactions.
+fn is_held(r: &LoggedRecord, holds: &[LegalHold]) -> bool {
+    holds
+        .iter()
+        .any(|h| h.client == r.client && (h.tx.is_none() || r.tx.is_none() || h.tx == r.tx))
+}
+
+fn compact(path: &str, before_line: u64, holds: &[LegalHold]) -> Result<()> {
+    let contents = encryption::read_log(path)
+        .map_err(|e| anyhow! {"cannot read audit log {}: {}", path, e})?;
+    let invalid = |n: usize, e: &dyn std::fmt::Display| {
+        anyhow! {"invalid audit log {}: line {}: {}", path, n + 1, e}
+    };
+    let mut folded = String::new();
+    let (mut records, mut through_line) = (0, 0);
+    let mut accounts: BTreeMap<(u16, Option<String>), CheckpointAccount> = BTreeMap::new();
+    let mut kept = Vec::new();
+    let mut newly_folded = 0;
+    for (n, line) in contents.lines().enumerate() {
+        if !kept.is_empty() {
+            kept.push(line);
+            continue;
+        }
+        if n == 0 && line.starts_with(r#"{"checkpoint":"#) {
+            let c: CheckpointRecord = serde_json::from_str(line).map_err(|e| invalid(n, &e))?;
+            (records, through_line) = (c.checkpoint.records, c.checkpoint.through_line);
+            for a in c.checkpoint.accounts {
+                accounts.insert((a.client, a.subaccount.clone()), a);
+            }
+        } else {
+            let r: LoggedRecord = serde_json::from_str(line).map_err(|e| invalid(n, &e))?;
+            if r.line >= before_line {
+                kept.push(line);
+                continue;
+            }
+            if is_held(&r, holds) {
+                eprintln!(
+                    "Record of input line {} is under legal hold, compaction stops before it",
+                    r.line
+                );
+                kept.push(line);
+                continue;
+            }
+            records += 1;
+            newly_folded += 1;
+            through_line = r.line;
+            // The tombstone of a forgotten client stands for all its sub-accounts.
+            if r.forgotten.is_some() {
+                accounts.retain(|(client, _), _| *client != r.client);
+            }
+            let account = CheckpointAccount {
+                client: r.client,
+                subaccount: r.subaccount,
+                available: r.available,
+                held: r.held,
+                escrow: r.escrow,
+                locked: r.locked,
+            };
+            accounts.insert((account.client, account.subaccount.clone()), account);
+        }
+        folded.push_str(line);
+        folded.push('\n');
+    }
+    if newly_folded == 0 {
+        eprintln!("Nothing to compact in {}", path);
+        return Ok(());
+    }
+    let checkpoint = CheckpointRecord {
+        checkpoint: Checkpoint {
+            records,
+            through_line,
+            sha256: crate::metadata::hex_digest(folded.as_bytes()),
+            accounts: accounts.into_values().collect(),
+        },
+    };
+    // The compacted log replaces the old one only once it is complete, encrypted like the log is
+    // written.
+    let tmp = format!("{}.tmp", path);
+    let mut out = LogWriter::create(&tmp)?;
+    serde_json::to_writer(&mut out, &checkpoint)?;
+    out.write_all(b"\n")?;
+    for line in &kept {
+        writeln!(out, "{}", line)?;
+    }
+    out.sync()?;
+    std::fs::rename(&tmp, path)?;
+    eprintln!(
+        "Compacted {} records through line {} into a checkpoint, kept {} records",
+        newly_folded,
+        through_line,
+        kept.len()
+    );
+    Ok(())
+}
+
+// Tombstone of a forgotten client (see `ledger forget`), in place of its last record: how many
+// records of the client were removed from the log, and the balances they left, over all its
+// sub-accounts. It starts with the forgotten field, for replay to tell it from records.
+#[derive(Debug, serde::Serialize)]
+struct ForgottenRecord {
+    forgotten: u64,
+    line: u64,
+    client: u16,
+    available: Amount,
+    held: Amount,
+    #[serde(skip_serializing_if = "is_zero")]
+    escrow: Amount,
+    locked: bool,
+}
+
+// Sum of the balances of the sub-accounts, locked if any of them is. The balances come from the
+// log, so their sum may be out of range.
+fn rolled_up<'a>(
+    client: u16,
+    mut balances: impl Iterator<Item = &'a CheckpointAccount>,
+) -> Result<CheckpointAccount> {
+    let zero = CheckpointAccount {
+        client,
+        subaccount: None,
+        available: Amount::ZERO,
+        held: Amount::ZERO,
+        escrow: Amount::ZERO,
+        locked: false,
+    };
+    balances
+        .try_fold(zero, |total, b| {
+            Some(CheckpointAccount {
+                available: total.available.checked_add(b.available)?,
+                held: total.held.checked_add(b.held)?,
+                escrow: total.escrow.checked_add(b.escrow)?,
+                locked: total.locked || b.locked,
+                ..total
+            })
+        })
+        .ok_or_else(|| anyhow! {"balances of client {} out of range", client})
+}
+
+// Removes the records of the client from an audit log, leaving a tombstone (see ForgottenRecord)
+// in place of the last one, and the balances of the client in the checkpoint of a compacted log
+// rolled up in the same way, so that compaction still adds up. The log is rewritten through a
+// temporary file, like by compaction. Returns the number of records removed, those of an earlier
+// tombstone included.
+pub fn forget(path: &str, client: u16) -> Result<u64> {
+    let contents = encryption::read_log(path)
+        .map_err(|e| anyhow! {"cannot read audit log {}: {}", path, e})?;
+    let invalid = |n: usize, e: &dyn std::fmt::Display| {
+        anyhow! {"invalid audit log {}: line {}: {}", path, n + 1, e}
+    };
+    let mut checkpoint = None;
+    // The latest balances of every sub-account of the client, and the lines of its records.
+    let mut balances: BTreeMap<Option<String>, CheckpointAccount> = BTreeMap::new();
+    let mut lines = BTreeSet::new();
+    let (mut removed, mut last_line) = (0, 0);
+    for (n, line) in contents.lines().enumerate() {
+        if n == 0 && line.starts_with(r#"{"checkpoint":"#) {
+            let mut c: CheckpointRecord = serde_json::from_str(line).map_err(|e| invalid(n, &e))?;
+            let (theirs, others): (Vec<_>, Vec<_>) = c
+                .checkpoint
+                .accounts
+                .into_iter()
+                .partition(|a| a.client == client);
+            c.checkpoint.accounts = others;
+            if !theirs.is_empty() {
+                c.checkpoint
+                    .accounts
+                    .push(rolled_up(client, theirs.iter())?);
+                c.checkpoint.accounts.sort_by_key(|a| a.client);
+                checkpoint = Some(c);
+            }
+            for a in theirs {
+                balances.insert(a.subaccount.clone(), a);
+            }
+            continue;
+        }
+        let r: LoggedRecord = serde_json::from_str(line).map_err(|e| invalid(n, &e))?;
+        if r.client != client {
+            continue;
+        }
+        if r.forgotten.is_some() {
+            balances.clear();
+        }
+        removed += r.forgotten.unwrap_or(1);
+        lines.insert(n);
+        last_line = r.line;
+        let account = CheckpointAccount {
+            client,
+            subaccount: r.subaccount,
+            available: r.available,
+            held: r.held,
+            escrow: r.escrow,
+            locked: r.locked,
+        };
+        balances.insert(account.subaccount.clone(), account);
+    }
+    if checkpoint.is_none() && lines.is_empty() {
+        return Ok(0);
+    }
+    let b = rolled_up(client, balances.values())?;
+    let tombstone = ForgottenRecord {
+        forgotten: removed,
+        line: last_line,
+        client,
+        available: b.available,
+        held: b.held,
+        escrow: b.escrow,
+        locked: b.locked,
+    };
+    let tmp = format!("{}.tmp", path);
+    let mut out = LogWriter::create(&tmp)?;
+    for (n, line) in contents.lines().enumerate() {
+        if let (0, Some(c)) = (n, &checkpoint) {
+            serde_json::to_writer(&mut out, c)?;
+            out.write_all(b"\n")?;
+        } else if lines.last() == Some(&n) {
+            serde_json::to_writer(&mut out, &tombstone)?;
+            out.write_all(b"\n")?;
+        } else if !lines.contains(&n) {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    out.sync()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(removed)
+}
@@ -0,0 +1,241 @@
+use crate::output::OutputFormat;
+use ledger::{Applied, LegalHold, Money, TransactionType};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+// Round amounts are whole multiples of this; they are only listed when seen at least twice.
+const ROUND_UNIT: i64 = 10;
+// Transactions of a client with the same amount from which they make a cluster.
+const MIN_CLUSTER: usize = 3;
+
+// Amount in ten-thousandths, the precision of the outputs, so that amounts can be compared
+// exactly.
+type Amount = i64;
+
+fn amount_key(amount: ledger::Amount) -> Amount {
+    (amount.to_f64() * 10000.0).round() as Amount
+}
+
+fn amount_value(amount: Amount) -> f64 {
+    amount as f64 / 10000.0
+}
+
+// First significant digit of a positive amount.
+fn first_digit(amount: Amount) -> Option<usize> {
+    let mut v = amount.checked_abs()?;
+    if v == 0 {
+        return None;
+    }
+    while v >= 10 {
+        v /= 10;
+    }
+    Some(v as usize)
+}
+
+// Statistics over the amounts of the applied transactions, which the fraud team uses to pick
+// samples for manual review: the distribution of the first digits against Benford's law, how often
+// round amounts repeat, and the clusters of transactions of a client with the same amount.
+// Transactions without an amount of their own in the input (disputes, resolves and chargebacks)
+// are left out.
+#[derive(Debug, Default)]
+pub struct AuditStats {
+    transactions: u64,
+    first_digits: [u64; 10],
+    amounts: HashMap<(u16, Amount), Vec<u32>>, // Transactions by client and amount
+}
+
+impl AuditStats {
+    pub fn record(&mut self, applied: &Applied) {
+        let dispute = matches!(
+            applied.kind,
+            TransactionType::Dispute
+                | TransactionType::Representment
+                | TransactionType::PreArbitration
+                | TransactionType::Arbitration
+                | TransactionType::ChargebackReversal
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+        );
+        let Some(amount) = applied.amount.filter(|_| !dispute) else {
+            return;
+        };
+        let amount = amount_key(amount);
+        self.transactions += 1;
+        if let Some(digit) = first_digit(amount) {
+            self.first_digits[digit] += 1;
+        }
+        self.amounts
+            .entry((applied.client_id, amount))
+            .or_default()
+            .push(applied.tx);
+    }
+
+    // Number of transactions of every round amount seen at least twice, by amount.
+    fn round_amounts(&self) -> BTreeMap<Amount, u64> {
+        let mut counts = BTreeMap::new();
+        for ((_, amount), txs) in &self.amounts {
+            if *amount != 0 && amount % (ROUND_UNIT * 10000) == 0 {
+                *counts.entry(*amount).or_default() += txs.len() as u64;
+            }
+        }
+        counts.retain(|_, count| *count > 1);
+        counts
+    }
+
+    // Clusters of transactions of a client with the same amount, by client and amount.
+    fn clusters(&self) -> BTreeMap<(u16, Amount), &[u32]> {
+        self.amounts
+            .iter()
+            .filter(|(_, txs)| txs.len() >= MIN_CLUSTER)
+            .map(|(key, txs)| (*key, txs.as_slice()))
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct DigitRecord {
+    digit: usize,
+    count: u64,
+    share: f64,
+    expected: f64, // Share expected by Benford's law
+}
+
+#[derive(serde::Serialize)]
+struct RoundAmountRecord {
+    amount: f64,
+    count: u64,
+}
+
+#[derive(serde::Serialize)]
+struct ClusterRecord<'a> {
+    client: u16,
+    amount: f64,
+    count: usize,
+    transactions: &'a [u32],
+}
+
+#[derive(serde::Serialize)]
+struct JsonStats<'a> {
+    transactions: u64,
+    first_digits: Vec<DigitRecord>,
+    round_amounts: Vec<RoundAmountRecord>,
+    clusters: Vec<ClusterRecord<'a>>,
+    legal_holds: &'a [&'a LegalHold],
+}
+
+fn share(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    (count as f64 / total as f64 * 10000.0).round() / 10000.0
+}
+
+// Writes the statistics in CSV, as a single table whose rows belong to one of four sections, the
+// last listing the legal holds of the ledger (by reference, all the history of the client without
+// a transaction):
+//
+//     section,key,client,count,share,expected,transactions
+//     first_digit,1,,31,0.3100,0.3010,
+//     round_amount,100.0000,,4,,,
+//     cluster,25.0000,7,3,,,12;15;19
+//     legal_hold,case-2291,7,,,,15
+//
+// or in JSON, as an object with a first_digits, a round_amounts, a clusters and a legal_holds
+// array.
+pub fn write(
+    stats: &AuditStats,
+    holds: &[&LegalHold],
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let digits = (1..10).map(|digit| DigitRecord {
+        digit,
+        count: stats.first_digits[digit],
+        share: share(stats.first_digits[digit], stats.transactions),
+        expected: ((1.0 + 1.0 / digit as f64).log10() * 10000.0).round() / 10000.0,
+    });
+    let round_amounts =
+        stats
+            .round_amounts()
+            .into_iter()
+            .map(|(amount, count)| RoundAmountRecord {
+                amount: amount_value(amount),
+                count,
+            });
+    let clusters = stats.clusters();
+    let clusters = clusters
+        .iter()
+        .map(|((client, amount), txs)| ClusterRecord {
+            client: *client,
+            amount: amount_value(*amount),
+            count: txs.len(),
+            transactions: txs,
+        });
+    if format == OutputFormat::Json {
+        let json = JsonStats {
+            transactions: stats.transactions,
+            first_digits: digits.collect(),
+            round_amounts: round_amounts.collect(),
+            clusters: clusters.collect(),
+            legal_holds: holds,
+        };
+        serde_json::to_writer_pretty(&mut *out, &json)?;
+        return writeln!(out);
+    }
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "section",
+        "key",
+        "client",
+        "count",
+        "share",
+        "expected",
+        "transactions",
+    ])?;
+    for d in digits {
+        writer.write_record([
+            "first_digit",
+            &d.digit.to_string(),
+            "",
+            &d.count.to_string(),
+            &format!("{:.4}", d.share),
+            &format!("{:.4}", d.expected),
+            "",
+        ])?;
+    }
+    for r in round_amounts {
+        writer.write_record([
+            "round_amount",
+            &format!("{:.4}", r.amount),
+            "",
+            &r.count.to_string(),
+            "",
+            "",
+            "",
+        ])?;
+    }
+    for c in clusters {
+        let txs: Vec<String> = c.transactions.iter().map(u32::to_string).collect();
+        writer.write_record([
+            "cluster",
+            &format!("{:.4}", c.amount),
+            &c.client.to_string(),
+            &c.count.to_string(),
+            "",
+            "",
+            &txs.join(";"),
+        ])?;
+    }
+    for h in holds {
+        writer.write_record([
+            "legal_hold",
+            h.reference.as_deref().unwrap_or_default(),
+            &h.client.to_string(),
+            "",
+            "",
+            "",
+            &h.tx.map(|tx| tx.to_string()).unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()
+}
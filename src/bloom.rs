@@ -0,0 +1,67 @@
+use crate::slab::Accounts;
+
+// Bits per expected transaction and hashes per id, for a false positive rate of about 1%.
+const BITS_PER_ID: u64 = 10;
+const HASHES: u32 = 7;
+
+// Bloom filter over the transaction ids logged by a ledger (Config::duplicate_filter_capacity).
+// Most transactions of a stream aren't duplicates, and the filter tells so without looking up the
+// oplog of the account; only ids it may have seen go on to the oplog lookup. Past its capacity
+// the filter still never misses a logged id, it just lets more new ids through to the lookup.
+#[derive(Clone, Debug)]
+pub(crate) struct Bloom {
+    words: Vec<u64>,
+}
+
+// SplitMix64 finalizer, spreading the bits of consecutive ids.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl Bloom {
+    pub(crate) fn new(capacity: u64) -> Bloom {
+        let bits = (capacity.max(1) * BITS_PER_ID).next_power_of_two().max(64);
+        Bloom {
+            words: vec![0; (bits / 64) as usize],
+        }
+    }
+
+    // The filter of a ledger made of existing accounts, holding the ids of all their oplogs.
+    pub(crate) fn of(capacity: u64, accounts: &Accounts) -> Bloom {
+        let mut bloom = Bloom::new(capacity);
+        for (_, a) in accounts.iter() {
+            for a in a.subaccounts.values().chain([a]) {
+                for (tx_id, _) in a.oplog.iter() {
+                    bloom.insert(tx_id);
+                }
+            }
+        }
+        bloom
+    }
+
+    // Bit positions of an id, by double hashing.
+    fn bits(&self, tx_id: u32) -> impl Iterator<Item = usize> {
+        let h = mix(u64::from(tx_id));
+        let (h1, h2) = (h & 0xffff_ffff, (h >> 32) | 1);
+        let mask = self.words.len() as u64 * 64 - 1;
+        (0..u64::from(HASHES)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & mask) as usize)
+    }
+
+    pub(crate) fn insert(&mut self, tx_id: u32) {
+        let mut bits = [0; HASHES as usize];
+        for (slot, bit) in bits.iter_mut().zip(self.bits(tx_id)) {
+            *slot = bit;
+        }
+        for bit in bits {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    // False if the id was never inserted, true if it may have been.
+    pub(crate) fn may_contain(&self, tx_id: u32) -> bool {
+        self.bits(tx_id)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
@@ -0,0 +1,121 @@
+use crate::output::Decimals;
+use ledger::{Applied, Ledger};
+use std::fs::File;
+use std::io;
+
+// Change of a field of an account, as written to the CDC output.
+#[derive(Debug, serde::Serialize)]
+pub struct Change<'a> {
+    client: u16,
+    subaccount: Option<&'a str>,
+    field: &'static str,
+    old: String,
+    new: String,
+    tx: u32, // Transaction causing the change
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+// Change-data-capture output (--cdc-out): a CSV row every time the available funds, the held
+// funds or the lock of an account (or sub-account) change, with the old and new values and the
+// transaction causing the change, in the order the changes happen. Replaying the rows keeps a
+// copy of the balances up to date. Deposits swept into the overflow account change its available
+// funds as well.
+pub struct CdcOut {
+    writer: csv::Writer<File>,
+    decimals: Decimals,
+    changes: u64,
+}
+
+// Changes caused by an applied operation, in the order they are written.
+pub fn changes<'a>(applied: &'a Applied, l: &Ledger, d: &Decimals) -> Vec<Change<'a>> {
+    let (before, after) = (&applied.before, &applied.after);
+    let client = applied.client_id;
+    let subaccount = applied.subaccount.as_deref();
+    let amounts = |column, old, new| (d.format(column, old), d.format(column, new));
+    let mut fields = vec![
+        (
+            client,
+            subaccount,
+            "available",
+            amounts("available", before.available, after.available),
+        ),
+        (
+            client,
+            subaccount,
+            "held",
+            amounts("held", before.held, after.held),
+        ),
+        (
+            client,
+            subaccount,
+            "locked",
+            (before.locked.to_string(), after.locked.to_string()),
+        ),
+    ];
+    if let Some((overflow, swept)) = applied.swept {
+        let new = l.account(overflow).map_or(swept, |a| a.available());
+        fields.push((
+            overflow,
+            None,
+            "available",
+            amounts("available", new - swept, new),
+        ));
+    }
+    fields
+        .into_iter()
+        .filter(|(_, _, _, (old, new))| old != new)
+        .map(|(client, subaccount, field, (old, new))| Change {
+            client,
+            subaccount,
+            field,
+            old,
+            new,
+            tx: applied.tx,
+            kind: applied.kind.as_str(),
+        })
+        .collect()
+}
+
+impl CdcOut {
+    pub fn create(path: &str, decimals: Decimals) -> Result<CdcOut, csv::Error> {
+        Ok(CdcOut {
+            writer: csv::Writer::from_path(path)?,
+            decimals,
+            changes: 0,
+        })
+    }
+
+    // Carries on the file of an interrupted run (see checkpoint::reopen), which has the header
+    // unless it is empty. The changes counted are those of this run.
+    pub fn resume(file: File, decimals: Decimals) -> io::Result<CdcOut> {
+        let empty = file.metadata()?.len() == 0;
+        Ok(CdcOut {
+            writer: csv::WriterBuilder::new()
+                .has_headers(empty)
+                .from_writer(file),
+            decimals,
+            changes: 0,
+        })
+    }
+
+    // Flushes the file and returns its length, for the checkpoints.
+    pub fn len(&mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+
+    pub fn record(&mut self, applied: &Applied, l: &Ledger) -> Result<(), csv::Error> {
+        for change in changes(applied, l, &self.decimals) {
+            self.changes += 1;
+            self.writer.serialize(change)?;
+        }
+        Ok(())
+    }
+
+    // Flushes the file and returns the number of changes written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.changes)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::encryption;
+use anyhow::{anyhow, Result};
+use ledger::{Config, Ledger};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom};
+use std::path::Path;
+
+// Name of the checkpoint in the --checkpoint-dir. Only the latest checkpoint is kept.
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+// Checkpoint of a run (--checkpoint-every), from which --resume-from-checkpoint carries on: the
+// ledger after the first rows records of the input, the input they were read from, and the length
+// of each output file of the records (audit log, rejects, ...) by path, see reopen.
+#[derive(serde::Serialize)]
+struct CheckpointRef<'a> {
+    input: &'a str,
+    rows: u64,
+    ledger: &'a Ledger,
+    outputs: &'a BTreeMap<String, u64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Checkpoint {
+    input: String,
+    pub rows: u64,
+    pub ledger: Ledger,
+    #[serde(default)]
+    pub outputs: BTreeMap<String, u64>,
+}
+
+// Writes the checkpoint through a temporary file, so that a crash while writing it leaves the
+// previous one in place. It is encrypted if there is an encryption key, like snapshots.
+pub fn write(
+    dir: &str,
+    input: &str,
+    rows: u64,
+    l: &Ledger,
+    outputs: &BTreeMap<String, u64>,
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = Path::new(dir).join(CHECKPOINT_FILE);
+    let tmp = path.with_extension("json.tmp");
+    let contents = serde_json::to_vec(&CheckpointRef {
+        input,
+        rows,
+        ledger: l,
+        outputs,
+    })?;
+    encryption::write(&tmp.to_string_lossy(), contents, true)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+// Reads the checkpoint of the directory, or None if there is no checkpoint yet. The checkpoint has
+// to be of the same input, and taken with the configuration of the run.
+pub fn read(dir: &str, input: &str, config: &Config) -> Result<Option<Checkpoint>> {
+    let path = Path::new(dir).join(CHECKPOINT_FILE);
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow! {"cannot read checkpoint {}: {}", path.display(), e}),
+    };
+    let contents = encryption::open(&path.to_string_lossy(), contents)?;
+    let checkpoint: Checkpoint = serde_json::from_slice(&contents)
+        .map_err(|e| anyhow! {"invalid checkpoint {}: {}", path.display(), e})?;
+    if checkpoint.input != input {
+        return Err(anyhow! {
+            "checkpoint {} is of another input ({})",
+            path.display(),
+            checkpoint.input
+        });
+    }
+    if checkpoint.ledger.config() != config {
+        return Err(
+            anyhow! {"checkpoint {} was taken with a different configuration", path.display()},
+        );
+    }
+    Ok(Some(checkpoint))
+}
+
+// Opens an output of the records for a run resuming from the checkpoint, cut back to the length
+// it had at the checkpoint (dropping what the interrupted run wrote after it) and positioned at the
+// end, so that the run carries on writing it and it covers the whole input once. An output the
+// checkpoint has no length for would only cover the records after the checkpoint, so it is an
+// error rather than overwritten.
+pub fn reopen(path: &str, outputs: &BTreeMap<String, u64>) -> Result<File> {
+    let len = *outputs.get(path).ok_or_else(|| {
+        anyhow! {"{} is not an output of the run of the checkpoint, so it can't be resumed", path}
+    })?;
+    let reopened = || -> std::io::Result<File> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() < len {
+            return Err(std::io::Error::other(format!(
+                "shorter than the {} bytes it had at the checkpoint",
+                len
+            )));
+        }
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(file)
+    };
+    reopened().map_err(|e| anyhow! {"cannot resume {}: {}", path, e})
+}
@@ -0,0 +1,299 @@
+use crate::output::Decimals;
+use anyhow::{anyhow, Result};
+use ledger::{Applied, Timestamp};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+// Events sent per INSERT.
+const BATCH_EVENTS: usize = 1000;
+// Attempts at sending a batch, the delay between attempts doubling from RETRY_DELAY.
+const ATTEMPTS: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Default table receiving the events.
+pub const EVENTS_TABLE: &str = "ledger_events";
+
+// Applied operation, as inserted into ClickHouse: a row of the events table, which must have the
+// columns
+//
+//     tx UInt32, client UInt16, type String, amount Nullable(Float64), available Float64,
+//     held Float64, total Float64, locked Bool, timestamp Nullable(DateTime)
+#[derive(Debug, serde::Serialize)]
+struct Event {
+    tx: u32,
+    client: u16,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<f64>,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    timestamp: Option<Timestamp>,
+}
+
+// Streams the applied operations into a ClickHouse table over its HTTP interface (--events-sink
+// http://[user:password@]host:port), in batches of JSONEachRow INSERTs. A batch failing even
+// after retrying is an error, which stops the run: the events are never dropped silently.
+pub struct EventsSink {
+    host: String, // host[:port]
+    credentials: Option<(String, String)>,
+    table: String,
+    batch: Vec<String>, // Events serialized as JSON
+    decimals: Decimals,
+}
+
+fn percent_encoded(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl EventsSink {
+    pub fn new(url: &str, table: &str, decimals: Decimals) -> Result<EventsSink> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow! {"unsupported events sink {} (expected http://...)", url})?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        let (credentials, host) = match authority.rsplit_once('@') {
+            Some((credentials, host)) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                (Some((user.to_string(), password.to_string())), host)
+            }
+            None => (None, authority),
+        };
+        if host.is_empty() {
+            return Err(anyhow! {"invalid events sink {}: missing host", url});
+        }
+        Ok(EventsSink {
+            host: host.to_string(),
+            credentials,
+            table: table.to_string(),
+            batch: Vec::new(),
+            decimals,
+        })
+    }
+
+    pub fn record(&mut self, applied: &Applied) -> Result<()> {
+        let d = &self.decimals;
+        let event = Event {
+            tx: applied.tx,
+            client: applied.client_id,
+            kind: applied.kind.as_str(),
+            amount: applied.amount.map(|v| d.round("amount", v)),
+            available: d.round("available", applied.after.available),
+            held: d.round("held", applied.after.held),
+            total: d.round("total", applied.after.total),
+            locked: applied.after.locked,
+            timestamp: applied.timestamp,
+        };
+        self.batch.push(serde_json::to_string(&event)?);
+        if self.batch.len() >= BATCH_EVENTS {
+            return self.flush();
+        }
+        Ok(())
+    }
+
+    // Sends the pending events, retrying on failures. When all attempts fail, the events are kept
+    // and the error returned.
+    fn flush(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let mut body = self.batch.join("\n");
+        body.push('\n');
+        let mut delay = RETRY_DELAY;
+        let mut result = self.send(&body);
+        for _ in 1..ATTEMPTS {
+            if result.is_ok() {
+                break;
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            result = self.send(&body);
+        }
+        if let Err(e) = result {
+            return Err(anyhow! {
+                "cannot send {} events after {} attempts: {}",
+                self.batch.len(),
+                ATTEMPTS,
+                e
+            });
+        }
+        self.batch.clear();
+        Ok(())
+    }
+
+    fn send(&self, body: &str) -> Result<()> {
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", self.table);
+        let target = format!(
+            "/?date_time_input_format=best_effort&query={}",
+            percent_encoded(&query)
+        );
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:8123", self.host)
+        };
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        let mut request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\n",
+            target,
+            self.host,
+            body.len()
+        );
+        if let Some((user, password)) = &self.credentials {
+            request.push_str(&format!(
+                "X-ClickHouse-User: {}\r\nX-ClickHouse-Key: {}\r\n",
+                user, password
+            ));
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(anyhow! {"{} {}", status, body.trim()});
+        }
+        Ok(())
+    }
+
+    // Sends the last events.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ledger::{Ledger, TransactionEntry};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // Server answering each of its requests with the next status, and returning the requests.
+    fn server(statuses: &'static [&'static str]) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    request.push_str(&line);
+                    if let Some(v) = line.strip_prefix("Content-Length: ") {
+                        length = v.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                let response = format!("HTTP/1.0 {}\r\n\r\nbody of {}", status, status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (address, handle)
+    }
+
+    fn deposit() -> Applied {
+        let mut l = Ledger::new();
+        l.apply_transaction(TransactionEntry {
+            t: "deposit".to_string(),
+            client_id: 1,
+            uid: 2,
+            amount: Some("1.5".parse().unwrap()),
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            subaccount: None,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn events_are_inserted_as_json_rows() {
+        let (address, server) = server(&["200 OK"]);
+        let url = format!("http://user:secret@{}", address);
+        let mut sink = EventsSink::new(&url, "events", Decimals::default()).unwrap();
+        sink.record(&deposit()).unwrap();
+        sink.finish().unwrap();
+        let requests = server.join().unwrap();
+        let (head, body) = requests[0].split_once("\r\n\r\n").unwrap();
+        let mut lines = head.lines();
+        assert_eq!(
+            lines.next(),
+            Some(
+                "POST /?date_time_input_format=best_effort\
+                 &query=INSERT%20INTO%20events%20FORMAT%20JSONEachRow HTTP/1.0"
+            )
+        );
+        assert!(lines.any(|l| l == "X-ClickHouse-User: user"));
+        let event: serde_json::Value = serde_json::from_str(body.trim_end()).unwrap();
+        assert_eq!(event["tx"], 2);
+        assert_eq!(event["client"], 1);
+        assert_eq!(event["type"], "deposit");
+        assert_eq!(event["amount"], 1.5);
+        assert_eq!(event["total"], 1.5);
+        assert!(body.ends_with('\n'));
+    }
+
+    #[test]
+    fn failed_attempts_are_retried() {
+        let (address, server) = server(&["503 Service Unavailable", "200 OK"]);
+        let mut sink = EventsSink::new(
+            &format!("http://{}", address),
+            "events",
+            Decimals::default(),
+        )
+        .unwrap();
+        sink.record(&deposit()).unwrap();
+        sink.finish().unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], requests[1]);
+    }
+
+    #[test]
+    fn batches_failing_every_attempt_are_errors() {
+        let (address, server) = server(&["500 Internal Server Error"; ATTEMPTS as usize]);
+        let mut sink = EventsSink::new(
+            &format!("http://{}", address),
+            "events",
+            Decimals::default(),
+        )
+        .unwrap();
+        sink.record(&deposit()).unwrap();
+        let e = sink.flush().unwrap_err().to_string();
+        assert!(e.contains("cannot send 1 events after 4 attempts"), "{}", e);
+        assert!(e.contains("500 Internal Server Error"), "{}", e);
+        // The events are kept, for whoever retries.
+        assert_eq!(sink.batch.len(), 1);
+        assert_eq!(server.join().unwrap().len(), ATTEMPTS as usize);
+    }
+}
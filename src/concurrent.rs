@@ -0,0 +1,117 @@
+use crate::{
+    authorization_expiries, bonus_expiries, credit_overflow, duplicate_filter, process_transaction,
+    sweep_target, Account, Applied, Config, ExchangeRateProvider, Ledger, LedgerError, Rates,
+    TransactionEntry,
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+// Ledger which can be shared between threads. Every account sits behind its own lock, so threads
+// working on different clients don't contend with each other, while transactions of the same
+// client are applied one at a time. Transactions of a client are applied in the order in which the
+// calls acquire the account lock, so an embedder which needs the input order preserved should feed
+// each client from a single thread (e.g. by sharding clients over threads). Bonuses don't expire
+// while in a ConcurrentLedger, see Ledger::expire_bonuses once converted with into_ledger.
+#[derive(Debug, Default)]
+pub struct ConcurrentLedger {
+    accounts: RwLock<HashMap<u16, Arc<Mutex<Account>>>>,
+    config: Config,
+    rates: Rates,
+}
+
+impl ConcurrentLedger {
+    pub fn new() -> ConcurrentLedger {
+        ConcurrentLedger::default()
+    }
+
+    pub fn with_config(config: Config) -> ConcurrentLedger {
+        ConcurrentLedger {
+            accounts: RwLock::default(),
+            config,
+            rates: Rates::default(),
+        }
+    }
+
+    pub fn set_rates(&mut self, rates: impl ExchangeRateProvider + 'static) {
+        self.rates = Rates(Box::new(rates));
+    }
+
+    // Returns the account of the given client, creating it if it does not exist yet. The map is
+    // only write-locked the first time a client is seen.
+    fn account(&self, client_id: u16) -> Arc<Mutex<Account>> {
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(account) = accounts.get(&client_id) {
+            return Arc::clone(account);
+        }
+        drop(accounts);
+        let mut accounts = self
+            .accounts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(
+            accounts
+                .entry(client_id)
+                .or_insert_with(|| Arc::new(Mutex::new(Account::new()))),
+        )
+    }
+
+    pub fn apply_transaction(&self, tx: TransactionEntry) -> Result<Applied, LedgerError> {
+        let account = self.account(tx.client_id);
+        // A panic while holding the lock can't leave the account half-updated, as the state and
+        // the oplog are only written once the operation has been validated.
+        let mut account = account.lock().unwrap_or_else(PoisonError::into_inner);
+        // A deposit which may be swept also locks the overflow account, so that the room it has
+        // for the swept part can't change before it is credited. The own deposits of the overflow
+        // account are never swept, so it never waits for another account, which rules out
+        // deadlocks.
+        let overflow = sweep_target(&tx, &self.config).map(|o| self.account(o));
+        let mut overflow = overflow
+            .as_ref()
+            .map(|o| o.lock().unwrap_or_else(PoisonError::into_inner));
+        let applied = process_transaction(
+            tx,
+            &mut account,
+            &self.config,
+            &*self.rates.0,
+            None,
+            overflow.as_ref().map(|o| o.rollup()),
+        )?;
+        if let (Some((_, excess)), Some(overflow)) = (applied.swept, overflow.as_mut()) {
+            credit_overflow(applied.client_id, applied.tx, excess, overflow)?;
+        }
+        Ok(applied)
+    }
+
+    // Consumes the concurrent ledger, once all threads are done with it, and returns a regular
+    // ledger holding the same accounts.
+    pub fn into_ledger(self) -> Ledger {
+        let accounts = self
+            .accounts
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        let accounts = accounts
+            .into_iter()
+            .map(|(client_id, account)| {
+                let account = match Arc::try_unwrap(account) {
+                    Ok(account) => account.into_inner(),
+                    // Nothing else can hold a reference once self is consumed, except for a
+                    // thread still inside apply_transaction, which would need &self.
+                    Err(_) => unreachable!("account still referenced"),
+                };
+                (client_id, account.unwrap_or_else(PoisonError::into_inner))
+            })
+            .collect();
+        Ledger {
+            bonus_expiries: bonus_expiries(&accounts),
+            authorization_expiries: authorization_expiries(&accounts),
+            duplicate_filter: duplicate_filter(&self.config, &accounts),
+            config: self.config,
+            rates: self.rates,
+            accounts,
+            undo: VecDeque::new(), // Concurrent application isn't journaled
+            idempotency_keys: BTreeMap::new(),
+            day_marks: VecDeque::new(), // Nor are the days of the transactions marked
+            legal_holds: Vec::new(),
+        }
+    }
+}
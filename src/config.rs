@@ -0,0 +1,1047 @@
+use crate::amount::MAX_EXPONENT;
+use crate::AccountOperation::{self, *};
+use crate::{Amount, Currency, Ledger, LedgerError, Money, Timestamp, TransactionType};
+use anyhow::anyhow;
+#[cfg(feature = "minor-units")]
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// What happens to transactions of a locked account (an account which had a chargeback).
+// RejectAll rejects every transaction, AllowDeposits still credits deposits and AllowAll applies
+// every transaction as if the account was open. In all cases, the account stays locked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedPolicy {
+    #[default]
+    RejectAll,
+    AllowDeposits,
+    AllowAll,
+}
+
+impl LockedPolicy {
+    pub(crate) fn permits(self, op: &AccountOperation) -> bool {
+        match self {
+            LockedPolicy::RejectAll => false,
+            LockedPolicy::AllowDeposits => matches!(op, Deposit { .. } | ForeignDeposit { .. }),
+            LockedPolicy::AllowAll => true,
+        }
+    }
+}
+
+impl FromStr for LockedPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-all" => Ok(LockedPolicy::RejectAll),
+            "allow-deposits" => Ok(LockedPolicy::AllowDeposits),
+            "allow-all" => Ok(LockedPolicy::AllowAll),
+            _ => Err(
+                anyhow! {"unknown locked policy {} (expected reject-all, allow-deposits or allow-all)", s},
+            ),
+        }
+    }
+}
+
+// What happens when a dispute holds more than the available funds, because the disputed deposit
+// has been (partly) withdrawn since. AllowNegative holds the full amount and lets the available
+// funds go negative, Reject rejects the dispute and Cap only holds what is still available.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WithdrawnDisputePolicy {
+    #[default]
+    AllowNegative,
+    Reject,
+    Cap,
+}
+
+impl FromStr for WithdrawnDisputePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow-negative" => Ok(WithdrawnDisputePolicy::AllowNegative),
+            "reject" => Ok(WithdrawnDisputePolicy::Reject),
+            "cap" => Ok(WithdrawnDisputePolicy::Cap),
+            _ => Err(
+                anyhow! {"unknown withdrawn dispute policy {} (expected allow-negative, reject or cap)", s},
+            ),
+        }
+    }
+}
+
+// Stage of a dispute: disputed, then escalated to representment (the merchant contesting the
+// dispute), pre-arbitration and arbitration, each by a transaction of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeStage {
+    Disputed,
+    Representment,
+    PreArbitration,
+    Arbitration,
+}
+
+impl DisputeStage {
+    // Transaction moving a dispute into the stage.
+    pub fn transaction_type(self) -> TransactionType {
+        match self {
+            DisputeStage::Disputed => TransactionType::Dispute,
+            DisputeStage::Representment => TransactionType::Representment,
+            DisputeStage::PreArbitration => TransactionType::PreArbitration,
+            DisputeStage::Arbitration => TransactionType::Arbitration,
+        }
+    }
+}
+
+const FROM_DISPUTED: [TransactionType; 3] = [
+    TransactionType::Representment,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+];
+const FROM_REPRESENTMENT: [TransactionType; 3] = [
+    TransactionType::PreArbitration,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+];
+const FROM_PRE_ARBITRATION: [TransactionType; 3] = [
+    TransactionType::Arbitration,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+];
+const FROM_ARBITRATION: [TransactionType; 2] =
+    [TransactionType::Resolve, TransactionType::Chargeback];
+
+// Transactions allowed on a dispute in each of its stages: escalations to another stage, and the
+// resolve or chargeback ending it (in part or in full). By default a dispute may be ended in any
+// stage or escalated to the next one, e.g. a network without pre-arbitration would have
+//
+//     [dispute-lifecycle]
+//     representment = ["arbitration", "resolve", "chargeback"]
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DisputeLifecycle {
+    pub disputed: Vec<TransactionType>,
+    pub representment: Vec<TransactionType>,
+    pub pre_arbitration: Vec<TransactionType>,
+    pub arbitration: Vec<TransactionType>,
+}
+
+impl Default for DisputeLifecycle {
+    fn default() -> DisputeLifecycle {
+        DisputeLifecycle {
+            disputed: FROM_DISPUTED.to_vec(),
+            representment: FROM_REPRESENTMENT.to_vec(),
+            pre_arbitration: FROM_PRE_ARBITRATION.to_vec(),
+            arbitration: FROM_ARBITRATION.to_vec(),
+        }
+    }
+}
+
+impl DisputeLifecycle {
+    pub fn transitions(&self, stage: DisputeStage) -> &[TransactionType] {
+        match stage {
+            DisputeStage::Disputed => &self.disputed,
+            DisputeStage::Representment => &self.representment,
+            DisputeStage::PreArbitration => &self.pre_arbitration,
+            DisputeStage::Arbitration => &self.arbitration,
+        }
+    }
+}
+
+// Credit line of a client: withdrawals may take the available funds of the client down to -limit.
+// Every withdrawal drawing on the credit line (leaving the available funds negative) is charged the
+// draw fee, and Ledger::charge_interest charges the interest rate on the negative available funds.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CreditLine {
+    pub client: u16,
+    pub limit: Amount,
+    #[serde(default)]
+    pub interest_rate: f32,
+    #[serde(default)]
+    pub draw_fee: Amount,
+}
+
+impl CreditLine {
+    // Part of the limit in use with the given available funds, between 0 and 1 (above 1 when fees
+    // or interest took the funds beyond the limit).
+    pub fn utilization(&self, available: Amount) -> f32 {
+        if self.limit > Amount::ZERO {
+            (-available).max(Amount::ZERO).ratio(self.limit)
+        } else {
+            0.0
+        }
+    }
+}
+
+// Tier of clients sharing the same requirements, e.g. a product with a reserve requirement.
+// Withdrawals which would take the available funds of a client of the tier below the minimum
+// balance are rejected.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Tier {
+    pub name: String,
+    pub min_balance: Amount,
+    pub clients: Vec<u16>,
+}
+
+// Maximum total balance of an account. Deposits taking an account beyond the limit are rejected,
+// unless an overflow account is given, in which case the account is credited up to the limit and
+// the excess is swept into the overflow account (which is not subject to the limit).
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MaxBalance {
+    pub limit: Amount,
+    pub overflow_account: Option<u16>,
+}
+
+// Caps on the amount of single transactions, to catch fat-finger entries. The cap of a transaction
+// type overrides the cap of all types.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MaxAmount {
+    pub all: Option<Amount>,
+    pub deposit: Option<Amount>,
+    pub withdrawal: Option<Amount>,
+    pub dispute: Option<Amount>,
+    pub resolve: Option<Amount>,
+    pub chargeback: Option<Amount>,
+    pub convert: Option<Amount>,
+    pub bonus: Option<Amount>,
+    pub escrow_hold: Option<Amount>,
+    pub escrow_release: Option<Amount>,
+    pub authorize: Option<Amount>,
+    pub capture: Option<Amount>,
+    pub representment: Option<Amount>,
+    pub pre_arbitration: Option<Amount>,
+    pub arbitration: Option<Amount>,
+    pub chargeback_reversal: Option<Amount>,
+    pub adjustment: Option<Amount>,
+}
+
+impl MaxAmount {
+    pub fn limit(&self, kind: TransactionType) -> Option<Amount> {
+        let limit = match kind {
+            TransactionType::Deposit => self.deposit,
+            TransactionType::Withdrawal => self.withdrawal,
+            TransactionType::Dispute => self.dispute,
+            TransactionType::Resolve => self.resolve,
+            TransactionType::Chargeback => self.chargeback,
+            TransactionType::Convert => self.convert,
+            TransactionType::Bonus => self.bonus,
+            TransactionType::EscrowHold => self.escrow_hold,
+            TransactionType::EscrowRelease => self.escrow_release,
+            TransactionType::Authorize => self.authorize,
+            TransactionType::Capture => self.capture,
+            TransactionType::Representment => self.representment,
+            TransactionType::PreArbitration => self.pre_arbitration,
+            TransactionType::Arbitration => self.arbitration,
+            TransactionType::ChargebackReversal => self.chargeback_reversal,
+            TransactionType::Adjustment => self.adjustment,
+        };
+        limit.or(self.all)
+    }
+}
+
+// Rule of the fee schedule: transactions of the given type, of clients of the given tier (or any
+// client) and with an amount within the band (min-amount inclusive, max-amount exclusive) are
+// charged the fixed fee plus the percentage of their amount. The first matching rule applies.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FeeRule {
+    #[serde(rename = "type")]
+    pub kind: TransactionType,
+    #[serde(default)]
+    pub tier: Option<String>,
+    #[serde(default)]
+    pub min_amount: Option<Amount>,
+    #[serde(default)]
+    pub max_amount: Option<Amount>,
+    #[serde(default)]
+    pub fixed: Amount,
+    #[serde(default)]
+    pub percent: f32,
+}
+
+impl FeeRule {
+    fn matches(&self, kind: TransactionType, tier: Option<&str>, amount: Amount) -> bool {
+        self.kind == kind
+            && self.tier.as_ref().is_none_or(|t| Some(t.as_str()) == tier)
+            && self.min_amount.is_none_or(|min| amount >= min)
+            && self.max_amount.is_none_or(|max| amount < max)
+    }
+
+    // None if the fee is out of range.
+    pub fn fee(&self, amount: Amount) -> Option<Amount> {
+        self.fixed.checked_add(amount.percent(self.percent)?)
+    }
+}
+
+// Day of the week, as listed in the weekend of a calendar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+// Business-day calendar of a jurisdiction: the days of its weekend and its holidays (dates, the
+// time of day being ignored), in UTC. With a calendar, the days of the expiry rules count business
+// days, and the daily settlement cut-offs only fall on business days.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Calendar {
+    pub weekend: Vec<Weekday>,
+    pub holidays: Vec<Timestamp>,
+}
+
+impl Default for Calendar {
+    fn default() -> Calendar {
+        Calendar {
+            weekend: vec![Weekday::Saturday, Weekday::Sunday],
+            holidays: Vec::new(),
+        }
+    }
+}
+
+// Days of the week from 1970-01-01, a Thursday.
+const WEEK: [Weekday; 7] = [
+    Weekday::Thursday,
+    Weekday::Friday,
+    Weekday::Saturday,
+    Weekday::Sunday,
+    Weekday::Monday,
+    Weekday::Tuesday,
+    Weekday::Wednesday,
+];
+
+impl Calendar {
+    // Whether the day (in days since 1970-01-01) is a business day.
+    pub fn is_business_day(&self, day: i64) -> bool {
+        !self.weekend.contains(&WEEK[day.rem_euclid(7) as usize])
+            && !self.holidays.iter().any(|h| h.0.div_euclid(86400) == day)
+    }
+
+    // Whether any day is a business day, which it isn't when the weekend takes the whole week.
+    pub fn has_business_days(&self) -> bool {
+        WEEK.iter().any(|d| !self.weekend.contains(d))
+    }
+
+    // The time the given number of business days after t, at the same time of day, if there are
+    // business days.
+    pub fn add_business_days(&self, t: Timestamp, days: u32) -> Option<Timestamp> {
+        if days > 0 && !self.has_business_days() {
+            return None;
+        }
+        let mut day = t.0.div_euclid(86400);
+        for _ in 0..days {
+            day += 1;
+            while !self.is_business_day(day) {
+                day += 1;
+            }
+        }
+        Some(Timestamp(day * 86400 + t.0.rem_euclid(86400)))
+    }
+}
+
+// Behavior which flags switch on and off, see Flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Behavior {
+    Overdraft,   // Withdrawals below zero, down to allow_overdraft
+    Fees,        // Fees of the fee schedule
+    CreditLines, // Withdrawals on the credit lines
+    ChargebackReversalUnlocks,
+    AdminOps, // See allow_admin_ops
+}
+
+// Switch of a behavior, as a [[flags]] table of the config file. From its effective-from time on
+// (always, without one), the behavior is enabled or disabled whatever the rest of the
+// configuration says, until the next flag of the behavior takes effect, so that a replay of
+// historical transactions applies each under the rules in force at its timestamp. Enabling
+// overdraft, fees and credit lines only brings back those configured. Transactions without a
+// timestamp get the flags in force last.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Flag {
+    pub behavior: Behavior,
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_from: Option<Timestamp>,
+}
+
+// Behaviors as the flags in force at a time leave them (see Config::flags_at): overdraft, fees
+// and credit lines are those configured or none, the others are switched on or off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags {
+    pub overdraft: bool,
+    pub fees: bool,
+    pub credit_lines: bool,
+    pub chargeback_reversal_unlocks: bool,
+    pub admin_ops: bool,
+}
+
+// Legal hold on the history of a client, or of one of its transactions only, as a [[legal-holds]]
+// table of the config file, or placed on a ledger with Ledger::place_legal_hold. What is under
+// hold is kept as it is: pruning leaves the oplog entries, Ledger::forget_client refuses to
+// forget the client and the CLI doesn't compact the audit log records away.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LegalHold {
+    pub client: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx: Option<u32>, // None holds all the history of the client
+    // Matter or case the hold is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+impl LegalHold {
+    // Whether the hold is on the transaction of the client.
+    pub fn covers(&self, client_id: u16, tx_id: u32) -> bool {
+        self.client == client_id && self.tx.is_none_or(|tx| tx == tx_id)
+    }
+}
+
+// Decimal places of the currencies which have neither an exponent nor a precision with minor
+// units, the four the input format has always had.
+#[cfg(feature = "minor-units")]
+pub const DEFAULT_EXPONENT: u8 = 4;
+
+// Configuration of the ledger behavior. The defaults match the behavior of the ledger before any
+// of these knobs existed. It can be deserialized, so that the CLI can read it from a config file.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    // Number of decimal places deposit and withdrawal amounts are rounded to, at most
+    // MAX_EXPONENT. None keeps the amounts as they are parsed.
+    #[serde(deserialize_with = "precision")]
+    pub precision: Option<u32>,
+    // How far below zero withdrawals may take the available funds.
+    pub allow_overdraft: Amount,
+    pub locked_policy: LockedPolicy,
+    // How many times the same deposit may be disputed (and resolved). None allows any number of
+    // disputes.
+    pub max_disputes: Option<u32>,
+    pub withdrawn_dispute_policy: WithdrawnDisputePolicy,
+    // Unlock the account on a chargeback reversal, rather than leaving it locked (by default, as
+    // it was locked by the chargeback).
+    pub chargeback_reversal_unlocks: bool,
+    // Transitions allowed between the stages of disputes. None allows the default ones, see
+    // DisputeLifecycle.
+    pub dispute_lifecycle: Option<DisputeLifecycle>,
+    // Currency of the main balance of the accounts, which transactions without a currency move.
+    // Conversions from or to the base currency require it to be set.
+    pub base_currency: Option<Currency>,
+    // Decimal places of the amounts of every currency with the minor-units feature (2 for USD, 0
+    // for JPY), which amounts are stored as integer numbers of units of (see MinorUnits).
+    // Transactions with more decimal places than their currency are rejected. Currencies which
+    // aren't listed have the precision, or DEFAULT_EXPONENT. Exponents are at most MAX_EXPONENT.
+    #[cfg(feature = "minor-units")]
+    #[serde(deserialize_with = "currency_exponents")]
+    pub currency_exponents: BTreeMap<Currency, u8>,
+    // Currency in which the outputs additionally report the total balance of every account, over
+    // all its currencies (see Ledger::total_in).
+    pub reporting_currency: Option<Currency>,
+    pub max_balance: Option<MaxBalance>,
+    pub max_amount: MaxAmount,
+    pub credit_lines: Vec<CreditLine>,
+    // A client belongs to the first tier listing it, if any.
+    pub tiers: Vec<Tier>,
+    pub fees: Vec<FeeRule>,
+    // Number of days after which the part of a bonus deposit which has not been withdrawn yet
+    // expires, see Ledger::expire_bonuses. Bonus deposits require a timestamp when it is set. None
+    // keeps bonuses forever.
+    pub bonus_expiry_days: Option<u32>,
+    // Number of days after which authorizations which haven't been captured expire, releasing the
+    // held funds, see Ledger::expire_authorizations. Authorizations require a timestamp when it is
+    // set. None keeps them until they are captured.
+    pub authorization_expiry_days: Option<u32>,
+    // Log withdrawals by id only, which is all duplicate detection needs, rather than as full oplog
+    // entries. Withdrawals can't be disputed, so this changes nothing but the memory footprint; if
+    // withdrawal disputes are ever supported, they will need the logged amount and won't be
+    // available with this set.
+    pub withdrawal_ids_only: bool,
+    // Apply the adjustments of administrators, rather than rejecting them.
+    pub allow_admin_ops: bool,
+    // Expected number of transactions, sizing a Bloom filter which spares the oplog lookup for
+    // most transaction ids which aren't duplicates. None checks the oplog every time.
+    pub duplicate_filter_capacity: Option<u64>,
+    // Number of the last applied transactions which can be reverted with Ledger::undo_last, whose
+    // inverses are journaled (and kept in snapshots). 0 journals nothing.
+    pub undo_depth: usize,
+    // Retention of the oplog, see Ledger::prune: the entries of transactions more than
+    // retention_transactions transaction ids before the latest, or from more than retention_days
+    // days before the latest timestamp, are dropped, unless they still hold funds. With both, an
+    // entry is dropped once it is outside both windows. None keeps the entries forever.
+    pub retention_transactions: Option<u32>,
+    pub retention_days: Option<u32>,
+    // Held whatever the retention, see LegalHold.
+    pub legal_holds: Vec<LegalHold>,
+    // Business-day calendar the days of bonus_expiry_days and authorization_expiry_days are
+    // counted in, and on which the daily settlement cut-offs of the CLI fall. None counts
+    // calendar days.
+    pub calendar: Option<Calendar>,
+    pub flags: Vec<Flag>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            precision: None,
+            allow_overdraft: Amount::ZERO,
+            locked_policy: LockedPolicy::RejectAll,
+            max_disputes: None,
+            withdrawn_dispute_policy: WithdrawnDisputePolicy::AllowNegative,
+            chargeback_reversal_unlocks: false,
+            dispute_lifecycle: None,
+            base_currency: None,
+            #[cfg(feature = "minor-units")]
+            currency_exponents: BTreeMap::new(),
+            reporting_currency: None,
+            max_balance: None,
+            max_amount: MaxAmount::default(),
+            credit_lines: Vec::new(),
+            tiers: Vec::new(),
+            fees: Vec::new(),
+            bonus_expiry_days: None,
+            authorization_expiry_days: None,
+            withdrawal_ids_only: false,
+            allow_admin_ops: false,
+            duplicate_filter_capacity: None,
+            undo_depth: 0,
+            retention_transactions: None,
+            retention_days: None,
+            legal_holds: Vec::new(),
+            calendar: None,
+            flags: Vec::new(),
+        }
+    }
+}
+
+// Decimal places beyond MAX_EXPONENT don't fit in the amounts, they are rejected with the config.
+fn precision<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    let precision = <Option<u32> as serde::Deserialize>::deserialize(d)?;
+    match precision {
+        Some(p) if p > u32::from(MAX_EXPONENT) => Err(serde::de::Error::custom(format!(
+            "precision {} above the maximum of {}",
+            p, MAX_EXPONENT
+        ))),
+        _ => Ok(precision),
+    }
+}
+
+#[cfg(feature = "minor-units")]
+fn currency_exponents<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<BTreeMap<Currency, u8>, D::Error> {
+    let exponents = <BTreeMap<Currency, u8> as serde::Deserialize>::deserialize(d)?;
+    match exponents.iter().find(|(_, e)| **e > MAX_EXPONENT) {
+        Some((currency, e)) => Err(serde::de::Error::custom(format!(
+            "exponent {} of {} above the maximum of {}",
+            e, currency, MAX_EXPONENT
+        ))),
+        None => Ok(exponents),
+    }
+}
+
+impl Config {
+    #[cfg(not(feature = "minor-units"))]
+    pub(crate) fn round(&self, amount: Amount) -> Amount {
+        match self.precision {
+            Some(p) => {
+                let scale = 10f64.powi(p as i32);
+                ((f64::from(amount) * scale).round() / scale) as f32
+            }
+            None => amount,
+        }
+    }
+
+    #[cfg(feature = "minor-units")]
+    pub(crate) fn round(&self, amount: Amount) -> Amount {
+        match self.precision {
+            Some(p) => amount.rounded(p.min(u32::from(MAX_EXPONENT)) as u8),
+            None => amount,
+        }
+    }
+
+    // Decimal places of the amounts of the currency (the base currency for None): its exponent in
+    // currency_exponents, else the precision, else DEFAULT_EXPONENT.
+    #[cfg(feature = "minor-units")]
+    pub fn exponent(&self, currency: Option<Currency>) -> u8 {
+        currency
+            .or(self.base_currency)
+            .and_then(|c| self.currency_exponents.get(&c).copied())
+            .or(self.precision.map(|p| p.min(u32::from(MAX_EXPONENT)) as u8))
+            .unwrap_or(DEFAULT_EXPONENT)
+    }
+
+    // The amount of a transaction in the currency, rounded to the precision. With minor units, it
+    // is also brought to the exponent of the currency, and rejected if it has more decimal places
+    // or is out of range there.
+    #[cfg(not(feature = "minor-units"))]
+    pub(crate) fn quantize(
+        &self,
+        amount: Amount,
+        _currency: Option<Currency>,
+    ) -> Result<Amount, LedgerError> {
+        Ok(self.round(amount))
+    }
+
+    #[cfg(feature = "minor-units")]
+    pub(crate) fn quantize(
+        &self,
+        amount: Amount,
+        currency: Option<Currency>,
+    ) -> Result<Amount, LedgerError> {
+        let amount = self.round(amount);
+        let exponent = self.exponent(currency);
+        amount.at(exponent).ok_or(if amount.exponent() > exponent {
+            LedgerError::ExcessPrecision
+        } else {
+            LedgerError::AmountOutOfRange
+        })
+    }
+
+    // The amount converted at the rate into the currency, rounded to the precision (and with minor
+    // units, to the exponent of the currency).
+    #[cfg(not(feature = "minor-units"))]
+    pub(crate) fn convert(
+        &self,
+        amount: Amount,
+        rate: f32,
+        _to: Currency,
+    ) -> Result<Amount, LedgerError> {
+        let converted = amount.times(rate).ok_or(LedgerError::AmountOutOfRange)?;
+        Ok(self.round(converted))
+    }
+
+    #[cfg(feature = "minor-units")]
+    pub(crate) fn convert(
+        &self,
+        amount: Amount,
+        rate: f32,
+        to: Currency,
+    ) -> Result<Amount, LedgerError> {
+        let converted = amount
+            .converted(rate, self.exponent(Some(to)))
+            .ok_or(LedgerError::AmountOutOfRange)?;
+        Ok(self.round(converted))
+    }
+
+    // The behaviors a transaction at the given time is applied with, the flags in force applied
+    // (see Flag).
+    pub fn flags_at(&self, t: Option<Timestamp>) -> Flags {
+        let mut flags = Flags {
+            overdraft: true,
+            fees: true,
+            credit_lines: true,
+            chargeback_reversal_unlocks: self.chargeback_reversal_unlocks,
+            admin_ops: self.allow_admin_ops,
+        };
+        let behaviors = [
+            Behavior::Overdraft,
+            Behavior::Fees,
+            Behavior::CreditLines,
+            Behavior::ChargebackReversalUnlocks,
+            Behavior::AdminOps,
+        ];
+        for behavior in behaviors {
+            // The last flag listed wins among those taking effect at the same time.
+            let flag = self
+                .flags
+                .iter()
+                .filter(|f| f.behavior == behavior)
+                .filter(|f| f.effective_from.zip(t).is_none_or(|(from, t)| from <= t))
+                .max_by_key(|f| f.effective_from);
+            let Some(flag) = flag else {
+                continue;
+            };
+            let enabled = flag.enabled;
+            match behavior {
+                Behavior::Overdraft => flags.overdraft = enabled,
+                Behavior::Fees => flags.fees = enabled,
+                Behavior::CreditLines => flags.credit_lines = enabled,
+                Behavior::ChargebackReversalUnlocks => flags.chargeback_reversal_unlocks = enabled,
+                Behavior::AdminOps => flags.admin_ops = enabled,
+            }
+        }
+        flags
+    }
+
+    pub fn allows_dispute_transition(&self, stage: DisputeStage, kind: TransactionType) -> bool {
+        let transitions: &[TransactionType] = match (&self.dispute_lifecycle, stage) {
+            (Some(lifecycle), _) => lifecycle.transitions(stage),
+            (None, DisputeStage::Disputed) => &FROM_DISPUTED,
+            (None, DisputeStage::Representment) => &FROM_REPRESENTMENT,
+            (None, DisputeStage::PreArbitration) => &FROM_PRE_ARBITRATION,
+            (None, DisputeStage::Arbitration) => &FROM_ARBITRATION,
+        };
+        transitions.contains(&kind)
+    }
+
+    pub fn credit_line(&self, client_id: u16) -> Option<&CreditLine> {
+        self.credit_lines.iter().find(|c| c.client == client_id)
+    }
+
+    pub fn tier(&self, client_id: u16) -> Option<&Tier> {
+        self.tiers.iter().find(|t| t.clients.contains(&client_id))
+    }
+
+    // Time at which a bonus deposited at the given time expires, None if bonuses don't expire.
+    pub fn bonus_expires_at(&self, deposited_at: Timestamp) -> Option<Timestamp> {
+        let days = self.bonus_expiry_days?;
+        match &self.calendar {
+            Some(calendar) => calendar.add_business_days(deposited_at, days),
+            None => Some(Timestamp(deposited_at.0 + i64::from(days) * 86400)),
+        }
+    }
+
+    // Time at which an authorization made at the given time expires, None if they don't expire.
+    pub fn authorization_expires_at(&self, authorized_at: Timestamp) -> Option<Timestamp> {
+        let days = self.authorization_expiry_days?;
+        match &self.calendar {
+            Some(calendar) => calendar.add_business_days(authorized_at, days),
+            None => Some(Timestamp(authorized_at.0 + i64::from(days) * 86400)),
+        }
+    }
+
+    // Fee of a transaction according to the fee schedule, rounded to the precision.
+    pub fn fee(
+        &self,
+        client_id: u16,
+        kind: TransactionType,
+        amount: Amount,
+    ) -> Result<Amount, LedgerError> {
+        let tier = self.tier(client_id).map(|t| t.name.as_str());
+        match self
+            .fees
+            .iter()
+            .find(|rule| rule.matches(kind, tier, amount))
+        {
+            Some(rule) => Ok(self.round(rule.fee(amount).ok_or(LedgerError::AmountOutOfRange)?)),
+            None => Ok(Amount::ZERO),
+        }
+    }
+}
+
+// Builder for a Ledger and its Config:
+//
+//     let ledger = Ledger::builder()
+//         .precision(4)
+//         .allow_overdraft(100.0)
+//         .locked_policy(LockedPolicy::AllowDeposits)
+//         .build();
+#[derive(Clone, Debug, Default)]
+pub struct LedgerBuilder {
+    config: Config,
+}
+
+impl LedgerBuilder {
+    pub fn new() -> LedgerBuilder {
+        LedgerBuilder::default()
+    }
+
+    // Starts from an existing configuration, e.g. one read from a config file.
+    pub fn from_config(config: Config) -> LedgerBuilder {
+        LedgerBuilder { config }
+    }
+
+    pub fn precision(mut self, decimal_places: u32) -> LedgerBuilder {
+        self.config.precision = Some(decimal_places);
+        self
+    }
+
+    pub fn allow_overdraft(mut self, limit: Amount) -> LedgerBuilder {
+        self.config.allow_overdraft = limit;
+        self
+    }
+
+    pub fn locked_policy(mut self, policy: LockedPolicy) -> LedgerBuilder {
+        self.config.locked_policy = policy;
+        self
+    }
+
+    pub fn max_disputes(mut self, max: u32) -> LedgerBuilder {
+        self.config.max_disputes = Some(max);
+        self
+    }
+
+    // Adds a credit line, replacing any previous credit line of the same client.
+    pub fn credit_line(mut self, credit_line: CreditLine) -> LedgerBuilder {
+        self.config
+            .credit_lines
+            .retain(|c| c.client != credit_line.client);
+        self.config.credit_lines.push(credit_line);
+        self
+    }
+
+    pub fn bonus_expiry_days(mut self, days: u32) -> LedgerBuilder {
+        self.config.bonus_expiry_days = Some(days);
+        self
+    }
+
+    pub fn authorization_expiry_days(mut self, days: u32) -> LedgerBuilder {
+        self.config.authorization_expiry_days = Some(days);
+        self
+    }
+
+    pub fn withdrawal_ids_only(mut self, ids_only: bool) -> LedgerBuilder {
+        self.config.withdrawal_ids_only = ids_only;
+        self
+    }
+
+    pub fn duplicate_filter_capacity(mut self, transactions: u64) -> LedgerBuilder {
+        self.config.duplicate_filter_capacity = Some(transactions);
+        self
+    }
+
+    pub fn undo_depth(mut self, transactions: usize) -> LedgerBuilder {
+        self.config.undo_depth = transactions;
+        self
+    }
+
+    pub fn retention_transactions(mut self, transactions: u32) -> LedgerBuilder {
+        self.config.retention_transactions = Some(transactions);
+        self
+    }
+
+    pub fn retention_days(mut self, days: u32) -> LedgerBuilder {
+        self.config.retention_days = Some(days);
+        self
+    }
+
+    pub fn legal_hold(mut self, hold: LegalHold) -> LedgerBuilder {
+        self.config.legal_holds.push(hold);
+        self
+    }
+
+    pub fn calendar(mut self, calendar: Calendar) -> LedgerBuilder {
+        self.config.calendar = Some(calendar);
+        self
+    }
+
+    // Appends a rule to the fee schedule.
+    pub fn fee(mut self, rule: FeeRule) -> LedgerBuilder {
+        self.config.fees.push(rule);
+        self
+    }
+
+    pub fn tier(mut self, tier: Tier) -> LedgerBuilder {
+        self.config.tiers.push(tier);
+        self
+    }
+
+    pub fn max_balance(mut self, limit: Amount, overflow_account: Option<u16>) -> LedgerBuilder {
+        self.config.max_balance = Some(MaxBalance {
+            limit,
+            overflow_account,
+        });
+        self
+    }
+
+    // Caps the amount of transactions of the given type, or of all types for None.
+    pub fn max_amount(mut self, kind: Option<TransactionType>, limit: Amount) -> LedgerBuilder {
+        let m = &mut self.config.max_amount;
+        let field = match kind {
+            None => &mut m.all,
+            Some(TransactionType::Deposit) => &mut m.deposit,
+            Some(TransactionType::Withdrawal) => &mut m.withdrawal,
+            Some(TransactionType::Dispute) => &mut m.dispute,
+            Some(TransactionType::Resolve) => &mut m.resolve,
+            Some(TransactionType::Chargeback) => &mut m.chargeback,
+            Some(TransactionType::Convert) => &mut m.convert,
+            Some(TransactionType::Bonus) => &mut m.bonus,
+            Some(TransactionType::EscrowHold) => &mut m.escrow_hold,
+            Some(TransactionType::EscrowRelease) => &mut m.escrow_release,
+            Some(TransactionType::Authorize) => &mut m.authorize,
+            Some(TransactionType::Capture) => &mut m.capture,
+            Some(TransactionType::Representment) => &mut m.representment,
+            Some(TransactionType::PreArbitration) => &mut m.pre_arbitration,
+            Some(TransactionType::Arbitration) => &mut m.arbitration,
+            Some(TransactionType::ChargebackReversal) => &mut m.chargeback_reversal,
+            Some(TransactionType::Adjustment) => &mut m.adjustment,
+        };
+        *field = Some(limit);
+        self
+    }
+
+    pub fn base_currency(mut self, currency: Currency) -> LedgerBuilder {
+        self.config.base_currency = Some(currency);
+        self
+    }
+
+    #[cfg(feature = "minor-units")]
+    pub fn currency_exponent(mut self, currency: Currency, exponent: u8) -> LedgerBuilder {
+        self.config.currency_exponents.insert(currency, exponent);
+        self
+    }
+
+    pub fn reporting_currency(mut self, currency: Currency) -> LedgerBuilder {
+        self.config.reporting_currency = Some(currency);
+        self
+    }
+
+    pub fn withdrawn_dispute_policy(mut self, policy: WithdrawnDisputePolicy) -> LedgerBuilder {
+        self.config.withdrawn_dispute_policy = policy;
+        self
+    }
+
+    pub fn allow_admin_ops(mut self, allow: bool) -> LedgerBuilder {
+        self.config.allow_admin_ops = allow;
+        self
+    }
+
+    pub fn chargeback_reversal_unlocks(mut self, unlocks: bool) -> LedgerBuilder {
+        self.config.chargeback_reversal_unlocks = unlocks;
+        self
+    }
+
+    pub fn dispute_lifecycle(mut self, lifecycle: DisputeLifecycle) -> LedgerBuilder {
+        self.config.dispute_lifecycle = Some(lifecycle);
+        self
+    }
+
+    pub fn flag(mut self, flag: Flag) -> LedgerBuilder {
+        self.config.flags.push(flag);
+        self
+    }
+
+    // The configuration built so far, for ledgers other than Ledger (ConcurrentLedger,
+    // AsyncLedger).
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn into_config(self) -> Config {
+        self.config
+    }
+
+    pub fn build(self) -> Ledger {
+        Ledger::with_config(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    fn config(toml: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn config_files_leave_the_rest_to_the_defaults() {
+        let c = config(
+            "precision = 2\nlocked-policy = \"allow-deposits\"\n\n[max-amount]\nall = 100\nwithdrawal = 10\n",
+        )
+        .unwrap();
+        assert_eq!(c.precision, Some(2));
+        assert_eq!(c.locked_policy, LockedPolicy::AllowDeposits);
+        assert_eq!(
+            c.max_amount.limit(TransactionType::Withdrawal),
+            Some(amount("10"))
+        );
+        assert_eq!(
+            c.max_amount.limit(TransactionType::Deposit),
+            Some(amount("100"))
+        );
+        assert_eq!(
+            Config {
+                precision: None,
+                locked_policy: LockedPolicy::RejectAll,
+                max_amount: MaxAmount::default(),
+                ..c
+            },
+            Config::default()
+        );
+        assert_eq!(config("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn config_files_with_unknown_or_invalid_settings_are_rejected() {
+        assert!(config("allow_overdraft = 10\n").is_err());
+        assert!(config("[max-amount]\nrefund = 10\n").is_err());
+        let e = config(&format!("precision = {}\n", MAX_EXPONENT + 1)).unwrap_err();
+        assert!(e.to_string().contains("above the maximum"), "{}", e);
+        assert!(config(&format!("precision = {}\n", MAX_EXPONENT)).is_ok());
+    }
+
+    #[test]
+    fn the_first_matching_fee_rule_applies() {
+        let c = config(
+            "fees = [
+                { type = \"withdrawal\", tier = \"gold\", fixed = 0 },
+                { type = \"withdrawal\", max-amount = 100, fixed = 1 },
+                { type = \"withdrawal\", min-amount = 100, fixed = 1, percent = 10 },
+            ]
+            [[tiers]]
+            name = \"gold\"
+            min-balance = 0
+            clients = [2]
+            ",
+        )
+        .unwrap();
+        let fee = |client_id, kind, value| c.fee(client_id, kind, amount(value)).unwrap();
+        assert_eq!(fee(1, TransactionType::Withdrawal, "99"), amount("1"));
+        // The band includes its minimum.
+        assert_eq!(fee(1, TransactionType::Withdrawal, "100"), amount("11"));
+        assert_eq!(fee(2, TransactionType::Withdrawal, "100"), Amount::ZERO);
+        assert_eq!(fee(1, TransactionType::Deposit, "100"), Amount::ZERO);
+    }
+
+    fn utc(s: &str) -> Timestamp {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn business_days_skip_weekends_and_holidays() {
+        let calendar = Calendar {
+            holidays: vec![utc("2024-03-29T00:00:00Z"), utc("2024-04-01T00:00:00Z")],
+            ..Calendar::default()
+        };
+        // From Thursday, skipping Good Friday, the weekend and Easter Monday, at the same time.
+        let thursday = utc("2024-03-28T15:30:00Z");
+        assert_eq!(
+            calendar.add_business_days(thursday, 1),
+            Some(utc("2024-04-02T15:30:00Z"))
+        );
+        assert_eq!(calendar.add_business_days(thursday, 0), Some(thursday));
+        // From a Saturday, the next business day is the first one after it.
+        let saturday = utc("2024-03-09T08:00:00Z");
+        assert_eq!(
+            Calendar::default().add_business_days(saturday, 1),
+            Some(utc("2024-03-11T08:00:00Z"))
+        );
+        let c = Config {
+            bonus_expiry_days: Some(5),
+            calendar: Some(Calendar::default()),
+            ..Config::default()
+        };
+        assert_eq!(
+            c.bonus_expires_at(saturday),
+            Some(utc("2024-03-15T08:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn weekends_may_take_the_whole_week() {
+        let calendar: Calendar = toml::from_str(
+            "weekend = [\"monday\", \"tuesday\", \"wednesday\", \"thursday\", \"friday\", \"saturday\", \"sunday\"]\n",
+        )
+        .unwrap();
+        assert!(!calendar.has_business_days());
+        let t = utc("2024-03-09T08:00:00Z");
+        assert_eq!(calendar.add_business_days(t, 1), None);
+        assert_eq!(calendar.add_business_days(t, 0), Some(t));
+    }
+}
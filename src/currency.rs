@@ -0,0 +1,139 @@
+use crate::time::Timestamp;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::str::FromStr;
+
+// ISO 4217 style currency code: three uppercase ASCII letters, e.g. EUR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // Only ever built from uppercase ASCII letters.
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match <[u8; 3]>::try_from(s.as_bytes()) {
+            Ok(code) if code.iter().all(u8::is_ascii_uppercase) => Ok(Currency(code)),
+            _ => Err(format!("invalid currency code {} (expected e.g. EUR)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Currency, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+// Source of exchange rates, used by conversions and to report balances in a single currency.
+// Implementations are shared by the threads of a ConcurrentLedger, hence Send and Sync.
+pub trait ExchangeRateProvider: Debug + Send + Sync {
+    // The rate of one unit of from in units of to at the given time (or now, for None), None if
+    // the provider has no rate for the currencies.
+    fn rate(&self, from: Currency, to: Currency, at: Option<Timestamp>) -> Option<f32>;
+}
+
+// The exchange rate provider of a ledger, an empty RateTable by default.
+#[derive(Debug)]
+pub(crate) struct Rates(pub(crate) Box<dyn ExchangeRateProvider>);
+
+impl Default for Rates {
+    fn default() -> Rates {
+        Rates(Box::new(RateTable::new()))
+    }
+}
+
+// A single rate between two currencies, e.g. for a currency pegged to another. The inverse rate
+// is used for the reverse conversion.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixedRate {
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: f32,
+}
+
+impl ExchangeRateProvider for FixedRate {
+    fn rate(&self, from: Currency, to: Currency, _at: Option<Timestamp>) -> Option<f32> {
+        match (from, to) {
+            _ if from == to => Some(1.0),
+            _ if (from, to) == (self.from, self.to) => Some(self.rate),
+            _ if (from, to) == (self.to, self.from) => Some(1.0 / self.rate),
+            _ => None,
+        }
+    }
+}
+
+// Rates of a pair of currencies with the time from which they are effective, sorted by time.
+type EffectiveRates = Vec<(Option<Timestamp>, f32)>;
+
+// Exchange rates between pairs of currencies, each effective from a point in time (or always),
+// e.g. read from a rates file.
+#[derive(Clone, Debug, Default)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), EffectiveRates>,
+}
+
+impl RateTable {
+    pub fn new() -> RateTable {
+        RateTable::default()
+    }
+
+    // Adds the rate of one unit of from in units of to, effective from the given time on (or
+    // always for None). A later rate of the pair with the same effective time replaces the rate.
+    pub fn insert(
+        &mut self,
+        from: Currency,
+        to: Currency,
+        effective: Option<Timestamp>,
+        rate: f32,
+    ) {
+        let rates = self.rates.entry((from, to)).or_default();
+        match rates.binary_search_by_key(&effective, |(e, _)| *e) {
+            Ok(i) => rates[i].1 = rate,
+            Err(i) => rates.insert(i, (effective, rate)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rates.is_empty()
+    }
+}
+
+impl ExchangeRateProvider for RateTable {
+    // The latest rate which became effective until the given time, or the latest rate overall
+    // without a time. The inverse of the rate of the reverse pair is used when the pair itself has
+    // no rate.
+    fn rate(&self, from: Currency, to: Currency, at: Option<Timestamp>) -> Option<f32> {
+        if from == to {
+            return Some(1.0);
+        }
+        let lookup = |from, to| {
+            let rates = self.rates.get(&(from, to))?;
+            match at {
+                Some(at) => rates.iter().rev().find(|(e, _)| e.is_none_or(|e| e <= at)),
+                None => rates.last(),
+            }
+            .map(|(_, rate)| *rate)
+        };
+        lookup(from, to).or_else(|| lookup(to, from).map(|rate| 1.0 / rate))
+    }
+}
@@ -0,0 +1,606 @@
+use crate::output::{self, Decimals};
+use anyhow::{anyhow, Result};
+use ledger::{Config, Ledger};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// How often the daemon looks for changes of the watched files, when idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Commands read from connections and waiting for the ledger, at most, by default (see --queue).
+const QUEUE: usize = 16;
+// Longest command line, and how long a connection has to send it.
+const MAX_COMMAND: u64 = 4096;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+// Connections read at the same time, at most.
+const READERS: usize = 64;
+
+// What the reader of the connections does when the queue of commands is full (--on-saturation).
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Saturation {
+    Block, // Waits for room, leaving the next connections in the backlog of the socket
+    Shed,  // Replies that the daemon is busy, for the client to try again later
+}
+
+// A command line, and the connection to reply on.
+type Command = (String, UnixStream);
+
+// Ledger kept resident by `ledger daemon`, with the commands it accepts.
+struct Daemon {
+    l: Ledger,
+    config_path: Option<String>, // Reloaded by reload-config without a path
+    fees_paths: Vec<String>,     // Fee schedules whose rules come after those of the config
+    modified: Vec<Option<SystemTime>>, // Of the config file and fee schedules, when last read
+    data_dir: PathBuf,           // Of the files of the commands, canonical
+    started: Instant,
+    records: u64, // Ingested so far
+}
+
+impl Daemon {
+    // The file of a command, which has to be in the data directory: relative paths are in it, and
+    // paths leading out of it, through .. or symbolic links, are refused. The file may not exist
+    // yet (a snapshot to write), its directory has to.
+    fn file(&self, path: &str) -> Result<PathBuf> {
+        let joined = self.data_dir.join(path);
+        let refused =
+            || anyhow! {"{} is not in the data directory {}", path, self.data_dir.display()};
+        let (Some(dir), Some(name)) = (joined.parent(), joined.file_name()) else {
+            return Err(refused());
+        };
+        let in_dir = dir
+            .canonicalize()
+            .map_err(|e| anyhow! {"cannot use {}: {}", path, e})?
+            .join(name);
+        let resolved = match in_dir.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => in_dir,
+            Err(e) => return Err(anyhow! {"cannot use {}: {}", path, e}),
+        };
+        if resolved == self.data_dir || !resolved.starts_with(&self.data_dir) {
+            return Err(refused());
+        }
+        Ok(resolved)
+    }
+
+    // Applies the records of a transaction file, expiring the bonuses and authorizations due
+    // first, like a run does.
+    fn ingest(&mut self, path: &str) -> Result<String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_path(self.file(path)?)
+            .map_err(|e| anyhow! {"cannot read {}: {}", path, e})?;
+        let headers = reader.headers()?.clone();
+        let (mut records, mut applied) = (0, 0);
+        let mut rejections: BTreeMap<&'static str, u64> = BTreeMap::new();
+        for record in reader.records() {
+            records += 1;
+            let entry =
+                match record.and_then(|r| crate::deserialize_transaction_entry(&r, &headers)) {
+                    Ok(entry) => entry,
+                    Err(_) => {
+                        *rejections.entry("parse_error").or_default() += 1;
+                        continue;
+                    }
+                };
+            if let Some(t) = entry.timestamp {
+                self.l.expire_bonuses(t);
+                self.l.expire_authorizations(t);
+            }
+            match self.l.apply_transaction(entry) {
+                Ok(_) => applied += 1,
+                Err(e) => *rejections.entry(e.code()).or_default() += 1,
+            }
+        }
+        self.records += records;
+        let reasons: Vec<String> = rejections
+            .iter()
+            .map(|(reason, count)| format!("{}: {}", reason, count))
+            .collect();
+        let mut message = format!(
+            "Ingested {} records of {}: {} applied, {} rejected",
+            records,
+            path,
+            applied,
+            records - applied
+        );
+        if !reasons.is_empty() {
+            message.push_str(&format!(" ({})", reasons.join(", ")));
+        }
+        Ok(message)
+    }
+
+    // Writes a snapshot of the ledger through a temporary file, like `ledger undo`.
+    fn snapshot(&self, path: &str) -> Result<String> {
+        let file = self.file(path)?;
+        let tmp = format!("{}.tmp", file.display());
+        crate::write_snapshot(&tmp, &self.l)?;
+        fs::rename(&tmp, &file)?;
+        Ok(format!("Wrote snapshot {}", path))
+    }
+
+    // The config file, if any, and the fee schedules.
+    fn files(&self, config_path: Option<&str>) -> Vec<String> {
+        let config_path = config_path.or(self.config_path.as_deref());
+        config_path
+            .map(String::from)
+            .into_iter()
+            .chain(self.fees_paths.iter().cloned())
+            .collect()
+    }
+
+    // Reads the configuration of the config file (the default one without) and the fee schedules.
+    fn configuration(&self, config_path: Option<&str>) -> Result<Config> {
+        let mut config = match config_path.or(self.config_path.as_deref()) {
+            Some(path) => crate::read_config(path)?,
+            None => Config::default(),
+        };
+        for path in &self.fees_paths {
+            config.fees.extend(crate::read_fees(path)?);
+        }
+        Ok(config)
+    }
+
+    // Reloads the configuration, from another config file if one is given, and applies it to the
+    // ledger unless the ledger is incompatible with the change.
+    fn reload(&mut self, config_path: Option<&str>) -> Result<String> {
+        let files = self.files(config_path);
+        // Taken before the files are read, so that a change made meanwhile is seen by watch.
+        let modified = modification_times(&files);
+        let config = self.configuration(config_path)?;
+        if let Err(found) = self.l.reconfigure(config) {
+            let found: Vec<String> = found.iter().map(|i| i.to_string()).collect();
+            return Err(anyhow! {"cannot reload {}: {}", files.join(", "), found.join("; ")});
+        }
+        if let Some(path) = config_path {
+            self.config_path = Some(path.to_string());
+        }
+        self.modified = modified;
+        Ok(format!("Reloaded configuration {}", files.join(", ")))
+    }
+
+    // Reloads the configuration if the config file or a fee schedule changed since it was last
+    // read. A reload which fails (an invalid file, an incompatible change) is reported once, and
+    // tried again when the files change again.
+    fn watch(&mut self) {
+        let modified = modification_times(&self.files(None));
+        if modified == self.modified {
+            return;
+        }
+        match self.reload(None) {
+            Ok(message) => eprintln!("{}", message),
+            Err(e) => {
+                eprintln!("Error occurred: {}", e);
+                self.modified = modified;
+            }
+        }
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "{} accounts, {} records ingested, up for {}s",
+            self.l.accounts().count(),
+            self.records,
+            self.started.elapsed().as_secs()
+        )
+    }
+
+    // Handles the command line of a connection, writing the response to it. Returns whether the
+    // daemon is to stop.
+    fn handle(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        let message = match (command, argument) {
+            ("ingest", Some(path)) => self.ingest(path),
+            ("snapshot", Some(path)) => self.snapshot(path),
+            ("report", None) => {
+                return output::write_csv(&self.l, None, &Decimals::default(), 1, out)
+                    .map(|_| false)
+            }
+            ("reload-config", None) => self.reload(None),
+            ("reload-config", Some(path)) => self
+                .file(path)
+                .and_then(|file| self.reload(Some(&file.to_string_lossy()))),
+            ("status", None) => Ok(self.status()),
+            ("shutdown", None) => {
+                writeln!(out, "Shutting down")?;
+                return Ok(true);
+            }
+            _ => Err(anyhow! {"unknown command {:?}", line.trim()}),
+        };
+        match message {
+            Ok(message) => writeln!(out, "{}", message)?,
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+        Ok(false)
+    }
+}
+
+fn modification_times(paths: &[String]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// Reads the command line of a connection, refusing longer ones than MAX_COMMAND.
+fn read_command(stream: &UnixStream) -> io::Result<String> {
+    stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_COMMAND)).read_line(&mut line)?;
+    if line.len() as u64 == MAX_COMMAND && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("command longer than {} bytes", MAX_COMMAND),
+        ));
+    }
+    Ok(line)
+}
+
+// Reads the command of a connection into the queue. When the queue is full, it blocks or sheds
+// the command, see Saturation. Returns whether the daemon is gone.
+fn queue_command(stream: UnixStream, queue: &SyncSender<Command>, saturation: Saturation) -> bool {
+    let line = match read_command(&stream) {
+        Ok(line) => line,
+        Err(e) => {
+            eprintln!("Error occurred while reading command: {}", e);
+            let _ = writeln!(&stream, "error: {}", e);
+            return false;
+        }
+    };
+    // Connections without a command, such as that of bind checking for a running daemon.
+    if line.trim().is_empty() {
+        return false;
+    }
+    let queued = match saturation {
+        Saturation::Block => queue
+            .send((line, stream))
+            .map_err(|e| TrySendError::Disconnected(e.0)),
+        Saturation::Shed => queue.try_send((line, stream)),
+    };
+    match queued {
+        Ok(()) => false,
+        Err(TrySendError::Full((_, stream))) => {
+            let _ = writeln!(&stream, "error: busy, try again later");
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => true,
+    }
+}
+
+// Connections being read, and whether the daemon is gone.
+#[derive(Default)]
+struct Readers {
+    reading: usize,
+    stopped: bool,
+}
+
+// Reads the commands of the connections into the queue, until the daemon stops. Each connection
+// is read on a thread of its own, so that a slow client doesn't hold up the others; beyond
+// READERS connections being read (or waiting for room in the queue), the next ones wait in the
+// backlog of the socket.
+fn read_commands(listener: UnixListener, queue: SyncSender<Command>, saturation: Saturation) {
+    let readers = Arc::new((Mutex::new(Readers::default()), Condvar::new()));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error occurred while accepting connection: {}", e);
+                continue;
+            }
+        };
+        let (state, done) = &*readers;
+        let state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut state = done
+            .wait_while(state, |s| s.reading >= READERS && !s.stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.stopped {
+            return;
+        }
+        state.reading += 1;
+        let (queue, readers) = (queue.clone(), Arc::clone(&readers));
+        thread::spawn(move || {
+            let stopped = queue_command(stream, &queue, saturation);
+            let (state, done) = &*readers;
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.reading -= 1;
+            state.stopped |= stopped;
+            done.notify_one();
+        });
+    }
+}
+
+// Binds the socket, replacing the file of a daemon which is gone, but not that of one still
+// listening. Only the user of the daemon may connect (mode 0600): the socket is bound in a
+// directory of its own which no one else can enter, restricted, and then moved into place.
+fn bind(path: &str) -> Result<UnixListener> {
+    if fs::metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow! {"a daemon is listening on {} already", path});
+        }
+        fs::remove_file(path)?;
+    }
+    let private = PathBuf::from(format!("{}.{}.d", path, std::process::id()));
+    let bound = private.join("socket");
+    let listener = fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .and_then(|()| {
+            let listener = UnixListener::bind(&bound)?;
+            fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&bound, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
+    listener.map_err(|e| anyhow! {"cannot listen on {}: {}", path, e})
+}
+
+// `ledger daemon --socket ledger.sock [--snapshot s.json] [--config c.toml] [--fees f.csv...]
+// [--data-dir DIR] [--queue N] [--on-saturation block|shed]`: keeps the ledger (that of the
+// snapshot, or an empty one) resident and accepts commands on a Unix socket, one per connection,
+// as a line of text, to avoid cold starts and snapshot loads between batches:
+//
+// - `ingest <file.csv>` applies a transaction file and replies with a summary;
+// - `snapshot <s.json>` writes a snapshot of the ledger;
+// - `report` replies with the balances, as the CSV output;
+// - `reload-config [<c.toml>]` reloads the configuration, by default from the --config file;
+// - `status` replies with the number of accounts and records ingested;
+// - `shutdown` stops the daemon.
+//
+// Only the user of the daemon may connect to the socket. The files of ingest, snapshot and
+// reload-config are those of the data directory (the working directory of the daemon by default),
+// relative to it; others are refused.
+//
+// Commands are handled one at a time, in the order they are read; failed ones reply with a line
+// starting with "error: ". See `ledger ctl` for a client. Each connection is read on a thread of
+// its own into a queue of N commands (16 by default); when it is full, the reader waits for room
+// with --on-saturation block (the default), so that clients beyond READERS wait to connect, or
+// replies "error: busy, try again later" with shed.
+//
+// The config file and fee schedules are watched, and reloaded when they change. Limits, fees,
+// policies and flags take effect for the transactions ingested next; changes the state of the
+// ledger is incompatible with (see Ledger::incompatibilities) are refused, with a diagnostic on
+// stderr, and the daemon carries on with the configuration it had.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut socket, mut snapshot, mut config_path) = (None, None, None);
+    let mut data_dir = String::from(".");
+    let mut fees_paths = Vec::new();
+    let (mut queue, mut saturation) = (QUEUE, Saturation::Block);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--socket" => socket = Some(value()?.clone()),
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--config" => config_path = Some(value()?.clone()),
+            "--fees" => fees_paths.push(value()?.clone()),
+            "--data-dir" => data_dir = value()?.clone(),
+            "--queue" => {
+                let v = value()?;
+                queue = v
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| anyhow! {"invalid queue size {}", v})?;
+            }
+            "--on-saturation" => {
+                saturation = match value()?.as_str() {
+                    "block" => Saturation::Block,
+                    "shed" => Saturation::Shed,
+                    v => {
+                        return Err(
+                            anyhow! {"invalid --on-saturation {} (expected block or shed)", v},
+                        )
+                    }
+                }
+            }
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let socket = socket.ok_or_else(|| anyhow! {"daemon requires the --socket"})?;
+    let mut daemon = Daemon {
+        l: Ledger::new(),
+        config_path,
+        fees_paths,
+        modified: Vec::new(),
+        data_dir: fs::canonicalize(&data_dir)
+            .map_err(|e| anyhow! {"cannot use data directory {}: {}", data_dir, e})?,
+        started: Instant::now(),
+        records: 0,
+    };
+    daemon.modified = modification_times(&daemon.files(None));
+    let config = daemon.configuration(None)?;
+    daemon.l = match snapshot.as_deref() {
+        Some(path) => crate::read_snapshot(path, &config)?,
+        None => Ledger::with_config(config),
+    };
+    let listener = bind(&socket)?;
+    eprintln!("Listening on {}", socket);
+    let (sender, commands) = mpsc::sync_channel(queue);
+    thread::spawn(move || read_commands(listener, sender, saturation));
+    loop {
+        let (line, stream) = match commands.recv_timeout(POLL_INTERVAL) {
+            Ok(command) => command,
+            Err(RecvTimeoutError::Timeout) => {
+                daemon.watch();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let mut out = io::BufWriter::new(&stream);
+        let stop = daemon.handle(&line, &mut out).and_then(|stop| {
+            out.flush()?;
+            Ok(stop)
+        });
+        match stop {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => eprintln!("Error occurred while replying to command: {}", e),
+        }
+    }
+    fs::remove_file(&socket)?;
+    Ok(())
+}
+
+// `ledger ctl --socket ledger.sock <command...>`: sends a command to `ledger daemon` and writes
+// its reply to stdout. Fails if the reply is an error.
+pub fn ctl(args: &[String]) -> Result<()> {
+    let (socket, words) = match args {
+        [option, socket, words @ ..] if option == "--socket" && !words.is_empty() => {
+            (socket, words)
+        }
+        _ => return Err(anyhow! {"ctl requires the --socket and a command"}),
+    };
+    let mut stream =
+        UnixStream::connect(socket).map_err(|e| anyhow! {"cannot connect to {}: {}", socket, e})?;
+    writeln!(stream, "{}", words.join(" "))?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    print!("{}", reply);
+    if let Some(e) = reply.strip_prefix("error: ") {
+        return Err(anyhow! {"{}", e.trim_end()});
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // Reader of the connections of a socket of the test, with a queue of the given size (0
+    // being full whenever the receiver isn't waiting for a command).
+    fn reader(
+        name: &str,
+        queue: usize,
+        saturation: Saturation,
+    ) -> (String, mpsc::Receiver<Command>) {
+        let path =
+            std::env::temp_dir().join(format!("ledger-{}-{}.sock", name, std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let listener = bind(&path).unwrap();
+        let (sender, commands) = mpsc::sync_channel(queue);
+        thread::spawn(move || read_commands(listener, sender, saturation));
+        (path, commands)
+    }
+
+    fn send(path: &str, command: &str) -> UnixStream {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(command.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        stream
+    }
+
+    fn reply(mut stream: UnixStream) -> String {
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn commands_beyond_the_queue_are_shed() {
+        let (path, commands) = reader("shed", 0, Saturation::Shed);
+        let shed = send(&path, "report\n");
+        assert_eq!(reply(shed), "error: busy, try again later\n");
+        drop(commands);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commands_beyond_the_queue_wait_for_room() {
+        let (path, commands) = reader("block", 1, Saturation::Block);
+        let _first = send(&path, "status\n");
+        let _second = send(&path, "report\n");
+        // In the order they were read, which need not be that of the connections.
+        let mut lines = [commands.recv().unwrap().0, commands.recv().unwrap().0];
+        lines.sort();
+        assert_eq!(lines, ["report\n", "status\n"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn slow_clients_dont_hold_up_the_others() {
+        let (path, commands) = reader("slow", 1, Saturation::Block);
+        // Connected, but yet to send its command.
+        let _slow = UnixStream::connect(&path).unwrap();
+        let _status = send(&path, "status\n");
+        let (line, _) = commands.recv_timeout(COMMAND_TIMEOUT / 2).unwrap();
+        assert_eq!(line, "status\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sockets_are_only_open_to_their_user() {
+        let (path, _commands) = reader("mode", 1, Saturation::Block);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!Path::new(&format!("{}.{}.d", path, std::process::id())).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn files_of_commands_are_in_the_data_directory() {
+        let dir = std::env::temp_dir().join(format!("ledger-data-{}", std::process::id()));
+        fs::create_dir_all(dir.join("in")).unwrap();
+        fs::write(
+            dir.join("in/batch.csv"),
+            "type,client,tx,amount\ndeposit,1,1,5\n",
+        )
+        .unwrap();
+        let mut daemon = Daemon {
+            l: Ledger::new(),
+            config_path: None,
+            fees_paths: Vec::new(),
+            modified: Vec::new(),
+            data_dir: dir.canonicalize().unwrap(),
+            started: Instant::now(),
+            records: 0,
+        };
+        let mut run = |line: &str| {
+            let mut out = Vec::new();
+            daemon.handle(line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(run("ingest in/batch.csv").starts_with("Ingested 1 records"));
+        assert!(run("snapshot s.json").starts_with("Wrote snapshot"));
+        assert!(dir.join("s.json").exists());
+        let outside = std::env::temp_dir().join("s.json");
+        for line in [
+            "ingest ../batch.csv".to_string(),
+            "ingest in/../../batch.csv".to_string(),
+            "ingest /etc/passwd".to_string(),
+            format!("snapshot {}", outside.display()),
+            "snapshot in/..".to_string(),
+            "snapshot .".to_string(),
+            "reload-config ../c.toml".to_string(),
+        ] {
+            let reply = run(&line);
+            assert!(reply.starts_with("error: "), "{}: {}", line, reply);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn long_commands_are_refused() {
+        let (path, commands) = reader("long", 1, Saturation::Block);
+        let long = send(&path, &"x".repeat(MAX_COMMAND as usize));
+        assert!(reply(long).starts_with("error: command longer than"));
+        let _short = send(&path, "status\n");
+        assert_eq!(commands.recv().unwrap().0, "status\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
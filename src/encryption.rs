@@ -0,0 +1,670 @@
+use anyhow::{anyhow, Result};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::process::Command;
+use std::sync::OnceLock;
+
+// Environment variables giving the key the state files (snapshots and checkpoints) and the audit
+// logs are encrypted with: a file holding the key, or a command printing it (the hook of a KMS, e.g.
+// `aws kms decrypt ...` or `vault kv get ...`), in hex. Without either, they are written in
+// plaintext.
+const KEY_FILE: &str = "LEDGER_ENCRYPTION_KEY_FILE";
+const KEY_COMMAND: &str = "LEDGER_ENCRYPTION_KEY_COMMAND";
+// Environment variable allowing the plaintext state files and logs to be read while there is a
+// key, to migrate them to encryption (state files are encrypted when written back). Without it,
+// they are refused, as a plaintext file where an encrypted one is expected may have been swapped
+// in to get around the authentication.
+const ALLOW_PLAINTEXT: &str = "LEDGER_ALLOW_PLAINTEXT";
+
+// Start of an encrypted state file, followed by the nonce, the ciphertext and the tag of
+// AES-256-GCM. It is the associated data of the encryption, so that it can't be altered either.
+const MAGIC: &[u8] = b"ledger-aes-256-gcm-v1\n";
+const NONCE: usize = 12;
+const TAG: usize = 16;
+
+// Start of an encrypted log, followed by frames of the length of the rest of the frame (4 bytes,
+// big endian), the nonce, the ciphertext and the tag. A log is written as it goes, so it is sealed
+// a frame at a time; the associated data of a frame is the magic and the index of the frame, so
+// that frames can't be reordered or dropped, short of cutting the log after a frame (as a
+// plaintext log can be cut after a line).
+const LOG_MAGIC: &[u8] = b"ledger-aes-256-gcm-log-v1\n";
+// Plaintext sealed per frame, at most (but for a single larger write).
+const FRAME: usize = 64 << 10;
+
+// 256-bit key, in hex (surrounding whitespace ignored).
+fn parse_key(hex: &str, source: &str) -> Result<[u8; 32]> {
+    let hex = hex.trim();
+    let invalid = || anyhow! {"invalid encryption key from {} (expected 64 hex digits)", source};
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+fn load_key() -> Result<Option<[u8; 32]>> {
+    if let Some(path) = std::env::var_os(KEY_FILE) {
+        let path = path.to_string_lossy();
+        let hex = fs::read_to_string(&*path)
+            .map_err(|e| anyhow! {"cannot read encryption key {}: {}", path, e})?;
+        return parse_key(&hex, &path).map(Some);
+    }
+    if let Some(command) = std::env::var_os(KEY_COMMAND) {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .output()
+            .map_err(|e| anyhow! {"cannot run {}: {}", KEY_COMMAND, e})?;
+        if !output.status.success() {
+            return Err(anyhow! {"{} failed with {}", KEY_COMMAND, output.status});
+        }
+        return parse_key(&String::from_utf8_lossy(&output.stdout), KEY_COMMAND).map(Some);
+    }
+    Ok(None)
+}
+
+// The key, loaded (and the command run) once per process, as state files are written at every
+// checkpoint.
+fn key() -> Result<Option<&'static [u8; 32]>> {
+    static KEY: OnceLock<Result<Option<[u8; 32]>, String>> = OnceLock::new();
+    match KEY.get_or_init(|| load_key().map_err(|e| e.to_string())) {
+        Ok(key) => Ok(key.as_ref()),
+        Err(e) => Err(anyhow! {"{}", e}),
+    }
+}
+
+fn nonce() -> Result<[u8; NONCE]> {
+    let mut nonce = [0; NONCE];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut nonce))
+        .map_err(|e| anyhow! {"cannot generate a nonce: {}", e})?;
+    Ok(nonce)
+}
+
+// Contents of a state file as written to disk: encrypted when there is a key, in plaintext
+// otherwise.
+pub fn seal(contents: Vec<u8>) -> Result<Vec<u8>> {
+    let key = match key()? {
+        Some(key) => key,
+        None => return Ok(contents),
+    };
+    let nonce = nonce()?;
+    let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE + contents.len() + TAG);
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    let mut data = contents;
+    let tag = Gcm::new(key).encrypt(&nonce, MAGIC, &mut data);
+    sealed.extend_from_slice(&data);
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+// Plaintext contents of a file, as they are: without a key, or with one while migrating, see
+// ALLOW_PLAINTEXT.
+fn plaintext(
+    path: &str,
+    contents: Vec<u8>,
+    key: Option<&[u8; 32]>,
+    allowed: bool,
+) -> Result<Vec<u8>> {
+    if key.is_some() && !allowed {
+        return Err(anyhow! {
+            "{} is not encrypted, but there is an encryption key (set {}=1 to read it while migrating to encryption)",
+            path,
+            ALLOW_PLAINTEXT
+        });
+    }
+    Ok(contents)
+}
+
+fn plaintext_allowed() -> bool {
+    std::env::var_os(ALLOW_PLAINTEXT).is_some_and(|v| v == "1")
+}
+
+// Contents of a state file as read from disk, decrypted if it is encrypted. Plaintext files are
+// read as they are without a key, see plaintext.
+pub fn open(path: &str, contents: Vec<u8>) -> Result<Vec<u8>> {
+    open_state(path, contents, key()?, plaintext_allowed())
+}
+
+fn open_state(
+    path: &str,
+    contents: Vec<u8>,
+    key: Option<&[u8; 32]>,
+    plaintext_allowed: bool,
+) -> Result<Vec<u8>> {
+    if !contents.starts_with(MAGIC) {
+        return plaintext(path, contents, key, plaintext_allowed);
+    }
+    let key = key.ok_or_else(|| {
+        anyhow! {"{} is encrypted, and neither {} nor {} is set", path, KEY_FILE, KEY_COMMAND}
+    })?;
+    let body = &contents[MAGIC.len()..];
+    if body.len() < NONCE + TAG {
+        return Err(anyhow! {"{} is truncated", path});
+    }
+    let (nonce, rest) = body.split_at(NONCE);
+    let (data, tag) = rest.split_at(rest.len() - TAG);
+    let mut data = data.to_vec();
+    if !Gcm::new(key).decrypt(nonce.try_into().unwrap(), MAGIC, &mut data, tag) {
+        return Err(anyhow! {"cannot decrypt {}: wrong key, or the file was altered", path});
+    }
+    Ok(data)
+}
+
+// Reads a state file, see open.
+pub fn read(path: &str) -> Result<Vec<u8>> {
+    let contents = fs::read(path)?;
+    open(path, contents)
+}
+
+// Writes a state file, see seal, and syncs it to disk if asked to.
+pub fn write(path: &str, contents: Vec<u8>, sync: bool) -> Result<()> {
+    let sealed = seal(contents)?;
+    let mut file = File::create(path)?;
+    file.write_all(&sealed)?;
+    if sync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Associated data of the frame of a log with the index.
+fn frame_aad(index: u64) -> Vec<u8> {
+    let mut aad = LOG_MAGIC.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad
+}
+
+// Writer of a log (the audit log), encrypted frame by frame when there is a key (see LOG_MAGIC),
+// in plaintext otherwise. The lines are buffered up to a frame, sealed when the buffer is full or
+// flushed.
+pub struct LogWriter<W: Write> {
+    out: W,
+    key: Option<&'static [u8; 32]>,
+    frame: Vec<u8>,
+    frames: u64,
+}
+
+impl LogWriter<BufWriter<File>> {
+    pub fn create(path: &str) -> Result<LogWriter<BufWriter<File>>> {
+        let key = key()?;
+        Ok(LogWriter::new(BufWriter::new(File::create(path)?), key)?)
+    }
+
+    // Carries on the log of the file, positioned at its end: encrypted if it is (numbering the
+    // frames on from those it has), in plaintext if it isn't, as a log can't switch halfway.
+    pub fn resume(path: &str, mut file: File) -> Result<LogWriter<BufWriter<File>>> {
+        let key = key()?;
+        let mut contents = Vec::new();
+        file.rewind()?;
+        file.read_to_end(&mut contents)?;
+        if contents.is_empty() {
+            return Ok(LogWriter::new(BufWriter::new(file), key)?);
+        }
+        let frames = match (contents.strip_prefix(LOG_MAGIC), key) {
+            (Some(rest), Some(_)) => {
+                frames(rest).ok_or_else(|| anyhow! {"{} is truncated", path})?
+            }
+            (None, None) => 0,
+            (Some(_), None) => {
+                return Err(
+                    anyhow! {"{} is encrypted, and neither {} nor {} is set", path, KEY_FILE, KEY_COMMAND},
+                )
+            }
+            (None, Some(_)) => {
+                return Err(anyhow! {"{} is in plaintext, and can't be carried on encrypted", path})
+            }
+        };
+        Ok(LogWriter {
+            out: BufWriter::new(file),
+            key,
+            frame: Vec::new(),
+            frames,
+        })
+    }
+
+    // Flushes the log and returns its length.
+    pub fn len(&mut self) -> io::Result<u64> {
+        self.flush()?;
+        Ok(self.out.get_ref().metadata()?.len())
+    }
+
+    // Flushes the log and syncs it to disk.
+    pub fn sync(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.out.get_ref().sync_all()
+    }
+}
+
+impl<W: Write> LogWriter<W> {
+    fn new(mut out: W, key: Option<&'static [u8; 32]>) -> io::Result<LogWriter<W>> {
+        if key.is_some() {
+            out.write_all(LOG_MAGIC)?;
+        }
+        Ok(LogWriter {
+            out,
+            key,
+            frame: Vec::new(),
+            frames: 0,
+        })
+    }
+
+    fn seal_frame(&mut self, key: &[u8; 32]) -> io::Result<()> {
+        let nonce = nonce().map_err(io::Error::other)?;
+        let tag = Gcm::new(key).encrypt(&nonce, &frame_aad(self.frames), &mut self.frame);
+        let len = u32::try_from(NONCE + self.frame.len() + TAG)
+            .map_err(|_| io::Error::other("frame too large"))?;
+        self.out.write_all(&len.to_be_bytes())?;
+        self.out.write_all(&nonce)?;
+        self.out.write_all(&self.frame)?;
+        self.out.write_all(&tag)?;
+        self.frame.clear();
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for LogWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(key) = self.key else {
+            return self.out.write(data);
+        };
+        self.frame.extend_from_slice(data);
+        if self.frame.len() >= FRAME {
+            self.seal_frame(key)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(key) = self.key.filter(|_| !self.frame.is_empty()) {
+            self.seal_frame(key)?;
+        }
+        self.out.flush()
+    }
+}
+
+// Number of frames of an encrypted log after the magic, or None if the last one is cut short.
+fn frames(mut rest: &[u8]) -> Option<u64> {
+    let mut frames = 0;
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().unwrap()) as usize;
+        rest = rest.get(4 + len..)?;
+        frames += 1;
+    }
+    Some(frames)
+}
+
+// Contents of a log as read from disk, decrypted if it is encrypted (see LOG_MAGIC). Plaintext
+// logs are read as they are without a key, like plaintext state files.
+fn open_log(
+    path: &str,
+    contents: Vec<u8>,
+    key: Option<&[u8; 32]>,
+    plaintext_allowed: bool,
+) -> Result<Vec<u8>> {
+    let Some(mut rest) = contents.strip_prefix(LOG_MAGIC) else {
+        return plaintext(path, contents, key, plaintext_allowed);
+    };
+    let key = key.ok_or_else(|| {
+        anyhow! {"{} is encrypted, and neither {} nor {} is set", path, KEY_FILE, KEY_COMMAND}
+    })?;
+    let gcm = Gcm::new(key);
+    let mut log = Vec::with_capacity(rest.len());
+    let mut index = 0;
+    while !rest.is_empty() {
+        let truncated = || anyhow! {"{} is truncated", path};
+        let len = rest.get(..4).ok_or_else(truncated)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let frame = rest.get(4..4 + len).ok_or_else(truncated)?;
+        if len < NONCE + TAG {
+            return Err(truncated());
+        }
+        let (nonce, frame) = frame.split_at(NONCE);
+        let (data, tag) = frame.split_at(frame.len() - TAG);
+        let mut data = data.to_vec();
+        if !gcm.decrypt(nonce.try_into().unwrap(), &frame_aad(index), &mut data, tag) {
+            return Err(anyhow! {"cannot decrypt {}: wrong key, or the file was altered", path});
+        }
+        log.extend_from_slice(&data);
+        rest = &rest[4 + len..];
+        index += 1;
+    }
+    Ok(log)
+}
+
+// Reads a log, see open_log.
+pub fn read_log(path: &str) -> Result<String> {
+    let contents = fs::read(path)?;
+    let log = open_log(path, contents, key()?, plaintext_allowed())?;
+    String::from_utf8(log).map_err(|e| anyhow! {"{}: {}", path, e})
+}
+
+// Multiplication by x in GF(2^8).
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+// AES-256 block cipher (FIPS 197), encryption only as GCM needs no more. The S-box is computed
+// rather than tabulated. Table lookups aren't constant-time: this protects files at rest, not
+// keys against an attacker timing the process on the same machine.
+struct Aes256 {
+    sbox: [u8; 256],
+    round_keys: [[u8; 16]; 15],
+}
+
+impl Aes256 {
+    fn new(key: &[u8; 32]) -> Aes256 {
+        // The affine transformation of the inverses, walking GF(2^8) by powers of 3.
+        let mut sbox = [0; 256];
+        let (mut p, mut q) = (1u8, 1u8);
+        loop {
+            p ^= xtime(p);
+            q ^= q << 1;
+            q ^= q << 2;
+            q ^= q << 4;
+            if q & 0x80 != 0 {
+                q ^= 0x09;
+            }
+            let affine =
+                q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4);
+            sbox[p as usize] = affine ^ 0x63;
+            if p == 1 {
+                break;
+            }
+        }
+        sbox[0] = 0x63;
+        let mut words = [[0u8; 4]; 60];
+        for (i, w) in words.iter_mut().take(8).enumerate() {
+            w.copy_from_slice(&key[4 * i..4 * i + 4]);
+        }
+        let mut rcon = 1;
+        for i in 8..60 {
+            let mut t = words[i - 1];
+            if i % 8 == 0 {
+                t.rotate_left(1);
+                t = t.map(|b| sbox[b as usize]);
+                t[0] ^= rcon;
+                rcon = xtime(rcon);
+            } else if i % 8 == 4 {
+                t = t.map(|b| sbox[b as usize]);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 8][j] ^ t[j];
+            }
+        }
+        let mut round_keys = [[0; 16]; 15];
+        for (r, k) in round_keys.iter_mut().enumerate() {
+            for c in 0..4 {
+                k[4 * c..4 * c + 4].copy_from_slice(&words[4 * r + c]);
+            }
+        }
+        Aes256 { sbox, round_keys }
+    }
+
+    fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let add = |block: &mut [u8; 16], k: &[u8; 16]| {
+            for (b, k) in block.iter_mut().zip(k) {
+                *b ^= k;
+            }
+        };
+        add(block, &self.round_keys[0]);
+        for round in 1..15 {
+            // SubBytes and ShiftRows: the byte of row r of column c comes from column c + r.
+            let s = *block;
+            for c in 0..4 {
+                for r in 0..4 {
+                    block[4 * c + r] = self.sbox[s[4 * ((c + r) % 4) + r] as usize];
+                }
+            }
+            if round < 14 {
+                for column in block.chunks_exact_mut(4) {
+                    let a = [column[0], column[1], column[2], column[3]];
+                    let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+                    for r in 0..4 {
+                        column[r] = a[r] ^ all ^ xtime(a[r] ^ a[(r + 1) % 4]);
+                    }
+                }
+            }
+            add(block, &self.round_keys[round]);
+        }
+    }
+}
+
+// AES-256-GCM (NIST SP 800-38D) with 96-bit nonces and 128-bit tags.
+struct Gcm {
+    aes: Aes256,
+    h: u128, // Hash key, the encrypted zero block
+}
+
+impl Gcm {
+    fn new(key: &[u8; 32]) -> Gcm {
+        let aes = Aes256::new(key);
+        let mut zero = [0; 16];
+        aes.encrypt_block(&mut zero);
+        Gcm {
+            aes,
+            h: u128::from_be_bytes(zero),
+        }
+    }
+
+    // Multiplication in GF(2^128), bit by bit, with the bit order of GCM. The bits select with
+    // masks rather than branches, so that the time doesn't depend on the hash key or the data.
+    fn multiply(&self, x: u128) -> u128 {
+        let (mut z, mut v) = (0, self.h);
+        for i in 0..128 {
+            z ^= v & 0u128.wrapping_sub((x >> (127 - i)) & 1);
+            v = (v >> 1) ^ ((0xe1 << 120) & 0u128.wrapping_sub(v & 1));
+        }
+        z
+    }
+
+    fn ghash(&self, aad: &[u8], ciphertext: &[u8]) -> u128 {
+        let mut y = 0;
+        for data in [aad, ciphertext] {
+            for chunk in data.chunks(16) {
+                let mut block = [0; 16];
+                block[..chunk.len()].copy_from_slice(chunk);
+                y = self.multiply(y ^ u128::from_be_bytes(block));
+            }
+        }
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        self.multiply(y ^ lengths)
+    }
+
+    fn counter_block(nonce: &[u8; NONCE], counter: u32) -> [u8; 16] {
+        let mut block = [0; 16];
+        block[..NONCE].copy_from_slice(nonce);
+        block[NONCE..].copy_from_slice(&counter.to_be_bytes());
+        block
+    }
+
+    // Encrypts or decrypts the data in place, with the counters following that of the tag.
+    fn ctr(&self, nonce: &[u8; NONCE], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(16).enumerate() {
+            let mut stream = Gcm::counter_block(nonce, (i as u32).wrapping_add(2));
+            self.aes.encrypt_block(&mut stream);
+            for (b, s) in chunk.iter_mut().zip(stream) {
+                *b ^= s;
+            }
+        }
+    }
+
+    fn tag(&self, nonce: &[u8; NONCE], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG] {
+        let mut mask = Gcm::counter_block(nonce, 1);
+        self.aes.encrypt_block(&mut mask);
+        (u128::from_be_bytes(mask) ^ self.ghash(aad, ciphertext)).to_be_bytes()
+    }
+
+    fn encrypt(&self, nonce: &[u8; NONCE], aad: &[u8], data: &mut [u8]) -> [u8; TAG] {
+        self.ctr(nonce, data);
+        self.tag(nonce, aad, data)
+    }
+
+    // Decrypts the data in place if the tag is that of the data, and returns whether it is.
+    fn decrypt(&self, nonce: &[u8; NONCE], aad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+        let expected = self.tag(nonce, aad, data);
+        // Compared in full, not up to the first difference.
+        let difference = expected.iter().zip(tag).fold(0, |d, (a, b)| d | (a ^ b));
+        if difference != 0 || tag.len() != TAG {
+            return false;
+        }
+        self.ctr(nonce, data);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // FIPS 197, appendix C.3.
+    #[test]
+    fn aes_256_encrypts_the_fips_197_example() {
+        let key: [u8; 32] = hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+            .try_into()
+            .unwrap();
+        let mut block: [u8; 16] = hex("00112233445566778899aabbccddeeff").try_into().unwrap();
+        Aes256::new(&key).encrypt_block(&mut block);
+        assert_eq!(block.to_vec(), hex("8ea2b7ca516745bfeafc49904b496089"));
+    }
+
+    // Test cases 13 to 16 of the GCM specification (McGrew and Viega), the AES-256 ones with
+    // 96-bit nonces, as used to validate SP 800-38D implementations.
+    #[test]
+    fn gcm_matches_the_known_answers() {
+        let k15 = "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308";
+        let p15 = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                   1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+        let c15 = "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+                   8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad";
+        let cases = [
+            (
+                &"0".repeat(64)[..],
+                "000000000000000000000000",
+                "",
+                "",
+                "",
+                "530f8afbc74536b9a963b4f1c4cb738b",
+            ),
+            (
+                &"0".repeat(64)[..],
+                "000000000000000000000000",
+                "",
+                "00000000000000000000000000000000",
+                "cea7403d4d606b6e074ec5d3baf39d18",
+                "d0d1c8a799996bf0265b98b5d48ab919",
+            ),
+            (
+                k15,
+                "cafebabefacedbaddecaf888",
+                "",
+                p15,
+                c15,
+                "b094dac5d93471bdec1a502270e3cc6c",
+            ),
+            (
+                k15,
+                "cafebabefacedbaddecaf888",
+                "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+                &p15[..120],
+                &c15[..120],
+                "76fc6ece0f4e1768cddf8853bb2d551b",
+            ),
+        ];
+        for (key, nonce, aad, plaintext, ciphertext, tag) in cases {
+            let key: [u8; 32] = hex(key).try_into().unwrap();
+            let nonce: [u8; NONCE] = hex(nonce).try_into().unwrap();
+            let gcm = Gcm::new(&key);
+            let mut data = hex(plaintext);
+            let sealed = gcm.encrypt(&nonce, &hex(aad), &mut data);
+            assert_eq!(data, hex(ciphertext));
+            assert_eq!(sealed.to_vec(), hex(tag));
+            assert!(gcm.decrypt(&nonce, &hex(aad), &mut data, &sealed));
+            assert_eq!(data, hex(plaintext));
+            let mut altered = hex(tag);
+            altered[0] ^= 1;
+            assert!(!gcm.decrypt(&nonce, &hex(aad), &mut hex(ciphertext), &altered));
+        }
+    }
+
+    static KEY: [u8; 32] = [7; 32];
+
+    fn sealed_log(lines: &[&str]) -> Vec<u8> {
+        let mut w = LogWriter::new(Vec::new(), Some(&KEY)).unwrap();
+        for line in lines {
+            writeln!(w, "{}", line).unwrap();
+            // A frame per line.
+            w.flush().unwrap();
+        }
+        w.out
+    }
+
+    #[test]
+    fn logs_are_sealed_frame_by_frame() {
+        let sealed = sealed_log(&[r#"{"line":1}"#, r#"{"line":2}"#]);
+        assert!(sealed.starts_with(LOG_MAGIC));
+        assert!(!String::from_utf8_lossy(&sealed).contains("line"));
+        let log = open_log("audit.log", sealed, Some(&KEY), false).unwrap();
+        assert_eq!(log, b"{\"line\":1}\n{\"line\":2}\n");
+    }
+
+    #[test]
+    fn plaintext_logs_are_written_and_read_as_they_are() {
+        let mut w = LogWriter::new(Vec::new(), None).unwrap();
+        writeln!(w, "{{}}").unwrap();
+        w.flush().unwrap();
+        assert_eq!(w.out, b"{}\n");
+        assert_eq!(open_log("audit.log", w.out, None, false).unwrap(), b"{}\n");
+    }
+
+    #[test]
+    fn plaintext_is_refused_with_a_key_unless_migrating() {
+        let log = b"{}\n".to_vec();
+        assert!(open_log("audit.log", log.clone(), Some(&KEY), false).is_err());
+        assert_eq!(
+            open_log("audit.log", log.clone(), Some(&KEY), true).unwrap(),
+            log
+        );
+        let state = br#"{"version":1}"#.to_vec();
+        let e = open_state("snapshot.json", state.clone(), Some(&KEY), false).unwrap_err();
+        assert!(e.to_string().contains(ALLOW_PLAINTEXT));
+        assert_eq!(
+            open_state("snapshot.json", state.clone(), Some(&KEY), true).unwrap(),
+            state
+        );
+        assert_eq!(
+            open_state("snapshot.json", state.clone(), None, false).unwrap(),
+            state
+        );
+    }
+
+    #[test]
+    fn altered_or_reordered_frames_are_refused() {
+        let sealed = sealed_log(&["a", "b"]);
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(open_log("audit.log", altered, Some(&KEY), false).is_err());
+        // Each frame is the length, the nonce, a line of 2 bytes and the tag.
+        let frame = 4 + NONCE + 2 + TAG;
+        let frames = &sealed[LOG_MAGIC.len()..];
+        let mut swapped = LOG_MAGIC.to_vec();
+        swapped.extend_from_slice(&frames[frame..]);
+        swapped.extend_from_slice(&frames[..frame]);
+        assert!(open_log("audit.log", swapped, Some(&KEY), false).is_err());
+        let truncated = sealed[..sealed.len() - 1].to_vec();
+        assert!(open_log("audit.log", truncated, Some(&KEY), false).is_err());
+        assert!(open_log("audit.log", sealed, None, false).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::audit;
+use crate::metadata::rfc3339;
+use anyhow::{anyhow, Result};
+use ledger::Amount;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::SystemTime;
+
+// Balances a tombstone retains.
+#[derive(Debug, serde::Serialize)]
+struct Retained {
+    available: Amount,
+    held: Amount,
+    escrow: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ErasedLog<'a> {
+    path: &'a str,
+    records: u64, // Removed from the log
+}
+
+// Record of an erasure in the erasure log, which only links the client to the erasure itself.
+#[derive(Debug, serde::Serialize)]
+struct ErasureRecord<'a> {
+    at: String,
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retained: Option<Retained>,
+    audit_logs: Vec<ErasedLog<'a>>,
+}
+
+// `ledger forget --client <id> --erasure-log erasures.ndjson [--snapshot s.json [--snapshot-out
+// o.json]] [--audit-log log.ndjson...] [--config c.toml]`: erases the personal linkage of a client (an erasure
+// request) from the persisted state. In the snapshot, the account of the client becomes a
+// tombstone which retains its balances, and nothing else of its history (see
+// Ledger::forget_client); in the audit logs, the records of the client are replaced by a
+// tombstone with the balances they left (see audit::forget). The totals of the ledger and of the
+// logs stay the same. It is refused while the ledger still needs the history of the account, for
+// open disputes or pending expiries, or while the client is under a legal hold of the snapshot or
+// config file, before anything is changed.
+//
+// Every erasure is appended to the erasure log, one JSON object per line, with the time, the
+// client, the files changed and what was retained. The snapshot is written back through a
+// temporary file, like with `ledger undo`, and encrypted as snapshots are.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut client, mut erasure_log, mut snapshot, mut snapshot_out) = (None, None, None, None);
+    let mut config = None;
+    let mut audit_logs = Vec::new();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--client" => {
+                let v = value()?;
+                client = Some(
+                    v.parse::<u16>()
+                        .map_err(|_| anyhow! {"invalid client {}", v})?,
+                );
+            }
+            "--erasure-log" => erasure_log = Some(value()?.clone()),
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--snapshot-out" => snapshot_out = Some(value()?.clone()),
+            "--audit-log" => audit_logs.push(value()?.clone()),
+            "--config" => config = Some(value()?.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let client = client.ok_or_else(|| anyhow! {"forget requires the --client"})?;
+    let erasure_log = erasure_log.ok_or_else(|| anyhow! {"forget requires the --erasure-log"})?;
+    if snapshot.is_none() && audit_logs.is_empty() {
+        return Err(anyhow! {"forget requires a --snapshot or audit logs"});
+    }
+    if snapshot.is_none() && snapshot_out.is_some() {
+        return Err(anyhow! {"--snapshot-out requires --snapshot"});
+    }
+    // Those of the snapshot are found by Ledger::forget_client.
+    let holds = crate::legal_hold::holds(config.as_deref(), None)?;
+    let holds = holds.iter().filter(|h| h.client == client).count();
+    if holds > 0 {
+        return Err(anyhow! {"cannot forget client {}: {} legal holds are on it", client, holds});
+    }
+    // Opened first, so that an erasure is never left unrecorded for want of its log.
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&erasure_log)
+        .map_err(|e| anyhow! {"cannot open erasure log {}: {}", erasure_log, e})?;
+    let mut retained = None;
+    if let Some(path) = snapshot.as_deref() {
+        let mut l = crate::load_snapshot(path)?;
+        let balance = l.forget_client(client).map_err(|found| {
+            let found: Vec<String> = found.iter().map(|b| b.to_string()).collect();
+            anyhow! {"cannot forget client {}: {}", client, found.join(", ")}
+        })?;
+        let out = snapshot_out.as_deref().unwrap_or(path);
+        let tmp = format!("{}.tmp", out);
+        crate::write_snapshot(&tmp, &l)?;
+        fs::rename(&tmp, out)?;
+        match balance {
+            Some(b) => eprintln!(
+                "Forgot client {} in {}: the tombstone retains available {}, held {}, total {}{}",
+                client,
+                out,
+                b.available,
+                b.held,
+                b.total,
+                if b.locked { ", locked" } else { "" }
+            ),
+            None => eprintln!("Client {} has no account in {}", client, path),
+        }
+        retained = balance.map(|b| Retained {
+            available: b.available,
+            held: b.held,
+            escrow: b.escrow,
+            total: b.total,
+            locked: b.locked,
+        });
+    }
+    let mut erased = Vec::new();
+    for path in &audit_logs {
+        let records = audit::forget(path, client)?;
+        eprintln!(
+            "Removed {} records of client {} from {}",
+            records, client, path
+        );
+        erased.push(ErasedLog { path, records });
+    }
+    let record = ErasureRecord {
+        at: rfc3339(SystemTime::now()),
+        client,
+        snapshot: snapshot_out.as_deref().or(snapshot.as_deref()),
+        retained,
+        audit_logs: erased,
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    log.write_all(&line)?;
+    log.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use thiserror::Error;
+
+// Reasons for which a transaction is rejected by the ledger. The messages are what gets reported
+// on stderr, the codes are stable identifiers for machine-readable outputs (the reason of the
+// rejects files): they never change once released, and new reasons get new codes. New reasons may
+// come in any release, so matches on it outside of the crate need a catch-all arm.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum LedgerError {
+    #[error("The account is locked! Skipping transaction")]
+    AccountLocked,
+    #[error("Insufficient funds. Skipping withdrawal")]
+    InsufficientFunds,
+    #[error("Illegal state transition. Skipping operation")]
+    IllegalStateTransition,
+    #[error("Duplicate transaction id. Skipping operation")]
+    DuplicateTransaction,
+    #[error("Transaction not found in log. Skipping operation")]
+    TransactionNotFound,
+    #[error("Unknown transaction type. Skipping operation")]
+    UnknownTransactionType,
+    #[error("Missing amount. Skipping operation")]
+    MissingAmount,
+    #[error("Transaction disputed too many times. Skipping dispute")]
+    MaxDisputesExceeded,
+    #[error("Amount exceeds the disputable amount. Skipping operation")]
+    InvalidDisputeAmount,
+    #[error("Insufficient available funds to hold the disputed amount. Skipping dispute")]
+    InsufficientFundsForDispute,
+    #[error("Withdrawal would breach the minimum balance of the client tier. Skipping withdrawal")]
+    BelowMinimumBalance,
+    #[error("Deposit would exceed the maximum balance. Skipping deposit")]
+    MaxBalanceExceeded,
+    #[error("Amount exceeds the maximum transaction amount. Skipping operation")]
+    AmountLimitExceeded,
+    #[error("Missing currency. Skipping conversion")]
+    MissingCurrency,
+    #[error("No exchange rate for the currencies. Skipping conversion")]
+    RateNotFound,
+    #[error("Missing timestamp. Skipping bonus")]
+    MissingTimestamp,
+    #[error("Invalid sub-account name. Skipping operation")]
+    InvalidSubaccount,
+    #[error("Too many distinct tags for the account. Skipping operation")]
+    TooManyTags,
+    #[error("Already applied as transaction {0} (same idempotency key). Acknowledging retry")]
+    AlreadyApplied(u32),
+    #[error(
+        "Idempotency key of transaction {0} reused with another payload. Skipping transaction"
+    )]
+    IdempotencyKeyReused(u32),
+    #[error("Admin operations are not allowed. Skipping adjustment")]
+    AdminOpsNotAllowed,
+    #[error("Missing reason code. Skipping adjustment")]
+    MissingReason,
+    #[error("The client was forgotten. Skipping transaction")]
+    ClientForgotten,
+    #[error("Amount has more decimal places than its currency. Skipping operation")]
+    ExcessPrecision,
+    #[error("Amount or balance out of range. Skipping operation")]
+    AmountOutOfRange,
+    #[error("Storage error: {0}")]
+    Storage(String),
+}
+
+impl LedgerError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LedgerError::AccountLocked => "account_locked",
+            LedgerError::InsufficientFunds => "insufficient_funds",
+            LedgerError::IllegalStateTransition => "illegal_state_transition",
+            LedgerError::DuplicateTransaction => "duplicate_transaction",
+            LedgerError::TransactionNotFound => "transaction_not_found",
+            LedgerError::UnknownTransactionType => "unknown_transaction_type",
+            LedgerError::MissingAmount => "missing_amount",
+            LedgerError::MaxDisputesExceeded => "max_disputes_exceeded",
+            LedgerError::InvalidDisputeAmount => "invalid_dispute_amount",
+            LedgerError::InsufficientFundsForDispute => "insufficient_funds_for_dispute",
+            LedgerError::BelowMinimumBalance => "below_minimum_balance",
+            LedgerError::MaxBalanceExceeded => "max_balance_exceeded",
+            LedgerError::AmountLimitExceeded => "amount_limit_exceeded",
+            LedgerError::MissingCurrency => "missing_currency",
+            LedgerError::RateNotFound => "rate_not_found",
+            LedgerError::MissingTimestamp => "missing_timestamp",
+            LedgerError::InvalidSubaccount => "invalid_subaccount",
+            LedgerError::TooManyTags => "too_many_tags",
+            LedgerError::AlreadyApplied(_) => "already_applied",
+            LedgerError::IdempotencyKeyReused(_) => "idempotency_key_reused",
+            LedgerError::AdminOpsNotAllowed => "admin_ops_not_allowed",
+            LedgerError::MissingReason => "missing_reason",
+            LedgerError::ClientForgotten => "client_forgotten",
+            LedgerError::ExcessPrecision => "excess_precision",
+            LedgerError::AmountOutOfRange => "amount_out_of_range",
+            LedgerError::Storage(_) => "storage_error",
+        }
+    }
+}
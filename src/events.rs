@@ -0,0 +1,111 @@
+use crate::output::Decimals;
+use ledger::{Applied, Balance, Timestamp};
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+
+// Balances of the account around an event.
+#[derive(Debug, serde::Serialize)]
+struct EventBalance {
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+}
+
+// Applied operation, as streamed: the operation and the balances of the account before and after
+// it.
+#[derive(Debug, serde::Serialize)]
+pub struct Event<'a> {
+    tx: u32,
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subaccount: Option<&'a str>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<f64>,
+    before: EventBalance,
+    after: EventBalance,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+}
+
+impl<'a> Event<'a> {
+    pub fn of(applied: &'a Applied, d: &Decimals) -> Event<'a> {
+        let balance = |b: &Balance| EventBalance {
+            available: d.round("available", b.available),
+            held: d.round("held", b.held),
+            total: d.round("total", b.total),
+            locked: b.locked,
+        };
+        Event {
+            tx: applied.tx,
+            client: applied.client_id,
+            subaccount: applied.subaccount.as_deref(),
+            kind: applied.kind.as_str(),
+            amount: applied.amount.map(|v| d.round("amount", v)),
+            before: balance(&applied.before),
+            after: balance(&applied.after),
+            timestamp: applied.timestamp,
+            note: applied.note.as_deref(),
+        }
+    }
+}
+
+// Whether --events-out streams NDJSON: to stdout with -, or to a .ndjson or .jsonl file. Any
+// other path is the directory of the Parquet files (see EventsOut).
+pub fn is_stream(path: &str) -> bool {
+    path == "-" || path.ends_with(".ndjson") || path.ends_with(".jsonl")
+}
+
+// Streams the applied operations as NDJSON, one event per line written out as soon as the
+// operation is applied, so that downstream systems can tail the run. On stdout, the output of the
+// run follows the events.
+pub struct EventStream {
+    writer: LineWriter<Box<dyn Write>>,
+    decimals: Decimals,
+    written: u64, // Bytes of the file, see len
+}
+
+impl EventStream {
+    pub fn create(path: &str, decimals: Decimals) -> io::Result<EventStream> {
+        let writer: Box<dyn Write> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path)?)
+        };
+        Ok(EventStream {
+            writer: LineWriter::new(writer),
+            decimals,
+            written: 0,
+        })
+    }
+
+    // Carries on the file of an interrupted run, see checkpoint::reopen.
+    pub fn resume(file: File, decimals: Decimals) -> io::Result<EventStream> {
+        let written = file.metadata()?.len();
+        Ok(EventStream {
+            writer: LineWriter::new(Box::new(file)),
+            decimals,
+            written,
+        })
+    }
+
+    // Length of the file, for the checkpoints (the lines are written out as they go).
+    pub fn len(&self) -> u64 {
+        self.written
+    }
+
+    pub fn record(&mut self, applied: &Applied) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&Event::of(applied, &self.decimals))?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
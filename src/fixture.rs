@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use csv::StringRecord;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::time::SystemTime;
+
+// Range of the factors amounts are scaled by, per client.
+const MIN_FACTOR: f64 = 0.5;
+const MAX_FACTOR: f64 = 1.5;
+
+// Keyed hashing of the values of a file. The same key gives the same fixture for the same file.
+struct Anonymizer {
+    key: String,
+}
+
+impl Anonymizer {
+    fn hash(&self, label: &str, value: &str) -> u64 {
+        let digest = Sha256::new()
+            .chain_update(self.key.as_bytes())
+            .chain_update([0])
+            .chain_update(label.as_bytes())
+            .chain_update([0])
+            .chain_update(value.as_bytes())
+            .finalize();
+        u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+    }
+
+    // Maps the ids to as many distinct pseudo-random ids up to max, keeping their order: the
+    // smallest id gets the smallest new id, and so on.
+    fn ids(&self, label: &str, ids: &BTreeSet<u64>, max: u64) -> HashMap<u64, u64> {
+        let mut new_ids = BTreeSet::new();
+        let mut counter = 0u64;
+        while new_ids.len() < ids.len() {
+            new_ids.insert(self.hash(label, &counter.to_string()) % (max + 1));
+            counter += 1;
+        }
+        ids.iter().copied().zip(new_ids).collect()
+    }
+
+    // Scales the amount by the factor of the client, rounded to as many decimals as the amount
+    // has. A non-zero amount stays non-zero.
+    fn amount(&self, client: &str, amount: &str) -> Option<String> {
+        let value: f64 = amount.parse().ok()?;
+        let decimals = amount.split_once('.').map_or(0, |(_, d)| d.len());
+        let unit = 10f64.powi(decimals as i32);
+        let fraction = (self.hash("amount", client) % 10_001) as f64 / 10_000.0;
+        let factor = MIN_FACTOR + (MAX_FACTOR - MIN_FACTOR) * fraction;
+        let mut scaled = (value * factor * unit).round() / unit;
+        if scaled == 0.0 && value != 0.0 {
+            scaled = value.signum() / unit;
+        }
+        Some(format!("{:.*}", decimals, scaled))
+    }
+}
+
+fn random_key() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    format!("{:016x}", hasher.finish())
+}
+
+fn reader(path: &str) -> Result<csv::Reader<std::fs::File>> {
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| anyhow! {"cannot read {}: {}", path, e})
+}
+
+// `ledger anonymize <input.csv> --out fixture.csv [--key KEY]`: writes a fixture with the
+// structure of a real transaction file, to reproduce a problem outside of production. The records
+// stay in their order, with their types, timestamps, currencies, sub-accounts and tags, but:
+//
+// - client and transaction ids are replaced by keyed hashes, consistently (a dispute still refers
+//   to its deposit) and keeping their order (a larger id stays larger);
+// - amounts are scaled by a factor between 0.5 and 1.5 drawn for each client, so that the amounts
+//   of a client keep their proportions (a withdrawal the funds didn't cover still isn't), rounded
+//   to their decimals;
+// - memos are dropped and idempotency keys replaced by keyed hashes.
+//
+// Fields which don't parse are kept as they are, to reproduce their rejections. Without a key, a
+// random one is used; the same key gives the same fixture.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut input, mut out, mut key) = (None, None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--out" => out = Some(value()?.clone()),
+            "--key" => key = Some(value()?.clone()),
+            _ if input.is_none() && !arg.starts_with("--") => input = Some(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let input = input.ok_or_else(|| anyhow! {"anonymize requires the transaction file"})?;
+    let out = out.ok_or_else(|| anyhow! {"anonymize requires the --out file"})?;
+    let a = Anonymizer {
+        key: key.unwrap_or_else(random_key),
+    };
+    let mut r = reader(&input)?;
+    let headers = r.headers()?.clone();
+    let column = |name| headers.iter().position(|h| h == name);
+    let (client, tx, amount) = (column("client"), column("tx"), column("amount"));
+    let (memo, key) = (column("memo"), column("idempotency_key"));
+    let id = |record: &StringRecord, column: Option<usize>| {
+        column
+            .and_then(|c| record.get(c))
+            .and_then(|v| v.parse::<u64>().ok())
+    };
+    // The ids are collected first, for the new ones to keep their order.
+    let (mut clients, mut txs) = (BTreeSet::new(), BTreeSet::new());
+    for record in r.records() {
+        let record = record.map_err(|e| anyhow! {"invalid record in {}: {}", input, e})?;
+        clients.extend(id(&record, client).filter(|&c| c <= u64::from(u16::MAX)));
+        txs.extend(id(&record, tx).filter(|&t| t <= u64::from(u32::MAX)));
+    }
+    let new_clients = a.ids("client", &clients, u64::from(u16::MAX));
+    let new_txs = a.ids("tx", &txs, u64::from(u32::MAX));
+    let mut w = csv::WriterBuilder::new().flexible(true).from_path(&out)?;
+    w.write_record(&headers)?;
+    let mut records = 0;
+    for record in reader(&input)?.records() {
+        let record = record?;
+        let client_id = client.and_then(|c| record.get(c)).unwrap_or_default();
+        let fields: Vec<String> = record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let c = Some(i);
+                let anonymized = if c == client {
+                    id(&record, c)
+                        .and_then(|v| new_clients.get(&v))
+                        .map(u64::to_string)
+                } else if c == tx {
+                    id(&record, c)
+                        .and_then(|v| new_txs.get(&v))
+                        .map(u64::to_string)
+                } else if c == amount {
+                    a.amount(client_id, field)
+                } else if c == memo {
+                    Some(String::new())
+                } else if c == key && !field.is_empty() {
+                    Some(format!("{:016x}", a.hash("idempotency_key", field)))
+                } else {
+                    None
+                };
+                anonymized.unwrap_or_else(|| field.to_string())
+            })
+            .collect();
+        w.write_record(&fields)?;
+        records += 1;
+    }
+    w.flush()?;
+    println!(
+        "Anonymized {} records of {} clients and {} transactions into {}",
+        records,
+        clients.len(),
+        txs.len(),
+        out
+    );
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use crate::reconfigure::open_disputes;
+use crate::{Account, Balance, Ledger, Timestamp};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::mem;
+
+// Reason Ledger::forget_client refuses to forget a client: the ledger still needs the history of
+// the account.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ErasureBlocker {
+    // Disputes of its deposits are open, and refer to them.
+    OpenDisputes(usize),
+    // Bonuses or authorizations of the account are still to expire.
+    PendingExpiries(usize),
+    // The account is the overflow account deposits of other clients are swept into.
+    OverflowAccount,
+    // The client, or transactions of the client, are under legal hold.
+    LegalHolds(usize),
+}
+
+impl ErasureBlocker {
+    // Stable identifier of the blocker, like LedgerError::code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErasureBlocker::OpenDisputes(_) => "open_disputes",
+            ErasureBlocker::PendingExpiries(_) => "pending_expiries",
+            ErasureBlocker::OverflowAccount => "overflow_account",
+            ErasureBlocker::LegalHolds(_) => "legal_holds",
+        }
+    }
+}
+
+impl fmt::Display for ErasureBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErasureBlocker::OpenDisputes(n) => write!(f, "{} disputes are open", n),
+            ErasureBlocker::PendingExpiries(n) => {
+                write!(f, "{} bonuses or authorizations are pending expiry", n)
+            }
+            ErasureBlocker::OverflowAccount => write!(f, "it is the overflow account"),
+            ErasureBlocker::LegalHolds(n) => write!(f, "{} legal holds are on it", n),
+        }
+    }
+}
+
+impl Account {
+    // Drops the history of the account (the transactions, their tags and memos, the bonuses and
+    // sweeps), keeping the balances.
+    fn erase_history(&mut self) {
+        self.oplog = Default::default();
+        self.bonuses.clear();
+        self.sweeps.clear();
+        self.tag_names.clear();
+        self.tags.clear();
+        self.memos.clear();
+    }
+}
+
+impl Ledger {
+    fn erasure_blockers(&self, client_id: u16, a: &Account) -> Vec<ErasureBlocker> {
+        let mut found = Vec::new();
+        let disputes: usize = a
+            .subaccounts()
+            .map(|(_, s)| s)
+            .chain([a])
+            .map(open_disputes)
+            .sum();
+        if disputes > 0 {
+            found.push(ErasureBlocker::OpenDisputes(disputes));
+        }
+        let pending = |expiries: &BTreeSet<(Timestamp, u16, u32)>| {
+            expiries.iter().filter(|&&(_, c, _)| c == client_id).count()
+        };
+        let pending = pending(&self.bonus_expiries) + pending(&self.authorization_expiries);
+        if pending > 0 {
+            found.push(ErasureBlocker::PendingExpiries(pending));
+        }
+        let overflow = self
+            .config
+            .max_balance
+            .as_ref()
+            .and_then(|m| m.overflow_account);
+        if overflow == Some(client_id) {
+            found.push(ErasureBlocker::OverflowAccount);
+        }
+        let holds = self.legal_holds().filter(|h| h.client == client_id).count();
+        if holds > 0 {
+            found.push(ErasureBlocker::LegalHolds(holds));
+        }
+        found
+    }
+
+    // Forgets a client (an erasure request): its account becomes a tombstone, which keeps the
+    // balances (so that the totals of the ledger don't change) but none of the history linked to
+    // the client, its transactions, their tags and memos, the names of its sub-accounts (whose
+    // balances are folded into the account), its idempotency keys and undo journal entries.
+    // Transactions of the client are rejected from then on. Returns the balances retained, or
+    // None if the client has no account; forgetting a forgotten client again changes nothing.
+    pub fn forget_client(
+        &mut self,
+        client_id: u16,
+    ) -> Result<Option<Balance>, Vec<ErasureBlocker>> {
+        let a = match self.accounts.get(client_id) {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        let found = self.erasure_blockers(client_id, a);
+        if !found.is_empty() {
+            return Err(found);
+        }
+        let a = self
+            .accounts
+            .get_mut(client_id)
+            .expect("account checked above");
+        for (_, mut subaccount) in mem::take(&mut a.subaccounts) {
+            subaccount.erase_history();
+            // Without history, there is nothing merging can conflict on but the lock, which the
+            // tombstone keeps if any sub-account was locked.
+            a.merge(subaccount);
+        }
+        a.erase_history();
+        a.forgotten = true;
+        let balance = a.rollup();
+        self.idempotency_keys.remove(&client_id);
+        self.undo.retain(|u| !u.touches(client_id));
+        Ok(Some(balance))
+    }
+
+    // Whether the client was forgotten, see Ledger::forget_client.
+    pub fn is_forgotten(&self, client_id: u16) -> bool {
+        self.accounts.get(client_id).is_some_and(|a| a.forgotten)
+    }
+}
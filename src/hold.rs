@@ -0,0 +1,37 @@
+use crate::{Ledger, LegalHold};
+
+impl Ledger {
+    // The legal holds of the configuration, then those placed on the ledger.
+    pub fn legal_holds(&self) -> impl Iterator<Item = &LegalHold> {
+        self.config.legal_holds.iter().chain(&self.legal_holds)
+    }
+
+    // Whether the transaction of the client is under a legal hold.
+    pub fn is_held(&self, client_id: u16, tx_id: u32) -> bool {
+        self.legal_holds().any(|h| h.covers(client_id, tx_id))
+    }
+
+    // Places a legal hold on the ledger (an administrator placing it rather than the
+    // configuration), which snapshots keep. Returns false if the same client or transaction was
+    // held already, in which case nothing changes.
+    pub fn place_legal_hold(&mut self, hold: LegalHold) -> bool {
+        if self
+            .legal_holds()
+            .any(|h| h.client == hold.client && h.tx == hold.tx)
+        {
+            return false;
+        }
+        self.legal_holds.push(hold);
+        true
+    }
+
+    // Releases the legal holds placed on the client (all its history for None) or transaction,
+    // and returns how many there were. The holds of the configuration are only released by
+    // changing the configuration.
+    pub fn release_legal_hold(&mut self, client_id: u16, tx_id: Option<u32>) -> usize {
+        let before = self.legal_holds.len();
+        self.legal_holds
+            .retain(|h| h.client != client_id || h.tx != tx_id);
+        before - self.legal_holds.len()
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{Amount, Currency, LedgerError, TransactionEntry, TransactionType};
+use std::collections::BTreeMap;
+
+// Transactions applied with an idempotency key, by client and key: keys are those of the upstream
+// system of each client, so the same key of two clients names two transactions.
+pub(crate) type IdempotencyKeys = BTreeMap<u16, BTreeMap<String, Keyed>>;
+
+// Transaction applied with an idempotency key: its id, and the payload a retry with the key has
+// to repeat, what the transaction does (its type, amount, currencies and sub-account). The id and
+// the metadata (timestamp, tags, memo) may differ between retries.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Keyed {
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: TransactionType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amount: Option<Amount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to_currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subaccount: Option<String>,
+}
+
+impl Keyed {
+    // The keyed transaction, None if it isn't of a known type (and so can't have been applied).
+    pub(crate) fn of(tx: &TransactionEntry) -> Option<Keyed> {
+        Some(Keyed {
+            tx: tx.uid,
+            kind: tx.t.parse().ok()?,
+            amount: tx.amount,
+            currency: tx.currency,
+            to_currency: tx.to_currency,
+            subaccount: tx.subaccount.clone(),
+        })
+    }
+
+    // A transaction with the key of this one is a retry, acknowledged with AlreadyApplied, when it
+    // has the same payload; another transaction reusing the key is rejected.
+    pub(crate) fn check(&self, tx: &TransactionEntry) -> LedgerError {
+        match Keyed::of(tx) {
+            Some(k)
+                if Keyed {
+                    tx: self.tx,
+                    ..k.clone()
+                } == *self =>
+            {
+                LedgerError::AlreadyApplied(self.tx)
+            }
+            _ => LedgerError::IdempotencyKeyReused(self.tx),
+        }
+    }
+}
+
+// The transaction applied with the key of the transaction, if it has one.
+pub(crate) fn lookup<'a>(keys: &'a IdempotencyKeys, tx: &TransactionEntry) -> Option<&'a Keyed> {
+    keys.get(&tx.client_id)?.get(tx.idempotency_key.as_ref()?)
+}
+
+// Forgets the key of the client, see Undo::revert.
+pub(crate) fn remove(keys: &mut IdempotencyKeys, client_id: u16, key: &str) {
+    if let Some(of_client) = keys.get_mut(&client_id) {
+        of_client.remove(key);
+        if of_client.is_empty() {
+            keys.remove(&client_id);
+        }
+    }
+}
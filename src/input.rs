@@ -0,0 +1,747 @@
+use crate::metadata::{self, DigestReader};
+use crate::rejects::Rejection;
+use csv::{Position, Reader, ReaderBuilder, StringRecord, Trim};
+use ledger::TransactionEntry;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// Size the chunks parsed in parallel aim for. A chunk ends at the first record boundary after it.
+// Tests split their small inputs into many chunks.
+#[cfg(not(test))]
+const CHUNK_BYTES: usize = 4 << 20;
+#[cfg(test)]
+const CHUNK_BYTES: usize = 16;
+
+// Blocks read ahead of the parsing with --read-ahead, and their default size with --direct-io.
+const READ_AHEAD_BLOCKS: usize = 4;
+pub const READ_AHEAD_BYTES: usize = 1 << 20;
+// Alignment of the buffers, offsets and lengths of direct I/O.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+// Bytes at the start of the input the dialect is detected from, and the delimiters it can have.
+const SNIFF_BYTES: usize = 1024;
+const DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+// How the input file is read. By default reads are served from the page cache as the parsing
+// needs them. With --read-ahead, a thread reads blocks of the given size ahead of the parsing, so
+// that slow storage (spinning disks, network filesystems) doesn't stall it, optionally bypassing
+// the page cache with direct I/O (--direct-io, Linux only).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions {
+    pub read_ahead: Option<usize>,
+    pub direct_io: bool,
+}
+
+pub enum Source {
+    File(File),
+    ReadAhead(ReadAhead),
+}
+
+impl Source {
+    pub fn open(path: &str, options: ReadOptions) -> io::Result<Source> {
+        let mut open = OpenOptions::new();
+        open.read(true);
+        if options.direct_io {
+            direct_io(&mut open)?;
+        }
+        let file = open.open(path)?;
+        match options.read_ahead {
+            None if !options.direct_io => Ok(Source::File(file)),
+            block => Ok(Source::ReadAhead(ReadAhead::spawn(
+                file,
+                block.unwrap_or(READ_AHEAD_BYTES),
+                options.direct_io,
+            ))),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn direct_io(open: &mut OpenOptions) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+    const O_DIRECT: i32 = 0o200000;
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+    const O_DIRECT: i32 = 0o40000;
+    open.custom_flags(O_DIRECT);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn direct_io(_: &mut OpenOptions) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on Linux",
+    ))
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::File(file) => file.read(buf),
+            Source::ReadAhead(r) => r.read(buf),
+        }
+    }
+}
+
+// Reader of the blocks read by a thread of its own, at most READ_AHEAD_BLOCKS ahead.
+pub struct ReadAhead {
+    blocks: Receiver<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadAhead {
+    fn spawn(mut file: File, size: usize, direct_io: bool) -> ReadAhead {
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD_BLOCKS);
+        thread::spawn(move || {
+            // Direct I/O reads whole aligned blocks into an aligned buffer.
+            let size = if direct_io {
+                size.div_ceil(DIRECT_IO_ALIGN).max(1) * DIRECT_IO_ALIGN
+            } else {
+                size.max(1)
+            };
+            let mut buf = vec![0; size + DIRECT_IO_ALIGN];
+            let offset = if direct_io {
+                buf.as_ptr().align_offset(DIRECT_IO_ALIGN)
+            } else {
+                0
+            };
+            loop {
+                let block = &mut buf[offset..offset + size];
+                let result = match file.read(block) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(block[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = result.is_err();
+                // The reader stopped listening, or the error ends the input.
+                if tx.send(result).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        ReadAhead {
+            blocks: rx,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.block.len() {
+            match self.blocks.recv() {
+                Ok(block) => (self.block, self.pos) = (block?, 0),
+                Err(_) => return Ok(0), // The whole file was read
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// CSV dialect of an input: its field delimiter, its quote character and whether its first row
+// holds the column names. Inputs are comma-separated, double-quoted and have a header row, unless
+// the dialect is detected from their first bytes (--sniff-dialect).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Dialect {
+    pub delimiter: char,
+    pub quote: char,
+    pub headers: bool,
+    #[serde(skip)]
+    fields: usize, // Fields of the first row, which name the columns without a header row
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect {
+            delimiter: ',',
+            quote: '"',
+            headers: true,
+            fields: 0,
+        }
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "delimiter {:?}, quote {:?}, ",
+            self.delimiter, self.quote
+        )?;
+        if self.headers {
+            write!(f, "header row")
+        } else {
+            write!(f, "no header row")
+        }
+    }
+}
+
+// Detects the dialect from the start of the input. The quote is a single quote when fields start
+// with one and none starts with a double quote. The delimiter is the candidate found as many times
+// (outside of quotes) on every complete line, the most frequent one if several are, or a comma.
+// The first row is a header row unless one of its fields is a number, which column names aren't.
+fn sniff(sample: &[u8]) -> Dialect {
+    let sample = &sample[..sample.len().min(SNIFF_BYTES)];
+    let starts_field = |q: u8| {
+        (0..sample.len()).any(|i| {
+            sample[i] == q
+                && (i == 0 || sample[i - 1] == b'\n' || DELIMITERS.contains(&sample[i - 1]))
+        })
+    };
+    let quote = if starts_field(b'\'') && !starts_field(b'"') {
+        b'\''
+    } else {
+        b'"'
+    };
+    // Delimiters on every non-empty line. The last line is only counted when it's the only one,
+    // as the sample may end in the middle of it.
+    let counts = |d: u8| {
+        let mut lines = Vec::new();
+        let (mut count, mut empty, mut quoted) = (0, true, false);
+        for &b in sample {
+            match b {
+                b'\n' if !quoted => {
+                    if !empty {
+                        lines.push(count);
+                    }
+                    (count, empty) = (0, true);
+                    continue;
+                }
+                b'\r' => continue,
+                _ if b == quote => quoted = !quoted,
+                _ if b == d && !quoted => count += 1,
+                _ => {}
+            }
+            empty = false;
+        }
+        if lines.is_empty() && !empty {
+            lines.push(count);
+        }
+        lines
+    };
+    // Candidates are tried from the last, so that the first of several as frequent ones wins.
+    let delimiter = DELIMITERS
+        .iter()
+        .rev()
+        .filter_map(|&d| {
+            let lines = counts(d);
+            let first = *lines.first()?;
+            (first > 0 && lines.iter().all(|n| *n == first)).then_some((first, d))
+        })
+        .max_by_key(|(count, _)| *count)
+        .map_or(b',', |(_, d)| d);
+    let mut dialect = Dialect {
+        delimiter: delimiter as char,
+        quote: quote as char,
+        headers: false,
+        fields: 0,
+    };
+    let mut first = StringRecord::new();
+    dialect.headers = match reader_builder(dialect)
+        .from_reader(sample)
+        .read_record(&mut first)
+    {
+        Ok(true) => !first.iter().any(|field| field.parse::<f64>().is_ok()),
+        _ => true,
+    };
+    dialect.fields = first.len();
+    dialect
+}
+
+// Column names of an input without a header row with the given number of fields: the required
+// columns, then the optional ones in the order of OPTIONAL_COLUMNS.
+fn columns(fields: usize) -> StringRecord {
+    REQUIRED_COLUMNS
+        .iter()
+        .chain(OPTIONAL_COLUMNS.iter())
+        .take(fields)
+        .collect()
+}
+
+fn reader_builder(dialect: Dialect) -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder
+        .flexible(true)
+        .trim(Trim::All)
+        .delimiter(dialect.delimiter as u8)
+        .quote(dialect.quote as u8)
+        .has_headers(dialect.headers);
+    builder
+}
+
+pub type Record = Result<StringRecord, Box<Rejection>>;
+
+// Records of the input file. By default the file is read and parsed record by record on the
+// thread applying them. With --parse-threads, the whole file is read in, split into chunks at
+// record boundaries and the chunks are parsed on that many threads, a round of chunks at a time,
+// while the records are still handed out one by one in input order, with their positions in the
+// whole file. Rejections of records which failed to parse are built at parse time.
+pub enum Input {
+    Sequential(Box<Reader<BufReader<DigestReader<Source>>>>, Dialect),
+    Parallel(ParallelInput),
+}
+
+pub struct ParallelInput {
+    data: Vec<u8>,
+    dialect: Dialect,
+    chunks: VecDeque<Chunk>,
+    threads: usize,
+    parsed: VecDeque<Parsed>,
+    records: u64, // Records handed out so far, the header included
+}
+
+// Record of a chunk, or the error reading it with its position in the whole input.
+type Parsed = Result<StringRecord, (csv::Error, Option<Position>)>;
+
+// Byte range of the input and the number of lines before it.
+#[derive(Clone, Copy, Debug)]
+struct Chunk {
+    start: usize,
+    end: usize,
+    lines: u64,
+}
+
+impl Input {
+    // Opens the input, detecting its dialect with sniff.
+    pub fn new(file: Source, threads: usize, sniff: bool) -> io::Result<Input> {
+        let detect = |sample: &[u8]| {
+            if sniff {
+                self::sniff(sample)
+            } else {
+                Dialect::default()
+            }
+        };
+        if threads <= 1 {
+            // BufReader ensures that we don't read in the whole file at once. The digest of the
+            // input is computed as it is read, for the run metadata.
+            let mut reader = BufReader::new(DigestReader::new(file));
+            let dialect = detect(reader.fill_buf()?);
+            return Ok(Input::Sequential(
+                Box::new(reader_builder(dialect).from_reader(reader)),
+                dialect,
+            ));
+        }
+        let mut data = Vec::new();
+        BufReader::new(file).read_to_end(&mut data)?;
+        Ok(Input::Parallel(ParallelInput {
+            dialect: detect(&data),
+            data,
+            chunks: VecDeque::new(),
+            threads,
+            parsed: VecDeque::new(),
+            records: 1,
+        }))
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        match self {
+            Input::Sequential(_, dialect) => *dialect,
+            Input::Parallel(input) => input.dialect,
+        }
+    }
+
+    // Reads the header row, or names the columns of an input without one (see columns).
+    pub fn headers(&mut self) -> Result<StringRecord, csv::Error> {
+        match self {
+            Input::Sequential(rdr, dialect) => {
+                if !dialect.headers {
+                    return Ok(columns(dialect.fields));
+                }
+                rdr.headers().cloned()
+            }
+            Input::Parallel(input) => {
+                if !input.dialect.headers {
+                    input.chunks = chunks(&input.data, 0, input.dialect);
+                    input.records = 0;
+                    return Ok(columns(input.dialect.fields));
+                }
+                let mut rdr = reader_builder(input.dialect).from_reader(input.data.as_slice());
+                let headers = rdr.headers()?.clone();
+                let start = rdr.position().byte() as usize;
+                input.chunks = chunks(&input.data, start, input.dialect);
+                Ok(headers)
+            }
+        }
+    }
+
+    pub fn digest(self) -> String {
+        match self {
+            Input::Sequential(rdr, _) => rdr.into_inner().into_inner().hex_digest(),
+            Input::Parallel(input) => metadata::hex_digest(&input.data),
+        }
+    }
+}
+
+impl Iterator for Input {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        match self {
+            Input::Sequential(rdr, _) => {
+                let mut record = StringRecord::new();
+                match rdr.read_record(&mut record) {
+                    Ok(true) => Some(Ok(record)),
+                    Ok(false) => None,
+                    Err(e) => Some(Err(Box::new(Rejection::parse_error(None, &e)))),
+                }
+            }
+            Input::Parallel(input) => input.next(),
+        }
+    }
+}
+
+// Where the scan splitting the input into chunks is, with regard to quoting.
+#[derive(Clone, Copy, PartialEq)]
+enum Scan {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    Closed, // Right after the closing quote of a quoted field
+}
+
+// Splits the input after the headers into chunks of about CHUNK_BYTES, ending at line breaks
+// outside of quoted fields, and counts the lines before each of them. Like the CSV parser, only a
+// quote starting a field opens a quoted field, and doubled quotes inside it are escaped quotes.
+// After a CRLF line end, the next chunk starts at the LF, where the sequential reader starts
+// reading the next record (and so its position).
+fn chunks(data: &[u8], start: usize, dialect: Dialect) -> VecDeque<Chunk> {
+    let (delimiter, quote) = (dialect.delimiter as u8, dialect.quote as u8);
+    let mut chunks = VecDeque::new();
+    let mut lines = data[..start].iter().filter(|b| **b == b'\n').count() as u64;
+    let (mut chunk_start, mut chunk_lines) = (start, lines);
+    let mut scan = Scan::FieldStart;
+    for (i, &b) in data.iter().enumerate().skip(start) {
+        if b == b'\n' {
+            lines += 1;
+        }
+        scan = match scan {
+            Scan::Quoted if b == quote => Scan::Closed,
+            Scan::Quoted => Scan::Quoted,
+            // After a closing quote, another quote is an escaped one.
+            Scan::FieldStart | Scan::Closed if b == quote => Scan::Quoted,
+            _ if b == b'\n' => {
+                let (end, lines) = match i.checked_sub(1).map(|j| data[j]) {
+                    Some(b'\r') => (i, lines - 1),
+                    _ => (i + 1, lines),
+                };
+                if end - chunk_start >= CHUNK_BYTES {
+                    chunks.push_back(Chunk {
+                        start: chunk_start,
+                        end,
+                        lines: chunk_lines,
+                    });
+                    (chunk_start, chunk_lines) = (end, lines);
+                }
+                Scan::FieldStart
+            }
+            _ if b == delimiter || b == b'\r' => Scan::FieldStart,
+            _ => Scan::Unquoted,
+        };
+    }
+    if chunk_start < data.len() {
+        chunks.push_back(Chunk {
+            start: chunk_start,
+            end: data.len(),
+            lines: chunk_lines,
+        });
+    }
+    chunks
+}
+
+// Parses a chunk, with the positions of the records and errors relative to the whole input
+// (except for the record indexes, which are fixed up when the records are handed out).
+fn parse(data: &[u8], chunk: Chunk, dialect: Dialect) -> Vec<Parsed> {
+    let global = |p: &Position| {
+        let mut global = p.clone();
+        global.set_byte(p.byte() + chunk.start as u64);
+        global.set_line(p.line() + chunk.lines);
+        global
+    };
+    let mut rdr = reader_builder(dialect)
+        .has_headers(false)
+        .from_reader(&data[chunk.start..chunk.end]);
+    let mut records = Vec::new();
+    loop {
+        let mut record = StringRecord::new();
+        match rdr.read_record(&mut record) {
+            Ok(true) => {
+                let position = record.position().map(global);
+                record.set_position(position);
+                records.push(Ok(record));
+            }
+            Ok(false) => break,
+            Err(e) => {
+                let position = e.position().map(global);
+                records.push(Err((e, position)));
+            }
+        }
+    }
+    records
+}
+
+impl ParallelInput {
+    fn next(&mut self) -> Option<Record> {
+        while self.parsed.is_empty() {
+            if self.chunks.is_empty() {
+                return None;
+            }
+            let round: Vec<Chunk> = (0..self.threads)
+                .map_while(|_| self.chunks.pop_front())
+                .collect();
+            let (data, dialect) = (&self.data, self.dialect);
+            let parsed: Vec<Vec<Parsed>> = thread::scope(|s| {
+                let handles: Vec<_> = round
+                    .iter()
+                    .map(|chunk| s.spawn(move || parse(data, *chunk, dialect)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap_or_default())
+                    .collect()
+            });
+            self.parsed.extend(parsed.into_iter().flatten());
+        }
+        let record = match self.parsed.pop_front()? {
+            Ok(mut record) => {
+                if let Some(mut position) = record.position().cloned() {
+                    position.set_record(self.records);
+                    record.set_position(Some(position));
+                }
+                Ok(record)
+            }
+            Err((e, position)) => Err(Box::new(self.rejection(&e, position))),
+        };
+        self.records += 1;
+        Some(record)
+    }
+
+    // Rejection of a record which failed to parse, as the sequential reader has it: the message
+    // of the error has its position in the chunk, which is replaced by that in the whole input.
+    fn rejection(&self, e: &csv::Error, position: Option<Position>) -> Rejection {
+        let mut rejection = Rejection::parse_error(None, e);
+        if let Some(mut p) = position {
+            p.set_record(self.records);
+            rejection.line = Some(p.line());
+            rejection.byte = Some(p.byte());
+            if let csv::ErrorKind::Utf8 { err, .. } = e.kind() {
+                rejection.message = format!(
+                    "CSV parse error: record {} (line {}, field: {}, byte: {}): {}",
+                    p.record(),
+                    p.line(),
+                    err.field(),
+                    p.byte(),
+                    err
+                );
+            }
+        }
+        rejection
+    }
+}
+
+// Columns every input starts with, in this order, and those which may follow them (in any order),
+// as read into TransactionEntry.
+pub const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+pub const OPTIONAL_COLUMNS: [&str; 8] = [
+    "currency",
+    "to_currency",
+    "timestamp",
+    "subaccount",
+    "tags",
+    "memo",
+    "idempotency_key",
+    "reason",
+];
+
+// Checks the header row against the expected schema (--require-headers): the required columns
+// first and in order, then only known optional columns, each at most once. Serde matches columns
+// by name and ignores unknown ones, so a misnamed or shifted column would otherwise go unnoticed.
+pub fn check_headers(headers: &StringRecord) -> Result<(), String> {
+    for (i, expected) in REQUIRED_COLUMNS.iter().enumerate() {
+        match headers.get(i) {
+            Some(h) if h == *expected => {}
+            Some(h) => {
+                return Err(format!(
+                    "expected column {} to be {}, found {}",
+                    i + 1,
+                    expected,
+                    h
+                ))
+            }
+            None => return Err(format!("missing column {} ({})", i + 1, expected)),
+        }
+    }
+    for (i, h) in headers.iter().enumerate().skip(REQUIRED_COLUMNS.len()) {
+        if !OPTIONAL_COLUMNS.contains(&h) {
+            return Err(format!("unknown column {} ({})", i + 1, h));
+        }
+        if headers.iter().take(i).any(|other| other == h) {
+            return Err(format!("duplicate column {} ({})", i + 1, h));
+        }
+    }
+    Ok(())
+}
+
+// Fast path deserializing the records of well-formed inputs without serde, for inputs whose only
+// columns are type, client, tx and amount (--fast-parse). A record it can't read as is (missing or
+// extra fields, numbers which aren't plain digits, ...) goes through the serde deserialization,
+// which also produces the error messages, so it doesn't change which records are rejected.
+#[derive(Debug)]
+pub struct FastPath {
+    t: usize,
+    client_id: usize,
+    uid: usize,
+    amount: Option<usize>,
+    fields: usize,
+}
+
+impl FastPath {
+    // Returns None when the headers have other columns than those the fast path knows.
+    pub fn new(headers: &StringRecord) -> Option<FastPath> {
+        let position = |name| headers.iter().position(|h| h == name);
+        let known = ["type", "client", "tx", "amount"];
+        if !headers.iter().all(|h| known.contains(&h)) {
+            return None;
+        }
+        Some(FastPath {
+            t: position("type")?,
+            client_id: position("client")?,
+            uid: position("tx")?,
+            amount: position("amount"),
+            fields: headers.len(),
+        })
+    }
+
+    pub fn entry(&self, record: &StringRecord) -> Option<TransactionEntry> {
+        if record.len() != self.fields {
+            return None;
+        }
+        let digits =
+            |i| Some(record.get(i)?).filter(|s: &&str| s.bytes().all(|b| b.is_ascii_digit()));
+        let t = record.get(self.t)?;
+        let amount = match self.amount.map(|i| record.get(i)) {
+            Some(Some("")) | None => None,
+            Some(amount) => Some(amount?.parse().ok()?),
+        };
+        Some(TransactionEntry {
+            t: t.to_string(),
+            client_id: digits(self.client_id)?.parse().ok()?,
+            uid: digits(self.uid)?.parse().ok()?,
+            amount,
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            subaccount: None,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Quoted fields with delimiters, line breaks and escaped quotes, CRLF line ends, blank lines,
+    // short and long records, and a record which isn't UTF-8.
+    const INPUT: &[u8] = b"type,client,tx,amount,memo\n\
+        deposit,1,1,10,plain\r\n\
+        deposit,1,2,5,\"a, b\"\n\
+        withdrawal,2,3,1,\"first\nsecond\"\n\
+        \n\
+        dispute,1,1,,\"say \"\"hi\"\"\n\"\n\
+        deposit,3,4\n\
+        deposit,3,5,1,\xff\xfe\n\
+        resolve,1,1,,,extra\n\
+        deposit,4,6,2.5,\"\"\n";
+
+    // What the input hands out, with the positions of the records and of the errors.
+    fn read(data: &[u8], threads: usize, sniff: bool) -> (StringRecord, Vec<String>) {
+        let path = std::env::temp_dir().join(format!(
+            "ledger-input-{}-{}-{}.csv",
+            std::process::id(),
+            threads,
+            sniff
+        ));
+        std::fs::write(&path, data).unwrap();
+        let file = Source::File(File::open(&path).unwrap());
+        let mut input = Input::new(file, threads, sniff).unwrap();
+        let headers = input.headers().unwrap();
+        let records = input
+            .map(|record| match record {
+                Ok(r) => {
+                    let p = r.position().unwrap();
+                    format!("{:?} at {}:{}:{}", r, p.record(), p.line(), p.byte())
+                }
+                Err(e) => format!("{} at {:?}:{:?}", e.message, e.line, e.byte),
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        (headers, records)
+    }
+
+    #[test]
+    fn chunks_end_at_record_boundaries() {
+        let start = INPUT.iter().position(|b| *b == b'\n').unwrap() + 1;
+        let chunks = chunks(INPUT, start, Dialect::default());
+        assert!(chunks.len() > 3);
+        assert_eq!(chunks.front().map(|c| c.start), Some(start));
+        assert_eq!(chunks.back().map(|c| c.end), Some(INPUT.len()));
+        for (chunk, next) in chunks.iter().zip(chunks.iter().skip(1)) {
+            assert_eq!(chunk.end, next.start);
+            let lines = INPUT[..next.start].iter().filter(|b| **b == b'\n').count();
+            assert_eq!(next.lines, lines as u64);
+        }
+        // No chunk starts within the quoted line breaks.
+        for quoted in [b"second\"".as_slice(), b"\"\n"] {
+            let at = INPUT
+                .windows(quoted.len())
+                .position(|w| w == quoted)
+                .unwrap();
+            assert!(chunks.iter().all(|c| c.start != at), "{:?}", quoted);
+        }
+    }
+
+    #[test]
+    fn parallel_parsing_matches_sequential_parsing() {
+        let sequential = read(INPUT, 1, false);
+        assert_eq!(sequential.1.len(), 8);
+        assert!(sequential.1.iter().any(|r| r.contains("invalid utf-8")));
+        for threads in [2, 3, 8] {
+            assert_eq!(
+                read(INPUT, threads, false),
+                sequential,
+                "{} threads",
+                threads
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_parsing_matches_sequential_parsing_without_headers() {
+        let input = b"deposit;1;1;10\ndeposit;1;2;'5'\nwithdrawal;1;3;'1;5'\ndispute;1;1;\n";
+        let sequential = read(input, 1, true);
+        assert_eq!(&sequential.0, &columns(4));
+        assert_eq!(sequential.1.len(), 4);
+        for threads in [2, 4] {
+            assert_eq!(
+                read(input, threads, true),
+                sequential,
+                "{} threads",
+                threads
+            );
+        }
+    }
+}
@@ -0,0 +1,939 @@
+use crate::output::Decimals;
+use anyhow::{anyhow, Result};
+use ledger::{Applied, Timestamp};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const CLIENT_ID: &str = "ledger";
+const TIMEOUT: Duration = Duration::from_secs(30);
+// How long the broker keeps a transaction open before aborting it, e.g. after a crash.
+const TRANSACTION_TIMEOUT_MS: i32 = 60_000;
+// Attempts at a request failing with a retriable error, the delay doubling from RETRY_DELAY.
+const ATTEMPTS: u32 = 8;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+// Largest response taken from a broker: those of the requests sent are small, a length beyond
+// this is a broken connection rather than something to allocate for.
+const MAX_RESPONSE: usize = 16 << 20;
+// Partition of the topic under which the position in the input is committed, in the offsets of
+// the consumer group named after the transactional id.
+const POSITION_PARTITION: i32 = 0;
+// Messages queued for the open transaction, at most: past them, the transaction is committed
+// before the next record, so that the run waits for the broker rather than piling up the changes
+// of all the records up to the next checkpoint in memory.
+const MAX_QUEUED: usize = 10_000;
+
+// API keys of the requests, with the versions spoken (the last ones before the flexible
+// encodings, supported by brokers since Kafka 0.11).
+const PRODUCE: (i16, i16) = (0, 3);
+const METADATA: (i16, i16) = (3, 1);
+const OFFSET_FETCH: (i16, i16) = (9, 1);
+const FIND_COORDINATOR: (i16, i16) = (10, 1);
+const INIT_PRODUCER_ID: (i16, i16) = (22, 0);
+const ADD_PARTITIONS_TO_TXN: (i16, i16) = (24, 0);
+const ADD_OFFSETS_TO_TXN: (i16, i16) = (25, 0);
+const END_TXN: (i16, i16) = (26, 0);
+const TXN_OFFSET_COMMIT: (i16, i16) = (28, 0);
+
+// Error codes after which a request is retried, the coordinators being looked up again for the
+// last two.
+const COORDINATOR_LOAD_IN_PROGRESS: i16 = 14;
+const CONCURRENT_TRANSACTIONS: i16 = 51;
+const COORDINATOR_NOT_AVAILABLE: i16 = 15;
+const NOT_COORDINATOR: i16 = 16;
+
+// Balance change of an account, as published: the value of a message keyed by the client id.
+#[derive(Debug, serde::Serialize)]
+struct Event<'a> {
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subaccount: Option<&'a str>,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<f64>,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug)]
+struct Message {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    timestamp: i64, // Milliseconds since the epoch
+}
+
+// Publishes the balance changes of the accounts to a Kafka topic (--kafka-sink
+// kafka://host:port/topic), exactly once. The changes are produced in transactions of a
+// transactional producer (--kafka-transactional-id), each committed together with the number of
+// input records it covers, as the offset of a consumer group of the same name. The transactions
+// are committed at every checkpoint, before the checkpoint is written, and at the end of the run:
+// when a run is resumed from a checkpoint older than the last commit, the changes of the records
+// in between are not published again, and a transaction left open by a crashed run is aborted by
+// the broker once the producer is initialized again. Messages are keyed by client id, and
+// partitioned as the Java client does (murmur2 of the key), so that the changes of an account
+// are read in order. Transactions are also committed whenever MAX_QUEUED messages are queued,
+// which resuming handles like a commit after the checkpoint.
+pub struct KafkaSink {
+    topic: String,
+    transactional_id: String,
+    input: String, // Name of the input, committed with the position in it
+    brokers: HashMap<i32, String>,
+    leaders: Vec<i32>, // Leader of every partition
+    connections: HashMap<String, Connection>,
+    transaction_coordinator: String,
+    group_coordinator: String,
+    producer_id: i64,
+    producer_epoch: i16,
+    sequences: Vec<i32>, // Next sequence number of every partition
+    pending: BTreeMap<i32, Vec<Message>>, // Messages of the open transaction, by partition
+    queued: usize,       // Messages in pending
+    row: u64,            // Input record of the last message queued
+    committed: u64,      // Input records covered by the last commit
+    decimals: Decimals,
+}
+
+impl KafkaSink {
+    pub fn connect(
+        url: &str,
+        transactional_id: &str,
+        input: &str,
+        decimals: Decimals,
+    ) -> Result<KafkaSink> {
+        let rest = url
+            .strip_prefix("kafka://")
+            .ok_or_else(|| anyhow! {"unsupported Kafka sink {} (expected kafka://...)", url})?;
+        let (address, topic) = rest
+            .split_once('/')
+            .filter(|(address, topic)| !address.is_empty() && !topic.is_empty())
+            .ok_or_else(
+                || anyhow! {"invalid Kafka sink {}: expected kafka://host:port/topic", url},
+            )?;
+        let bootstrap = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:9092", address)
+        };
+        let mut sink = KafkaSink {
+            topic: topic.to_string(),
+            transactional_id: transactional_id.to_string(),
+            input: input.to_string(),
+            brokers: HashMap::new(),
+            leaders: Vec::new(),
+            connections: HashMap::new(),
+            transaction_coordinator: bootstrap.clone(),
+            group_coordinator: bootstrap.clone(),
+            producer_id: -1,
+            producer_epoch: -1,
+            sequences: Vec::new(),
+            pending: BTreeMap::new(),
+            queued: 0,
+            row: 0,
+            committed: 0,
+            decimals,
+        };
+        sink.metadata(&bootstrap)?;
+        sink.find_coordinators()?;
+        sink.init_producer()?;
+        sink.committed = sink.fetch_position()?;
+        Ok(sink)
+    }
+
+    // Input records covered by the last commit: their changes have been published already.
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    fn connection(&mut self, address: &str) -> Result<&mut Connection> {
+        if !self.connections.contains_key(address) {
+            let c = Connection::open(address)
+                .map_err(|e| anyhow! {"cannot connect to Kafka broker {}: {}", address, e})?;
+            self.connections.insert(address.to_string(), c);
+        }
+        Ok(self
+            .connections
+            .get_mut(address)
+            .expect("connection just opened"))
+    }
+
+    fn call(&mut self, address: &str, api: (i16, i16), body: &[u8]) -> Result<Vec<u8>> {
+        let result = self.connection(address)?.call(api, body);
+        if result.is_err() {
+            self.connections.remove(address);
+        }
+        result
+    }
+
+    // Sends the request built by the closure, retrying it on errors which go away (coordinators
+    // loading or moving, transactions still completing).
+    fn retry<T>(
+        &mut self,
+        what: &str,
+        mut request: impl FnMut(&mut KafkaSink) -> Result<Result<T, i16>>,
+    ) -> Result<T> {
+        let mut delay = RETRY_DELAY;
+        for _ in 1..ATTEMPTS {
+            match request(self)? {
+                Ok(v) => return Ok(v),
+                Err(COORDINATOR_LOAD_IN_PROGRESS | CONCURRENT_TRANSACTIONS) => {}
+                Err(COORDINATOR_NOT_AVAILABLE | NOT_COORDINATOR) => self.find_coordinators()?,
+                Err(code) => return Err(anyhow! {"{} failed with Kafka error {}", what, code}),
+            }
+            thread::sleep(delay);
+            delay *= 2;
+        }
+        request(self)?.map_err(|code| anyhow! {"{} failed with Kafka error {}", what, code})
+    }
+
+    // Looks up the brokers and the leaders of the partitions of the topic.
+    fn metadata(&mut self, bootstrap: &str) -> Result<()> {
+        let mut request = Encoder::default();
+        request.i32(1);
+        request.string(&self.topic);
+        let response = self.call(bootstrap, METADATA, &request.0)?;
+        let mut r = Decoder::new(&response);
+        for _ in 0..r.i32()? {
+            let node = r.i32()?;
+            let host = r.string()?;
+            let port = r.i32()?;
+            r.nullable_string()?; // Rack
+            self.brokers.insert(node, format!("{}:{}", host, port));
+        }
+        r.i32()?; // Controller
+        for _ in 0..r.i32()? {
+            let error = r.i16()?;
+            let name = r.string()?;
+            r.i8()?; // Internal
+            if error != 0 {
+                return Err(anyhow! {"Kafka topic {} is not available (error {})", name, error});
+            }
+            let mut leaders = Vec::new();
+            for _ in 0..r.i32()? {
+                r.i16()?; // Error
+                let partition = r.i32()?;
+                let leader = r.i32()?;
+                for _ in 0..2 {
+                    for _ in 0..r.i32()? {
+                        r.i32()?; // Replicas and in-sync replicas
+                    }
+                }
+                leaders.push((partition, leader));
+            }
+            leaders.sort();
+            self.leaders = leaders.into_iter().map(|(_, leader)| leader).collect();
+        }
+        if self.leaders.is_empty() {
+            return Err(anyhow! {"Kafka topic {} has no partitions", self.topic});
+        }
+        self.sequences = vec![0; self.leaders.len()];
+        Ok(())
+    }
+
+    fn find_coordinators(&mut self) -> Result<()> {
+        let broker = self.any_broker();
+        for key_type in [0, 1] {
+            let mut address = None;
+            for _ in 0..ATTEMPTS {
+                let mut request = Encoder::default();
+                request.string(&self.transactional_id);
+                request.i8(key_type);
+                let response = self.call(&broker, FIND_COORDINATOR, &request.0)?;
+                let mut r = Decoder::new(&response);
+                r.i32()?; // Throttle time
+                let error = r.i16()?;
+                let message = r.nullable_string()?;
+                r.i32()?; // Node
+                let host = r.string()?;
+                let port = r.i32()?;
+                match error {
+                    0 => {
+                        address = Some(format!("{}:{}", host, port));
+                        break;
+                    }
+                    COORDINATOR_LOAD_IN_PROGRESS | COORDINATOR_NOT_AVAILABLE => {
+                        thread::sleep(RETRY_DELAY)
+                    }
+                    _ => {
+                        return Err(anyhow! {
+                            "cannot find the Kafka coordinator of {}: error {} {}",
+                            self.transactional_id,
+                            error,
+                            message.unwrap_or_default()
+                        })
+                    }
+                }
+            }
+            let address = address
+                .ok_or_else(|| anyhow! {"no Kafka coordinator of {}", self.transactional_id})?;
+            if key_type == 0 {
+                self.group_coordinator = address;
+            } else {
+                self.transaction_coordinator = address;
+            }
+        }
+        Ok(())
+    }
+
+    fn any_broker(&self) -> String {
+        let node = self.brokers.keys().min();
+        node.and_then(|node| self.brokers.get(node))
+            .cloned()
+            .unwrap_or_else(|| self.transaction_coordinator.clone())
+    }
+
+    // Initializes the producer with the transactional id, which fences the producers of earlier
+    // runs and aborts the transaction they left open.
+    fn init_producer(&mut self) -> Result<()> {
+        let (producer_id, producer_epoch) = self.retry("InitProducerId", |s| {
+            let mut request = Encoder::default();
+            request.string(&s.transactional_id);
+            request.i32(TRANSACTION_TIMEOUT_MS);
+            let coordinator = s.transaction_coordinator.clone();
+            let response = s.call(&coordinator, INIT_PRODUCER_ID, &request.0)?;
+            let mut r = Decoder::new(&response);
+            r.i32()?; // Throttle time
+            let error = r.i16()?;
+            let (id, epoch) = (r.i64()?, r.i16()?);
+            Ok(if error == 0 {
+                Ok((id, epoch))
+            } else {
+                Err(error)
+            })
+        })?;
+        self.producer_id = producer_id;
+        self.producer_epoch = producer_epoch;
+        Ok(())
+    }
+
+    // Position in the input committed by the last transaction, in records.
+    fn fetch_position(&mut self) -> Result<u64> {
+        let (offset, input) = self.retry("OffsetFetch", |s| {
+            let mut request = Encoder::default();
+            request.string(&s.transactional_id);
+            request.i32(1);
+            request.string(&s.topic);
+            request.i32(1);
+            request.i32(POSITION_PARTITION);
+            let coordinator = s.group_coordinator.clone();
+            let response = s.call(&coordinator, OFFSET_FETCH, &request.0)?;
+            let mut r = Decoder::new(&response);
+            let mut position = Ok((-1, None));
+            for _ in 0..r.i32()? {
+                r.string()?;
+                for _ in 0..r.i32()? {
+                    r.i32()?; // Partition
+                    let offset = r.i64()?;
+                    let input = r.nullable_string()?;
+                    let error = r.i16()?;
+                    position = if error == 0 {
+                        Ok((offset, input))
+                    } else {
+                        Err(error)
+                    };
+                }
+            }
+            Ok(position)
+        })?;
+        if offset < 0 {
+            return Ok(0);
+        }
+        if input.as_deref() != Some(self.input.as_str()) {
+            return Err(anyhow! {
+                "Kafka transactional id {} was used for another input ({})",
+                self.transactional_id,
+                input.unwrap_or_default()
+            });
+        }
+        Ok(offset as u64)
+    }
+
+    // Queues the balance change of an applied operation, unless it was published by an earlier
+    // run already. Row is the number of input records read so far.
+    pub fn record(&mut self, row: u64, applied: &Applied) -> Result<()> {
+        if row <= self.committed {
+            return Ok(());
+        }
+        // The changes of a record all go in the same transaction.
+        if self.queued >= MAX_QUEUED && row > self.row {
+            self.commit(self.row)?;
+        }
+        let d = &self.decimals;
+        let event = Event {
+            client: applied.client_id,
+            subaccount: applied.subaccount.as_deref(),
+            tx: applied.tx,
+            kind: applied.kind.as_str(),
+            amount: applied.amount.map(|v| d.round("amount", v)),
+            available: d.round("available", applied.after.available),
+            held: d.round("held", applied.after.held),
+            total: d.round("total", applied.after.total),
+            locked: applied.after.locked,
+            timestamp: applied.timestamp,
+        };
+        let key = applied.client_id.to_string().into_bytes();
+        let partition = (murmur2(&key) & 0x7fffffff) % self.leaders.len() as i32;
+        let timestamp = match applied.timestamp {
+            Some(t) => t.0 * 1000,
+            None => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64),
+        };
+        self.pending.entry(partition).or_default().push(Message {
+            key,
+            value: serde_json::to_vec(&event)?,
+            timestamp,
+        });
+        self.queued += 1;
+        self.row = row;
+        Ok(())
+    }
+
+    // Publishes the queued changes and commits them, along with the position in the input, in a
+    // single transaction. On error, the transaction is left to be aborted by the broker.
+    pub fn commit(&mut self, rows: u64) -> Result<()> {
+        if rows <= self.committed {
+            return Ok(());
+        }
+        let (id, epoch) = (self.producer_id, self.producer_epoch);
+        let partitions: Vec<i32> = self.pending.keys().copied().collect();
+        if !partitions.is_empty() {
+            self.retry("AddPartitionsToTxn", |s| {
+                let mut request = Encoder::default();
+                request.string(&s.transactional_id);
+                request.i64(id);
+                request.i16(epoch);
+                request.i32(1);
+                request.string(&s.topic);
+                request.i32(partitions.len() as i32);
+                for p in &partitions {
+                    request.i32(*p);
+                }
+                let coordinator = s.transaction_coordinator.clone();
+                let response = s.call(&coordinator, ADD_PARTITIONS_TO_TXN, &request.0)?;
+                let mut r = Decoder::new(&response);
+                r.i32()?; // Throttle time
+                partition_errors(&mut r)
+            })?;
+        }
+        self.retry("AddOffsetsToTxn", |s| {
+            let mut request = Encoder::default();
+            request.string(&s.transactional_id);
+            request.i64(id);
+            request.i16(epoch);
+            request.string(&s.transactional_id);
+            let coordinator = s.transaction_coordinator.clone();
+            let response = s.call(&coordinator, ADD_OFFSETS_TO_TXN, &request.0)?;
+            let mut r = Decoder::new(&response);
+            r.i32()?; // Throttle time
+            let error = r.i16()?;
+            Ok(if error == 0 { Ok(()) } else { Err(error) })
+        })?;
+        self.produce()?;
+        self.retry("TxnOffsetCommit", |s| {
+            let mut request = Encoder::default();
+            request.string(&s.transactional_id);
+            request.string(&s.transactional_id);
+            request.i64(id);
+            request.i16(epoch);
+            request.i32(1);
+            request.string(&s.topic);
+            request.i32(1);
+            request.i32(POSITION_PARTITION);
+            request.i64(rows as i64);
+            request.nullable_string(Some(&s.input));
+            let coordinator = s.group_coordinator.clone();
+            let response = s.call(&coordinator, TXN_OFFSET_COMMIT, &request.0)?;
+            let mut r = Decoder::new(&response);
+            r.i32()?; // Throttle time
+            partition_errors(&mut r)
+        })?;
+        self.retry("EndTxn", |s| {
+            let mut request = Encoder::default();
+            request.string(&s.transactional_id);
+            request.i64(id);
+            request.i16(epoch);
+            request.i8(1); // Commit
+            let coordinator = s.transaction_coordinator.clone();
+            let response = s.call(&coordinator, END_TXN, &request.0)?;
+            let mut r = Decoder::new(&response);
+            r.i32()?; // Throttle time
+            let error = r.i16()?;
+            Ok(if error == 0 { Ok(()) } else { Err(error) })
+        })?;
+        self.committed = rows;
+        Ok(())
+    }
+
+    // Produces the queued messages to the leaders of their partitions, one record batch per
+    // partition.
+    fn produce(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut by_leader: BTreeMap<i32, Vec<(i32, Vec<u8>)>> = BTreeMap::new();
+        for (partition, messages) in &pending {
+            let sequence = self.sequences[*partition as usize];
+            let batch = record_batch(self.producer_id, self.producer_epoch, sequence, messages);
+            by_leader
+                .entry(self.leaders[*partition as usize])
+                .or_default()
+                .push((*partition, batch));
+        }
+        for (leader, batches) in by_leader {
+            let address = self
+                .brokers
+                .get(&leader)
+                .cloned()
+                .ok_or_else(|| anyhow! {"unknown Kafka broker {}", leader})?;
+            let mut request = Encoder::default();
+            request.nullable_string(Some(&self.transactional_id));
+            request.i16(-1); // Acknowledged by all in-sync replicas
+            request.i32(TIMEOUT.as_millis() as i32);
+            request.i32(1);
+            request.string(&self.topic);
+            request.i32(batches.len() as i32);
+            for (partition, batch) in &batches {
+                request.i32(*partition);
+                request.bytes(batch);
+            }
+            let response = self.call(&address, PRODUCE, &request.0)?;
+            let mut r = Decoder::new(&response);
+            for _ in 0..r.i32()? {
+                r.string()?;
+                for _ in 0..r.i32()? {
+                    let partition = r.i32()?;
+                    let error = r.i16()?;
+                    r.i64()?; // Base offset
+                    r.i64()?; // Log append time
+                    if error != 0 {
+                        return Err(anyhow! {
+                            "producing to partition {} of {} failed with Kafka error {}",
+                            partition,
+                            self.topic,
+                            error
+                        });
+                    }
+                }
+            }
+        }
+        for (partition, messages) in &pending {
+            self.sequences[*partition as usize] += messages.len() as i32;
+        }
+        self.queued = 0;
+        Ok(())
+    }
+}
+
+// First error of the partitions of a response listing topics with their partitions' errors.
+fn partition_errors(r: &mut Decoder) -> Result<Result<(), i16>> {
+    let mut result = Ok(());
+    for _ in 0..r.i32()? {
+        r.string()?;
+        for _ in 0..r.i32()? {
+            r.i32()?; // Partition
+            let error = r.i16()?;
+            if error != 0 && result.is_ok() {
+                result = Err(error);
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Record batch (magic 2) of transactional messages, with the sequence number of the first one.
+fn record_batch(
+    producer_id: i64,
+    producer_epoch: i16,
+    sequence: i32,
+    messages: &[Message],
+) -> Vec<u8> {
+    let first = messages.iter().map(|m| m.timestamp).min().unwrap_or(0);
+    let max = messages.iter().map(|m| m.timestamp).max().unwrap_or(0);
+    // From the attributes on, the part covered by the CRC.
+    let mut body = Encoder::default();
+    body.i16(0x10); // Transactional, uncompressed, create time
+    body.i32(messages.len() as i32 - 1); // Last offset delta
+    body.i64(first);
+    body.i64(max);
+    body.i64(producer_id);
+    body.i16(producer_epoch);
+    body.i32(sequence);
+    body.i32(messages.len() as i32);
+    for (i, m) in messages.iter().enumerate() {
+        let mut record = Encoder::default();
+        record.i8(0); // Attributes
+        record.varint(m.timestamp - first);
+        record.varint(i as i64);
+        record.varint(m.key.len() as i64);
+        record.0.extend_from_slice(&m.key);
+        record.varint(m.value.len() as i64);
+        record.0.extend_from_slice(&m.value);
+        record.varint(0); // Headers
+        body.varint(record.0.len() as i64);
+        body.0.extend_from_slice(&record.0);
+    }
+    let mut batch = Encoder::default();
+    batch.i64(0); // Base offset, assigned by the broker
+    batch.i32(4 + 1 + 4 + body.0.len() as i32); // Length of what follows
+    batch.i32(-1); // Partition leader epoch
+    batch.i8(2); // Magic
+    batch.0.extend_from_slice(&crc32c(&body.0).to_be_bytes());
+    batch.0.extend_from_slice(&body.0);
+    batch.0
+}
+
+// CRC-32C (Castagnoli), as used by record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Murmur2 hash of the Java client, by which it partitions keyed messages.
+fn murmur2(data: &[u8]) -> i32 {
+    const M: u32 = 0x5bd1e995;
+    let mut h = 0x9747b28c ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= u32::from(*b) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+// Request body being encoded, in the Kafka protocol's big-endian primitives.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn i8(&mut self, v: i8) {
+        self.0.push(v as u8);
+    }
+
+    fn i16(&mut self, v: i16) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn i32(&mut self, v: i32) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn i64(&mut self, v: i64) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.i16(s.len() as i16);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn nullable_string(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => self.string(s),
+            None => self.i16(-1),
+        }
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.i32(b.len() as i32);
+        self.0.extend_from_slice(b);
+    }
+
+    // Zigzag varint, as in records.
+    fn varint(&mut self, v: i64) {
+        let mut z = ((v << 1) ^ (v >> 63)) as u64;
+        while z >= 0x80 {
+            self.0.push(z as u8 | 0x80);
+            z >>= 7;
+        }
+        self.0.push(z as u8);
+    }
+}
+
+// Response body being decoded.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(anyhow! {"truncated Kafka response"});
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn i8(&mut self) -> Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16> {
+        let b = self.take(2)?;
+        Ok(i16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        let b = self.take(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(i64::from_be_bytes(b))
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let s = self.take(len as usize)?;
+        Ok(Some(String::from_utf8_lossy(s).into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+}
+
+// Connection to a broker, sending one request at a time.
+struct Connection {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl Connection {
+    fn open(address: &str) -> Result<Connection> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Connection {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    // Sends a request (header v1) and returns the body of the response.
+    fn call(&mut self, (key, version): (i16, i16), body: &[u8]) -> Result<Vec<u8>> {
+        self.correlation_id += 1;
+        let mut request = Encoder::default();
+        request.i16(key);
+        request.i16(version);
+        request.i32(self.correlation_id);
+        request.nullable_string(Some(CLIENT_ID));
+        request.0.extend_from_slice(body);
+        self.stream
+            .write_all(&(request.0.len() as i32).to_be_bytes())?;
+        self.stream.write_all(&request.0)?;
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = i32::from_be_bytes(len);
+        if !(4..=MAX_RESPONSE as i32).contains(&len) {
+            return Err(anyhow! {"invalid Kafka response length {}", len});
+        }
+        let mut response = vec![0; len as usize];
+        self.stream.read_exact(&mut response)?;
+        let mut r = Decoder::new(&response);
+        if r.i32()? != self.correlation_id {
+            return Err(anyhow! {"unexpected Kafka response"});
+        }
+        Ok(response[4..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // Zigzag varint at the start of the data, and the rest.
+    fn varint(data: &[u8]) -> (i64, &[u8]) {
+        let (mut z, mut shift) = (0u64, 0);
+        for (i, b) in data.iter().enumerate() {
+            z |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return (((z >> 1) as i64) ^ -((z & 1) as i64), &data[i + 1..]);
+            }
+            shift += 7;
+        }
+        panic!("truncated varint");
+    }
+
+    // The check values of the CRC catalogue and of RFC 3720 (iSCSI), appendix B.4.
+    #[test]
+    fn crc32c_matches_the_reference_vectors() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a9136aa);
+        assert_eq!(crc32c(&[0xff; 32]), 0x62a8ab43);
+        let ascending: Vec<u8> = (0..32).collect();
+        assert_eq!(crc32c(&ascending), 0x46dd794e);
+    }
+
+    // The cases of the Java client's tests, so that messages land in the same partitions.
+    #[test]
+    fn murmur2_matches_the_java_client() {
+        let cases: [(&[u8], i32); 6] = [
+            (b"21", -973932308),
+            (b"foobar", -790332482),
+            (b"a-little-bit-long-string", -985981536),
+            (b"a-little-bit-longer-string", -1486304829),
+            (
+                b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            (b"abc", 479470107),
+        ];
+        for (data, hash) in cases {
+            assert_eq!(murmur2(data), hash, "{}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn varints_are_zigzag_encoded() {
+        for (v, bytes) in [
+            (0, &[0][..]),
+            (-1, &[1]),
+            (1, &[2]),
+            (-64, &[0x7f]),
+            (300, &[0xd8, 0x04]),
+        ] {
+            let mut e = Encoder::default();
+            e.varint(v);
+            assert_eq!(e.0, bytes, "{}", v);
+            assert_eq!(varint(bytes).0, v);
+        }
+    }
+
+    #[test]
+    fn record_batches_are_framed_as_magic_2() {
+        let messages = [
+            Message {
+                key: b"1".to_vec(),
+                value: b"{\"a\":1}".to_vec(),
+                timestamp: 1000,
+            },
+            Message {
+                key: b"22".to_vec(),
+                value: b"{}".to_vec(),
+                timestamp: 1005,
+            },
+        ];
+        let batch = record_batch(7, 3, 42, &messages);
+        let be32 = |at: usize| i32::from_be_bytes(batch[at..at + 4].try_into().unwrap());
+        let be64 = |at: usize| i64::from_be_bytes(batch[at..at + 8].try_into().unwrap());
+        assert_eq!(be64(0), 0); // Base offset
+        assert_eq!(be32(8) as usize, batch.len() - 12);
+        assert_eq!(be32(12), -1); // Partition leader epoch
+        assert_eq!(batch[16], 2); // Magic
+        assert_eq!(be32(17) as u32, crc32c(&batch[21..]));
+        assert_eq!(i16::from_be_bytes([batch[21], batch[22]]), 0x10);
+        assert_eq!(be32(23), 1); // Last offset delta
+        assert_eq!((be64(27), be64(35)), (1000, 1005));
+        assert_eq!(be64(43), 7); // Producer id
+        assert_eq!(i16::from_be_bytes([batch[51], batch[52]]), 3);
+        assert_eq!(be32(53), 42); // Base sequence
+        assert_eq!(be32(57), 2); // Records
+        let mut rest = &batch[61..];
+        for (i, m) in messages.iter().enumerate() {
+            let (len, r) = varint(rest);
+            let (record, next) = r.split_at(len as usize);
+            rest = next;
+            assert_eq!(record[0], 0); // Attributes
+            let (timestamp_delta, r) = varint(&record[1..]);
+            assert_eq!(timestamp_delta, m.timestamp - 1000);
+            let (offset_delta, r) = varint(r);
+            assert_eq!(offset_delta, i as i64);
+            let (key_len, r) = varint(r);
+            let (key, r) = r.split_at(key_len as usize);
+            assert_eq!(key, m.key);
+            let (value_len, r) = varint(r);
+            let (value, r) = r.split_at(value_len as usize);
+            assert_eq!(value, m.value);
+            assert_eq!(r, [0]); // Headers
+        }
+        assert!(rest.is_empty());
+    }
+
+    // Broker answering one request with the response built from its correlation id.
+    fn broker(
+        response: impl FnOnce(i32) -> Vec<u8> + Send + 'static,
+    ) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0; i32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut request).unwrap();
+            let correlation_id = i32::from_be_bytes(request[4..8].try_into().unwrap());
+            stream.write_all(&response(correlation_id)).unwrap();
+            request
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn requests_and_responses_are_framed_with_their_length() {
+        let (address, broker) = broker(|correlation_id| {
+            let mut response = 7i32.to_be_bytes().to_vec();
+            response.extend_from_slice(&correlation_id.to_be_bytes());
+            response.extend_from_slice(b"abc");
+            response
+        });
+        let mut c = Connection::open(&address).unwrap();
+        assert_eq!(c.call(METADATA, b"body").unwrap(), b"abc");
+        let request = broker.join().unwrap();
+        let mut expected = Encoder::default();
+        expected.i16(METADATA.0);
+        expected.i16(METADATA.1);
+        expected.i32(1); // Correlation id
+        expected.string(CLIENT_ID);
+        expected.0.extend_from_slice(b"body");
+        assert_eq!(request, expected.0);
+    }
+
+    #[test]
+    fn oversized_responses_are_refused() {
+        let (address, _broker) = broker(|_| i32::MAX.to_be_bytes().to_vec());
+        let mut c = Connection::open(&address).unwrap();
+        let e = c.call(METADATA, b"").unwrap_err();
+        assert!(
+            e.to_string().contains("invalid Kafka response length"),
+            "{}",
+            e
+        );
+    }
+}
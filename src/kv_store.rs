@@ -0,0 +1,77 @@
+use crate::oplog::Oplog;
+use crate::{Account, LedgerError, OperationState};
+use std::fmt::Display;
+use std::mem;
+
+// Layout of the accounts in the key-value stores (RocksDbStore and SledStore): the account of a client under its id (2 bytes,
+// big-endian), without its oplog, and each entry of its oplog under the client id followed by the
+// transaction id (4 bytes, big-endian), so that the entries of a client are contiguous and sorted
+// by transaction id. Accounts and entries are JSON, like in snapshots. An update rewrites the
+// account, and only writes the entries the change added, modified or removed. The oplogs of
+// sub-accounts are part of the account of the client.
+pub(crate) fn account_key(client_id: u16) -> [u8; 2] {
+    client_id.to_be_bytes()
+}
+
+pub(crate) fn entry_key(client_id: u16, tx_id: u32) -> [u8; 6] {
+    let mut key = [0; 6];
+    key[..2].copy_from_slice(&client_id.to_be_bytes());
+    key[2..].copy_from_slice(&tx_id.to_be_bytes());
+    key
+}
+
+pub(crate) fn storage_error(e: impl Display) -> LedgerError {
+    LedgerError::Storage(e.to_string())
+}
+
+// Account of its stored record and oplog entries, as (key, value) pairs.
+pub(crate) fn decode_account<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    record: &[u8],
+    entries: impl IntoIterator<Item = Result<(K, V), LedgerError>>,
+) -> Result<Account, LedgerError> {
+    let mut account: Account = serde_json::from_slice(record).map_err(storage_error)?;
+    for entry in entries {
+        let (key, value) = entry?;
+        let tx_id = key
+            .as_ref()
+            .get(2..6)
+            .and_then(|id| id.try_into().ok())
+            .map(u32::from_be_bytes)
+            .ok_or_else(|| storage_error("invalid oplog key"))?;
+        let op: OperationState = serde_json::from_slice(value.as_ref()).map_err(storage_error)?;
+        account.oplog.insert(tx_id, op);
+    }
+    Ok(account)
+}
+
+// Record of the account, without its oplog.
+pub(crate) fn encode_account(account: &mut Account) -> Result<Vec<u8>, LedgerError> {
+    let oplog = mem::take(&mut account.oplog);
+    let record = serde_json::to_vec(account).map_err(storage_error);
+    account.oplog = oplog;
+    record
+}
+
+// Key of an oplog entry to write, with its new value, or to delete, without.
+pub(crate) type OplogChange = ([u8; 6], Option<Vec<u8>>);
+
+// Entries of the oplog to write or delete, to go from the log before a change to the log after it.
+pub(crate) fn oplog_changes(
+    client_id: u16,
+    before: &Oplog,
+    after: &Oplog,
+) -> Result<Vec<OplogChange>, LedgerError> {
+    let mut changes = Vec::new();
+    for (tx_id, op) in after.iter() {
+        if before.get(tx_id) != Some(op) {
+            let value = serde_json::to_vec(&op).map_err(storage_error)?;
+            changes.push((entry_key(client_id, tx_id), Some(value)));
+        }
+    }
+    for (tx_id, _) in before.iter() {
+        if !after.contains_key(tx_id) {
+            changes.push((entry_key(client_id, tx_id), None));
+        }
+    }
+    Ok(changes)
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use ledger::LegalHold;
+use std::fs;
+
+// Legal holds the maintenance of the persisted state has to leave alone: those of the config file
+// and those of the ledger of the snapshot (of the configuration it was taken with, and placed
+// with `ledger hold`).
+pub fn holds(config: Option<&str>, snapshot: Option<&str>) -> Result<Vec<LegalHold>> {
+    let mut holds = match config {
+        Some(path) => crate::read_config(path)?.legal_holds,
+        None => Vec::new(),
+    };
+    if let Some(path) = snapshot {
+        holds.extend(crate::load_snapshot(path)?.legal_holds().cloned());
+    }
+    Ok(holds)
+}
+
+// `ledger hold --snapshot s.json [--snapshot-out o.json] --client <id> [--tx <id>] [--reference
+// <matter>] [--release]`: places a legal hold on the client (or one of its transactions) in the
+// ledger of the snapshot, or releases the one placed, and writes the ledger back like `ledger
+// undo`, through a temporary file. Holds of the config file are listed with the others in the
+// audit-stats report, but only released by changing the config file.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut snapshot, mut snapshot_out, mut client, mut tx) = (None, None, None, None);
+    let (mut reference, mut release) = (None, false);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--snapshot-out" => snapshot_out = Some(value()?.clone()),
+            "--client" => {
+                let v = value()?;
+                client = Some(
+                    v.parse::<u16>()
+                        .map_err(|_| anyhow! {"invalid client {}", v})?,
+                );
+            }
+            "--tx" => {
+                let v = value()?;
+                tx = Some(
+                    v.parse::<u32>()
+                        .map_err(|_| anyhow! {"invalid transaction {}", v})?,
+                );
+            }
+            "--reference" => reference = Some(value()?.clone()),
+            "--release" => release = true,
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let path = snapshot.ok_or_else(|| anyhow! {"hold requires the --snapshot"})?;
+    let client = client.ok_or_else(|| anyhow! {"hold requires the --client"})?;
+    if release && reference.is_some() {
+        return Err(anyhow! {"--reference can't be given with --release"});
+    }
+    let mut l = crate::load_snapshot(&path)?;
+    let held = match tx {
+        Some(tx) => format!("transaction {} of client {}", tx, client),
+        None => format!("client {}", client),
+    };
+    if release {
+        if l.release_legal_hold(client, tx) == 0 {
+            let configured = l.legal_holds().any(|h| h.client == client && h.tx == tx);
+            if configured {
+                return Err(
+                    anyhow! {"the legal hold on {} is configured, and released by changing the configuration", held},
+                );
+            }
+            return Err(anyhow! {"{} is not under legal hold in {}", held, path});
+        }
+    } else if !l.place_legal_hold(LegalHold {
+        client,
+        tx,
+        reference,
+    }) {
+        return Err(anyhow! {"{} is under legal hold in {} already", held, path});
+    }
+    let out = snapshot_out.unwrap_or_else(|| path.clone());
+    let tmp = format!("{}.tmp", out);
+    crate::write_snapshot(&tmp, &l)?;
+    fs::rename(&tmp, &out)?;
+    if release {
+        eprintln!("Released the legal hold on {} in {}", held, out);
+    } else {
+        eprintln!("Placed a legal hold on {} in {}", held, out);
+    }
+    Ok(())
+}
@@ -0,0 +1,2256 @@
+use crate::AccountOperation::*;
+use crate::AccountOperationResult::*;
+use crate::AccountState::*;
+use crate::OperationState::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::str::FromStr;
+
+pub use crate::amount::{Amount, InvalidAmount, MinorUnits, Money, MAX_EXPONENT};
+#[cfg(feature = "async")]
+pub use crate::async_ledger::{AsyncLedger, AsyncLedgerStore, MemoryStore};
+use crate::bloom::Bloom;
+pub use crate::concurrent::ConcurrentLedger;
+pub use crate::config::{
+    Behavior, Calendar, Config, CreditLine, DisputeLifecycle, DisputeStage, FeeRule, Flag, Flags,
+    LedgerBuilder, LegalHold, LockedPolicy, MaxAmount, MaxBalance, Tier, Weekday,
+    WithdrawnDisputePolicy,
+};
+use crate::currency::Rates;
+pub use crate::currency::{Currency, ExchangeRateProvider, FixedRate, RateTable};
+pub use crate::error::LedgerError;
+pub use crate::forget::ErasureBlocker;
+use crate::idempotency::{IdempotencyKeys, Keyed};
+use crate::oplog::Oplog;
+pub use crate::reconfigure::Incompatibility;
+pub use crate::remap::{RemapConflict, RemapConflictKind};
+#[cfg(feature = "rocksdb")]
+pub use crate::rocksdb_store::RocksDbStore;
+pub use crate::simulate::{SimulatedAccount, SimulationReport};
+use crate::slab::Accounts;
+#[cfg(feature = "sled")]
+pub use crate::sled_store::SledStore;
+pub use crate::snapshot::SNAPSHOT_VERSION;
+pub use crate::time::{Timestamp, Zone};
+use crate::undo::Undo;
+
+mod amount;
+#[cfg(feature = "async")]
+mod async_ledger;
+mod bloom;
+mod concurrent;
+mod config;
+mod currency;
+mod error;
+mod forget;
+mod hold;
+mod idempotency;
+#[cfg(any(feature = "rocksdb", feature = "sled"))]
+mod kv_store;
+mod oplog;
+mod reconfigure;
+mod remap;
+mod retention;
+#[cfg(feature = "rocksdb")]
+mod rocksdb_store;
+mod simulate;
+mod slab;
+#[cfg(feature = "sled")]
+mod sled_store;
+mod snapshot;
+mod time;
+mod undo;
+
+// Record used to deserialize the csv. We map field names to avoid clash with "type" keyword and
+// also to assign something nicer.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct TransactionEntry {
+    #[serde(rename = "type")]
+    pub t: String,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    #[serde(rename = "tx")]
+    pub uid: u32,
+    #[serde(default, deserialize_with = "amount::csv_field")]
+    pub amount: Option<Amount>, // Only deposits and withdrawals carry an amount, it may be left out
+    // The optional columns below are matched by header name. Without a currency, or with the base
+    // currency, deposits and withdrawals move the base balance of the account, other currencies
+    // have a balance of their own. Conversions move funds from currency to to_currency.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    // Sub-account of the client the transaction applies to, see Account::subaccount. Without one,
+    // or with MAIN_SUBACCOUNT, it applies to the main balance of the client. Names are made of
+    // ASCII letters, digits, '-' and '_'.
+    #[serde(default)]
+    pub subaccount: Option<String>,
+    // Tags (categories such as payroll or refunds) of the transaction, separated by ';'. Tags of
+    // a dispute, resolve or chargeback are added to those of the disputed deposit.
+    #[serde(default)]
+    pub tags: Option<String>,
+    // Free text describing the transaction, kept with it for whoever investigates it later.
+    #[serde(default)]
+    pub memo: Option<String>,
+    // Key the upstream system submitted the transaction with, the same for all its retries (which
+    // may come with other transaction ids). A transaction of the client with the key of an applied
+    // one is acknowledged with LedgerError::AlreadyApplied instead of being applied again, if it
+    // has the same payload (see idempotency::Keyed), and rejected otherwise.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    // Reason code of an adjustment, which adjustments require (see Config::allow_admin_ops).
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// Name of the main balance of a client, in the subaccount column and in the output.
+pub const MAIN_SUBACCOUNT: &str = "main";
+
+// Types of transactions, as they appear in the type column of the input (see as_str), which is
+// also the kind of the applied operations the outputs and sinks report. New types may come in any
+// release, so matches on it outside of the crate need a catch-all arm.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Convert,
+    Bonus,
+    EscrowHold,
+    EscrowRelease,
+    Authorize,
+    Capture,
+    Representment,
+    PreArbitration,
+    Arbitration,
+    ChargebackReversal,
+    Adjustment,
+}
+
+impl TransactionType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Convert => "convert",
+            TransactionType::Bonus => "bonus",
+            TransactionType::EscrowHold => "escrow_hold",
+            TransactionType::EscrowRelease => "escrow_release",
+            TransactionType::Authorize => "authorize",
+            TransactionType::Capture => "capture",
+            TransactionType::Representment => "representment",
+            TransactionType::PreArbitration => "pre_arbitration",
+            TransactionType::Arbitration => "arbitration",
+            TransactionType::ChargebackReversal => "chargeback_reversal",
+            TransactionType::Adjustment => "adjustment",
+        }
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = LedgerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "convert" => Ok(TransactionType::Convert),
+            "bonus" => Ok(TransactionType::Bonus),
+            "escrow_hold" => Ok(TransactionType::EscrowHold),
+            "escrow_release" => Ok(TransactionType::EscrowRelease),
+            "authorize" => Ok(TransactionType::Authorize),
+            "capture" => Ok(TransactionType::Capture),
+            "representment" => Ok(TransactionType::Representment),
+            "pre_arbitration" => Ok(TransactionType::PreArbitration),
+            "arbitration" => Ok(TransactionType::Arbitration),
+            "chargeback_reversal" => Ok(TransactionType::ChargebackReversal),
+            "adjustment" => Ok(TransactionType::Adjustment),
+            _ => Err(LedgerError::UnknownTransactionType),
+        }
+    }
+}
+
+// Description of a transaction which has been applied to the ledger: the amount it moved (which
+// may differ from the amount in the input, e.g. for capped disputes), the balances of the account
+// before and after, and a note when a policy decided how it was applied.
+#[derive(Clone, Debug)]
+pub struct Applied {
+    pub client_id: u16,
+    pub tx: u32,
+    pub kind: TransactionType,
+    pub amount: Option<Amount>,
+    pub before: Balance,
+    pub after: Balance,
+    pub note: Option<String>,
+    // Part of a deposit swept into the overflow account, see MaxBalance.
+    pub swept: Option<(u16, Amount)>,
+    pub timestamp: Option<Timestamp>, // From the input, if it has a timestamp column
+    pub fee: Amount,                  // Fees charged for the transaction, see FeeRule
+    pub subaccount: Option<String>,   // None for the main balance of the client
+    pub tags: Vec<String>,            // Tags of the transaction, see Account::tags
+    pub memo: Option<String>,         // From the input, if it has a memo column
+    pub reason: Option<String>,       // Reason code of an adjustment
+}
+
+// This is operation state. We can be in RegularDeposit (after deposit or resolved dispute,
+// DisputedDeposit after dispute, FinalDeposit after chargeback or Afterwithdrawal after
+// a withdrawal). A chargeback reversal takes a FinalDeposit back to RegularDeposit.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OperationState {
+    // After Deposit or after Deposit -> Dispute -> Resolve. Disputes counts how many times the
+    // deposit has been disputed so far. Charged back is the part of the deposit taken back by
+    // partial chargebacks so far, which amount no longer includes (in every state of deposits).
+    RegularDeposit {
+        amount: Amount,
+        #[serde(default)]
+        disputes: u32,
+        #[serde(default, skip_serializing_if = "snapshot::is_zero")]
+        charged_back: Amount,
+    },
+    // After Deposit -> Dispute. Disputes may cover only part of the deposit, in which case
+    // disputed is the part currently held and the rest of the amount stays available. A partial
+    // resolve or chargeback reduces the disputed part, the deposit returns to RegularDeposit once
+    // nothing is disputed anymore.
+    DisputedDeposit {
+        amount: Amount,
+        disputed: Amount,
+        #[serde(default)]
+        disputes: u32,
+        #[serde(default, skip_serializing_if = "snapshot::is_zero")]
+        charged_back: Amount,
+    },
+    // After DisputedDeposit -> Representment (the merchant contesting the dispute), then ->
+    // PreArbitration and -> Arbitration as the dispute escalates, along the transitions of the
+    // DisputeLifecycle. The disputed part stays held until the dispute is resolved or charged back.
+    RepresentedDeposit {
+        amount: Amount,
+        disputed: Amount,
+        disputes: u32,
+        #[serde(default, skip_serializing_if = "snapshot::is_zero")]
+        charged_back: Amount,
+    },
+    PreArbitrationDeposit {
+        amount: Amount,
+        disputed: Amount,
+        disputes: u32,
+        #[serde(default, skip_serializing_if = "snapshot::is_zero")]
+        charged_back: Amount,
+    },
+    ArbitrationDeposit {
+        amount: Amount,
+        disputed: Amount,
+        disputes: u32,
+        #[serde(default, skip_serializing_if = "snapshot::is_zero")]
+        charged_back: Amount,
+    },
+    // After Deposit -> Chargeback of the whole amount, with the amount the chargebacks took back
+    // in total (which a ChargebackReversal restores).
+    FinalDeposit {
+        #[serde(default)]
+        charged_back: Amount,
+        #[serde(default)]
+        disputes: u32,
+    },
+    AfterWithdrawal, // After Withdrawal, in any currency
+    // After Deposit in a currency other than the base currency. These can't be disputed.
+    CurrencyDeposit {
+        currency: Currency,
+        amount: Amount,
+    },
+    // After Convert, with both legs: from_amount taken from the from currency and to_amount added
+    // to the to currency.
+    Conversion {
+        from: Currency,
+        from_amount: Amount,
+        to: Currency,
+        to_amount: Amount,
+    },
+    // After a bonus deposit. These can't be disputed.
+    BonusDeposit {
+        amount: Amount,
+    },
+    // After EscrowHold, with the part of the amount which hasn't been released yet.
+    Escrow {
+        amount: Amount,
+    },
+    EscrowReleased, // After EscrowHold -> EscrowRelease of the whole amount
+    // After Authorize, with the held amount and when the authorization expires if it isn't
+    // captured by then (see Config::authorization_expiry_days).
+    Authorization {
+        amount: Amount,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<Timestamp>,
+    },
+    Captured,             // After Authorize -> Capture
+    AuthorizationExpired, // After Authorize, once expired without a capture
+    // After an adjustment, with the amount credited (or debited, when negative). These can't be
+    // disputed.
+    Adjustment {
+        amount: Amount,
+    },
+}
+
+// This is AccountState - the account can either be open (for normal operation) or locked (after a
+// chargeback).
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AccountState {
+    Open { available: Amount, held: Amount }, // Normal operation
+    Locked { available: Amount, held: Amount }, // Chargeback happened, corresponding operation is in
+                                                // FinalDeposit OperationState
+}
+
+impl AccountState {
+    // The same state with the amount taken from the available funds, for charges which are not
+    // transactions of the input (fees, interest, expired bonuses), or swept deposits credited with
+    // a negative amount.
+    fn debited(&self, amount: Amount) -> Result<AccountState, LedgerError> {
+        Ok(match *self {
+            Open { available, held } => Open {
+                available: minus(available, amount)?,
+                held,
+            },
+            Locked { available, held } => Locked {
+                available: minus(available, amount)?,
+                held,
+            },
+        })
+    }
+}
+
+// AccountOperation - reflecting the original operation.
+#[derive(Debug)]
+enum AccountOperation {
+    Deposit {
+        amount: Amount,
+    },
+    Withdrawal {
+        amount: Amount,
+    },
+    // The amounts of disputes, resolves and chargebacks are optional. Without an amount, they
+    // cover the whole deposit (for disputes) or the whole disputed part (for resolves and
+    // chargebacks).
+    Dispute {
+        amount: Option<Amount>,
+    },
+    Resolve {
+        amount: Option<Amount>,
+    },
+    Chargeback {
+        amount: Option<Amount>,
+    },
+    // Moves a dispute to another stage (representment, pre-arbitration, arbitration).
+    Escalate {
+        to: DisputeStage,
+    },
+    // Restores a charged back deposit, when the merchant wins the second presentment.
+    ReverseChargeback,
+    // Correction of an operational error by an administrator: credits the amount to the available
+    // funds, or debits it when negative, even if this takes them below zero.
+    Adjust {
+        amount: Amount,
+    },
+    // Deposits and withdrawals in a currency other than the base currency.
+    ForeignDeposit {
+        currency: Currency,
+        amount: Amount,
+    },
+    ForeignWithdrawal {
+        currency: Currency,
+        amount: Amount,
+    },
+    // Conversion of amount from one currency to converted in the other, either of which may be
+    // the base currency.
+    Convert {
+        from: Currency,
+        to: Currency,
+        amount: Amount,
+        converted: Amount,
+    },
+    // Promotional credit, which expires unless it is withdrawn in time (see Bonus).
+    PromotionalDeposit {
+        amount: Amount,
+    },
+    // Moves funds from the available funds into escrow and back. Escrowed funds are distinct from
+    // the funds held by disputes. Without an amount, a release covers whatever is left in escrow
+    // of the hold.
+    EscrowHold {
+        amount: Amount,
+    },
+    EscrowRelease {
+        amount: Option<Amount>,
+    },
+    // Holds funds for a card payment, until the payment is captured: the captured amount (the
+    // whole authorization without an amount) leaves the account and the rest is released.
+    Authorize {
+        amount: Amount,
+        expires_at: Option<Timestamp>,
+    },
+    Capture {
+        amount: Option<Amount>,
+    },
+}
+
+// Account, including its state.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Account {
+    state: AccountState,
+    oplog: Oplog, // This is a map of transaction id -> OperationState
+    // Balances in currencies other than the base currency. They are only moved by deposits,
+    // withdrawals and conversions, so there is nothing held.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Currency, Amount>,
+    // Total of the fees charged to the account so far, already taken from the available funds.
+    #[serde(default)]
+    fees: Amount,
+    // Funds in escrow, part of the total but neither available nor held.
+    #[serde(default)]
+    escrow: Amount,
+    // Bonuses which may still expire, in the order they were deposited.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bonuses: Vec<Bonus>,
+    // Purpose-specific balances of the client (savings, ...), by name. They are accounts of their
+    // own, with their own oplog, state and lock: a deposit made into a sub-account can only be
+    // disputed there. They never have sub-accounts themselves.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    subaccounts: BTreeMap<String, Account>,
+    // Tags of the transactions, stored as bit sets over the tag names of the account (so at most
+    // MAX_TAGS distinct tags per account), by transaction id.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tag_names: Vec<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "snapshot::serialize_sorted"
+    )]
+    tags: HashMap<u32, u64>,
+    // Memos of the transactions by transaction id, in input order: the memo of a deposit is
+    // followed by those of its disputes, resolves and chargebacks.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "snapshot::serialize_sorted"
+    )]
+    memos: HashMap<u32, Vec<String>>,
+    // Tombstone of a forgotten client, see Ledger::forget_client: the balances are retained, the
+    // history is gone and transactions are rejected.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    forgotten: bool,
+    // Parts of deposits of other clients swept into the account, when it is the overflow account
+    // of MaxBalance, in the order they were swept. They are kept out of the oplog: they are not
+    // transactions of the account, so they can't be disputed, and their ids are those of the
+    // depositors, which may be ids of transactions of the account too.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sweeps: Vec<Sweep>,
+}
+
+// Part of a deposit of another client swept into the overflow account, see Account::sweeps.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Sweep {
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Amount,
+}
+
+// Maximum number of distinct tags of an account.
+pub const MAX_TAGS: usize = 64;
+
+// Bonus deposit which has not expired yet. Withdrawals are taken from the remaining part of the
+// bonuses first (the oldest first), whatever is left of a bonus at its expiry is reversed.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Bonus {
+    pub tx: u32,
+    pub remaining: Amount,
+    pub expires_at: Timestamp,
+}
+
+// The balances of an account at a given point, as reported in the output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Balance {
+    pub available: Amount,
+    pub held: Amount,
+    pub escrow: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl Balance {
+    const ZERO: Balance = Balance {
+        available: Amount::ZERO,
+        held: Amount::ZERO,
+        escrow: Amount::ZERO,
+        total: Amount::ZERO,
+        locked: false,
+    };
+
+    // Balances of two accounts (of a client) together, locked if either is, or None if a sum is
+    // out of range.
+    fn checked_add(&self, other: &Balance) -> Option<Balance> {
+        Some(Balance {
+            available: self.available.checked_add(other.available)?,
+            held: self.held.checked_add(other.held)?,
+            escrow: self.escrow.checked_add(other.escrow)?,
+            total: self.total.checked_add(other.total)?,
+            locked: self.locked || other.locked,
+        })
+    }
+}
+
+impl Account {
+    // A new, empty and open account, as created when a client is first seen.
+    pub fn new() -> Account {
+        Account {
+            state: Open {
+                available: Amount::ZERO,
+                held: Amount::ZERO,
+            },
+            oplog: Oplog::default(),
+            currencies: BTreeMap::new(),
+            fees: Amount::ZERO,
+            escrow: Amount::ZERO,
+            bonuses: Vec::new(),
+            subaccounts: BTreeMap::new(),
+            tag_names: Vec::new(),
+            tags: HashMap::new(),
+            memos: HashMap::new(),
+            forgotten: false,
+            sweeps: Vec::new(),
+        }
+    }
+}
+
+impl Default for Account {
+    fn default() -> Account {
+        Account::new()
+    }
+}
+
+impl Account {
+    pub fn state(&self) -> &AccountState {
+        &self.state
+    }
+
+    pub fn available(&self) -> Amount {
+        match self.state {
+            Open { available, .. } | Locked { available, .. } => available,
+        }
+    }
+
+    pub fn held(&self) -> Amount {
+        match self.state {
+            Open { held, .. } | Locked { held, .. } => held,
+        }
+    }
+
+    pub fn escrow(&self) -> Amount {
+        self.escrow
+    }
+
+    // In range, like the sums of rollup.
+    pub fn total(&self) -> Amount {
+        self.available() + self.held() + self.escrow
+    }
+
+    pub fn is_locked(&self) -> bool {
+        matches!(self.state, Locked { .. })
+    }
+
+    pub fn balance(&self) -> Balance {
+        Balance {
+            available: self.available(),
+            held: self.held(),
+            escrow: self.escrow,
+            total: self.total(),
+            locked: self.is_locked(),
+        }
+    }
+
+    // State of the given transaction of this account, if it is in the log.
+    pub fn operation(&self, tx_id: u32) -> Option<OperationState> {
+        self.oplog.get(tx_id)
+    }
+
+    // Balance in a currency other than the base currency, zero if the account never held any.
+    pub fn currency_balance(&self, currency: Currency) -> Amount {
+        self.currencies
+            .get(&currency)
+            .copied()
+            .unwrap_or(Amount::ZERO)
+    }
+
+    pub fn fees(&self) -> Amount {
+        self.fees
+    }
+
+    // Deposits of other clients swept into the account, see MaxBalance.
+    pub fn sweeps(&self) -> &[Sweep] {
+        &self.sweeps
+    }
+
+    // The named sub-account of the account, if it has been used.
+    pub fn subaccount(&self, name: &str) -> Option<&Account> {
+        self.subaccounts.get(name)
+    }
+
+    // Iterates over the sub-accounts, as (name, account) pairs sorted by name.
+    pub fn subaccounts(&self) -> impl Iterator<Item = (&str, &Account)> {
+        self.subaccounts.iter().map(|(name, a)| (name.as_str(), a))
+    }
+
+    // Balances of the main balance and all sub-accounts together. The rollup is locked if any of
+    // them is. Its sums are in range, as the ledger rejects the transactions which would take them
+    // out of it (see process_in_account), and the snapshots with them out of it.
+    pub fn rollup(&self) -> Balance {
+        self.subaccounts
+            .values()
+            .map(Account::balance)
+            .fold(self.balance(), |total, b| Balance {
+                available: total.available + b.available,
+                held: total.held + b.held,
+                escrow: total.escrow + b.escrow,
+                total: total.total + b.total,
+                locked: total.locked || b.locked,
+            })
+    }
+
+    // The rollup, or None if one of its sums is out of range.
+    pub(crate) fn checked_rollup(&self) -> Option<Balance> {
+        let balances = self.subaccounts.values().map(Account::checked_balance);
+        balances.fold(self.checked_balance(), |total, b| total?.checked_add(&b?))
+    }
+
+    fn checked_balance(&self) -> Option<Balance> {
+        let total = self.available().checked_add(self.held())?;
+        Some(Balance {
+            available: self.available(),
+            held: self.held(),
+            escrow: self.escrow,
+            total: total.checked_add(self.escrow)?,
+            locked: self.is_locked(),
+        })
+    }
+
+    // Debits the available funds of the account by the amount (credits them if it is negative),
+    // unless this takes its balances or its rollup out of range.
+    fn debit(&mut self, amount: Amount) -> Result<(), LedgerError> {
+        let state = self.state.debited(amount)?;
+        let before = std::mem::replace(&mut self.state, state);
+        if self.checked_rollup().is_none() {
+            self.state = before;
+            return Err(LedgerError::AmountOutOfRange);
+        }
+        Ok(())
+    }
+
+    // The sub-account (None for the main balance) holding the given pending bonus.
+    fn bonus_holder(&mut self, tx_id: u32) -> Option<(Option<String>, &mut Account)> {
+        if self.bonuses.iter().any(|b| b.tx == tx_id) {
+            return Some((None, self));
+        }
+        self.subaccounts
+            .iter_mut()
+            .find(|(_, a)| a.bonuses.iter().any(|b| b.tx == tx_id))
+            .map(|(name, a)| (Some(name.clone()), a))
+    }
+
+    // The sub-account (None for the main balance) holding the given pending authorization.
+    fn authorization_holder(&mut self, tx_id: u32) -> Option<(Option<String>, &mut Account)> {
+        let pending = |a: &Account| matches!(a.oplog.get(tx_id), Some(Authorization { .. }));
+        if pending(self) {
+            return Some((None, self));
+        }
+        self.subaccounts
+            .iter_mut()
+            .find(|(_, a)| pending(a))
+            .map(|(name, a)| (Some(name.clone()), a))
+    }
+
+    // When the authorization just applied expires, if it does.
+    fn authorization_expiry(&self, applied: &Applied) -> Option<Timestamp> {
+        match self.oplog.get(applied.tx) {
+            Some(Authorization { expires_at, .. })
+                if applied.kind == TransactionType::Authorize =>
+            {
+                expires_at
+            }
+            _ => None,
+        }
+    }
+
+    // Tags of the given transaction of this account, in the order the account first saw them.
+    pub fn tags(&self, tx_id: u32) -> impl Iterator<Item = &str> {
+        let set = self.tags.get(&tx_id).copied().unwrap_or(0);
+        self.tag_names
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| set & (1 << i) != 0)
+            .map(|(_, name)| name.as_str())
+    }
+
+    // Memos of the given transaction of this account, see TransactionEntry::memo.
+    pub fn memos(&self, tx_id: u32) -> &[String] {
+        self.memos.get(&tx_id).map_or(&[], Vec::as_slice)
+    }
+
+    // Checks that the given tags fit into MAX_TAGS along with the known ones.
+    fn check_tags(&self, tags: &[&str]) -> Result<(), LedgerError> {
+        let new: BTreeSet<&&str> = tags
+            .iter()
+            .filter(|t| !self.tag_names.iter().any(|n| n == *t))
+            .collect();
+        if self.tag_names.len() + new.len() > MAX_TAGS {
+            return Err(LedgerError::TooManyTags);
+        }
+        Ok(())
+    }
+
+    // Adds tags to the given transaction. They must have been checked with check_tags.
+    fn add_tags(&mut self, tx_id: u32, tags: &[&str]) {
+        let mut set = 0;
+        for tag in tags {
+            let i = match self.tag_names.iter().position(|n| n == tag) {
+                Some(i) => i,
+                None => {
+                    self.tag_names.push(tag.to_string());
+                    self.tag_names.len() - 1
+                }
+            };
+            set |= 1 << i;
+        }
+        if set != 0 {
+            *self.tags.entry(tx_id).or_default() |= set;
+        }
+    }
+
+    pub fn bonuses(&self) -> &[Bonus] {
+        &self.bonuses
+    }
+
+    // Takes a withdrawn amount from the remaining part of the bonuses, the oldest first.
+    fn use_bonuses(&mut self, mut amount: Amount) {
+        for bonus in &mut self.bonuses {
+            let used = bonus.remaining.min(amount);
+            bonus.remaining -= used;
+            amount -= used;
+        }
+        self.bonuses.retain(|b| b.remaining > Amount::ZERO);
+    }
+
+    // Iterates over the balances in currencies other than the base currency, sorted by currency.
+    pub fn currencies(&self) -> impl Iterator<Item = (Currency, Amount)> + '_ {
+        self.currencies.iter().map(|(c, balance)| (*c, *balance))
+    }
+
+    // Iterates over the oplog, as (transaction id, state) pairs in no particular order.
+    pub fn operations(&self) -> impl Iterator<Item = (u32, OperationState)> + '_ {
+        self.oplog.iter()
+    }
+}
+
+// Ledger - the map of all accounts, by their respective client_id.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    accounts: Accounts, // Accounts by client_id
+    config: Config,
+    rates: Rates, // Used by conversions and Ledger::total_in
+    // Pending bonus and authorization expiries, as (expiry, client id, transaction id).
+    bonus_expiries: BTreeSet<(Timestamp, u16, u32)>,
+    authorization_expiries: BTreeSet<(Timestamp, u16, u32)>,
+    duplicate_filter: Option<Bloom>, // See Config::duplicate_filter_capacity
+    // Inverses of the last applied transactions, the latest last, see Config::undo_depth.
+    undo: VecDeque<Undo>,
+    // Transactions applied with an idempotency key, by client and key. Only checked by
+    // Ledger::apply_transaction and apply_batch, not by the concurrent and async ledgers.
+    idempotency_keys: IdempotencyKeys,
+    // Highest transaction id applied on each day (in days since 1970-01-01) of the retention
+    // window, the latest last, which Config::retention_days counts with. Days before the window
+    // are folded into the first mark.
+    day_marks: VecDeque<(i64, u32)>,
+    // Placed with Ledger::place_legal_hold, in addition to those of the configuration.
+    legal_holds: Vec<LegalHold>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn with_config(config: Config) -> Ledger {
+        Ledger {
+            accounts: Accounts::default(),
+            duplicate_filter: config.duplicate_filter_capacity.map(Bloom::new),
+            config,
+            rates: Rates::default(),
+            bonus_expiries: BTreeSet::new(),
+            authorization_expiries: BTreeSet::new(),
+            undo: VecDeque::new(),
+            idempotency_keys: BTreeMap::new(),
+            day_marks: VecDeque::new(),
+            legal_holds: Vec::new(),
+        }
+    }
+
+    pub fn builder() -> LedgerBuilder {
+        LedgerBuilder::new()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn rates(&self) -> &dyn ExchangeRateProvider {
+        &*self.rates.0
+    }
+
+    pub fn set_rates(&mut self, rates: impl ExchangeRateProvider + 'static) {
+        self.rates = Rates(Box::new(rates));
+    }
+
+    pub fn apply_transaction(&mut self, tx: TransactionEntry) -> Result<Applied, LedgerError> {
+        apply_transaction(tx, self)
+    }
+
+    // Applies a batch of transactions, with the same results (in the same order) as applying them
+    // one by one. Transactions of a client only ever touch its own account, so they are applied
+    // client by client, in input order for each client, looking up each account once. Sweeping
+    // deposits into the overflow account touches another account, so with an overflow account
+    // configured the batch is applied in input order, as it is when journaling for undo, whose
+    // journal is in input order, and when idempotency keys are used, as the keys of a client
+    // aren't in its account.
+    pub fn apply_batch(&mut self, batch: &[TransactionEntry]) -> Vec<Result<Applied, LedgerError>> {
+        let sweeps = self
+            .config
+            .max_balance
+            .as_ref()
+            .is_some_and(|m| m.overflow_account.is_some());
+        let keys = batch.iter().any(|tx| tx.idempotency_key.is_some());
+        if sweeps || keys || self.config.undo_depth > 0 {
+            return batch
+                .iter()
+                .map(|tx| self.apply_transaction(tx.clone()))
+                .collect();
+        }
+        let mut order: Vec<usize> = (0..batch.len()).collect();
+        order.sort_by_key(|&i| batch[i].client_id);
+        let mut results: Vec<Option<Result<Applied, LedgerError>>> =
+            batch.iter().map(|_| None).collect();
+        for group in order.chunk_by(|i, j| batch[*i].client_id == batch[*j].client_id) {
+            let client_id = batch[group[0]].client_id;
+            let a = self.accounts.get_or_default(client_id);
+            for &i in group {
+                let result = process_transaction(
+                    batch[i].clone(),
+                    a,
+                    &self.config,
+                    &*self.rates.0,
+                    self.duplicate_filter.as_ref(),
+                    None, // Nothing is swept, see above
+                );
+                if let Ok(applied) = &result {
+                    if let Some(b) = self.duplicate_filter.as_mut() {
+                        b.insert(applied.tx);
+                    }
+                    let holder = match &applied.subaccount {
+                        Some(name) => &a.subaccounts[name],
+                        None => &*a,
+                    };
+                    if let Some(bonus) = holder.bonuses.last().filter(|b| b.tx == applied.tx) {
+                        self.bonus_expiries
+                            .insert((bonus.expires_at, client_id, applied.tx));
+                    }
+                    if let Some(expires_at) = holder.authorization_expiry(applied) {
+                        self.authorization_expiries
+                            .insert((expires_at, client_id, applied.tx));
+                    }
+                    if let (Some(days), Some(t)) = (self.config.retention_days, batch[i].timestamp)
+                    {
+                        retention::mark_day(&mut self.day_marks, days, t, applied.tx);
+                    }
+                }
+                results[i] = Some(result);
+            }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    // Reverts the last n applied transactions (or as many as the journal holds), the latest first,
+    // as if they had never been applied. Returns the reverted (client id, transaction id) pairs.
+    // Only transactions applied since the last interest charge or bonus expiry can be reverted,
+    // and at most Config::undo_depth of them.
+    pub fn undo_last(&mut self, n: usize) -> Vec<(u16, u32)> {
+        let mut reverted = Vec::new();
+        while reverted.len() < n {
+            let Some(undo) = self.undo.pop_back() else {
+                break;
+            };
+            reverted.push(undo.applied());
+            // The duplicate filter keeps the reverted ids, which only costs oplog lookups.
+            undo.revert(self);
+        }
+        reverted
+    }
+
+    // Number of transactions undo_last can revert.
+    pub fn undoable(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn account(&self, client_id: u16) -> Option<&Account> {
+        self.accounts.get(client_id)
+    }
+
+    // Whether the account of the client only changes with transactions of the client from now on
+    // (or with Ledger::charge_interest): it has no bonus or authorization left to expire, and it
+    // isn't the overflow account deposits of other clients are swept into.
+    pub fn is_final(&self, client_id: u16) -> bool {
+        let overflow = self
+            .config
+            .max_balance
+            .as_ref()
+            .and_then(|m| m.overflow_account);
+        let pending = |expiries: &BTreeSet<(Timestamp, u16, u32)>| {
+            expiries.iter().any(|&(_, client, _)| client == client_id)
+        };
+        overflow != Some(client_id)
+            && !pending(&self.bonus_expiries)
+            && !pending(&self.authorization_expiries)
+    }
+
+    // Iterates over all accounts, as (client id, account) pairs in the order the accounts were
+    // opened.
+    pub fn accounts(&self) -> impl Iterator<Item = (u16, &Account)> {
+        self.accounts.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    pub fn balance(&self, client_id: u16) -> Option<Balance> {
+        self.account(client_id).map(Account::balance)
+    }
+
+    // Total balance of a client over all its currencies and sub-accounts, converted to the given
+    // currency at the current rates. None if the client is unknown or a rate is missing (the base
+    // balance needs the base currency to be configured unless it is zero).
+    pub fn total_in(&self, client_id: u16, currency: Currency) -> Option<Amount> {
+        let a = self.account(client_id)?;
+        let rates = self.rates();
+        let total = a.rollup().total;
+        let base = match self.config.base_currency {
+            Some(base) => total.times(rates.rate(base, currency, None)?)?,
+            None if total == Amount::ZERO => Amount::ZERO,
+            None => return None,
+        };
+        let mut currencies = a
+            .subaccounts()
+            .flat_map(|(_, a)| a.currencies())
+            .chain(a.currencies());
+        currencies.try_fold(base, |total, (c, balance)| {
+            total.checked_add(balance.times(rates.rate(c, currency, None)?)?)
+        })
+    }
+
+    // Charges the interest of every credit line on the negative available funds of its client, e.g.
+    // at the end of a statement period. Returns the charged (client id, interest) pairs.
+    pub fn charge_interest(&mut self) -> Vec<(u16, Amount)> {
+        let mut charged = Vec::new();
+        for credit_line in &self.config.credit_lines {
+            let Some(a) = self.accounts.get_mut(credit_line.client) else {
+                continue;
+            };
+            // An interest out of range (of a debt close to the largest amount) isn't charged.
+            let interest = (-a.available())
+                .max(Amount::ZERO)
+                .times(credit_line.interest_rate);
+            let Some(interest) = interest.map(|interest| self.config.round(interest)) else {
+                continue;
+            };
+            if interest > Amount::ZERO && a.debit(interest).is_ok() {
+                charged.push((credit_line.client, interest));
+            }
+        }
+        if !charged.is_empty() {
+            self.undo.clear(); // Transactions before the charge can't be undone past it
+        }
+        charged
+    }
+
+    // Reverses what is left of the bonuses which expired by the given time, even on locked
+    // accounts, and returns the reversals for the audit trail. A reversal never takes the
+    // available funds below zero. With bonus expiry configured, callers are expected to call this
+    // with the timestamp of every transaction before applying it, so that expired bonuses can't
+    // be withdrawn.
+    pub fn expire_bonuses(&mut self, now: Timestamp) -> Vec<Applied> {
+        let mut expired = Vec::new();
+        while let Some(&(expires_at, client_id, tx)) = self.bonus_expiries.first() {
+            if expires_at > now {
+                break;
+            }
+            self.bonus_expiries.pop_first();
+            self.undo.clear(); // Transactions before the expiry can't be undone past it
+            let Some((subaccount, a)) = self
+                .accounts
+                .get_mut(client_id)
+                .and_then(|a| a.bonus_holder(tx))
+            else {
+                continue; // Withdrawn in full already
+            };
+            let Some(i) = a.bonuses.iter().position(|b| b.tx == tx) else {
+                continue;
+            };
+            let bonus = a.bonuses.remove(i);
+            let amount = bonus.remaining.min(a.available().max(Amount::ZERO));
+            if amount <= Amount::ZERO {
+                continue;
+            }
+            let before = a.balance();
+            // The available funds only go down to zero, so they stay in range.
+            if a.debit(amount).is_err() {
+                continue;
+            }
+            expired.push(Applied {
+                client_id,
+                tx,
+                kind: TransactionType::Bonus,
+                amount: Some(amount),
+                before,
+                after: a.balance(),
+                note: Some(format!("unused bonus of {} expired", amount)),
+                swept: None,
+                timestamp: Some(expires_at),
+                fee: Amount::ZERO,
+                subaccount,
+                tags: a.tags(tx).map(String::from).collect(),
+                memo: None,
+                reason: None,
+            });
+        }
+        expired
+    }
+
+    // Releases the held funds of the authorizations which expired by the given time without being
+    // captured, even on locked accounts, and returns the releases for the audit trail. Like
+    // expire_bonuses, callers are expected to call this with the timestamp of every transaction
+    // before applying it, so that expired authorizations can't be captured.
+    pub fn expire_authorizations(&mut self, now: Timestamp) -> Vec<Applied> {
+        let mut expired = Vec::new();
+        while let Some(&(expires_at, client_id, tx)) = self.authorization_expiries.first() {
+            if expires_at > now {
+                break;
+            }
+            self.authorization_expiries.pop_first();
+            self.undo.clear(); // Transactions before the expiry can't be undone past it
+            let Some((subaccount, a)) = self
+                .accounts
+                .get_mut(client_id)
+                .and_then(|a| a.authorization_holder(tx))
+            else {
+                continue; // Captured already
+            };
+            let Some(Authorization { amount, .. }) = a.oplog.get(tx) else {
+                continue;
+            };
+            let before = a.balance();
+            // What is released moves from the held funds to the available ones, which add up to
+            // the total at most, so they stay in range.
+            let (Ok(available), Ok(held)) = (plus(a.available(), amount), minus(a.held(), amount))
+            else {
+                continue;
+            };
+            a.state = match a.state {
+                Open { .. } => Open { available, held },
+                Locked { .. } => Locked { available, held },
+            };
+            a.oplog.insert(tx, AuthorizationExpired);
+            expired.push(Applied {
+                client_id,
+                tx,
+                kind: TransactionType::Authorize,
+                amount: Some(amount),
+                before,
+                after: a.balance(),
+                note: Some(format!("authorization of {} expired, released", amount)),
+                swept: None,
+                timestamp: Some(expires_at),
+                fee: Amount::ZERO,
+                subaccount,
+                tags: a.tags(tx).map(String::from).collect(),
+                memo: None,
+                reason: None,
+            });
+        }
+        expired
+    }
+}
+
+// Duplicate filter holding the ids logged by the accounts, for ledgers built from existing
+// accounts.
+pub(crate) fn duplicate_filter(config: &Config, accounts: &Accounts) -> Option<Bloom> {
+    config
+        .duplicate_filter_capacity
+        .map(|capacity| Bloom::of(capacity, accounts))
+}
+
+// Index of the pending bonus expiries of the accounts, for ledgers built from existing accounts.
+pub(crate) fn bonus_expiries(accounts: &Accounts) -> BTreeSet<(Timestamp, u16, u32)> {
+    accounts
+        .iter()
+        .flat_map(|(client_id, a)| {
+            a.subaccounts.values().chain([a]).flat_map(move |a| {
+                a.bonuses
+                    .iter()
+                    .map(move |b| (b.expires_at, client_id, b.tx))
+            })
+        })
+        .collect()
+}
+
+// Index of the pending authorization expiries of the accounts, for ledgers built from existing
+// accounts.
+pub(crate) fn authorization_expiries(accounts: &Accounts) -> BTreeSet<(Timestamp, u16, u32)> {
+    accounts
+        .iter()
+        .flat_map(|(client_id, a)| {
+            a.subaccounts.values().chain([a]).flat_map(move |a| {
+                a.oplog.iter().filter_map(move |(tx, op)| match op {
+                    Authorization {
+                        expires_at: Some(expires_at),
+                        ..
+                    } => Some((expires_at, client_id, tx)),
+                    _ => None,
+                })
+            })
+        })
+        .collect()
+}
+
+// The result of applying an operation on an account.
+#[derive(Debug)]
+enum AccountOperationResult {
+    AppendOperation {
+        state: AccountState,
+        op: OperationState,
+    },
+    ModifyOperation {
+        state: AccountState,
+        op: OperationState,
+    },
+    // An appended operation which also sets the balances of the given currencies.
+    AppendWithBalances {
+        state: AccountState,
+        op: OperationState,
+        balances: Vec<(Currency, Amount)>,
+    },
+}
+
+impl AccountOperationResult {
+    fn state(&self) -> &AccountState {
+        match self {
+            AppendOperation { state, .. }
+            | ModifyOperation { state, .. }
+            | AppendWithBalances { state, .. } => state,
+        }
+    }
+
+    // The same result, with the resulting account state locked.
+    fn locked(self) -> AccountOperationResult {
+        let lock = |state: AccountState| match state {
+            Open { available, held } | Locked { available, held } => Locked { available, held },
+        };
+        match self {
+            AppendOperation { state, op } => AppendOperation {
+                state: lock(state),
+                op,
+            },
+            ModifyOperation { state, op } => ModifyOperation {
+                state: lock(state),
+                op,
+            },
+            AppendWithBalances {
+                state,
+                op,
+                balances,
+            } => AppendWithBalances {
+                state: lock(state),
+                op,
+                balances,
+            },
+        }
+    }
+}
+
+// What an operation did besides changing the account state, for the audit trail: the amount it
+// actually moved and a note on the path taken when a policy decided it.
+#[derive(Debug, Default)]
+struct Effect {
+    amount: Option<Amount>,
+    note: Option<String>,
+    swept: Option<(u16, Amount)>,
+    fee: Amount,
+}
+
+fn process_operation(
+    op: AccountOperation,
+    op_to_modify: Option<OperationState>,
+    client_id: u16,
+    a: &mut Account,
+    config: &Config,
+    flags: Flags,
+) -> Result<(AccountOperationResult, Effect), LedgerError> {
+    // Main state machine. Takes an AccountOperation (representing a current operation), an Option
+    // of OperationState, which will be the operation to modify for modifying operations or None
+    // for AppendOperations and a mutable account and results in the mutation on the account.
+    // Returns AccountOperationResult. It mutates the state of the account, but does not change
+    // the oplog. Oplog is then modified in the subsequent function.
+    // Operations on a locked account are rejected unless the locked policy permits them, in which
+    // case they are applied as on an open account and the account stays locked.
+    // Chargeback reversals are always permitted, since the account is locked by the very
+    // chargeback they reverse, and so are the corrections of administrators.
+    let locked = a.is_locked();
+    if locked
+        && !matches!(op, ReverseChargeback | Adjust { .. })
+        && !config.locked_policy.permits(&op)
+    {
+        return Err(LedgerError::AccountLocked);
+    }
+    let (available, held) = (a.available(), a.held());
+    let mut effect = Effect::default();
+    // Disputes are resolved, charged back and escalated the same way in every stage.
+    let op = match (op_to_modify.and_then(OpenDispute::of), op) {
+        (Some(dispute), op @ (Resolve { .. } | Chargeback { .. } | Escalate { .. })) => {
+            let result = process_dispute(op, dispute, available, held, config, &mut effect)?;
+            return Ok((if locked { result.locked() } else { result }, effect));
+        }
+        (_, op) => op,
+    };
+    let result = match (op_to_modify, op) {
+        (None, Deposit { mut amount }) => {
+            let total = a.total();
+            match &config.max_balance {
+                Some(max)
+                    if max.overflow_account != Some(client_id)
+                        && plus(total, amount)? > max.limit =>
+                {
+                    let Some(overflow_account) = max.overflow_account else {
+                        return Err(LedgerError::MaxBalanceExceeded);
+                    };
+                    let excess = amount - minus(max.limit, total)?.max(Amount::ZERO);
+                    effect.note = Some(format!(
+                        "excess of {} swept into account {}",
+                        excess, overflow_account
+                    ));
+                    effect.swept = Some((overflow_account, excess));
+                    amount -= excess;
+                }
+                _ => {}
+            }
+            effect.amount = Some(amount);
+            AppendOperation {
+                op: RegularDeposit {
+                    amount,
+                    disputes: 0,
+                    charged_back: Amount::ZERO,
+                },
+                state: Open {
+                    available: plus(available, amount)?,
+                    held,
+                },
+            }
+        }
+        (None, Withdrawal { amount }) => {
+            let credit_line = config.credit_line(client_id).filter(|_| flags.credit_lines);
+            let limit = credit_line.map_or(Amount::ZERO, |c| c.limit);
+            let mut remaining = minus(available, amount)?;
+            // The fee of drawing on the credit line counts against the limits like the amount.
+            let draw_fee = credit_line
+                .map(|c| c.draw_fee)
+                .filter(|fee| remaining < Amount::ZERO && *fee > Amount::ZERO);
+            if let Some(fee) = draw_fee {
+                remaining = minus(remaining, fee)?;
+            }
+            let overdraft = if flags.overdraft {
+                config.allow_overdraft
+            } else {
+                Amount::ZERO
+            };
+            if remaining < -overdraft.max(limit) {
+                return Err(LedgerError::InsufficientFunds);
+            }
+            if config
+                .tier(client_id)
+                .is_some_and(|t| remaining < t.min_balance)
+            {
+                return Err(LedgerError::BelowMinimumBalance);
+            }
+            if let Some(fee) = draw_fee {
+                effect.note = Some(format!("credit line drawn, fee of {} charged", fee));
+                effect.fee = fee;
+            }
+            effect.amount = Some(amount);
+            AppendOperation {
+                op: AfterWithdrawal,
+                state: Open {
+                    available: remaining,
+                    held,
+                },
+            }
+        }
+        (
+            Some(RegularDeposit {
+                amount,
+                disputes,
+                charged_back,
+            }),
+            Dispute { amount: requested },
+        ) => {
+            if config.max_disputes.is_some_and(|max| disputes >= max) {
+                return Err(LedgerError::MaxDisputesExceeded);
+            }
+            let mut disputed = portion(requested, amount)?;
+            // The deposited funds may have been withdrawn (in part) since.
+            if disputed > available {
+                match config.withdrawn_dispute_policy {
+                    WithdrawnDisputePolicy::AllowNegative => {
+                        effect.note = Some(format!(
+                            "dispute of {} exceeds available funds of {}, available goes negative",
+                            disputed, available
+                        ));
+                    }
+                    WithdrawnDisputePolicy::Reject => {
+                        return Err(LedgerError::InsufficientFundsForDispute)
+                    }
+                    WithdrawnDisputePolicy::Cap => {
+                        if available <= Amount::ZERO {
+                            return Err(LedgerError::InsufficientFundsForDispute);
+                        }
+                        effect.note = Some(format!(
+                            "dispute of {} capped at available funds of {}",
+                            disputed, available
+                        ));
+                        disputed = available;
+                    }
+                }
+            }
+            effect.amount = Some(disputed);
+            ModifyOperation {
+                op: DisputedDeposit {
+                    amount,
+                    disputed,
+                    disputes: disputes + 1,
+                    charged_back,
+                },
+                state: Open {
+                    available: minus(available, disputed)?,
+                    held: plus(held, disputed)?,
+                },
+            }
+        }
+        (None, Adjust { amount }) => {
+            effect.amount = Some(amount);
+            AppendOperation {
+                op: Adjustment { amount },
+                state: Open {
+                    available: plus(available, amount)?,
+                    held,
+                },
+            }
+        }
+        (None, PromotionalDeposit { amount }) => {
+            effect.amount = Some(amount);
+            AppendOperation {
+                op: BonusDeposit { amount },
+                state: Open {
+                    available: plus(available, amount)?,
+                    held,
+                },
+            }
+        }
+        (None, EscrowHold { amount }) => {
+            if amount > available {
+                return Err(LedgerError::InsufficientFunds);
+            }
+            effect.amount = Some(amount);
+            AppendOperation {
+                op: Escrow { amount },
+                state: Open {
+                    available: minus(available, amount)?,
+                    held,
+                },
+            }
+        }
+        (Some(Escrow { amount }), EscrowRelease { amount: requested }) => {
+            let released = portion(requested, amount)?;
+            effect.amount = Some(released);
+            ModifyOperation {
+                op: if released < amount {
+                    Escrow {
+                        amount: amount - released,
+                    }
+                } else {
+                    EscrowReleased
+                },
+                state: Open {
+                    available: plus(available, released)?,
+                    held,
+                },
+            }
+        }
+        (None, Authorize { amount, expires_at }) => {
+            if amount > available {
+                return Err(LedgerError::InsufficientFunds);
+            }
+            effect.amount = Some(amount);
+            AppendOperation {
+                op: Authorization { amount, expires_at },
+                state: Open {
+                    available: minus(available, amount)?,
+                    held: plus(held, amount)?,
+                },
+            }
+        }
+        (Some(Authorization { amount, .. }), Capture { amount: requested }) => {
+            let captured = portion(requested, amount)?;
+            if captured < amount {
+                effect.note = Some(format!(
+                    "remaining {} of the authorization released",
+                    amount - captured
+                ));
+            }
+            effect.amount = Some(captured);
+            ModifyOperation {
+                op: Captured,
+                state: Open {
+                    available: plus(available, amount - captured)?,
+                    held: minus(held, amount)?,
+                },
+            }
+        }
+        (None, ForeignDeposit { currency, amount }) => {
+            effect.amount = Some(amount);
+            AppendWithBalances {
+                op: CurrencyDeposit { currency, amount },
+                state: Open { available, held },
+                balances: vec![(currency, plus(a.currency_balance(currency), amount)?)],
+            }
+        }
+        (None, ForeignWithdrawal { currency, amount }) => {
+            let balance = a.currency_balance(currency);
+            if amount > balance {
+                return Err(LedgerError::InsufficientFunds);
+            }
+            effect.amount = Some(amount);
+            AppendWithBalances {
+                op: AfterWithdrawal,
+                state: Open { available, held },
+                balances: vec![(currency, minus(balance, amount)?)],
+            }
+        }
+        (
+            None,
+            Convert {
+                from,
+                to,
+                amount,
+                converted,
+            },
+        ) => {
+            if from == to {
+                return Err(LedgerError::IllegalStateTransition);
+            }
+            let is_base = |c| config.base_currency == Some(c);
+            let mut available = available;
+            let mut balances = Vec::new();
+            if is_base(from) {
+                if amount > available {
+                    return Err(LedgerError::InsufficientFunds);
+                }
+                available = minus(available, amount)?;
+            } else {
+                let balance = a.currency_balance(from);
+                if amount > balance {
+                    return Err(LedgerError::InsufficientFunds);
+                }
+                balances.push((from, minus(balance, amount)?));
+            }
+            if is_base(to) {
+                available = plus(available, converted)?;
+            } else {
+                balances.push((to, plus(a.currency_balance(to), converted)?));
+            }
+            effect.amount = Some(amount);
+            effect.note = Some(format!(
+                "converted {} {} to {} {}",
+                amount, from, converted, to
+            ));
+            AppendWithBalances {
+                op: Conversion {
+                    from,
+                    from_amount: amount,
+                    to,
+                    to_amount: converted,
+                },
+                state: Open { available, held },
+                balances,
+            }
+        }
+        (
+            Some(FinalDeposit {
+                charged_back,
+                disputes,
+            }),
+            ReverseChargeback,
+        ) => {
+            effect.amount = Some(charged_back);
+            let result = ModifyOperation {
+                op: RegularDeposit {
+                    amount: charged_back,
+                    disputes,
+                    charged_back: Amount::ZERO,
+                },
+                state: Open {
+                    available: plus(available, charged_back)?,
+                    held,
+                },
+            };
+            // The account is unlocked only when the config says so, another chargeback may have
+            // locked it too.
+            let relock = locked && !flags.chargeback_reversal_unlocks;
+            return Ok((if relock { result.locked() } else { result }, effect));
+        }
+        _ => return Err(LedgerError::IllegalStateTransition),
+    };
+    Ok((if locked { result.locked() } else { result }, effect))
+}
+
+// Sum of a balance and an amount, rejecting the transaction when it is out of range.
+fn plus(balance: Amount, amount: Amount) -> Result<Amount, LedgerError> {
+    balance
+        .checked_add(amount)
+        .ok_or(LedgerError::AmountOutOfRange)
+}
+
+fn minus(balance: Amount, amount: Amount) -> Result<Amount, LedgerError> {
+    balance
+        .checked_sub(amount)
+        .ok_or(LedgerError::AmountOutOfRange)
+}
+
+// Amount covered by a dispute, resolve or chargeback, which may not exceed the given limit (the
+// disputable or disputed amount of the deposit).
+fn portion(requested: Option<Amount>, limit: Amount) -> Result<Amount, LedgerError> {
+    match requested {
+        None => Ok(limit),
+        Some(amount) if amount > Amount::ZERO && amount <= limit => Ok(amount),
+        Some(_) => Err(LedgerError::InvalidDisputeAmount),
+    }
+}
+
+// Deposit under dispute, in any stage of the dispute.
+#[derive(Clone, Copy, Debug)]
+struct OpenDispute {
+    stage: DisputeStage,
+    amount: Amount,
+    disputed: Amount,
+    disputes: u32,
+    charged_back: Amount,
+}
+
+impl OpenDispute {
+    fn of(op: OperationState) -> Option<OpenDispute> {
+        let (stage, amount, disputed, disputes, charged_back) = match op {
+            DisputedDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            } => (
+                DisputeStage::Disputed,
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            ),
+            RepresentedDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            } => (
+                DisputeStage::Representment,
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            ),
+            PreArbitrationDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            } => (
+                DisputeStage::PreArbitration,
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            ),
+            ArbitrationDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            } => (
+                DisputeStage::Arbitration,
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            ),
+            _ => return None,
+        };
+        Some(OpenDispute {
+            stage,
+            amount,
+            disputed,
+            disputes,
+            charged_back,
+        })
+    }
+}
+
+// Resolves, charges back or escalates a dispute, if the dispute lifecycle allows it from the
+// stage the dispute is in.
+fn process_dispute(
+    op: AccountOperation,
+    dispute: OpenDispute,
+    available: Amount,
+    held: Amount,
+    config: &Config,
+    effect: &mut Effect,
+) -> Result<AccountOperationResult, LedgerError> {
+    let OpenDispute {
+        stage,
+        amount,
+        disputed,
+        disputes,
+        charged_back: charged_back_before,
+    } = dispute;
+    let state = |amount, disputed| DepositState {
+        amount,
+        disputed,
+        disputes,
+        charged_back: charged_back_before,
+    };
+    let kind = match op {
+        Resolve { .. } => TransactionType::Resolve,
+        Chargeback { .. } => TransactionType::Chargeback,
+        Escalate { to } => to.transaction_type(),
+        _ => return Err(LedgerError::IllegalStateTransition),
+    };
+    if !config.allows_dispute_transition(stage, kind) {
+        return Err(LedgerError::IllegalStateTransition);
+    }
+    Ok(match op {
+        Resolve { amount: requested } => {
+            let resolved = portion(requested, disputed)?;
+            effect.amount = Some(resolved);
+            ModifyOperation {
+                op: state(amount, disputed - resolved).in_stage(stage),
+                state: Open {
+                    available: plus(available, resolved)?,
+                    held: minus(held, resolved)?,
+                },
+            }
+        }
+        Chargeback { amount: requested } => {
+            let charged_back = portion(requested, disputed)?;
+            effect.amount = Some(charged_back);
+            let op = if charged_back >= amount {
+                FinalDeposit {
+                    charged_back: plus(charged_back_before, charged_back)?,
+                    disputes,
+                }
+            } else {
+                DepositState {
+                    charged_back: plus(charged_back_before, charged_back)?,
+                    ..state(amount - charged_back, disputed - charged_back)
+                }
+                .in_stage(stage)
+            };
+            ModifyOperation {
+                op,
+                state: Locked {
+                    available,
+                    held: minus(held, charged_back)?,
+                },
+            }
+        }
+        Escalate { to } => {
+            effect.amount = Some(disputed);
+            ModifyOperation {
+                op: state(amount, disputed).in_stage(to),
+                state: Open { available, held },
+            }
+        }
+        _ => return Err(LedgerError::IllegalStateTransition),
+    })
+}
+
+// Deposit of which the given amount remains, with the given part of it disputed.
+struct DepositState {
+    amount: Amount,
+    disputed: Amount,
+    disputes: u32,
+    charged_back: Amount,
+}
+
+impl DepositState {
+    // State of the deposit with its dispute in the given stage, a regular deposit once nothing is
+    // disputed.
+    fn in_stage(self, stage: DisputeStage) -> OperationState {
+        let DepositState {
+            amount,
+            disputed,
+            disputes,
+            charged_back,
+        } = self;
+        if disputed <= Amount::ZERO {
+            return RegularDeposit {
+                amount,
+                disputes,
+                charged_back,
+            };
+        }
+        match stage {
+            DisputeStage::Disputed => DisputedDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            },
+            DisputeStage::Representment => RepresentedDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            },
+            DisputeStage::PreArbitration => PreArbitrationDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            },
+            DisputeStage::Arbitration => ArbitrationDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            },
+        }
+    }
+}
+
+// This function mutates the oplog of a given account by applying the modification
+// contained in the AccountOperationResult.
+fn apply_result_to_account(
+    result: AccountOperationResult,
+    tx_id: u32,
+    a: &mut Account,
+    config: &Config,
+) -> Result<(), LedgerError> {
+    match result {
+        AppendOperation {
+            state,
+            op: AfterWithdrawal,
+        } if config.withdrawal_ids_only => {
+            a.state = state;
+            a.oplog.insert_withdrawal(tx_id);
+            Ok(())
+        }
+        AppendOperation { state, op } => {
+            a.state = state;
+            a.oplog.insert(tx_id, op);
+            Ok(())
+        }
+        ModifyOperation { state, op } => {
+            a.state = state;
+            if a.oplog.contains_key(tx_id) {
+                a.oplog.insert(tx_id, op);
+            }
+            Ok(())
+        }
+        AppendWithBalances {
+            state,
+            op,
+            balances,
+        } => {
+            a.state = state;
+            a.oplog.insert(tx_id, op);
+            a.currencies.extend(balances);
+            Ok(())
+        }
+    }
+}
+
+// Credits the overflow account with the part of a deposit of the client swept into it. The swept
+// part is recorded as a sweep, by client and id of the deposit, so that it can be traced back; it
+// is credited even if the overflow account is locked. The deposit was only applied if the
+// overflow account had room for the swept part (see Others), so this only fails if another sweep
+// took the room in between, which only AsyncLedger allows.
+pub(crate) fn credit_overflow(
+    client_id: u16,
+    tx_id: u32,
+    amount: Amount,
+    a: &mut Account,
+) -> Result<(), LedgerError> {
+    a.debit(-amount)?;
+    a.sweeps.push(Sweep {
+        client: client_id,
+        tx: tx_id,
+        amount,
+    });
+    Ok(())
+}
+
+// The overflow account the transaction may be swept into (see MaxBalance): that of the config
+// for the deposits of the other clients.
+pub(crate) fn sweep_target(tx: &TransactionEntry, config: &Config) -> Option<u16> {
+    let overflow = config.max_balance.as_ref()?.overflow_account?;
+    let deposit = matches!(tx.t.parse(), Ok(TransactionType::Deposit));
+    (deposit && overflow != tx.client_id).then_some(overflow)
+}
+
+// What a transaction of an account involves of the other accounts: whether another account of the
+// client logged its id, which makes new transactions with it duplicates, the rollup of the other
+// accounts of the client (sub-accounts, or main balance) and the rollup of the overflow account the
+// deposit may be swept into, if it has one. The transaction is rejected if it would take either
+// rollup out of range.
+struct Others {
+    logged: bool,
+    rollup: Balance,
+    overflow: Option<Balance>,
+}
+
+// The duplicate filter of the ledger, if any, answers for most ids which aren't in the log.
+fn is_transaction_in_log(tx: &TransactionEntry, a: &Account, seen: Option<&Bloom>) -> bool {
+    seen.is_none_or(|b| b.may_contain(tx.uid)) && a.oplog.contains_key(tx.uid)
+}
+
+// Balances of the accounts together, see Others.
+fn rollup_of<'a>(mut accounts: impl Iterator<Item = &'a Account>) -> Result<Balance, LedgerError> {
+    accounts.try_fold(Balance::ZERO, |total, a| {
+        a.checked_balance()
+            .and_then(|b| total.checked_add(&b))
+            .ok_or(LedgerError::AmountOutOfRange)
+    })
+}
+
+// Applies a transaction to the account of its client, or to the sub-account it names. Overflow
+// is the rollup of the overflow account the transaction may be swept into (see sweep_target), if
+// it has one.
+pub(crate) fn process_transaction(
+    tx: TransactionEntry,
+    a: &mut Account,
+    config: &Config,
+    rates: &dyn ExchangeRateProvider,
+    seen: Option<&Bloom>,
+    overflow: Option<Balance>,
+) -> Result<Applied, LedgerError> {
+    if a.forgotten {
+        return Err(LedgerError::ClientForgotten);
+    }
+    let flags = config.flags_at(tx.timestamp);
+    // Transaction ids are unique per client: a new transaction is a duplicate when another
+    // account of the client (the main balance or a sub-account) logged its id already.
+    let logged =
+        |s: &Account| seen.is_none_or(|b| b.may_contain(tx.uid)) && s.oplog.contains_key(tx.uid);
+    match tx.subaccount.as_deref() {
+        None | Some(MAIN_SUBACCOUNT) => {
+            let others = Others {
+                logged: a.subaccounts.values().any(logged),
+                rollup: rollup_of(a.subaccounts.values())?,
+                overflow,
+            };
+            process_in_account(tx, a, config, flags, rates, seen, others)
+        }
+        Some(name) => {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if name.is_empty() || !name.chars().all(valid) {
+                return Err(LedgerError::InvalidSubaccount);
+            }
+            let siblings = || {
+                a.subaccounts
+                    .iter()
+                    .filter(|(other, _)| *other != name)
+                    .map(|(_, s)| s)
+            };
+            let others = Others {
+                logged: logged(a) || siblings().any(logged),
+                rollup: rollup_of(std::iter::once(&*a).chain(siblings()))?,
+                overflow,
+            };
+            let subaccount = a.subaccounts.entry(name.to_string()).or_default();
+            process_in_account(tx, subaccount, config, flags, rates, seen, others)
+        }
+    }
+}
+
+// Applies a transaction to the given account, see Others for what it involves of the others.
+fn process_in_account(
+    tx: TransactionEntry,
+    a: &mut Account,
+    config: &Config,
+    flags: Flags,
+    rates: &dyn ExchangeRateProvider,
+    seen: Option<&Bloom>,
+    others: Others,
+) -> Result<Applied, LedgerError> {
+    let kind: TransactionType = tx.t.parse()?;
+    let currency = tx.currency.or(config.base_currency);
+    let partial_amount = tx
+        .amount
+        .map(|amount| config.quantize(amount, currency))
+        .transpose()?;
+    let amount = || partial_amount.ok_or(LedgerError::MissingAmount);
+    if let (Some(amount), Some(limit)) = (partial_amount, config.max_amount.limit(kind)) {
+        // Debits (negative adjustments) are limited by their size.
+        if amount.abs() > limit {
+            return Err(LedgerError::AmountLimitExceeded);
+        }
+    }
+    let tags: Vec<&str> = tx
+        .tags
+        .iter()
+        .flat_map(|tags| tags.split(';'))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .collect();
+    a.check_tags(&tags)?;
+    let before = a.balance();
+    let (result, effect) = match kind {
+        TransactionType::Deposit | TransactionType::Withdrawal => {
+            if others.logged || is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::DuplicateTransaction);
+            }
+            let foreign = tx.currency.filter(|c| config.base_currency != Some(*c));
+            let op = match (kind, foreign) {
+                (TransactionType::Deposit, None) => Deposit { amount: amount()? },
+                (TransactionType::Deposit, Some(currency)) => ForeignDeposit {
+                    currency,
+                    amount: amount()?,
+                },
+                (_, None) => Withdrawal { amount: amount()? },
+                (_, Some(currency)) => ForeignWithdrawal {
+                    currency,
+                    amount: amount()?,
+                },
+            };
+            process_operation(op, None, tx.client_id, a, config, flags)?
+        }
+        TransactionType::EscrowHold => {
+            if others.logged || is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::DuplicateTransaction);
+            }
+            let op = AccountOperation::EscrowHold { amount: amount()? };
+            process_operation(op, None, tx.client_id, a, config, flags)?
+        }
+        TransactionType::EscrowRelease => {
+            if !is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::TransactionNotFound);
+            }
+            let op = EscrowRelease {
+                amount: partial_amount,
+            };
+            process_operation(op, a.oplog.get(tx.uid), tx.client_id, a, config, flags)?
+        }
+        TransactionType::Authorize => {
+            if others.logged || is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::DuplicateTransaction);
+            }
+            if config.authorization_expiry_days.is_some() && tx.timestamp.is_none() {
+                return Err(LedgerError::MissingTimestamp);
+            }
+            let op = Authorize {
+                amount: amount()?,
+                expires_at: tx
+                    .timestamp
+                    .and_then(|t| config.authorization_expires_at(t)),
+            };
+            process_operation(op, None, tx.client_id, a, config, flags)?
+        }
+        TransactionType::Capture => {
+            if !is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::TransactionNotFound);
+            }
+            let op = Capture {
+                amount: partial_amount,
+            };
+            process_operation(op, a.oplog.get(tx.uid), tx.client_id, a, config, flags)?
+        }
+        TransactionType::Bonus => {
+            if others.logged || is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::DuplicateTransaction);
+            }
+            if config.bonus_expiry_days.is_some() && tx.timestamp.is_none() {
+                return Err(LedgerError::MissingTimestamp);
+            }
+            let op = PromotionalDeposit { amount: amount()? };
+            process_operation(op, None, tx.client_id, a, config, flags)?
+        }
+        TransactionType::Convert => {
+            if others.logged || is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::DuplicateTransaction);
+            }
+            let from = tx.currency.or(config.base_currency);
+            let to = tx.to_currency.or(config.base_currency);
+            let (Some(from), Some(to)) = (from, to) else {
+                return Err(LedgerError::MissingCurrency);
+            };
+            let amount = amount()?;
+            let rate = rates
+                .rate(from, to, tx.timestamp)
+                .ok_or(LedgerError::RateNotFound)?;
+            let op = Convert {
+                from,
+                to,
+                amount,
+                converted: config.convert(amount, rate, to)?,
+            };
+            process_operation(op, None, tx.client_id, a, config, flags)?
+        }
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+            if !is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::TransactionNotFound);
+            }
+            let op = match kind {
+                TransactionType::Dispute => Dispute {
+                    amount: partial_amount,
+                },
+                TransactionType::Resolve => Resolve {
+                    amount: partial_amount,
+                },
+                _ => Chargeback {
+                    amount: partial_amount,
+                },
+            };
+            process_operation(op, a.oplog.get(tx.uid), tx.client_id, a, config, flags)?
+        }
+        TransactionType::Representment
+        | TransactionType::PreArbitration
+        | TransactionType::Arbitration => {
+            if !is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::TransactionNotFound);
+            }
+            let to = match kind {
+                TransactionType::Representment => DisputeStage::Representment,
+                TransactionType::PreArbitration => DisputeStage::PreArbitration,
+                _ => DisputeStage::Arbitration,
+            };
+            process_operation(
+                Escalate { to },
+                a.oplog.get(tx.uid),
+                tx.client_id,
+                a,
+                config,
+                flags,
+            )?
+        }
+        TransactionType::Adjustment => {
+            if !flags.admin_ops {
+                return Err(LedgerError::AdminOpsNotAllowed);
+            }
+            if others.logged || is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::DuplicateTransaction);
+            }
+            if tx.reason.as_deref().is_none_or(|r| r.trim().is_empty()) {
+                return Err(LedgerError::MissingReason);
+            }
+            let op = Adjust { amount: amount()? };
+            process_operation(op, None, tx.client_id, a, config, flags)?
+        }
+        TransactionType::ChargebackReversal => {
+            if !is_transaction_in_log(&tx, a, seen) {
+                return Err(LedgerError::TransactionNotFound);
+            }
+            process_operation(
+                ReverseChargeback,
+                a.oplog.get(tx.uid),
+                tx.client_id,
+                a,
+                config,
+                flags,
+            )?
+        }
+    };
+    // Scheduled fees are charged on top of the amount of the transaction, even when this takes
+    // the available funds negative. What the transaction leaves of the balances is computed
+    // before the account is changed, so that one taking them out of range is rejected whole.
+    let fee = match effect.amount {
+        Some(amount) if flags.fees => config.fee(tx.client_id, kind, amount)?,
+        Some(_) => Amount::ZERO,
+        None => Amount::ZERO,
+    };
+    let escrow = match (kind, effect.amount) {
+        (TransactionType::EscrowHold, Some(amount)) => plus(a.escrow, amount)?,
+        (TransactionType::EscrowRelease, Some(amount)) => minus(a.escrow, amount)?,
+        _ => a.escrow,
+    };
+    let (Open { available, held } | Locked { available, held }) = *result.state();
+    let left = minus(available, fee)?;
+    let after = Balance {
+        available: left,
+        held,
+        escrow,
+        total: plus(plus(left, held)?, escrow)?,
+        locked: false,
+    };
+    // The rollup of the client, and that of the overflow account once credited with the swept part.
+    if others.rollup.checked_add(&after).is_none() {
+        return Err(LedgerError::AmountOutOfRange);
+    }
+    if let (Some((_, excess)), Some(overflow)) = (effect.swept, others.overflow) {
+        plus(overflow.available, excess)?;
+        plus(overflow.total, excess)?;
+    }
+    let fees = plus(a.fees, plus(fee, effect.fee)?)?;
+    apply_result_to_account(result, tx.uid, a, config)?;
+    a.add_tags(tx.uid, &tags);
+    let memo = tx.memo.filter(|m| !m.is_empty());
+    if let Some(memo) = &memo {
+        a.memos.entry(tx.uid).or_default().push(memo.clone());
+    }
+    match (kind, effect.amount) {
+        (TransactionType::Bonus, Some(amount)) => {
+            if let Some(expires_at) = tx.timestamp.and_then(|t| config.bonus_expires_at(t)) {
+                a.bonuses.push(Bonus {
+                    tx: tx.uid,
+                    remaining: amount,
+                    expires_at,
+                });
+            }
+        }
+        (TransactionType::Withdrawal, Some(amount)) if before.available != a.available() => {
+            a.use_bonuses(amount);
+        }
+        _ => {}
+    }
+    a.escrow = escrow;
+    if fee > Amount::ZERO {
+        a.state = a.state.debited(fee)?;
+    }
+    a.fees = fees;
+    Ok(Applied {
+        client_id: tx.client_id,
+        tx: tx.uid,
+        kind,
+        amount: effect.amount,
+        before,
+        after: a.balance(),
+        note: effect.note,
+        swept: effect.swept,
+        timestamp: tx.timestamp,
+        fee: fee + effect.fee,
+        subaccount: tx.subaccount.filter(|name| name != MAIN_SUBACCOUNT),
+        tags: a.tags(tx.uid).map(String::from).collect(),
+        memo,
+        reason: tx
+            .reason
+            .map(|r| r.trim().to_string())
+            .filter(|r| !r.is_empty()),
+    })
+}
+
+pub fn apply_transaction(tx: TransactionEntry, l: &mut Ledger) -> Result<Applied, LedgerError> {
+    // A retry is acknowledged before anything else, so it doesn't even open an account.
+    if let Some(keyed) = idempotency::lookup(&l.idempotency_keys, &tx) {
+        return Err(keyed.check(&tx));
+    }
+    let key = tx.idempotency_key.clone().zip(Keyed::of(&tx));
+    let timestamp = tx.timestamp;
+    let mut undo = (l.config.undo_depth > 0).then(|| {
+        let subaccount = tx
+            .subaccount
+            .as_deref()
+            .filter(|&name| name != MAIN_SUBACCOUNT);
+        Undo::before(l, tx.client_id, subaccount, tx.uid)
+    });
+    let overflow = sweep_target(&tx, &l.config)
+        .and_then(|o| l.accounts.get(o))
+        .map(Account::rollup);
+    // The account is created on the first transaction of a client, even if that transaction ends
+    // up being rejected.
+    let account = l.accounts.get_or_default(tx.client_id);
+    let applied = process_transaction(
+        tx,
+        account,
+        &l.config,
+        &*l.rates.0,
+        l.duplicate_filter.as_ref(),
+        overflow,
+    )?;
+    if let Some(b) = l.duplicate_filter.as_mut() {
+        b.insert(applied.tx);
+    }
+    if let Some((overflow_account, excess)) = applied.swept {
+        if let Some(undo) = undo.as_mut() {
+            undo.before_sweep(l, overflow_account);
+        }
+        let overflow = l.accounts.get_or_default(overflow_account);
+        credit_overflow(applied.client_id, applied.tx, excess, overflow)?;
+    }
+    let a = &l.accounts[applied.client_id];
+    let a = match &applied.subaccount {
+        Some(name) => &a.subaccounts[name],
+        None => a,
+    };
+    if let Some(bonus) = a.bonuses.last().filter(|b| b.tx == applied.tx) {
+        l.bonus_expiries
+            .insert((bonus.expires_at, applied.client_id, applied.tx));
+        if let Some(undo) = undo.as_mut() {
+            undo.set_bonus_expiry(bonus.expires_at);
+        }
+    }
+    if let Some(expires_at) = a.authorization_expiry(&applied) {
+        l.authorization_expiries
+            .insert((expires_at, applied.client_id, applied.tx));
+        if let Some(undo) = undo.as_mut() {
+            undo.set_authorization_expiry(expires_at);
+        }
+    }
+    if let Some((key, keyed)) = key {
+        let of_client = l.idempotency_keys.entry(applied.client_id).or_default();
+        of_client.insert(key.clone(), keyed);
+        if let Some(undo) = undo.as_mut() {
+            undo.set_idempotency_key(key);
+        }
+    }
+    if let (Some(days), Some(t)) = (l.config.retention_days, timestamp) {
+        retention::mark_day(&mut l.day_marks, days, t, applied.tx);
+    }
+    if let Some(undo) = undo {
+        if l.undo.len() == l.config.undo_depth {
+            l.undo.pop_front();
+        }
+        l.undo.push_back(undo);
+    }
+    Ok(applied)
+}
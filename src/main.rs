@@ -1,291 +1,1961 @@
-use crate::AccountOperation::*;
-use crate::AccountOperationResult::*;
-use crate::AccountState::*;
-use crate::OperationState::*;
+use crate::anomalies::Anomalies;
+use crate::audit::AuditLog;
+use crate::cdc::CdcOut;
+use crate::clickhouse::EventsSink;
+use crate::events::EventStream;
+use crate::input::{Dialect, FastPath, Input, ReadOptions, Source};
+use crate::kafka::KafkaSink;
+use crate::merge::Records;
+use crate::metadata::RunMetadata;
+use crate::outbox::Outbox;
+use crate::output::{ColorChoice, Decimals, FinalizedRows, Locale, OutputFormat};
+use crate::overlap::Overlaps;
+use crate::parquet::EventsOut;
+use crate::postgres::PostgresSink;
+use crate::profile::{Phase, Profiler};
+use crate::rates::RatesSource;
+use crate::rejects::{AmountViolation, ErrorsFormat, Rejection, RejectsWriter};
+use crate::report::{CashFlow, ChargebackRatios, Hierarchy, Period, SchemeThreshold, TagTotals};
+use crate::review::ReviewQueue;
+use crate::risk::{RiskReview, RiskRules};
+use crate::sample::Sample;
+use crate::settlement::{Cutoff, ExportLayout, Settlement, SettlementExport};
+use crate::suspicious::SuspiciousActivity;
 use anyhow::{anyhow, Result};
-use csv::{StringRecord,ReaderBuilder, Trim};
-use std::collections::HashMap;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use ledger::{
+    apply_transaction, AccountState, Amount, Applied, Config, Currency, FeeRule, Ledger,
+    LedgerBuilder, Timestamp, TransactionEntry, TransactionType, Zone, MAX_EXPONENT,
+};
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
-
-// Record used to deserialize the csv. We map field names to avoid clash with "type" keyword and
-// also to assign something nicer.
-#[derive(Debug, serde::Deserialize)]
-struct TransactionEntry {
-    #[serde(rename = "type")]
-    t: String,
-    #[serde(rename = "client")]
-    client_id: u16,
-    #[serde(rename = "tx")]
-    uid: u32,
-    amount: f32,
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process::ExitCode;
+
+mod anomalies;
+mod assert_state;
+mod audit;
+mod audit_stats;
+mod cdc;
+mod checkpoint;
+mod clickhouse;
+mod compare;
+mod daemon;
+mod encryption;
+mod erasure;
+mod events;
+mod fixture;
+mod input;
+mod kafka;
+mod legal_hold;
+mod memory;
+mod merge;
+mod metadata;
+mod migration;
+mod outbox;
+mod output;
+mod overlap;
+mod parquet;
+mod postgres;
+mod profile;
+mod prune;
+mod rates;
+mod rejects;
+mod replay;
+mod report;
+mod review;
+mod risk;
+mod rollback;
+mod sample;
+mod settlement;
+mod stress;
+mod suspicious;
+mod template;
+mod trial_balance;
+mod whatif;
+mod xlsx;
+
+#[cfg(feature = "memory-accounting")]
+#[global_allocator]
+static ALLOCATOR: memory::Counting = memory::Counting;
+
+// Fields are matched by the names in the header, so that optional columns (currency, ...) may be
+// left out or appear in any order.
+fn deserialize_transaction_entry(
+    record: &StringRecord,
+    headers: &StringRecord,
+) -> Result<TransactionEntry, csv::Error> {
+    let te: TransactionEntry = record.deserialize(Some(headers))?;
+    Ok(te)
 }
 
-// This is operation state. We can be in RegularDeposit (after deposit or resolved dispute,
-// DisputedDeposit after dispute, FinalDeposit after chargeback or Afterwithdrawal after
-// a withdrawal).
-#[derive(Clone,Copy,Debug)]
-enum OperationState {
-    RegularDeposit { amount: f32 }, // After Deposit or after Deposit -> Dispute -> Resolve
-    DisputedDeposit { amount: f32 }, // After Deposit -> Dispute
-    FinalDeposit,   // After Deposit -> Chargeback
-    AfterWithdrawal, // After Withdrawal
+// Parses a single record of the input and applies it to the ledger, reporting progress according
+// to the verbosity. Returns the line of the record along with what was applied. Bonuses and
+// authorizations which expired by the time of the record are reversed (released) first, and the
+// reversals added to expired.
+fn process_record(
+    record: Result<StringRecord, Box<Rejection>>,
+    headers: &StringRecord,
+    fast: Option<&FastPath>,
+    l: &mut Ledger,
+    options: &Options,
+    expired: &mut Vec<(u64, Applied)>,
+) -> Result<(u64, Applied), Box<Rejection>> {
+    let record = record?;
+    let line = record.position().map_or(0, |p| p.line());
+    let field = |name| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .and_then(|i| record.get(i))
+    };
+    let memo = field("memo");
+    // A missing amount is left to the ledger, which knows which transactions need one.
+    let violation = field("amount")
+        .map(str::trim)
+        .filter(|amount| options.strict_amounts && !amount.is_empty())
+        .and_then(AmountViolation::of);
+    if let Some(violation) = violation {
+        return Err(Box::new(
+            Rejection::invalid_amount(&record, violation).with_memo(memo),
+        ));
+    }
+    let mut entry = fast
+        .and_then(|f| f.entry(&record))
+        .map_or_else(|| deserialize_transaction_entry(&record, headers), Ok)
+        .map_err(|e| Box::new(Rejection::parse_error(Some(&record), &e).with_memo(memo)))?;
+    // The timestamps without an offset or zone were read in UTC.
+    if options.assume_tz != Zone::UTC && entry.timestamp.is_some() {
+        match field("timestamp").map(|t| Timestamp::parse_in(t.trim(), options.assume_tz)) {
+            Some(Ok(t)) => entry.timestamp = Some(t),
+            Some(Err(e)) => {
+                return Err(Box::new(
+                    Rejection::invalid_timestamp(&record, e).with_memo(memo),
+                ))
+            }
+            None => {}
+        }
+    }
+    if options.verbosity >= Verbosity::Debug {
+        eprintln!("Line {}: parsed {}", line, entry_fields(&entry));
+    }
+    let expiries = entry.timestamp.map(|t| {
+        let mut reversals = l.expire_bonuses(t);
+        reversals.extend(l.expire_authorizations(t));
+        reversals
+    });
+    for reversal in expiries.unwrap_or_default() {
+        if options.verbosity >= Verbosity::Verbose {
+            eprintln!(
+                "Line {}: {} tx {} for client {} expired, {} {}",
+                line,
+                reversal.kind.as_str(),
+                reversal.tx,
+                reversal.client_id,
+                reversal.amount.unwrap_or_default(),
+                if reversal.kind == TransactionType::Bonus {
+                    "reversed"
+                } else {
+                    "released"
+                }
+            );
+        }
+        expired.push((line, reversal));
+    }
+    let (client_id, uid) = (entry.client_id, entry.uid);
+    let applied = apply_transaction(entry, l)
+        .map_err(|e| Box::new(Rejection::new(&record, uid, client_id, e).with_memo(memo)))?;
+    if options.verbosity >= Verbosity::Verbose {
+        eprintln!(
+            "Line {}: applied {} tx {} for client {}",
+            line,
+            applied.kind.as_str(),
+            uid,
+            client_id
+        );
+        if let Some(note) = &applied.note {
+            eprintln!("Line {}: {}", line, note);
+        }
+    }
+    if options.verbosity >= Verbosity::Debug {
+        if let Some(account) = l.account(client_id) {
+            eprintln!(
+                "Line {}: account of client {} now {}",
+                line,
+                client_id,
+                state_fields(account.state())
+            );
+        }
+    }
+    Ok((line, applied))
 }
 
-// This is AccountState - the account can either be open (for normal operation) or locked (after a
-// chargeback).
-#[derive(Debug)]
-enum AccountState {
-    Open { available: f32, held: f32 }, // Normal operation
-    Locked { available: f32, held: f32 }, // Chargeback happened, corresponding operation is in
-                                        // FinalDeposit OperationState
+// Fields of the entry as name=value pairs for the debug output, in the order of the input columns
+// and without the optional columns the entry has no value for. Text is quoted like JSON strings.
+fn entry_fields(entry: &TransactionEntry) -> String {
+    let text = |s: &String| serde_json::to_string(s).unwrap_or_default();
+    let mut fields = vec![
+        format!("type={}", text(&entry.t)),
+        format!("client={}", entry.client_id),
+        format!("tx={}", entry.uid),
+    ];
+    let optional = [
+        ("amount", entry.amount.map(|v| v.to_string())),
+        ("currency", entry.currency.map(|c| c.to_string())),
+        ("to_currency", entry.to_currency.map(|c| c.to_string())),
+        ("timestamp", entry.timestamp.map(|t| t.to_string())),
+        ("subaccount", entry.subaccount.as_ref().map(text)),
+        ("tags", entry.tags.as_ref().map(text)),
+        ("memo", entry.memo.as_ref().map(text)),
+        ("idempotency_key", entry.idempotency_key.as_ref().map(text)),
+        ("reason", entry.reason.as_ref().map(text)),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            fields.push(format!("{}={}", name, value));
+        }
+    }
+    fields.join(" ")
 }
 
-// AccountOperation - reflecting the original operation.
-#[derive(Debug)]
-enum AccountOperation {
-    Deposit { amount: f32 },
-    Withdrawal { amount: f32 },
-    Dispute,
-    Resolve,
-    Chargeback,
+// Balances of the account state as name=value pairs for the debug output.
+fn state_fields(state: &AccountState) -> String {
+    let (available, held, locked) = match *state {
+        AccountState::Open { available, held } => (available, held, false),
+        AccountState::Locked { available, held } => (available, held, true),
+    };
+    format!("available={} held={} locked={}", available, held, locked)
+}
+
+// Counters collected while reading the input, reported in the end-of-run summary.
+#[derive(Debug, Default, serde::Serialize)]
+struct Summary {
+    records: u64,                            // Number of records read from the input
+    applied: u64,                            // Number of records successfully applied to the ledger
+    rejected: u64,                           // Number of records which failed to parse or to apply
+    rejections: BTreeMap<&'static str, u64>, // Rejected records, by reason code
+    #[serde(skip_serializing_if = "is_zero")]
+    fees: Amount, // Fees charged by the applied records
+    #[serde(skip_serializing_if = "is_zero")]
+    skipped: u64, // Records already in the base snapshot or checkpoint, see --since-tx
+    #[serde(skip_serializing_if = "is_zero")]
+    acknowledged: u64, // Retries of applied records, by idempotency key
+    #[serde(skip_serializing_if = "is_zero")]
+    queued: u64, // Chargebacks queued for review, see --review-chargebacks
+    #[serde(skip_serializing_if = "is_zero")]
+    unsampled: u64, // Records of clients out of the --sample
+    #[serde(skip_serializing_if = "is_zero")]
+    pruned: u64, // Oplog entries dropped by the retention, see Ledger::prune
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dialects: Vec<SniffedDialect>, // Dialects detected with --sniff-dialect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory: Option<memory::MemoryStats>, // With --mem-stats
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SniffedDialect {
+    input: String,
+    #[serde(flatten)]
+    dialect: Dialect,
+}
+
+fn is_zero<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
 }
 
-// Account, including its state.
+impl Summary {
+    fn reject(&mut self, rejection: &Rejection) {
+        self.rejected += 1;
+        *self.rejections.entry(rejection.reason).or_default() += 1;
+    }
+}
+
+// How much is reported on stderr while processing. Quiet only prints the end-of-run summary,
+// Normal adds a message for every rejected record, Verbose reports every applied record as well and
+// Debug additionally lists the fields of the parsed entries and the resulting balances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+// What the run produces: the balances of the accounts (by default), with `ledger report` one of
+// the reports, with `ledger trial-balance` the trial balance of the books, or with
+// `ledger audit-stats` the statistics used to pick samples for manual review and the legal holds.
 #[derive(Debug)]
-struct Account {
-    state: AccountState,
-    oplog: HashMap<u32, OperationState>, // This is a map of transaction id -> OperationState
+enum Command {
+    Balances,
+    Report(Report),
+    TrialBalance,
+    AuditStats,
+    AssertState(String), // Expected balances file
 }
 
-// Ledger - the map of all accounts, by their respective client_id.
+// Names of the subcommands, given before the options.
+const SUBCOMMANDS: [&str; 4] = ["report", "trial-balance", "audit-stats", "assert-state"];
+
+// Records between two applications of the retention (--retention-transactions, --retention-days)
+// during a run, and at its end.
+const PRUNE_EVERY: u64 = 65_536;
+
 #[derive(Debug)]
-struct Ledger {
-    accounts: HashMap<u16, Account>, // This is a map of client_id -> Account
+enum Report {
+    Hierarchy(Hierarchy), // Balances rolled up the account hierarchy (--hierarchy)
+    Tags,                 // Transactions and amounts per tag (--tags)
+    CashFlow(Period),     // Movements per period and client (--cash-flow)
+    Disputes,             // Lifecycle of every dispute (--disputes)
+    // Chargeback ratios per period and client (--chargeback-ratio), against the scheme thresholds
+    // (--chargeback-thresholds).
+    ChargebackRatio(Period, Vec<SchemeThreshold>),
 }
 
-// The result of applying an operation on an account.
+// Command line options.
 #[derive(Debug)]
-enum AccountOperationResult {
-    AppendOperation {
-        state: AccountState,
-        op: OperationState,
-    },
-    ModifyOperation {
-        state: AccountState,
-        op: OperationState,
-    },
+struct Options {
+    command: Command,
+    transactions_filenames: Vec<String>, // Processed one after the other
+    output_format: OutputFormat,
+    color: ColorChoice,
+    csv_metadata: bool, // Prefix the CSV output with the run metadata as comment lines
+    decimals: Decimals, // Of the amounts in the outputs and sinks
+    locale: Option<Locale>, // Number formatting of the table and xlsx outputs
+    verbosity: Verbosity,
+    errors_format: ErrorsFormat,
+    rejects_filename: Option<String>, // CSV file receiving every rejected record
+    review_filename: Option<String>, // CSV file receiving the chargebacks, instead of applying them
+    audit_filename: Option<String>,  // NDJSON file receiving every applied transaction
+    anomalies_filename: Option<String>, // CSV file receiving the anomalies found
+    review_queue: Option<String>,    // CSV file receiving the operations matching the risk rules
+    // Template and output of the suspicious-activity export of the operations matching the risk
+    // rules.
+    suspicious_activity: Option<(String, String)>,
+    risk_rules: RiskRules,
+    overlap_filename: Option<String>, // CSV file receiving the ids found in several input files
+    sink: Option<PostgresSink>,       // Database receiving the final account states (and rejects)
+    events_sink: Option<String>,      // ClickHouse HTTP interface receiving the applied operations
+    events_table: String,
+    kafka_sink: Option<String>, // Kafka topic receiving the balance changes, exactly once
+    kafka_transactional_id: String,
+    events_out: Option<String>, // Parquet directory or NDJSON stream of the applied operations
+    cdc_out: Option<String>,    // CSV file receiving the changes of the account balances
+    parse_threads: usize,       // Threads parsing the input, in chunks when more than one
+    output_threads: usize,      // Threads formatting the CSV output and encoding Parquet files
+    fast_parse: bool,           // Deserialize well-formed records without serde
+    strict_amounts: bool,       // Reject amounts which aren't plain decimals
+    require_headers: bool,      // Refuse inputs whose header row isn't the expected one
+    sniff_dialect: bool,        // Detect the delimiter, quote and header row of the inputs
+    merge_by_timestamp: bool,   // Interleave the records of the inputs in timestamp order
+    assume_tz: Zone,            // Zone of the timestamps without an offset or zone
+    read: ReadOptions,
+    max_memory: Option<usize>, // Heap the run may use, in bytes, before it is aborted
+    profile: Option<String>,   // Flame graph (.svg) or pprof (.pb) file profiling the run
+    mem_stats: bool,           // Report the memory used, overall and by phase, in the summary
+    stream_finalized: bool,    // Write the rows of the accounts as soon as they are final
+    base_snapshot: Option<String>, // Snapshot of the ledger the input is applied on
+    since_tx: Option<u32>,     // Transactions up to this id are in the base snapshot already
+    sample: Option<Sample>,    // Clients whose records are processed, the others are left out
+    snapshot_out: Option<String>, // File receiving a snapshot of the ledger at the end of the run
+    checkpoint_every: Option<u64>, // Records between two checkpoints
+    checkpoint_dir: Option<String>, // Directory receiving the checkpoints
+    resume: bool,              // Carry on from the checkpoint of the directory, if any
+    rates: Option<RatesSource>, // Exchange rates used by conversions and reporting
+    charge_interest: bool,     // Charge the interest of the credit lines at the end of the run
+    settlement_filename: Option<String>, // CSV file receiving the settlement batches
+    cutoffs: Vec<Cutoff>,      // Cut-offs closing the settlement batches
+    settlement_out: Option<String>, // CSV file receiving the settlement export
+    settlement_layout: ExportLayout,
+    config: Config, // From the --config file, overridden by the individual options
 }
 
-fn process_operation(
-    op: AccountOperation,
-    op_to_modify: Option<OperationState>,
-    a: &mut Account,
-) -> Result<AccountOperationResult> {
-    // Main state machine. Takes an AccountOperation (representing a current operation), an Option
-    // of OperationState, which will be the operation to modify for modifying operations or None
-    // for AppendOperations and a mutable account and results in the mutation on the account.
-    // Returns AccountOperationResult. It mutates the state of the account, but does not change
-    // the oplog. Oplog is then modified in the subsequent function.
-    match (&a.state, op_to_modify, op) {
-        (Locked { .. }, _, _) => Err(anyhow! {"The account is locked! Skipping transaction"}),
-        (Open { available, held }, None, Deposit { amount }) => Ok(AppendOperation {
-            op: RegularDeposit { amount: amount },
-            state: Open {
-                available: *available + amount,
-                held: *held,
-            },
+impl Options {
+    // Name of the input of the run, as recorded in checkpoints.
+    fn input_name(&self) -> String {
+        self.transactions_filenames.join(" ")
+    }
+
+    // Canonical description of the options which influence the produced balances, hashed into
+    // the run metadata so that consumers can tell runs with different configurations apart. How
+    // the outputs present them (format, decimal places, locale, ...) is left out: the same run
+    // written as CSV and as JSON has the same fingerprint.
+    fn config_fingerprint(&self) -> String {
+        let sample = match self.sample {
+            Some(s) => format!("sample={}/{}\n", s.fraction, s.seed),
+            None => String::new(),
+        };
+        format!(
+            "{}{}",
+            sample,
+            toml::to_string(&self.config).unwrap_or_default()
+        )
+    }
+}
+
+// Records of the input in the base snapshot already, with --since-tx: the snapshot was taken right
+// after transaction since_tx, so it has the transactions up to that id. Disputes, resolves and
+// chargebacks refer to earlier transactions by id, so they are in it when they come before that
+// transaction (or the first one with a larger id) in the input.
+struct BaseSnapshot {
+    since_tx: u32,
+    passed: bool, // The input is past the transactions of the snapshot
+}
+
+impl BaseSnapshot {
+    fn new(since_tx: u32) -> BaseSnapshot {
+        BaseSnapshot {
+            since_tx,
+            passed: false,
+        }
+    }
+
+    // Whether the record, the next one of the input, is in the snapshot.
+    fn has(&mut self, record: &StringRecord, headers: &StringRecord) -> bool {
+        let field = |name| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .and_then(|i| record.get(i))
+        };
+        let refers = matches!(
+            field("type").map(str::parse),
+            Some(Ok(TransactionType::Dispute
+                | TransactionType::Representment
+                | TransactionType::PreArbitration
+                | TransactionType::Arbitration
+                | TransactionType::ChargebackReversal
+                | TransactionType::Resolve
+                | TransactionType::Chargeback))
+        );
+        if refers {
+            return !self.passed;
+        }
+        let tx = field("tx").and_then(|tx| tx.parse::<u32>().ok());
+        self.passed |= tx.is_some_and(|tx| tx >= self.since_tx);
+        tx.is_some_and(|tx| tx <= self.since_tx)
+    }
+}
+
+// Input file of the run, ready to be read record by record.
+struct InputFile {
+    input: Input,
+    headers: StringRecord,
+    fast: Option<FastPath>, // With --fast-parse, unless the file has other columns
+}
+
+impl InputFile {
+    // Opens the file, and reads its headers. Errors say what failed ("while reading headers: ...").
+    fn open(path: &str, options: &Options) -> Result<InputFile> {
+        let file =
+            Source::open(path, options.read).map_err(|e| anyhow! {"while opening input: {}", e})?;
+        let mut input = Input::new(file, options.parse_threads, options.sniff_dialect)
+            .map_err(|e| anyhow! {"while reading input: {}", e})?;
+        let headers = input
+            .headers()
+            .map_err(|e| anyhow! {"while reading headers: {}", e})?;
+        if options.require_headers {
+            input::check_headers(&headers)
+                .map_err(|e| anyhow! {"while checking headers of {}: {}", path, e})?;
+        }
+        let fast = options
+            .fast_parse
+            .then(|| FastPath::new(&headers))
+            .flatten();
+        if options.fast_parse && fast.is_none() && options.verbosity >= Verbosity::Verbose {
+            eprintln!(
+                "Fast parsing disabled for {}: other columns than type, client, tx and amount",
+                path
+            );
+        }
+        Ok(InputFile {
+            input,
+            headers,
+            fast,
+        })
+    }
+}
+
+// Ledger snapshot, in JSON (see src/snapshot.rs), decrypted if it is encrypted (see
+// src/encryption.rs).
+fn load_snapshot(path: &str) -> Result<Ledger> {
+    let contents =
+        encryption::read(path).map_err(|e| anyhow! {"cannot read snapshot {}: {}", path, e})?;
+    serde_json::from_slice(&contents).map_err(|e| anyhow! {"invalid snapshot {}: {}", path, e})
+}
+
+// Ledger snapshot given with --base-snapshot. The snapshot carries the configuration it was taken
+// with, which has to match the one of the run so that the delta is applied under the same rules
+// as the history.
+fn read_snapshot(path: &str, config: &Config) -> Result<Ledger> {
+    let l = load_snapshot(path)?;
+    if l.config() != config {
+        return Err(anyhow! {"snapshot {} was taken with a different configuration", path});
+    }
+    Ok(l)
+}
+
+// Writes a snapshot of the ledger, encrypted if there is an encryption key.
+fn write_snapshot(path: &str, l: &Ledger) -> Result<()> {
+    encryption::write(path, serde_json::to_vec(l)?, false)
+}
+
+fn read_config(path: &str) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow! {"cannot read config file {}: {}", path, e})?;
+    toml::from_str(&contents).map_err(|e| anyhow! {"invalid config file {}: {}", path, e})
+}
+
+// Fee schedule file given with --fees: either a CSV file (with a .csv extension) with the
+// type, tier, min-amount, max-amount, fixed and percent columns, or a TOML file with a [[fees]]
+// table per rule, like in the config file.
+fn read_fees(path: &str) -> Result<Vec<FeeRule>> {
+    let invalid = |e: &dyn std::fmt::Display| anyhow! {"invalid fee schedule {}: {}", path, e};
+    if path.ends_with(".csv") {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_path(path)
+            .map_err(|e| anyhow! {"cannot read fee schedule {}: {}", path, e})?;
+        return rdr
+            .deserialize()
+            .map(|r| r.map_err(|e| invalid(&e)))
+            .collect();
+    }
+    #[derive(serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct FeeSchedule {
+        fees: Vec<FeeRule>,
+    }
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow! {"cannot read fee schedule {}: {}", path, e})?;
+    let schedule: FeeSchedule = toml::from_str(&contents).map_err(|e| invalid(&e))?;
+    Ok(schedule.fees)
+}
+
+// Returns the value following an option which requires one.
+fn option_value<'a>(it: &mut impl Iterator<Item = &'a String>, name: &str) -> Result<&'a String> {
+    it.next()
+        .ok_or_else(|| anyhow! {"{} requires a value", name})
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+    let mut transactions_filenames = Vec::new();
+    let mut output_format = OutputFormat::Csv;
+    let mut color = ColorChoice::Auto;
+    let mut csv_metadata = false;
+    let mut decimals = Decimals::default();
+    let mut locale = None;
+    let mut verbosity = Verbosity::Normal;
+    let mut errors_format = ErrorsFormat::Text;
+    let mut rejects_filename = None;
+    let mut review_filename = None;
+    let mut anomalies_filename = None;
+    let mut review_queue = None;
+    let (mut suspicious_template, mut suspicious_out) = (None, None);
+    let mut risk_rules = RiskRules::default();
+    let mut custom_risk_rules = false;
+    let mut overlap_filename = None;
+    let mut sink = None;
+    let mut sink_accounts_table = None;
+    let mut sink_rejects_table = None;
+    let mut sink_outbox_table = None;
+    let mut events_sink = None;
+    let mut events_table = None;
+    let mut kafka_sink = None;
+    let mut kafka_transactional_id = None;
+    let mut events_out = None;
+    let mut cdc_out = None;
+    let mut parse_threads = 1;
+    let mut output_threads = 1;
+    let mut fast_parse = false;
+    let mut strict_amounts = false;
+    let mut require_headers = false;
+    let mut sniff_dialect = false;
+    let mut merge_by_timestamp = false;
+    let mut assume_tz = Zone::UTC;
+    let mut read = ReadOptions::default();
+    let mut max_memory = None;
+    let mut profile = None;
+    let mut mem_stats = false;
+    let mut stream_finalized = false;
+    let mut base_snapshot = None;
+    let mut since_tx = None;
+    let mut sample = None;
+    let mut seed = None;
+    let mut snapshot_out = None;
+    let mut checkpoint_every = None;
+    let mut checkpoint_dir = None;
+    let mut resume = false;
+    let mut audit_filename = None;
+    let mut rates = None;
+    let mut reporting_currency = None;
+    let mut charge_interest = false;
+    let mut withdrawal_ids_only = false;
+    let mut chargeback_reversal_unlocks = false;
+    let mut allow_admin_ops = false;
+    let mut duplicate_filter = None;
+    let mut undo_depth = None;
+    let mut settlement_filename = None;
+    let mut cutoffs = Vec::new();
+    let mut settlement_out = None;
+    let mut settlement_layout = None;
+    let mut config_filename = None;
+    let mut precision = None;
+    let mut allow_overdraft = None;
+    let mut locked_policy = None;
+    let mut max_disputes = None;
+    let mut withdrawn_dispute_policy = None;
+    let mut max_amount = None;
+    let mut base_currency = None;
+    let mut fees = Vec::new();
+    let mut bonus_expiry_days = None;
+    let mut authorization_expiry_days = None;
+    let mut retention_transactions = None;
+    let mut retention_days = None;
+    let mut hierarchy = None;
+    let mut tags = false;
+    let mut cash_flow = None;
+    let mut disputes = false;
+    let (mut chargeback_ratio, mut thresholds) = (None, None);
+    let mut it = args.iter().skip(1).peekable();
+    let subcommand = it
+        .next_if(|arg| SUBCOMMANDS.contains(&arg.as_str()))
+        .map(String::as_str);
+    let report = subcommand == Some("report");
+    let expected_balances = match subcommand {
+        Some("assert-state") => Some(
+            it.next_if(|arg| !arg.starts_with("--"))
+                .ok_or_else(|| anyhow! {"assert-state requires the expected balances file"})?
+                .clone(),
+        ),
+        _ => None,
+    };
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--output-format" => output_format = option_value(&mut it, arg)?.parse()?,
+            "--color" => color = option_value(&mut it, arg)?.parse()?,
+            "--csv-metadata" => csv_metadata = true,
+            "--decimals" => decimals.set(option_value(&mut it, arg)?)?,
+            "--locale" => locale = Some(option_value(&mut it, arg)?.parse()?),
+            "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => verbosity = verbosity.max(Verbosity::Normal).increased(),
+            "-vv" => verbosity = Verbosity::Debug,
+            "--errors-format" => errors_format = option_value(&mut it, arg)?.parse()?,
+            "--rejects" => rejects_filename = Some(option_value(&mut it, arg)?.clone()),
+            "--review-chargebacks" => review_filename = Some(option_value(&mut it, arg)?.clone()),
+            "--audit-log" => audit_filename = Some(option_value(&mut it, arg)?.clone()),
+            "--anomalies" => anomalies_filename = Some(option_value(&mut it, arg)?.clone()),
+            "--review-queue" => review_queue = Some(option_value(&mut it, arg)?.clone()),
+            "--suspicious-activity" => {
+                suspicious_template = Some(option_value(&mut it, arg)?.clone())
+            }
+            "--suspicious-activity-out" => {
+                suspicious_out = Some(option_value(&mut it, arg)?.clone())
+            }
+            "--risk-amount" => {
+                risk_rules.amount_threshold = option_value(&mut it, arg)?.parse()?;
+                custom_risk_rules = true;
+            }
+            "--risk-velocity" => {
+                risk_rules.velocity = option_value(&mut it, arg)?.parse()?;
+                custom_risk_rules = true;
+            }
+            "--risk-disputes" => {
+                risk_rules.repeated_disputes = option_value(&mut it, arg)?.parse()?;
+                custom_risk_rules = true;
+            }
+            "--overlap-report" => overlap_filename = Some(option_value(&mut it, arg)?.clone()),
+            "--sink" => sink = Some(PostgresSink::parse(option_value(&mut it, arg)?)?),
+            "--sink-accounts-table" => {
+                sink_accounts_table = Some(postgres::check_table(option_value(&mut it, arg)?)?)
+            }
+            "--events-sink" => events_sink = Some(option_value(&mut it, arg)?.clone()),
+            "--events-out" => events_out = Some(option_value(&mut it, arg)?.clone()),
+            "--cdc-out" => cdc_out = Some(option_value(&mut it, arg)?.clone()),
+            "--parse-threads" => parse_threads = option_value(&mut it, arg)?.parse()?,
+            "--output-threads" => output_threads = option_value(&mut it, arg)?.parse()?,
+            "--fast-parse" => fast_parse = true,
+            "--strict-amounts" => strict_amounts = true,
+            "--require-headers" => require_headers = true,
+            "--sniff-dialect" => sniff_dialect = true,
+            "--merge-by-timestamp" => merge_by_timestamp = true,
+            "--assume-tz" => {
+                assume_tz = option_value(&mut it, arg)?
+                    .parse()
+                    .map_err(|e| anyhow! {"invalid --assume-tz: {}", e})?
+            }
+            "--read-ahead" => read.read_ahead = Some(option_value(&mut it, arg)?.parse()?),
+            "--direct-io" => read.direct_io = true,
+            "--base-snapshot" => base_snapshot = Some(option_value(&mut it, arg)?.clone()),
+            "--since-tx" => since_tx = Some(option_value(&mut it, arg)?.parse()?),
+            "--sample" => {
+                let value = option_value(&mut it, arg)?;
+                let fraction: f64 = value.parse()?;
+                if !(fraction > 0.0 && fraction <= 1.0) {
+                    return Err(
+                        anyhow! {"invalid sample {} (expected a fraction in (0, 1])", value},
+                    );
+                }
+                sample = Some(fraction);
+            }
+            "--seed" => seed = Some(option_value(&mut it, arg)?.parse()?),
+            "--snapshot-out" => snapshot_out = Some(option_value(&mut it, arg)?.clone()),
+            "--checkpoint-every" => {
+                let every: u64 = option_value(&mut it, arg)?.parse()?;
+                if every == 0 {
+                    return Err(anyhow! {"--checkpoint-every must be at least 1"});
+                }
+                checkpoint_every = Some(every);
+            }
+            "--checkpoint-dir" => checkpoint_dir = Some(option_value(&mut it, arg)?.clone()),
+            "--resume-from-checkpoint" => resume = true,
+            "--max-memory" | "--mem-stats" if !memory::COUNTED => {
+                return Err(anyhow! {"{} requires the memory-accounting feature", arg});
+            }
+            "--max-memory" => {
+                let value = option_value(&mut it, arg)?;
+                max_memory = Some(
+                    memory::parse_bytes(value)
+                        .ok_or_else(|| anyhow! {"invalid --max-memory {}", value})?,
+                );
+            }
+            "--mem-stats" => mem_stats = true,
+            "--stream-finalized" => stream_finalized = true,
+            "--profile" => {
+                let value = option_value(&mut it, arg)?;
+                profile::Format::of(value)?;
+                profile = Some(value.clone());
+            }
+            "--kafka-sink" => kafka_sink = Some(option_value(&mut it, arg)?.clone()),
+            "--kafka-transactional-id" => {
+                kafka_transactional_id = Some(option_value(&mut it, arg)?.clone())
+            }
+            "--events-table" => {
+                events_table = Some(postgres::check_table(option_value(&mut it, arg)?)?)
+            }
+            "--sink-rejects-table" => {
+                sink_rejects_table = Some(postgres::check_table(option_value(&mut it, arg)?)?)
+            }
+            "--sink-outbox-table" => {
+                sink_outbox_table = Some(postgres::check_table(option_value(&mut it, arg)?)?)
+            }
+            "--rates" | "--fixed-rate" | "--rates-url" if rates.is_some() => {
+                return Err(
+                    anyhow! {"only one of --rates, --fixed-rate and --rates-url may be given"},
+                )
+            }
+            "--rates" => rates = Some(RatesSource::File(option_value(&mut it, arg)?.clone())),
+            "--fixed-rate" => {
+                rates = Some(RatesSource::Fixed(rates::parse_fixed_rate(option_value(
+                    &mut it, arg,
+                )?)?))
+            }
+            "--rates-url" => rates = Some(RatesSource::Http(option_value(&mut it, arg)?.clone())),
+            "--reporting-currency" => {
+                reporting_currency = Some(
+                    option_value(&mut it, arg)?
+                        .parse::<Currency>()
+                        .map_err(|e| anyhow!(e))?,
+                )
+            }
+            "--base-currency" => {
+                base_currency = Some(
+                    option_value(&mut it, arg)?
+                        .parse::<Currency>()
+                        .map_err(|e| anyhow!(e))?,
+                )
+            }
+            "--settlement-batches" => {
+                settlement_filename = Some(option_value(&mut it, arg)?.clone())
+            }
+            "--settlement-out" => settlement_out = Some(option_value(&mut it, arg)?.clone()),
+            "--settlement-layout" => {
+                settlement_layout = Some(settlement::read_layout(option_value(&mut it, arg)?)?)
+            }
+            "--cutoff" => cutoffs.push(option_value(&mut it, arg)?.parse()?),
+            "--charge-interest" => charge_interest = true,
+            "--withdrawal-ids-only" => withdrawal_ids_only = true,
+            "--chargeback-reversal-unlocks" => chargeback_reversal_unlocks = true,
+            "--allow-admin-ops" => allow_admin_ops = true,
+            "--duplicate-filter" => duplicate_filter = Some(option_value(&mut it, arg)?.parse()?),
+            "--undo-depth" => undo_depth = Some(option_value(&mut it, arg)?.parse()?),
+            "--config" => config_filename = Some(option_value(&mut it, arg)?.clone()),
+            "--precision" => precision = Some(option_value(&mut it, arg)?.parse()?),
+            "--allow-overdraft" => allow_overdraft = Some(option_value(&mut it, arg)?.parse()?),
+            "--locked-policy" => locked_policy = Some(option_value(&mut it, arg)?.parse()?),
+            "--max-disputes" => max_disputes = Some(option_value(&mut it, arg)?.parse()?),
+            "--bonus-expiry-days" => bonus_expiry_days = Some(option_value(&mut it, arg)?.parse()?),
+            "--authorization-expiry-days" => {
+                authorization_expiry_days = Some(option_value(&mut it, arg)?.parse()?)
+            }
+            "--retention-transactions" => {
+                retention_transactions = Some(option_value(&mut it, arg)?.parse()?)
+            }
+            "--retention-days" => retention_days = Some(option_value(&mut it, arg)?.parse()?),
+            "--hierarchy" => hierarchy = Some(report::read_hierarchy(option_value(&mut it, arg)?)?),
+            "--tags" => tags = true,
+            "--cash-flow" => cash_flow = Some(option_value(&mut it, arg)?.parse()?),
+            "--disputes" => disputes = true,
+            "--chargeback-ratio" => chargeback_ratio = Some(option_value(&mut it, arg)?.parse()?),
+            "--chargeback-thresholds" => {
+                thresholds = Some(report::read_thresholds(option_value(&mut it, arg)?)?)
+            }
+            "--fees" => fees.extend(read_fees(option_value(&mut it, arg)?)?),
+            "--max-amount" => max_amount = Some(option_value(&mut it, arg)?.parse()?),
+            "--withdrawn-dispute-policy" => {
+                withdrawn_dispute_policy = Some(option_value(&mut it, arg)?.parse()?)
+            }
+            _ if arg.starts_with("--") => return Err(anyhow! {"unknown option {}", arg}),
+            _ => transactions_filenames.push(arg.clone()),
+        }
+    }
+    if thresholds.is_some() && chargeback_ratio.is_none() {
+        return Err(anyhow! {"--chargeback-thresholds requires --chargeback-ratio"});
+    }
+    let reports = [
+        hierarchy.is_some(),
+        tags,
+        cash_flow.is_some(),
+        disputes,
+        chargeback_ratio.is_some(),
+    ];
+    let command = match (report, reports.iter().filter(|r| **r).count()) {
+        (true, 1) => Command::Report(match (hierarchy, cash_flow, chargeback_ratio) {
+            (Some(hierarchy), _, _) => Report::Hierarchy(hierarchy),
+            (_, Some(period), _) => Report::CashFlow(period),
+            (_, _, Some(period)) => Report::ChargebackRatio(period, thresholds.unwrap_or_default()),
+            _ if disputes => Report::Disputes,
+            _ => Report::Tags,
         }),
-        (Open { available, held }, None, Withdrawal { amount }) => {
-            if amount > *available {
-                Err(anyhow! {"Insufficient funds. Skipping withdrawal"})
-            } else {
-                Ok(AppendOperation {
-                    op: AfterWithdrawal,
-                    state: Open {
-                        available: *available - amount,
-                        held: *held,
-                    },
-                })
-            }
-        }
-        (Open { available, held }, Some(RegularDeposit { amount }), Dispute) => {
-            Ok(ModifyOperation {
-                op: DisputedDeposit { amount: amount },
-                state: Open {
-                    available: *available - amount,
-                    held: *held + amount,
-                },
+        (true, _) => {
+            return Err(anyhow! {
+                "report requires one of --hierarchy, --tags, --cash-flow, --disputes and --chargeback-ratio"
             })
         }
-        (Open { available, held }, Some(DisputedDeposit { amount }), Resolve) => {
-            Ok(ModifyOperation {
-                op: RegularDeposit { amount: amount },
-                state: Open {
-                    available: *available + amount,
-                    held: *held - amount,
-                },
-            })
+        (false, 0) => match subcommand {
+            Some("trial-balance") => Command::TrialBalance,
+            Some("audit-stats") => Command::AuditStats,
+            _ => match expected_balances {
+                Some(path) => Command::AssertState(path),
+                None => Command::Balances,
+            },
+        },
+        (false, _) => {
+            return Err(
+                anyhow! {"--hierarchy, --tags, --cash-flow, --disputes and --chargeback-ratio are only used by report"},
+            )
         }
-        (Open { available, held }, Some(DisputedDeposit { amount }), Chargeback) => {
-            Ok(ModifyOperation {
-                op: FinalDeposit,
-                state: Locked {
-                    available: *available,
-                    held: *held - amount,
-                },
-            })
+    };
+    if let Some(name) = subcommand {
+        if !matches!(output_format, OutputFormat::Csv | OutputFormat::Json) {
+            return Err(anyhow! {"{} only supports the csv and json output formats", name});
+        }
+    }
+    if let Some(sink) = sink.as_mut() {
+        if let Some(table) = sink_accounts_table {
+            sink.accounts_table = table;
+        }
+        sink.rejects_table = sink_rejects_table;
+        sink.outbox_table = sink_outbox_table;
+        sink.decimals = decimals.clone();
+    } else if sink_accounts_table.is_some()
+        || sink_rejects_table.is_some()
+        || sink_outbox_table.is_some()
+    {
+        return Err(
+            anyhow! {"--sink-accounts-table, --sink-rejects-table and --sink-outbox-table require --sink"},
+        );
+    }
+    if events_sink.is_none() && events_table.is_some() {
+        return Err(anyhow! {"--events-table requires --events-sink"});
+    }
+    if kafka_sink.is_none() && kafka_transactional_id.is_some() {
+        return Err(anyhow! {"--kafka-transactional-id requires --kafka-sink"});
+    }
+    // The transactions are committed at the checkpoints, which a failed run resumes from.
+    if kafka_sink.is_some() && (checkpoint_every.is_none() || checkpoint_dir.is_none()) {
+        return Err(anyhow! {"--kafka-sink requires --checkpoint-every and --checkpoint-dir"});
+    }
+    let suspicious_activity = match (suspicious_template, suspicious_out) {
+        (Some(template), Some(out)) => Some((template, out)),
+        (None, None) => None,
+        _ => {
+            return Err(anyhow! {"--suspicious-activity and --suspicious-activity-out go together"})
         }
-        _ => Err(anyhow! {"Illegal state transition. Skipping operation"}),
+    };
+    if custom_risk_rules && review_queue.is_none() && suspicious_activity.is_none() {
+        return Err(anyhow! {
+            "--risk-amount, --risk-velocity and --risk-disputes require --review-queue or --suspicious-activity"
+        });
+    }
+    if since_tx.is_some() && base_snapshot.is_none() {
+        return Err(anyhow! {"--since-tx requires --base-snapshot"});
+    }
+    if seed.is_some() && sample.is_none() {
+        return Err(anyhow! {"--seed requires --sample"});
+    }
+    if (checkpoint_every.is_some() || resume) && checkpoint_dir.is_none() {
+        return Err(
+            anyhow! {"--checkpoint-every and --resume-from-checkpoint require --checkpoint-dir"},
+        );
+    }
+    if resume && base_snapshot.is_some() {
+        return Err(
+            anyhow! {"--resume-from-checkpoint and --base-snapshot are mutually exclusive"},
+        );
+    }
+    // The decisions are applied to the snapshot of the run, see review::command.
+    if review_filename.is_some() && snapshot_out.is_none() {
+        return Err(anyhow! {"--review-chargebacks requires --snapshot-out"});
+    }
+    if precision.is_some_and(|p: u32| p > u32::from(MAX_EXPONENT)) {
+        return Err(anyhow! {"--precision is at most {}", MAX_EXPONENT});
+    }
+    if settlement_filename.is_some() && cutoffs.is_empty() {
+        return Err(anyhow! {"--settlement-batches requires at least one --cutoff"});
+    }
+    // The run metadata is only known at the end, and the interest changes the accounts then.
+    if stream_finalized
+        && (!matches!(command, Command::Balances)
+            || output_format != OutputFormat::Csv
+            || csv_metadata
+            || charge_interest)
+    {
+        return Err(
+            anyhow! {"--stream-finalized requires the csv output of the balances, without --csv-metadata and --charge-interest"},
+        );
+    }
+    let config = match config_filename {
+        Some(path) => read_config(&path)?,
+        None => Config::default(),
+    };
+    let mut builder = LedgerBuilder::from_config(config);
+    if let Some(p) = precision {
+        builder = builder.precision(p);
+    }
+    if let Some(limit) = allow_overdraft {
+        builder = builder.allow_overdraft(limit);
+    }
+    if let Some(policy) = locked_policy {
+        builder = builder.locked_policy(policy);
+    }
+    if let Some(max) = max_disputes {
+        builder = builder.max_disputes(max);
+    }
+    if let Some(currency) = base_currency {
+        builder = builder.base_currency(currency);
+    }
+    if let Some(currency) = reporting_currency {
+        builder = builder.reporting_currency(currency);
+    }
+    if let Some(limit) = max_amount {
+        builder = builder.max_amount(None, limit);
     }
+    if let Some(policy) = withdrawn_dispute_policy {
+        builder = builder.withdrawn_dispute_policy(policy);
+    }
+    if let Some(days) = bonus_expiry_days {
+        builder = builder.bonus_expiry_days(days);
+    }
+    if let Some(days) = authorization_expiry_days {
+        builder = builder.authorization_expiry_days(days);
+    }
+    if withdrawal_ids_only {
+        builder = builder.withdrawal_ids_only(true);
+    }
+    if chargeback_reversal_unlocks {
+        builder = builder.chargeback_reversal_unlocks(true);
+    }
+    if allow_admin_ops {
+        builder = builder.allow_admin_ops(true);
+    }
+    if let Some(capacity) = duplicate_filter {
+        builder = builder.duplicate_filter_capacity(capacity);
+    }
+    if let Some(depth) = undo_depth {
+        builder = builder.undo_depth(depth);
+    }
+    if let Some(transactions) = retention_transactions {
+        builder = builder.retention_transactions(transactions);
+    }
+    if let Some(days) = retention_days {
+        builder = builder.retention_days(days);
+    }
+    // Rules of the fee schedule files come after those of the config file.
+    for rule in fees {
+        builder = builder.fee(rule);
+    }
+    if transactions_filenames.is_empty() {
+        return Err(anyhow! {"should contain name of a transaction file"});
+    }
+    Ok(Options {
+        command,
+        transactions_filenames,
+        output_format,
+        color,
+        csv_metadata,
+        decimals,
+        locale,
+        verbosity,
+        errors_format,
+        rejects_filename,
+        review_filename,
+        audit_filename,
+        anomalies_filename,
+        review_queue,
+        suspicious_activity,
+        risk_rules,
+        overlap_filename,
+        sink,
+        events_sink,
+        events_table: events_table.unwrap_or_else(|| clickhouse::EVENTS_TABLE.to_string()),
+        kafka_sink,
+        kafka_transactional_id: kafka_transactional_id.unwrap_or_else(|| "ledger".to_string()),
+        events_out,
+        cdc_out,
+        parse_threads,
+        output_threads,
+        fast_parse,
+        strict_amounts,
+        require_headers,
+        sniff_dialect,
+        merge_by_timestamp,
+        assume_tz,
+        read,
+        max_memory,
+        profile,
+        mem_stats,
+        stream_finalized,
+        base_snapshot,
+        since_tx,
+        sample: sample.map(|fraction| Sample {
+            fraction,
+            seed: seed.unwrap_or(0),
+        }),
+        snapshot_out,
+        checkpoint_every,
+        checkpoint_dir,
+        resume,
+        rates,
+        charge_interest,
+        settlement_filename,
+        cutoffs,
+        settlement_out,
+        settlement_layout: settlement_layout.unwrap_or_default(),
+        config: builder.into_config(),
+    })
 }
 
-// This function mutates the oplog of a given account by applying the modification
-// contained in the AccountOperationResult.
-fn apply_result_to_account(
-    result: AccountOperationResult,
-    tx_id: u32,
-    a: &mut Account,
-) -> Result<()> {
-    match result {
-        AppendOperation { state, op } => {
-            a.state = state;
-            a.oplog.insert(tx_id, op);
-            return Ok(());
-        }
-        ModifyOperation { state, op } => {
-            a.state = state;
-            a.oplog.get_mut(&tx_id).map(|val| {
-                *val = op;
-            });
-            return Ok(());
+impl Verbosity {
+    fn increased(self) -> Verbosity {
+        match self {
+            Verbosity::Quiet => Verbosity::Normal,
+            Verbosity::Normal => Verbosity::Verbose,
+            Verbosity::Verbose | Verbosity::Debug => Verbosity::Debug,
         }
     }
 }
 
-fn is_transaction_in_log(tx: &TransactionEntry, a: &Account) -> bool {
-    a.oplog.contains_key(&tx.uid)
-}
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    // Maintenance and replay of an audit log, undo, remapping or simulation on a snapshot,
+    // dispatch of an outbox, extraction of a fixture, comparison of configurations, a stress
+    // test, the daemon keeping a ledger resident and its control, the erasure of a client, the
+    // pruning of a snapshot or legal holds on it, rather than processing an input.
+    let log_command = match args.get(1).map(String::as_str) {
+        Some("audit") => Some(audit::command as fn(&[String]) -> Result<()>),
+        Some("replay") => Some(replay::command as fn(&[String]) -> Result<()>),
+        Some("undo") => Some(rollback::command as fn(&[String]) -> Result<()>),
+        Some("apply-decisions") => Some(review::command as fn(&[String]) -> Result<()>),
+        Some("dispatch-outbox") => Some(outbox::command as fn(&[String]) -> Result<()>),
+        Some("remap") => Some(migration::command as fn(&[String]) -> Result<()>),
+        Some("anonymize") => Some(fixture::command as fn(&[String]) -> Result<()>),
+        Some("compare") => Some(compare::command as fn(&[String]) -> Result<()>),
+        Some("simulate") => Some(whatif::command as fn(&[String]) -> Result<()>),
+        Some("stress") => Some(stress::command as fn(&[String]) -> Result<()>),
+        Some("daemon") => Some(daemon::command as fn(&[String]) -> Result<()>),
+        Some("ctl") => Some(daemon::ctl as fn(&[String]) -> Result<()>),
+        Some("forget") => Some(erasure::command as fn(&[String]) -> Result<()>),
+        Some("prune") => Some(prune::command as fn(&[String]) -> Result<()>),
+        Some("hold") => Some(legal_hold::command as fn(&[String]) -> Result<()>),
+        _ => None,
+    };
+    if let Some(command) = log_command {
+        if let Err(e) = command(&args[2..]) {
+            eprintln!("Error occurred: {}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Invalid input - {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if options.mem_stats {
+        memory::track();
+    }
+    // Written when dropped, at the end of the run however it ends.
+    let _profiler = match options.profile.as_deref().map(Profiler::start).transpose() {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Invalid input - {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let checkpoint = match options.checkpoint_dir.as_deref().filter(|_| options.resume) {
+        Some(dir) => checkpoint::read(dir, &options.input_name(), &options.config),
+        None => Ok(None),
+    };
+    // The outputs of the records are carried on from their lengths at the checkpoint (see
+    // checkpoint::reopen) when resuming.
+    let (resumed_rows, resumed_outputs, l) = match checkpoint {
+        Ok(Some(c)) => (c.rows, Some(c.outputs), Ok(c.ledger)),
+        Ok(None) => match options.base_snapshot.as_deref() {
+            Some(path) => (0, None, read_snapshot(path, &options.config)),
+            None => (0, None, Ok(Ledger::with_config(options.config.clone()))),
+        },
+        Err(e) => (0, None, Err(e)),
+    };
+    let mut l = match l {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Invalid input - {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if options.resume && options.verbosity >= Verbosity::Verbose {
+        eprintln!("Resuming after {} records", resumed_rows);
+    }
+    // The other outputs of the records can't be carried on, so they only cover the records after
+    // the checkpoint: they are written to new files rather than over those of the interrupted run.
+    let restarted = [
+        options.review_filename.as_ref(),
+        options.anomalies_filename.as_ref(),
+        options.review_queue.as_ref(),
+        options.suspicious_activity.as_ref().map(|(_, path)| path),
+        options.settlement_filename.as_ref(),
+        options.settlement_out.as_ref(),
+        options.overlap_filename.as_ref(),
+        options
+            .events_out
+            .as_ref()
+            .filter(|p| !events::is_stream(p)),
+    ];
+    let overwritten = restarted
+        .into_iter()
+        .flatten()
+        .find(|path| resumed_outputs.is_some() && Path::new(path).exists());
+    if let Some(path) = overwritten {
+        eprintln!(
+            "Invalid input - {} exists, and would be overwritten with the records after the checkpoint only",
+            path
+        );
+        return ExitCode::FAILURE;
+    }
+    if let Some(Err(e)) = options.rates.as_ref().map(|r| r.install(&mut l)) {
+        eprintln!("Invalid input - {}", e);
+        return ExitCode::FAILURE;
+    }
+    let mut summary = Summary::default();
+    let mut inputs = Vec::new();
+    for path in &options.transactions_filenames {
+        match InputFile::open(path, &options) {
+            Ok(input) => {
+                if options.sniff_dialect {
+                    summary.dialects.push(SniffedDialect {
+                        input: path.clone(),
+                        dialect: input.input.dialect(),
+                    });
+                }
+                inputs.push(input)
+            }
+            Err(e) => {
+                eprintln!("Error occurred {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let rejects_writer = options.rejects_filename.as_deref().map(|path| {
+        Ok::<_, anyhow::Error>(match &resumed_outputs {
+            Some(outputs) => RejectsWriter::resume(checkpoint::reopen(path, outputs)?)?,
+            None => RejectsWriter::create(path)?,
+        })
+    });
+    let mut rejects_writer = match rejects_writer.transpose() {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("Error occurred while creating rejects file: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let kafka = options.kafka_sink.as_deref().map(|url| {
+        KafkaSink::connect(
+            url,
+            &options.kafka_transactional_id,
+            &options.input_name(),
+            options.decimals.clone(),
+        )
+    });
+    let mut kafka = match kafka.transpose() {
+        Ok(Some(k)) if k.committed() < resumed_rows => {
+            eprintln!(
+                "Error occurred: the checkpoint covers {} records but the Kafka sink only {}",
+                resumed_rows,
+                k.committed()
+            );
+            return ExitCode::FAILURE;
+        }
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("Error occurred while connecting to Kafka sink: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let review = options.review_filename.as_deref().map(ReviewQueue::create);
+    let mut review = match review.transpose() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error occurred while creating review file: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let audit_log = options
+        .audit_filename
+        .as_deref()
+        .map(|path| match &resumed_outputs {
+            Some(outputs) => AuditLog::resume(path, checkpoint::reopen(path, outputs)?),
+            None => AuditLog::create(path),
+        });
+    let mut audit_log = match audit_log.transpose() {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("Error occurred while creating audit log: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let events_sink = options
+        .events_sink
+        .as_deref()
+        .map(|url| EventsSink::new(url, &options.events_table, options.decimals.clone()));
+    let mut events_sink = match events_sink.transpose() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Invalid input - {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let cdc_out = options.cdc_out.as_deref().map(|path| {
+        let decimals = options.decimals.clone();
+        Ok::<_, anyhow::Error>(match &resumed_outputs {
+            Some(outputs) => CdcOut::resume(checkpoint::reopen(path, outputs)?, decimals)?,
+            None => CdcOut::create(path, decimals)?,
+        })
+    });
+    let mut cdc_out = match cdc_out.transpose() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error occurred while creating CDC output: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut outbox = options
+        .sink
+        .as_ref()
+        .filter(|s| s.outbox_table.is_some())
+        .map(|_| Outbox::new(options.decimals.clone()));
+
+    let event_stream = options
+        .events_out
+        .as_deref()
+        .filter(|path| events::is_stream(path))
+        .map(|path| {
+            let decimals = options.decimals.clone();
+            Ok::<_, anyhow::Error>(match resumed_outputs.as_ref().filter(|_| path != "-") {
+                Some(outputs) => EventStream::resume(checkpoint::reopen(path, outputs)?, decimals)?,
+                None => EventStream::create(path, decimals)?,
+            })
+        });
+    let mut event_stream = match event_stream.transpose() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error occurred while creating events stream: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let events_out = options
+        .events_out
+        .as_deref()
+        .filter(|path| !events::is_stream(path))
+        .map(|dir| EventsOut::create(dir, options.decimals.clone(), options.output_threads));
+    let mut events_out = match events_out.transpose() {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error occurred while creating events directory: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let anomalies = options.anomalies_filename.as_deref().map(Anomalies::create);
+    let mut anomalies = match anomalies.transpose() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("Error occurred while creating anomalies file: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let risk_review = options
+        .review_queue
+        .as_deref()
+        .map(|path| RiskReview::create(path, options.risk_rules.clone()));
+    let mut risk_review = match risk_review.transpose() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error occurred while creating review queue: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let suspicious = options
+        .suspicious_activity
+        .as_ref()
+        .map(|(template, path)| {
+            SuspiciousActivity::create(template, path, options.risk_rules.clone())
+        });
+    let mut suspicious = match suspicious.transpose() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!(
+                "Error occurred while creating suspicious-activity export: {}",
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let settlement = options.settlement_filename.as_deref().map(|path| {
+        Settlement::create(
+            path,
+            options.cutoffs.clone(),
+            options.config.calendar.clone(),
+        )
+    });
+    let mut settlement = match settlement.transpose() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error occurred while creating settlement file: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut settlement_export = options
+        .settlement_out
+        .as_deref()
+        .map(|path| SettlementExport::new(path, options.settlement_layout.clone()));
+
+    let overlaps = options
+        .overlap_filename
+        .as_deref()
+        .map(|path| Overlaps::create(path, &options.transactions_filenames));
+    let mut overlaps = match overlaps.transpose() {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error occurred while creating overlap report: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-fn process_transaction(tx: TransactionEntry, a: &mut Account) -> Result<()> {
-    let result: AccountOperationResult;
-    match tx.t.as_str() {
-        "deposit" => {
-            if is_transaction_in_log(&tx, a) {
-                return Err(anyhow! {"Duplicate transaction id. Skipping operation"});
-            } else {
-                result = process_operation(Deposit { amount: tx.amount }, None, a)?;
+    let mut tag_totals =
+        matches!(options.command, Command::Report(Report::Tags)).then(TagTotals::default);
+    let mut cash_flow = match options.command {
+        Command::Report(Report::CashFlow(period)) => Some(CashFlow::new(period)),
+        _ => None,
+    };
+    let mut chargeback_ratios = match &options.command {
+        Command::Report(Report::ChargebackRatio(period, thresholds)) => {
+            Some(ChargebackRatios::new(*period, thresholds.clone()))
+        }
+        _ => None,
+    };
+    let mut disputes = matches!(options.command, Command::Report(Report::Disputes))
+        .then(report::Disputes::default);
+    let mut audit_stats =
+        matches!(options.command, Command::AuditStats).then(audit_stats::AuditStats::default);
+    let mut journal =
+        matches!(options.command, Command::TrialBalance).then(trial_balance::Journal::default);
+    let finalized = options
+        .stream_finalized
+        .then(|| FinalizedRows::create(&l, options.decimals.clone(), io::stdout()));
+    let mut finalized = match finalized.transpose() {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error occurred while writing output: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut expired = Vec::new();
+    let mut rejections = Vec::new();
+    // Whether an output could not be written: the run goes on, but exits with a failure.
+    let mut failed = false;
+    let retention =
+        l.config().retention_transactions.is_some() || l.config().retention_days.is_some();
+    let mut base_snapshot = options.since_tx.map(BaseSnapshot::new);
+    // Records read from the input, including those of the checkpoint resumed from.
+    let mut rows = 0;
+    // The input takes care of reading the files record by record.
+    let (readers, files): (Vec<&mut Input>, Vec<_>) = inputs
+        .iter_mut()
+        .map(|f| (&mut f.input, (&f.headers, f.fast.as_ref())))
+        .unzip();
+    let headers: Vec<&StringRecord> = files.iter().map(|(headers, _)| *headers).collect();
+    let records = Records::new(
+        readers,
+        &headers,
+        options.merge_by_timestamp,
+        options.assume_tz,
+    );
+    for (file, record) in profile::phased(records, Phase::Read) {
+        profile::enter(Phase::Process);
+        let (headers, fast) = files[file];
+        rows += 1;
+        // The checkpoint covers the first records, and the outputs written along the way (audit
+        // log, events, ...) were carried on from it.
+        if rows <= resumed_rows {
+            summary.skipped += 1;
+            continue;
+        }
+        // There is no disk backend to spill the accounts and oplogs to, so a run going over the
+        // budget stops before the system runs out of memory, without any output.
+        if let Some(max) = options.max_memory.filter(|max| memory::allocated() > *max) {
+            eprintln!(
+                "Error occurred: memory budget of {} bytes exceeded after {} records ({} bytes allocated), aborting",
+                max,
+                summary.records,
+                memory::allocated()
+            );
+            return ExitCode::FAILURE;
+        }
+        if let (Some(o), Ok(record)) = (overlaps.as_mut(), &record) {
+            if let Err(e) = o.record(file, record, headers) {
+                eprintln!("Error occurred while writing overlap report: {}", e);
+                failed = true;
             }
         }
-        "withdrawal" => {
-            if is_transaction_in_log(&tx, a) {
-                return Err(anyhow! {"Duplicate transaction id. Skipping operation"});
-            } else {
-                result = process_operation(Withdrawal { amount: tx.amount }, None, a)?;
+        if let (Some(base), Ok(record)) = (base_snapshot.as_mut(), &record) {
+            if base.has(record, headers) {
+                summary.skipped += 1;
+                continue;
             }
         }
-        "dispute" => {
-            if !is_transaction_in_log(&tx, a) {
-                return Err(anyhow! {"Transaction not found in log. Skipping operation"});
-            } else {
-                result = process_operation(Dispute, Some(a.oplog[&tx.uid]), a)?;
+        if let Some(sample) = options.sample {
+            if !record.as_ref().is_ok_and(|r| sample.includes(r, headers)) {
+                summary.unsampled += 1;
+                continue;
+            }
+        }
+        summary.records += 1;
+        if let (Some(review), Ok(record)) = (review.as_mut(), &record) {
+            match review.queue(record, headers) {
+                Ok(true) => {
+                    summary.queued += 1;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Error occurred while writing review file: {}", e);
+                    failed = true;
+                }
+            }
+        }
+        // With --stream-finalized, the records of each client are expected to be contiguous:
+        // one of another client than the record before closes those of that client.
+        let mut result = None;
+        if let (Some(f), Ok(r)) = (finalized.as_mut(), &record) {
+            let client = headers
+                .iter()
+                .position(|h| h == "client")
+                .and_then(|i| r.get(i))
+                .and_then(|c| c.trim().parse().ok());
+            if let Some(client) = client {
+                match f.record(&l, client) {
+                    Ok(true) => {}
+                    Ok(false) => result = Some(Err(Box::new(Rejection::finalized(r, client)))),
+                    Err(e) => {
+                        eprintln!("Error occurred while writing output: {}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+        }
+        let result = result.unwrap_or_else(|| {
+            process_record(record, headers, fast, &mut l, &options, &mut expired)
+        });
+        let applied = match result {
+            Ok((line, applied)) => {
+                summary.applied += 1;
+                summary.fees += applied.fee;
+                Some((line, applied))
+            }
+            Err(retry) if retry.is_acknowledged() => {
+                summary.acknowledged += 1;
+                if options.verbosity >= Verbosity::Verbose {
+                    eprintln!("Line {}: {}", retry.line.unwrap_or_default(), retry.message);
+                }
+                None
+            }
+            Err(rejection) => {
+                summary.reject(&rejection);
+                if options.verbosity >= Verbosity::Normal {
+                    rejects::report(options.errors_format, &rejection);
+                }
+                if let Some(w) = rejects_writer.as_mut() {
+                    if let Err(e) = w.write(&rejection) {
+                        eprintln!("Error occurred while writing rejects file: {}", e);
+                        failed = true;
+                    }
+                }
+                // The xlsx output has a sheet of the rejections, the sink may have a table.
+                let sink_rejects = options
+                    .sink
+                    .as_ref()
+                    .is_some_and(|s| s.rejects_table.is_some());
+                if options.output_format == OutputFormat::Xlsx || sink_rejects {
+                    rejections.push(*rejection);
+                }
+                None
+            }
+        };
+        profile::enter(Phase::Record);
+        // Bonus reversals are traced like applied transactions, before the record itself.
+        let expiries = expired.len();
+        for (i, (line, applied)) in expired.drain(..).chain(applied).enumerate() {
+            if let Some(w) = audit_log.as_mut() {
+                if let Err(e) = w.write(line, &applied, i < expiries) {
+                    eprintln!("Error occurred while writing audit log: {}", e);
+                    failed = true;
+                }
+            }
+            if let Some(Err(e)) = events_sink.as_mut().map(|s| s.record(&applied)) {
+                eprintln!("Error occurred while writing to events sink: {}", e);
+                return ExitCode::FAILURE;
+            }
+            if let Some(Err(e)) = events_out.as_mut().map(|o| o.record(&applied)) {
+                eprintln!("Error occurred while writing events: {}", e);
+                failed = true;
+            }
+            if let Some(Err(e)) = event_stream.as_mut().map(|s| s.record(&applied)) {
+                eprintln!("Error occurred while writing events stream: {}", e);
+                failed = true;
+            }
+            if let Some(Err(e)) = cdc_out.as_mut().map(|c| c.record(&applied, &l)) {
+                eprintln!("Error occurred while writing CDC output: {}", e);
+                failed = true;
+            }
+            if let Some(Err(e)) = outbox.as_mut().map(|o| o.record(&applied, &l)) {
+                eprintln!("Error occurred while writing to outbox: {}", e);
+                failed = true;
+            }
+            // Recording may commit to the sink, when enough changes are queued.
+            if let Some(Err(e)) = kafka.as_mut().map(|k| k.record(rows, &applied)) {
+                eprintln!("Error occurred while writing to Kafka sink: {}", e);
+                return ExitCode::FAILURE;
+            }
+            if let Some(Err(e)) = anomalies.as_mut().map(|a| a.record(&applied)) {
+                eprintln!("Error occurred while writing anomalies file: {}", e);
+                failed = true;
+            }
+            if let Some(Err(e)) = risk_review.as_mut().map(|r| r.record(line, &applied)) {
+                eprintln!("Error occurred while writing review queue: {}", e);
+                failed = true;
+            }
+            if let Some(suspicious) = suspicious.as_mut() {
+                suspicious.record(line, &applied);
+            }
+            if let Some(export) = settlement_export.as_mut() {
+                export.record(&applied);
+            }
+            if let Some(totals) = tag_totals.as_mut() {
+                totals.record(&applied);
+            }
+            if let Some(journal) = journal.as_mut() {
+                journal.record(&applied);
+            }
+            if let Some(stats) = audit_stats.as_mut() {
+                stats.record(&applied);
+            }
+            if let Some(cash_flow) = cash_flow.as_mut() {
+                cash_flow.record(&applied);
+            }
+            if let Some(disputes) = disputes.as_mut() {
+                disputes.record(line, &applied);
+            }
+            if let Some(ratios) = chargeback_ratios.as_mut() {
+                ratios.record(&applied);
+            }
+            match settlement.as_mut().map(|s| s.record(&applied)) {
+                Some(Ok(Some(cutoff))) if options.verbosity >= Verbosity::Verbose => {
+                    eprintln!("Settlement batch closed at {}", cutoff)
+                }
+                Some(Err(e)) => {
+                    eprintln!("Error occurred while writing settlement file: {}", e);
+                    failed = true;
+                }
+                _ => {}
             }
         }
-        "resolve" => {
-            if !is_transaction_in_log(&tx, a) {
-                return Err(anyhow! {"Transaction not found in log. Skipping operation"});
-            } else {
-                result = process_operation(Resolve, Some(a.oplog[&tx.uid]), a)?;
+        // The retention is applied every PRUNE_EVERY records, as it is a pass over the oplogs.
+        if retention && rows % PRUNE_EVERY == 0 {
+            summary.pruned += l.prune();
+        }
+        if let (Some(every), Some(dir)) = (options.checkpoint_every, &options.checkpoint_dir) {
+            if rows % every == 0 {
+                profile::enter(Phase::Checkpoint);
+                // The changes up to the checkpoint are committed first, see KafkaSink.
+                if let Some(Err(e)) = kafka.as_mut().map(|k| k.commit(rows)) {
+                    eprintln!("Error occurred while committing to Kafka sink: {}", e);
+                    return ExitCode::FAILURE;
+                }
+                // The outputs are flushed, for their lengths, see checkpoint::reopen.
+                let lengths = [
+                    (
+                        options.audit_filename.as_ref(),
+                        audit_log.as_mut().map(AuditLog::len),
+                    ),
+                    (
+                        options.rejects_filename.as_ref(),
+                        rejects_writer.as_mut().map(RejectsWriter::len),
+                    ),
+                    (options.cdc_out.as_ref(), cdc_out.as_mut().map(CdcOut::len)),
+                    (
+                        options.events_out.as_ref().filter(|p| *p != "-"),
+                        event_stream.as_ref().map(|s| Ok(s.len())),
+                    ),
+                ];
+                let outputs = lengths
+                    .into_iter()
+                    .filter_map(|(path, len)| Some((path?.clone(), len?)))
+                    .map(|(path, len)| len.map(|len| (path, len)))
+                    .collect::<io::Result<BTreeMap<_, _>>>();
+                let written = outputs.map_err(anyhow::Error::from).and_then(|outputs| {
+                    checkpoint::write(dir, &options.input_name(), rows, &l, &outputs)
+                });
+                if let Err(e) = written {
+                    eprintln!("Error occurred while writing checkpoint: {}", e);
+                    failed = true;
+                }
             }
         }
-        "chargeback" => {
-            if !is_transaction_in_log(&tx, a) {
-                return Err(anyhow! {"Transaction not found in log. Skipping operation"});
-            } else {
-                result =
-                    process_operation(Chargeback, Some(a.oplog[&tx.uid]), a)?;
+    }
+    profile::enter(Phase::Finish);
+    if retention {
+        summary.pruned += l.prune();
+    }
+    if let Some(Err(e)) = kafka.as_mut().map(|k| k.commit(rows)) {
+        eprintln!("Error occurred while committing to Kafka sink: {}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Some(Err(e)) = rejects_writer.map(RejectsWriter::finish) {
+        eprintln!("Error occurred while writing rejects file: {}", e);
+        failed = true;
+    }
+    if let Some(Err(e)) = review.map(ReviewQueue::finish) {
+        eprintln!("Error occurred while writing review file: {}", e);
+        failed = true;
+    }
+    if let Some(Err(e)) = settlement_export.map(SettlementExport::write) {
+        eprintln!("Error occurred while writing settlement export: {}", e);
+        failed = true;
+    }
+    if let Some(Err(e)) = settlement.map(Settlement::finish) {
+        eprintln!("Error occurred while writing settlement file: {}", e);
+        failed = true;
+    }
+    if let Some(Err(e)) = audit_log.map(AuditLog::finish) {
+        eprintln!("Error occurred while writing audit log: {}", e);
+        failed = true;
+    }
+    match cdc_out.map(CdcOut::finish) {
+        Some(Ok(changes)) if options.verbosity >= Verbosity::Verbose => {
+            eprintln!("Wrote {} balance changes", changes)
+        }
+        Some(Err(e)) => {
+            eprintln!("Error occurred while writing CDC output: {}", e);
+            failed = true;
+        }
+        _ => {}
+    }
+    if let Some(Err(e)) = event_stream.map(EventStream::finish) {
+        eprintln!("Error occurred while writing events stream: {}", e);
+        failed = true;
+    }
+    if let Some(Err(e)) = events_out.map(EventsOut::finish) {
+        eprintln!("Error occurred while writing events: {}", e);
+        failed = true;
+    }
+    if let Some(Err(e)) = events_sink.map(EventsSink::finish) {
+        eprintln!("Error occurred while writing to events sink: {}", e);
+        failed = true;
+    }
+    match anomalies.map(Anomalies::finish) {
+        Some(Ok(flagged)) if flagged > 0 && options.verbosity >= Verbosity::Normal => {
+            eprintln!("Found {} anomalies", flagged)
+        }
+        Some(Err(e)) => {
+            eprintln!("Error occurred while writing anomalies file: {}", e);
+            failed = true;
+        }
+        _ => {}
+    }
+    match risk_review.map(RiskReview::finish) {
+        Some(Ok(queued)) if queued > 0 && options.verbosity >= Verbosity::Normal => {
+            eprintln!("Queued {} operations for review", queued)
+        }
+        Some(Err(e)) => {
+            eprintln!("Error occurred while writing review queue: {}", e);
+            failed = true;
+        }
+        _ => {}
+    }
+    match suspicious.map(|s| s.finish(&l)) {
+        Some(Ok(flagged)) if flagged > 0 && options.verbosity >= Verbosity::Normal => {
+            eprintln!("Exported {} suspicious operations", flagged)
+        }
+        Some(Err(e)) => {
+            eprintln!(
+                "Error occurred while writing suspicious-activity export: {}",
+                e
+            );
+            failed = true;
+        }
+        _ => {}
+    }
+    match overlaps.map(Overlaps::finish) {
+        Some(Ok(found)) if found > 0 && options.verbosity >= Verbosity::Normal => eprintln!(
+            "Found {} transactions already seen in an earlier input file",
+            found
+        ),
+        Some(Err(e)) => {
+            eprintln!("Error occurred while writing overlap report: {}", e);
+            failed = true;
+        }
+        _ => {}
+    }
+    if options.charge_interest {
+        for (client_id, interest) in l.charge_interest() {
+            if options.verbosity >= Verbosity::Verbose {
+                eprintln!("Charged interest of {} to client {}", interest, client_id);
+            }
+            if let Some(journal) = journal.as_mut() {
+                journal.record_interest(interest);
             }
         }
-        _ => return Err(anyhow! {"Unknown transaction type. Skipping operation"}),
     }
-    apply_result_to_account(result, tx.uid, a)
-}
-
-fn apply_transaction(tx: TransactionEntry, l: &mut Ledger) -> Result<()> {
-    match l.accounts.get_mut(&tx.client_id) {
-        Some(mut account) => process_transaction(tx, &mut account)?,
+    if let Some(Err(e)) = options
+        .snapshot_out
+        .as_deref()
+        .map(|path| write_snapshot(path, &l))
+    {
+        eprintln!("Error occurred while writing snapshot: {}", e);
+        failed = true;
+    }
+    let messages = outbox.as_ref().map_or(&[][..], Outbox::messages);
+    if let Some(Err(e)) = options
+        .sink
+        .as_ref()
+        .map(|s| s.write(&l, &rejections, messages))
+    {
+        eprintln!("Error occurred while writing to sink: {}", e);
+        failed = true;
+    }
+    // The digest of several input files is that of their digests, in order.
+    let input_sha256 = match inputs.len() {
+        1 => inputs.remove(0).input.digest(),
         _ => {
-            let a = Account {
-                state: Open {
-                    available: 0.0,
-                    held: 0.0,
-                },
-                oplog: HashMap::new(),
-            };
-            l.accounts.insert(tx.client_id, a);
-            // we can unwrap here, because we have just inserted this entry, so if it does not
-            // exist, it would mean something is seriously wrong.
-            let account = &mut l.accounts.get_mut(&tx.client_id).unwrap();
-            process_transaction(tx, account)?;
-        }
-    }
-    Ok(())
-}
+            let digests: Vec<String> = inputs.into_iter().map(|f| f.input.digest()).collect();
+            metadata::hex_digest(digests.join("\n").as_bytes())
+        }
+    };
+    let metadata = RunMetadata::new(input_sha256, &options.config_fingerprint());
 
-fn deserialize_transaction_entry(record: Result<StringRecord,csv::Error>) -> Result<TransactionEntry,Box<dyn std::error::Error>> {
-    let te: TransactionEntry = record?.deserialize(None)?;
-    Ok(te)
+    if options.mem_stats {
+        summary.memory = Some(memory::stats());
+    }
+    // The table output already contains the summary, but for the memory statistics.
+    if options.output_format != OutputFormat::Table {
+        rejects::report_summary(options.errors_format, &summary);
+    } else if let Some(stats) = &summary.memory {
+        rejects::report_memory(stats);
+    }
+
+    profile::enter(Phase::Output);
+    let stdout = io::stdout();
+    let color = options.color.enabled(stdout.is_terminal());
+    let mut out = stdout.lock();
+    // Status of assert-state: 1 when the balances differ, 2 when they can't be checked.
+    let mut asserted = None;
+    let written = match &options.command {
+        Command::Balances if finalized.is_some() => finalized.map_or(Ok(()), |f| f.finish(&l)),
+        Command::Balances => output::write(
+            &options,
+            color,
+            &l,
+            &summary,
+            &rejections,
+            &metadata,
+            &mut out,
+        ),
+        Command::Report(Report::Hierarchy(hierarchy)) => {
+            report::write(&l, hierarchy, options.output_format, &mut out)
+        }
+        Command::Report(Report::Tags) => report::write_tags(
+            &tag_totals.unwrap_or_default(),
+            options.output_format,
+            &mut out,
+        ),
+        Command::Report(Report::CashFlow(period)) => report::write_cash_flow(
+            &cash_flow.unwrap_or_else(|| CashFlow::new(*period)),
+            options.output_format,
+            &mut out,
+        ),
+        Command::Report(Report::ChargebackRatio(period, thresholds)) => {
+            report::write_chargeback_ratios(
+                &chargeback_ratios
+                    .unwrap_or_else(|| ChargebackRatios::new(*period, thresholds.clone())),
+                options.output_format,
+                &mut out,
+            )
+        }
+        Command::Report(Report::Disputes) => report::write_disputes(
+            &disputes.unwrap_or_default(),
+            options.output_format,
+            &mut out,
+        ),
+        Command::AuditStats => audit_stats::write(
+            &audit_stats.unwrap_or_default(),
+            &l.legal_holds().collect::<Vec<_>>(),
+            options.output_format,
+            &mut out,
+        ),
+        Command::TrialBalance => trial_balance::write(
+            &journal.unwrap_or_default(),
+            &l,
+            options.output_format,
+            &mut out,
+        ),
+        Command::AssertState(expected) => match assert_state::write(expected, &l, &mut out) {
+            Ok(matched) => {
+                asserted = Some(1).filter(|_| !matched);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Error occurred: {}", e);
+                asserted = Some(2);
+                Ok(())
+            }
+        },
+    };
+    if let Err(e) = written {
+        eprintln!("Error occurred while writing output: {}", e);
+        failed = true;
+    }
+    if let Some(status) = asserted {
+        ExitCode::from(status)
+    } else if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Invalid input - should contain name of a transaction file");
-        return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        let args: Vec<String> = ["ledger", "transactions.csv"]
+            .iter()
+            .chain(args)
+            .map(|a| a.to_string())
+            .collect();
+        parse_args(&args).unwrap()
     }
-    let transactions_filename = &args[1];
 
-    let mut l = Ledger {
-        accounts: HashMap::new(),
-    };
-    let file = File::open(transactions_filename).unwrap();
-    
-    let mut rdr = ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        // BufReader ensures that we don't read in the whole file at once.
-        .from_reader(BufReader::new(file));
-
-    // The iterator takes care of reading the file record by record.
-    for record in rdr.records() {
-        match deserialize_transaction_entry(record) {
-        Ok(entry) => if let Err(e) = apply_transaction(entry, &mut l) {
-            eprintln!("Error occurred: {}", e);
-        },
-        Err(e) => eprintln!("Error occurred: {}", e),
-        }
-    }
-    println!("client,available,held,total,locked");
-    for (aid, account) in l.accounts.iter() {
-        //        println!("Ledger entry {}: {:?}", aid, &account);
-        match &account.state {
-            Open { available, held } => println!(
-                "{},{:.4},{:.4},{:.4},{}",
-                aid,
-                available,
-                held,
-                available + held,
-                false
-            ),
-            Locked { available, held } => println!(
-                "{},{:.4},{:.4},{:.4},{}",
-                aid,
-                available,
-                held,
-                available + held,
-                true
-            ),
+    #[test]
+    fn debug_output_lists_the_fields_by_name() {
+        let mut entry = deserialize_transaction_entry(
+            &StringRecord::from(vec!["deposit", "1", "2", "1.5"]),
+            &StringRecord::from(vec!["type", "client", "tx", "amount"]),
+        )
+        .unwrap();
+        assert_eq!(
+            entry_fields(&entry),
+            "type=\"deposit\" client=1 tx=2 amount=1.5"
+        );
+        entry.amount = None;
+        entry.memo = Some("refund \"A\"".to_string());
+        assert_eq!(
+            entry_fields(&entry),
+            "type=\"deposit\" client=1 tx=2 memo=\"refund \\\"A\\\"\""
+        );
+        let state = AccountState::Locked {
+            available: "1.5".parse().unwrap(),
+            held: "2".parse().unwrap(),
+        };
+        assert_eq!(state_fields(&state), "available=1.5 held=2 locked=true");
+    }
+
+    #[test]
+    fn config_fingerprint_leaves_out_the_presentation() {
+        let csv = options(&[]).config_fingerprint();
+        let json = options(&["--output-format", "json", "--decimals", "2"]).config_fingerprint();
+        assert_eq!(csv, json);
+        assert_ne!(csv, options(&["--precision", "2"]).config_fingerprint());
+    }
+
+    #[test]
+    fn base_snapshot_has_the_records_before_since_tx() {
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let input = [
+            ("deposit,1,1,10", true),
+            ("deposit,1,2,5", true),
+            ("dispute,1,1,", true),
+            ("resolve,1,1,", true),
+            ("withdrawal,1,3,1", true),
+            ("dispute,1,2,", false),
+            ("deposit,1,4,1", false),
+            ("chargeback,1,2,", false),
+            ("dispute,1,1,", false),
+        ];
+        let mut base = BaseSnapshot::new(3);
+        for (line, in_base) in input {
+            let record = StringRecord::from(line.split(',').collect::<Vec<_>>());
+            assert_eq!(base.has(&record, &headers), in_base, "{}", line);
+        }
+    }
+
+    #[test]
+    fn base_snapshot_ends_at_larger_ids() {
+        // The transaction of --since-tx may not be in the input, e.g. when it was rejected.
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let mut base = BaseSnapshot::new(3);
+        let mut has = |line: &str| {
+            let record = StringRecord::from(line.split(',').collect::<Vec<_>>());
+            base.has(&record, &headers)
         };
+        assert!(has("deposit,1,2,5"));
+        assert!(has("dispute,1,2,"));
+        assert!(!has("deposit,1,5,1"));
+        assert!(!has("resolve,1,2,"));
+        assert!(has("deposit,1,1,1")); // Out of order, but up to the id
     }
 }
@@ -0,0 +1,147 @@
+use crate::profile::PHASES;
+#[cfg(feature = "memory-accounting")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// System allocator keeping count of the bytes currently allocated, which --max-memory checks the
+// ledger against. With --mem-stats, it also keeps the peak and the number of allocations, overall
+// and in every phase of the run (see profile::Phase). It is the allocator of the binary with the
+// memory-accounting feature only, as the counting costs every allocation; without it, nothing is
+// counted.
+#[cfg(feature = "memory-accounting")]
+pub struct Counting;
+
+// Whether the allocations are counted.
+pub const COUNTED: bool = cfg!(feature = "memory-accounting");
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static PHASE_PEAKS: [AtomicUsize; PHASES.len()] = [const { AtomicUsize::new(0) }; PHASES.len()];
+static PHASE_ALLOCATIONS: [AtomicUsize; PHASES.len()] =
+    [const { AtomicUsize::new(0) }; PHASES.len()];
+
+// Counts an allocation of size bytes.
+#[cfg(feature = "memory-accounting")]
+fn allocate(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    if TRACKING.load(Ordering::Relaxed) {
+        let phase = crate::profile::current();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        PHASE_PEAKS[phase].fetch_max(allocated, Ordering::Relaxed);
+        PHASE_ALLOCATIONS[phase].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "memory-accounting")]
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            allocate(layout.size());
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        System.dealloc(p, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc_zeroed(layout);
+        if !p.is_null() {
+            allocate(layout.size());
+        }
+        p
+    }
+
+    unsafe fn realloc(&self, p: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let q = System.realloc(p, layout, new_size);
+        if !q.is_null() {
+            allocate(new_size);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        q
+    }
+}
+
+// Bytes currently allocated on the heap.
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+// Starts keeping the statistics reported by stats().
+pub fn track() {
+    PEAK.store(allocated(), Ordering::Relaxed);
+    TRACKING.store(true, Ordering::Relaxed);
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PhaseStats {
+    pub phase: &'static str,
+    pub peak_heap: usize, // Bytes allocated at the peak reached during the phase
+    pub allocations: usize,
+}
+
+// Memory used by the run, reported with --mem-stats.
+#[derive(Debug, serde::Serialize)]
+pub struct MemoryStats {
+    // Peak resident set size of the process, in bytes, where the system tells it (Linux).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_rss: Option<usize>,
+    pub peak_heap: usize, // Bytes allocated on the heap at the peak
+    pub allocations: usize,
+    pub phases: Vec<PhaseStats>, // The phases with allocations, in the order of the run
+}
+
+// Peak resident set size of the process, from /proc.
+fn peak_rss() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: usize = line["VmHWM:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+pub fn stats() -> MemoryStats {
+    let phases = PHASES
+        .iter()
+        .enumerate()
+        .map(|(i, phase)| PhaseStats {
+            phase: phase.as_str(),
+            peak_heap: PHASE_PEAKS[i].load(Ordering::Relaxed),
+            allocations: PHASE_ALLOCATIONS[i].load(Ordering::Relaxed),
+        })
+        .filter(|p| p.allocations > 0)
+        .collect();
+    MemoryStats {
+        peak_rss: peak_rss(),
+        peak_heap: PEAK.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        phases,
+    }
+}
+
+// Byte count in MiB, for people.
+pub fn mib(bytes: usize) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1 << 20) as f64)
+}
+
+// Parses a byte count, optionally with a K, M or G suffix (powers of 1024), e.g. 512M.
+pub fn parse_bytes(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
@@ -0,0 +1,98 @@
+use crate::input::{Input, Record};
+use csv::StringRecord;
+use ledger::{Timestamp, Zone};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Records of the inputs of the run, with the index of the input they come from. By default the
+// inputs are read one after the other. With --merge-by-timestamp, their records are interleaved in
+// timestamp order by a k-way merge: the next record is the earliest of the next records of every
+// input, ties going to the first input given. A record without a valid timestamp (or which failed
+// to parse) comes right after the record before it in its input, so that every input is still
+// read in order, and one which isn't in timestamp order itself is merged as it comes.
+pub struct Records<'a> {
+    inputs: Vec<&'a mut Input>,
+    current: usize, // Input being read, when they are read one after the other
+    merge: Option<Merge>,
+}
+
+struct Merge {
+    timestamps: Vec<Option<usize>>, // Timestamp column of every input
+    zone: Zone,                     // Of the timestamps without an offset or zone
+    heads: Vec<Option<Record>>,     // Next record of every input
+    last: Vec<i64>,                 // Timestamp of the last record of every input
+    queue: BinaryHeap<Reverse<(i64, usize)>>, // Timestamps of the heads, and their inputs
+}
+
+impl<'a> Records<'a> {
+    pub fn new(
+        inputs: Vec<&'a mut Input>,
+        headers: &[&StringRecord],
+        by_timestamp: bool,
+        zone: Zone,
+    ) -> Self {
+        let mut records = Records {
+            inputs,
+            current: 0,
+            merge: None,
+        };
+        if by_timestamp {
+            let mut merge = Merge {
+                timestamps: headers
+                    .iter()
+                    .map(|h| h.iter().position(|h| h == "timestamp"))
+                    .collect(),
+                zone,
+                heads: Vec::new(),
+                last: vec![i64::MIN; headers.len()],
+                queue: BinaryHeap::new(),
+            };
+            for i in 0..records.inputs.len() {
+                let head = records.inputs[i].next();
+                merge.heads.push(head);
+                merge.push(i);
+            }
+            records.merge = Some(merge);
+        }
+        records
+    }
+}
+
+impl Merge {
+    // Queues the head of the input, if it has one left.
+    fn push(&mut self, input: usize) {
+        let Some(head) = &self.heads[input] else {
+            return;
+        };
+        let timestamp = head
+            .as_ref()
+            .ok()
+            .zip(self.timestamps[input])
+            .and_then(|(record, i)| record.get(i))
+            .and_then(|t| Timestamp::parse_in(t.trim(), self.zone).ok())
+            .map_or(self.last[input], |t| t.0);
+        self.last[input] = timestamp;
+        self.queue.push(Reverse((timestamp, input)));
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = (usize, Record);
+
+    fn next(&mut self) -> Option<(usize, Record)> {
+        let Some(merge) = self.merge.as_mut() else {
+            while let Some(input) = self.inputs.get_mut(self.current) {
+                if let Some(record) = input.next() {
+                    return Some((self.current, record));
+                }
+                self.current += 1;
+            }
+            return None;
+        };
+        let Reverse((_, input)) = merge.queue.pop()?;
+        let next = self.inputs[input].next();
+        let record = std::mem::replace(&mut merge.heads[input], next)?;
+        merge.push(input);
+        Some((input, record))
+    }
+}
@@ -0,0 +1,97 @@
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Version of the structured output schema (JSON, YAML, XML and the CSV comment header). Consumers
+// use it to detect format changes, so any change to field names or their meaning has to bump it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Metadata describing the run which produced an output.
+#[derive(Debug, serde::Serialize)]
+pub struct RunMetadata {
+    pub schema_version: u32,
+    pub tool_version: &'static str,
+    pub generated_at: String, // RFC 3339 timestamp, in UTC
+    pub input_sha256: String,
+    pub config_sha256: String,
+}
+
+impl RunMetadata {
+    pub fn new(input_sha256: String, config: &str) -> RunMetadata {
+        RunMetadata {
+            schema_version: SCHEMA_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION"),
+            generated_at: rfc3339(SystemTime::now()),
+            input_sha256,
+            config_sha256: hex(&Sha256::digest(config.as_bytes())),
+        }
+    }
+}
+
+// Reader computing the SHA-256 digest of everything read through it, so that the input can be
+// fingerprinted in the same pass that processes it.
+pub struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> DigestReader<R> {
+        DigestReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn hex_digest(&self) -> String {
+        hex(&self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// SHA-256 digest of data read in full, as given by DigestReader::hex_digest.
+pub fn hex_digest(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Formats a point in time as an RFC 3339 timestamp in UTC, with second precision.
+pub fn rfc3339(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Converts a number of days since 1970-01-01 to a (year, month, day) triple in the proleptic
+// Gregorian calendar (Howard Hinnant's civil_from_days algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Result};
+use ledger::RemapConflictKind;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Row of the mapping file.
+#[derive(Debug, serde::Deserialize)]
+struct Mapping {
+    from: u16,
+    to: u16,
+}
+
+fn read_mapping(path: &str) -> Result<HashMap<u16, u16>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| anyhow! {"cannot read mapping {}: {}", path, e})?;
+    let mut mapping = HashMap::new();
+    for row in reader.deserialize() {
+        let row: Mapping = row.map_err(|e| anyhow! {"invalid mapping {}: {}", path, e})?;
+        match mapping.insert(row.from, row.to) {
+            Some(to) if to != row.to => {
+                return Err(anyhow! {
+                    "client {} is mapped to both {} and {} in {}",
+                    row.from,
+                    to,
+                    row.to,
+                    path
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(mapping)
+}
+
+// Rewrites the client column of a transaction file into the output directory, under the same
+// name, and returns the number of records remapped. The other fields are written as they are.
+fn remap_file(path: &str, out_dir: &str, mapping: &HashMap<u16, u16>) -> Result<u64> {
+    let name = Path::new(path)
+        .file_name()
+        .ok_or_else(|| anyhow! {"invalid transaction file {}", path})?;
+    let out = Path::new(out_dir).join(name);
+    if fs::canonicalize(path).ok() == fs::canonicalize(&out).ok() {
+        return Err(anyhow! {"{} would overwrite the transaction file itself", out.display()});
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| anyhow! {"cannot read {}: {}", path, e})?;
+    let headers = reader.headers()?.clone();
+    let client = headers
+        .iter()
+        .position(|h| h.trim() == "client")
+        .ok_or_else(|| anyhow! {"{} has no client column", path})?;
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_path(&out)?;
+    writer.write_record(&headers)?;
+    let mut remapped = 0;
+    for record in reader.records() {
+        let record = record.map_err(|e| anyhow! {"invalid record in {}: {}", path, e})?;
+        let to = record
+            .get(client)
+            .and_then(|c| c.trim().parse::<u16>().ok())
+            .and_then(|c| mapping.get(&c));
+        let Some(to) = to else {
+            writer.write_record(&record)?;
+            continue;
+        };
+        remapped += 1;
+        let to = to.to_string();
+        let fields = record
+            .iter()
+            .enumerate()
+            .map(|(i, f)| if i == client { to.as_str() } else { f });
+        writer.write_record(fields)?;
+    }
+    writer.flush()?;
+    Ok(remapped)
+}
+
+// `ledger remap --mapping map.csv [--snapshot s.json [--snapshot-out o.json]] [--out-dir dir
+// file.csv...]`: renames clients according to the mapping file, with the columns from and to, in
+// the ledger of a snapshot and/or in transaction files. The accounts of clients which end up with
+// the same id are merged (see Ledger::remap_clients), and every merge and conflict is reported.
+// The snapshot is written back like with `ledger undo`, through a temporary file. The
+// transaction files are rewritten into the output directory, under their own names, records of
+// clients which are not mapped (or don't parse) as they are.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut mapping, mut snapshot, mut snapshot_out, mut out_dir) = (None, None, None, None);
+    let mut files = Vec::new();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--mapping" => mapping = Some(value()?.clone()),
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--snapshot-out" => snapshot_out = Some(value()?.clone()),
+            "--out-dir" => out_dir = Some(value()?.clone()),
+            _ if !arg.starts_with("--") => files.push(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let mapping = read_mapping(&mapping.ok_or_else(|| anyhow! {"remap requires the --mapping"})?)?;
+    if snapshot.is_none() && files.is_empty() {
+        return Err(anyhow! {"remap requires a --snapshot or transaction files"});
+    }
+    if snapshot.is_none() && snapshot_out.is_some() {
+        return Err(anyhow! {"--snapshot-out requires --snapshot"});
+    }
+    if !files.is_empty() && out_dir.is_none() {
+        return Err(anyhow! {"remapping transaction files requires the --out-dir"});
+    }
+    if let Some(path) = snapshot {
+        let mut l = crate::load_snapshot(&path)?;
+        let conflicts = l.remap_clients(&mapping);
+        let out = snapshot_out.unwrap_or_else(|| path.clone());
+        let tmp = format!("{}.tmp", out);
+        crate::write_snapshot(&tmp, &l)?;
+        fs::rename(&tmp, &out)?;
+        for c in &conflicts {
+            match c.kind {
+                RemapConflictKind::Merged => println!(
+                    "Merged the account of client {} into that of client {}, as client {}",
+                    c.merged, c.into, c.client_id
+                ),
+                RemapConflictKind::DuplicateTransaction(tx) => println!(
+                    "Conflict: transaction {} of client {} is also in client {}, kept the latter",
+                    tx, c.merged, c.into
+                ),
+                RemapConflictKind::Locked => println!(
+                    "Conflict: only one of clients {} and {} was locked, client {} is",
+                    c.into, c.merged, c.client_id
+                ),
+                RemapConflictKind::TagsDropped(tx) => println!(
+                    "Conflict: tags of transaction {} of client {} don't fit into client {}, \
+                     dropped",
+                    tx, c.merged, c.client_id
+                ),
+                _ => println!(
+                    "Conflict: {} merging client {} into client {}, as client {}",
+                    c.kind.as_str(),
+                    c.merged,
+                    c.into,
+                    c.client_id
+                ),
+            }
+        }
+    }
+    if let Some(out_dir) = out_dir {
+        fs::create_dir_all(&out_dir)?;
+        for path in &files {
+            let remapped = remap_file(path, &out_dir, &mapping)?;
+            println!("Remapped {} records of {}", remapped, path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,348 @@
+use crate::OperationState::{self, *};
+use crate::{Amount, Money};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Packed states, in the two top bits of the state word of a packed entry.
+const REGULAR: u32 = 0;
+const DISPUTED: u32 = 1; // The whole amount is disputed
+const FINAL: u32 = 2;
+const WITHDRAWAL: u32 = 3;
+const STATE_SHIFT: u32 = 30;
+const DISPUTES_MASK: u32 = (1 << STATE_SHIFT) - 1;
+
+// Oplog entry packed into 8 bytes: the bits of the amount (the charged back amount for finalized
+// deposits, zero for withdrawals, which don't need it) and a word holding the state and the
+// dispute count.
+#[derive(Clone, Copy, Debug)]
+struct Packed {
+    amount: u32,
+    state: u32,
+}
+
+// Amount word of a packed entry: the bits of the f32, or with minor units the exponent in the four
+// top bits and the units in the others, which holds up to 2^27 units (larger amounts are kept
+// unpacked).
+#[cfg(not(feature = "minor-units"))]
+fn amount_bits(amount: Amount) -> Option<u32> {
+    Some(amount.to_bits())
+}
+
+#[cfg(not(feature = "minor-units"))]
+fn bits_amount(bits: u32) -> Amount {
+    f32::from_bits(bits)
+}
+
+#[cfg(feature = "minor-units")]
+const UNITS_BITS: u32 = 28;
+
+#[cfg(feature = "minor-units")]
+fn amount_bits(amount: Amount) -> Option<u32> {
+    let limit = 1i64 << (UNITS_BITS - 1);
+    let fits = amount.exponent() < 16 && (-limit..limit).contains(&amount.units());
+    fits.then(|| {
+        u32::from(amount.exponent()) << UNITS_BITS
+            | (amount.units() as u32 & ((1 << UNITS_BITS) - 1))
+    })
+}
+
+#[cfg(feature = "minor-units")]
+fn bits_amount(bits: u32) -> Amount {
+    // Shifting the units to the top bits and back extends their sign.
+    let units = ((bits << (32 - UNITS_BITS)) as i32) >> (32 - UNITS_BITS);
+    crate::MinorUnits::new(i64::from(units), (bits >> UNITS_BITS) as u8)
+}
+
+impl Packed {
+    fn new(op: &OperationState) -> Option<Packed> {
+        let packed = |amount: Amount, state: u32, disputes: u32| {
+            (disputes <= DISPUTES_MASK).then_some(Packed {
+                amount: amount_bits(amount)?,
+                state: state << STATE_SHIFT | disputes,
+            })
+        };
+        match *op {
+            RegularDeposit {
+                amount,
+                disputes,
+                charged_back,
+            } if charged_back == Amount::ZERO => packed(amount, REGULAR, disputes),
+            DisputedDeposit {
+                amount,
+                disputed,
+                disputes,
+                charged_back,
+            } if amount_bits(disputed) == amount_bits(amount) && charged_back == Amount::ZERO => {
+                packed(amount, DISPUTED, disputes)
+            }
+            FinalDeposit {
+                charged_back,
+                disputes,
+            } => packed(charged_back, FINAL, disputes),
+            AfterWithdrawal => packed(Amount::ZERO, WITHDRAWAL, 0),
+            _ => None,
+        }
+    }
+
+    fn state(self) -> OperationState {
+        let amount = bits_amount(self.amount);
+        let disputes = self.state & DISPUTES_MASK;
+        match self.state >> STATE_SHIFT {
+            REGULAR => RegularDeposit {
+                amount,
+                disputes,
+                charged_back: Amount::ZERO,
+            },
+            DISPUTED => DisputedDeposit {
+                amount,
+                disputed: amount,
+                disputes,
+                charged_back: Amount::ZERO,
+            },
+            FINAL => FinalDeposit {
+                charged_back: amount,
+                disputes,
+            },
+            _ => AfterWithdrawal,
+        }
+    }
+}
+
+// Log of the operations of an account by transaction id. Only what later transactions can refer
+// to is kept: the states of plain deposits (regular, disputed in full or charged back) and of
+// withdrawals are packed into 12 bytes per entry, about half the size of a full OperationState
+// entry, and the rarer states (partial disputes, other currencies, conversions, bonuses, escrows)
+// are kept as they are next to them. With Config::withdrawal_ids_only, withdrawals are only
+// logged by id, in 4 bytes. Every transaction id is in one of the maps or the set at most, so the
+// log still detects duplicate ids.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Oplog {
+    packed: HashMap<u32, Packed>,
+    other: HashMap<u32, OperationState>,
+    withdrawals: HashSet<u32>,
+}
+
+impl Oplog {
+    pub(crate) fn get(&self, tx_id: u32) -> Option<OperationState> {
+        match self.packed.get(&tx_id) {
+            Some(packed) => Some(packed.state()),
+            None if self.withdrawals.contains(&tx_id) => Some(AfterWithdrawal),
+            None => self.other.get(&tx_id).copied(),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, tx_id: u32) -> bool {
+        self.packed.contains_key(&tx_id)
+            || self.withdrawals.contains(&tx_id)
+            || self.other.contains_key(&tx_id)
+    }
+
+    // Logs a withdrawal by id only. Withdrawals are never modified afterwards.
+    pub(crate) fn insert_withdrawal(&mut self, tx_id: u32) {
+        self.withdrawals.insert(tx_id);
+    }
+
+    pub(crate) fn insert(&mut self, tx_id: u32, op: OperationState) {
+        match Packed::new(&op) {
+            Some(packed) => {
+                self.other.remove(&tx_id);
+                self.packed.insert(tx_id, packed);
+            }
+            None => {
+                self.packed.remove(&tx_id);
+                self.other.insert(tx_id, op);
+            }
+        }
+    }
+
+    // Removes a transaction, when undoing it.
+    pub(crate) fn remove(&mut self, tx_id: u32) {
+        self.packed.remove(&tx_id);
+        self.other.remove(&tx_id);
+        self.withdrawals.remove(&tx_id);
+    }
+
+    // Iterates over the log, as (transaction id, state) pairs in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u32, OperationState)> + '_ {
+        let packed = self.packed.iter().map(|(tx_id, p)| (*tx_id, p.state()));
+        let withdrawals = self
+            .withdrawals
+            .iter()
+            .map(|tx_id| (*tx_id, AfterWithdrawal));
+        packed
+            .chain(withdrawals)
+            .chain(self.other.iter().map(|(tx_id, op)| (*tx_id, *op)))
+    }
+}
+
+// In snapshots, the log is a map of transaction id to state, sorted by transaction id.
+impl Serialize for Oplog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let sorted: BTreeMap<u32, OperationState> = self.iter().collect();
+        sorted.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Oplog {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Oplog, D::Error> {
+        let mut oplog = Oplog::default();
+        for (tx_id, op) in HashMap::<u32, OperationState>::deserialize(deserializer)? {
+            oplog.insert(tx_id, op);
+        }
+        Ok(oplog)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    // States of plain deposits and withdrawals, which are packed.
+    fn packable() -> Vec<OperationState> {
+        vec![
+            RegularDeposit {
+                amount: amount("12.5"),
+                disputes: 0,
+                charged_back: Amount::ZERO,
+            },
+            RegularDeposit {
+                amount: amount("-3.25"),
+                disputes: 7,
+                charged_back: Amount::ZERO,
+            },
+            DisputedDeposit {
+                amount: amount("0.0001"),
+                disputed: amount("0.0001"),
+                disputes: 1,
+                charged_back: Amount::ZERO,
+            },
+            FinalDeposit {
+                charged_back: amount("40"),
+                disputes: 2,
+            },
+            AfterWithdrawal,
+        ]
+    }
+
+    #[test]
+    fn packs_plain_states_and_unpacks_them_as_they_were() {
+        let mut oplog = Oplog::default();
+        for (tx_id, op) in (1..).zip(packable()) {
+            oplog.insert(tx_id, op);
+            assert!(oplog.packed.contains_key(&tx_id), "{:?}", op);
+            assert_eq!(oplog.get(tx_id), Some(op));
+        }
+        assert!(oplog.other.is_empty());
+    }
+
+    #[test]
+    fn keeps_other_states_as_they_are() {
+        let others = [
+            // Disputed in part, or after a partial chargeback.
+            DisputedDeposit {
+                amount: amount("10"),
+                disputed: amount("4"),
+                disputes: 1,
+                charged_back: Amount::ZERO,
+            },
+            RegularDeposit {
+                amount: amount("6"),
+                disputes: 1,
+                charged_back: amount("4"),
+            },
+            RepresentedDeposit {
+                amount: amount("10"),
+                disputed: amount("10"),
+                disputes: 1,
+                charged_back: Amount::ZERO,
+            },
+            // More disputes than the state word holds.
+            RegularDeposit {
+                amount: amount("1"),
+                disputes: DISPUTES_MASK + 1,
+                charged_back: Amount::ZERO,
+            },
+            BonusDeposit {
+                amount: amount("5"),
+            },
+        ];
+        let mut oplog = Oplog::default();
+        for (tx_id, op) in (1..).zip(others) {
+            oplog.insert(tx_id, op);
+            assert!(oplog.other.contains_key(&tx_id), "{:?}", op);
+            assert_eq!(oplog.get(tx_id), Some(op));
+        }
+        assert!(oplog.packed.is_empty());
+    }
+
+    #[cfg(feature = "minor-units")]
+    #[test]
+    fn keeps_amounts_beyond_the_packed_units_as_they_are() {
+        let mut oplog = Oplog::default();
+        let large = RegularDeposit {
+            amount: amount("13421.7728"), // 2^27 units
+            disputes: 0,
+            charged_back: Amount::ZERO,
+        };
+        oplog.insert(1, large);
+        assert!(oplog.other.contains_key(&1));
+        assert_eq!(oplog.get(1), Some(large));
+    }
+
+    #[test]
+    fn entries_move_between_the_maps_as_their_state_changes() {
+        let mut oplog = Oplog::default();
+        let deposit = packable()[0];
+        oplog.insert(1, deposit);
+        let partly_disputed = DisputedDeposit {
+            amount: amount("12.5"),
+            disputed: amount("2"),
+            disputes: 1,
+            charged_back: Amount::ZERO,
+        };
+        oplog.insert(1, partly_disputed);
+        assert!(!oplog.packed.contains_key(&1));
+        assert_eq!(oplog.get(1), Some(partly_disputed));
+        oplog.insert(1, deposit);
+        assert!(!oplog.other.contains_key(&1));
+        assert_eq!(oplog.get(1), Some(deposit));
+        oplog.insert_withdrawal(2);
+        assert_eq!(oplog.get(2), Some(AfterWithdrawal));
+        for tx_id in [1, 2] {
+            assert!(oplog.contains_key(tx_id));
+            oplog.remove(tx_id);
+            assert!(!oplog.contains_key(tx_id));
+        }
+        assert_eq!(oplog.iter().count(), 0);
+    }
+
+    #[test]
+    fn serializes_by_transaction_id_and_reads_back_the_same_log() {
+        let mut oplog = Oplog::default();
+        for (tx_id, op) in [5, 3, 9, 1, 4].into_iter().zip(packable()) {
+            oplog.insert(tx_id, op);
+        }
+        oplog.insert(
+            2,
+            BonusDeposit {
+                amount: amount("5"),
+            },
+        );
+        oplog.insert_withdrawal(7);
+        let json = serde_json::to_string(&oplog).unwrap();
+        let ids: Vec<u32> = serde_json::from_str::<BTreeMap<u32, OperationState>>(&json)
+            .unwrap()
+            .into_keys()
+            .collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 7, 9]);
+        assert!(json.find("\"1\"") < json.find("\"9\""));
+        let read: Oplog = serde_json::from_str(&json).unwrap();
+        let sorted = |o: &Oplog| o.iter().collect::<BTreeMap<_, _>>();
+        assert_eq!(sorted(&read), sorted(&oplog));
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+    }
+}
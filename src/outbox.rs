@@ -0,0 +1,226 @@
+use crate::cdc;
+use crate::events::Event;
+use crate::output::Decimals;
+use crate::postgres::{self, PostgresSink};
+use anyhow::{anyhow, Result};
+use ledger::{Applied, Ledger};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+// Messages per dispatched batch, locked together in the outbox table.
+const BATCH_MESSAGES: usize = 100;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: u64 = 5; // Seconds
+
+// Default table of the dispatcher.
+pub const OUTBOX_TABLE: &str = "ledger_outbox";
+
+// Outbound message, as stored in the outbox table.
+#[derive(Debug)]
+pub struct Message {
+    pub topic: &'static str,
+    pub payload: String, // JSON
+}
+
+// Outbound messages of the run (--sink-outbox-table): every applied operation, as streamed with
+// --events-out (topic "event"), and every change of the balances, as written with --cdc-out
+// (topic "cdc"). They are kept until the end of the run, when the sink inserts them into the
+// outbox table in the transaction writing the accounts, so that a crash leaves either the new
+// states along with their messages or neither. Delivering them is left to `ledger
+// dispatch-outbox`.
+pub struct Outbox {
+    messages: Vec<Message>,
+    decimals: Decimals,
+}
+
+impl Outbox {
+    pub fn new(decimals: Decimals) -> Outbox {
+        Outbox {
+            messages: Vec::new(),
+            decimals,
+        }
+    }
+
+    pub fn record(&mut self, applied: &Applied, l: &Ledger) -> serde_json::Result<()> {
+        self.messages.push(Message {
+            topic: "event",
+            payload: serde_json::to_string(&Event::of(applied, &self.decimals))?,
+        });
+        for change in cdc::changes(applied, l, &self.decimals) {
+            self.messages.push(Message {
+                topic: "cdc",
+                payload: serde_json::to_string(&change)?,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+}
+
+// Receiver of the dispatched messages, from an http://host[:port]/path URL.
+struct Webhook {
+    host: String, // host[:port]
+    path: String,
+}
+
+impl Webhook {
+    fn parse(url: &str) -> Result<Webhook> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow! {"unsupported webhook {} (expected http://...)", url})?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(anyhow! {"invalid webhook {}: missing host", url});
+        }
+        Ok(Webhook {
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    // Posts the payload of a message, with its id as the idempotency key: a message posted again
+    // after a crash of the dispatcher has the same key, for the receiver to ignore it.
+    fn post(&self, id: &str, topic: &str, payload: &str) -> Result<()> {
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:80", self.host)
+        };
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Idempotency-Key: {}\r\nX-Ledger-Topic: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            payload.len(),
+            id,
+            topic
+        );
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(payload.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head.lines().next().unwrap_or_default();
+        if !status
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|code| code.starts_with('2'))
+        {
+            return Err(anyhow! {"{} {}", status, body.trim()});
+        }
+        Ok(())
+    }
+}
+
+// Posts the oldest undispatched messages, in order, and marks those delivered as dispatched, in
+// a transaction locking them so that several dispatchers don't post the same messages. Returns
+// the number of messages delivered, and the error which stopped the batch, if any.
+fn dispatch(
+    sink: &PostgresSink,
+    table: &str,
+    webhook: &Webhook,
+) -> Result<(usize, Option<anyhow::Error>)> {
+    let mut c = sink.connect()?;
+    c.query("BEGIN")?;
+    let rows = c.execute(
+        &format!(
+            "SELECT id, topic, payload FROM {} WHERE dispatched_at IS NULL ORDER BY id LIMIT $1 \
+             FOR UPDATE SKIP LOCKED",
+            table
+        ),
+        &[Some(BATCH_MESSAGES.to_string())],
+    )?;
+    let mut delivered = Vec::new();
+    let mut failure = None;
+    for row in &rows {
+        let [Some(id), Some(topic), Some(payload)] = row.as_slice() else {
+            return Err(anyhow! {"unexpected row in outbox table {}", table});
+        };
+        match webhook.post(id, topic, payload) {
+            Ok(()) => delivered.push(id.as_str()),
+            Err(e) => {
+                failure = Some(anyhow! {"message {} not delivered: {}", id, e});
+                break;
+            }
+        }
+    }
+    if !delivered.is_empty() {
+        c.execute(
+            &format!(
+                "UPDATE {} SET dispatched_at = now() WHERE id = ANY($1::bigint[])",
+                table
+            ),
+            &[Some(format!("{{{}}}", delivered.join(",")))],
+        )?;
+    }
+    c.query("COMMIT")?;
+    Ok((delivered.len(), failure))
+}
+
+// `ledger dispatch-outbox <postgres://...> --webhook http://host:port/path [--outbox-table t]
+// [--poll-interval SECONDS] [--once]`: delivers the messages of the outbox table (see Outbox) to
+// the webhook, one POST per message in the order they were inserted, and polls for more until
+// interrupted. A message is marked as dispatched once the webhook accepted it (any 2xx status);
+// failed deliveries are retried at the next poll, so that no message is lost. With --once, the
+// dispatcher stops when the outbox is drained, or on the first failure.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut url, mut webhook, mut table, mut interval, mut once) = (None, None, None, None, false);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--webhook" => webhook = Some(Webhook::parse(value()?)?),
+            "--outbox-table" => table = Some(postgres::check_table(value()?)?),
+            "--poll-interval" => {
+                let v = value()?;
+                interval = Some(
+                    v.parse()
+                        .map_err(|_| anyhow! {"invalid poll interval {}", v})?,
+                )
+            }
+            "--once" => once = true,
+            _ if url.is_none() && !arg.starts_with("--") => url = Some(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let url = url.ok_or_else(|| anyhow! {"dispatch-outbox requires the database URL"})?;
+    let sink = PostgresSink::parse(&url)?;
+    let webhook = webhook.ok_or_else(|| anyhow! {"dispatch-outbox requires the --webhook"})?;
+    let table = table.unwrap_or_else(|| OUTBOX_TABLE.to_string());
+    let interval = Duration::from_secs(interval.unwrap_or(POLL_INTERVAL));
+    let mut total = 0;
+    loop {
+        let (delivered, failure) = match dispatch(&sink, &table, &webhook) {
+            Ok(result) => result,
+            Err(e) => (0, Some(e)),
+        };
+        total += delivered;
+        if let Some(e) = failure {
+            if once {
+                println!("Dispatched {} messages", total);
+                return Err(e);
+            }
+            eprintln!("Error occurred while dispatching outbox: {}", e);
+        } else if delivered > 0 {
+            continue;
+        } else if once {
+            println!("Dispatched {} messages", total);
+            return Ok(());
+        }
+        thread::sleep(interval);
+    }
+}
@@ -0,0 +1,1333 @@
+use crate::metadata::RunMetadata;
+use crate::rejects::Rejection;
+use crate::xlsx::{Cell, Workbook};
+use crate::{Options, Summary};
+use anyhow::{anyhow, Error};
+use ledger::{Account, Amount, Currency, Ledger, Money, OperationState, MAIN_SUBACCOUNT};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::thread;
+
+// Formats in which the final state of the ledger can be written out. Csv is the default, machine
+// readable format; Table is meant for humans running the tool interactively. Json, Yaml and Xml
+// produce a single document holding the run metadata, the accounts and the summary (see write_xml
+// for the XML schema). Xlsx produces an Excel workbook with the balances, the summary and the
+// rejections on separate sheets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Table,
+    Json,
+    Yaml,
+    Xml,
+    Xlsx,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "xml" => Ok(OutputFormat::Xml),
+            "xlsx" => Ok(OutputFormat::Xlsx),
+            _ => Err(
+                anyhow! {"unknown output format {} (expected csv, table, json, yaml, xml or xlsx)", s},
+            ),
+        }
+    }
+}
+
+// Whether human-facing output should be colored. Auto colors only when writing to a terminal and
+// NO_COLOR is not set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Auto => is_terminal && std::env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(anyhow! {"unknown color choice {} (expected auto, always or never)", s}),
+        }
+    }
+}
+
+// Number formatting of the human-facing outputs (--locale): the table output uses the thousands
+// and decimal separators of the locale, while the xlsx output groups thousands in its number
+// formats, the spreadsheet application showing them with the separators of its own locale. The
+// machine-readable formats always write the canonical 1234.5678.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Locale {
+    thousands: Option<char>,
+    decimal: char,
+}
+
+impl FromStr for Locale {
+    type Err = Error;
+
+    // Accepts POSIX names (de_DE.UTF-8) as well as language tags (de-DE, de). The separators
+    // depend on the language, except for Switzerland.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.split('.').next().unwrap_or_default().replace('_', "-");
+        let (language, region) = name.split_once('-').unwrap_or((&name, ""));
+        let separators = match (language.to_ascii_lowercase().as_str(), region) {
+            ("c" | "posix", _) => (None, '.'),
+            ("de" | "fr" | "it", "CH") => (Some('\''), '.'),
+            ("en" | "ja" | "zh" | "ko" | "he" | "th" | "ms", _) => (Some(','), '.'),
+            (
+                "de" | "es" | "it" | "nl" | "pt" | "da" | "id" | "tr" | "el" | "ro" | "hr" | "sl"
+                | "sr",
+                _,
+            ) => (Some('.'), ','),
+            (
+                "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "nb" | "no" | "fi" | "uk" | "hu" | "bg"
+                | "lt" | "lv" | "et",
+                _,
+            ) => (Some('\u{a0}'), ','),
+            _ => return Err(anyhow! {"unsupported locale {}", s}),
+        };
+        Ok(Locale {
+            thousands: separators.0,
+            decimal: separators.1,
+        })
+    }
+}
+
+impl Locale {
+    // The cell with its numbers (the cell may be a single amount, a list of currency balances or
+    // a percentage) written with the separators of the locale: "-1234.5678" reads "-1.234,5678"
+    // in German.
+    pub fn localize(&self, cell: &str) -> String {
+        let mut localized = String::with_capacity(cell.len() + cell.len() / 3);
+        let mut rest = cell;
+        while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+            localized.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            let (integer, decimals) = rest[..end].split_once('.').unwrap_or((&rest[..end], ""));
+            for (i, digit) in integer.chars().enumerate() {
+                if i > 0 && (integer.len() - i) % 3 == 0 {
+                    localized.extend(self.thousands);
+                }
+                localized.push(digit);
+            }
+            if !decimals.is_empty() {
+                localized.push(self.decimal);
+                localized.push_str(decimals);
+            }
+            rest = &rest[end..];
+        }
+        localized.push_str(rest);
+        localized
+    }
+}
+
+// Columns whose decimal places can be set, "amount" being the amount of the transactions in the
+// events sinks.
+const DECIMAL_COLUMNS: [&str; 10] = [
+    "available",
+    "held",
+    "escrow",
+    "total",
+    "credit_limit",
+    "credit_utilization",
+    "currencies",
+    "reporting_total",
+    "fees",
+    "amount",
+];
+
+// Decimal places of the amounts in the outputs and sinks (--decimals), 4 by default, possibly
+// different for some columns. None writes the amounts in full, as the shortest decimal
+// representation of the f32 value (or all the decimal places of minor units), e.g. for
+// reconciliation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decimals {
+    default: Option<usize>,
+    columns: BTreeMap<&'static str, Option<usize>>,
+}
+
+impl Default for Decimals {
+    fn default() -> Decimals {
+        Decimals {
+            default: Some(4),
+            columns: BTreeMap::new(),
+        }
+    }
+}
+
+impl Decimals {
+    // Applies a --decimals value: a number of decimal places (or "full") for all columns, or
+    // comma-separated column=places pairs for some of them, e.g. "2" or "available=2,fees=full".
+    pub fn set(&mut self, spec: &str) -> Result<(), Error> {
+        let places = |s: &str| match s {
+            "full" => Ok(None),
+            _ => s
+                .parse()
+                .map(Some)
+                .map_err(|_| anyhow! {"invalid decimal places {} (expected a number or full)", s}),
+        };
+        if !spec.contains('=') {
+            self.default = places(spec)?;
+            self.columns.clear();
+            return Ok(());
+        }
+        for pair in spec.split(',') {
+            let (column, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let Some(column) = DECIMAL_COLUMNS.iter().find(|c| **c == column.trim()) else {
+                return Err(anyhow! {
+                    "unknown column {} for --decimals (expected one of {})",
+                    column,
+                    DECIMAL_COLUMNS.join(", ")
+                });
+            };
+            self.columns.insert(column, places(value.trim())?);
+        }
+        Ok(())
+    }
+
+    fn places(&self, column: &str) -> Option<usize> {
+        self.columns.get(column).copied().unwrap_or(self.default)
+    }
+
+    // The amount (or ratio) as written in text outputs.
+    pub fn format(&self, column: &str, v: impl Money + Display) -> String {
+        match self.places(column) {
+            Some(places) => format!("{:.*}", places, v),
+            None => v.to_string(),
+        }
+    }
+
+    // The amount as a number for the JSON output and the sinks, rounded so that it doesn't expose
+    // the float representation noise (e.g. 0.30000001).
+    pub fn round(&self, column: &str, v: impl Money + Display) -> f64 {
+        match self.places(column) {
+            Some(places) => {
+                let scale = 10f64.powi(places as i32);
+                (v.to_f64() * scale).round() / scale
+            }
+            None => v.to_string().parse().unwrap_or(v.to_f64()),
+        }
+    }
+}
+
+// ANSI escape sequences used by the table output.
+// Accounts formatted by an output thread at a time, see write_csv.
+const OUTPUT_CHUNK: usize = 4096;
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+// Flattened view of an account, as it appears in the output.
+struct Row {
+    client_id: u16,
+    available: Amount,
+    held: Amount,
+    escrow: Amount,
+    total: Amount,
+    locked: bool,
+    credit: Option<Credit>,              // Only for clients with a credit line
+    currencies: Vec<(Currency, Amount)>, // Balances in other currencies than the base currency
+    // Total over all currencies in the reporting currency, if one is configured. None when a rate
+    // is missing.
+    reporting_total: Option<Amount>,
+    fees: Amount, // Fees charged so far
+    // For clients using sub-accounts, the row of each of them (the main balance first), while the
+    // row of the client itself rolls them up. None and empty for the rows of sub-accounts.
+    subaccount: Option<String>,
+    subaccounts: Vec<Row>,
+}
+
+struct Credit {
+    limit: Amount,
+    utilization: f32,
+}
+
+fn row(l: &Ledger, client_id: u16, account: &Account) -> Row {
+    let b = account.rollup();
+    let mut subaccounts = Vec::new();
+    if account.subaccounts().next().is_some() {
+        subaccounts.push(subaccount_row(client_id, MAIN_SUBACCOUNT, account));
+        for (name, a) in account.subaccounts() {
+            subaccounts.push(subaccount_row(client_id, name, a));
+        }
+    }
+    let mut currencies: BTreeMap<Currency, Amount> = BTreeMap::new();
+    for r in subaccounts.iter().filter(|r| !r.currencies.is_empty()) {
+        for (c, balance) in &r.currencies {
+            *currencies.entry(*c).or_default() += *balance;
+        }
+    }
+    Row {
+        client_id,
+        available: b.available,
+        held: b.held,
+        escrow: b.escrow,
+        total: b.total,
+        locked: b.locked,
+        // Credit lines are drawn on the main balance.
+        credit: l.config().credit_line(client_id).map(|c| Credit {
+            limit: c.limit,
+            utilization: c.utilization(account.available()),
+        }),
+        currencies: if subaccounts.is_empty() {
+            account.currencies().collect()
+        } else {
+            currencies.into_iter().collect()
+        },
+        reporting_total: l
+            .config()
+            .reporting_currency
+            .and_then(|c| l.total_in(client_id, c)),
+        fees: account.fees() + account.subaccounts().map(|(_, a)| a.fees()).sum::<Amount>(),
+        subaccount: None,
+        subaccounts,
+    }
+}
+
+fn subaccount_row(client_id: u16, name: &str, a: &Account) -> Row {
+    let b = a.balance();
+    Row {
+        client_id,
+        available: b.available,
+        held: b.held,
+        escrow: b.escrow,
+        total: b.total,
+        locked: b.locked,
+        credit: None,
+        currencies: a.currencies().collect(),
+        reporting_total: None,
+        fees: a.fees(),
+        subaccount: Some(name.to_string()),
+        subaccounts: Vec::new(),
+    }
+}
+
+// The rows of the sub-accounts of a client followed by the row of the client, as listed by the
+// CSV and table outputs.
+fn flattened(mut r: Row) -> impl Iterator<Item = Row> {
+    std::mem::take(&mut r.subaccounts)
+        .into_iter()
+        .chain(std::iter::once(r))
+}
+
+// Balances in other currencies, as they appear in the currencies column: "EUR:1.5000 GBP:2.0000".
+fn currencies_cell(r: &Row, d: &Decimals) -> String {
+    let balances: Vec<String> = r
+        .currencies
+        .iter()
+        .map(|(c, balance)| format!("{}:{}", c, d.format("currencies", *balance)))
+        .collect();
+    balances.join(" ")
+}
+
+// Columns of the CSV and table outputs beyond the original client, available, held, total and
+// locked ones. Each only appears when the ledger uses the corresponding feature, so that the
+// output keeps its original format otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Column {
+    Subaccount,
+    Escrow,
+    CreditLimit,
+    CreditUtilization,
+    Currencies,
+    ReportingTotal,
+    Fees,
+}
+
+fn extra_columns(l: &Ledger) -> Vec<Column> {
+    let config = l.config();
+    let mut columns = Vec::new();
+    if has_subaccounts(l) {
+        columns.push(Column::Subaccount);
+    }
+    if has_escrow(l) {
+        columns.push(Column::Escrow);
+    }
+    if !config.credit_lines.is_empty() {
+        columns.extend([Column::CreditLimit, Column::CreditUtilization]);
+    }
+    if l.accounts().any(|(_, a)| a.currencies().next().is_some()) {
+        columns.push(Column::Currencies);
+    }
+    if config.reporting_currency.is_some() {
+        columns.push(Column::ReportingTotal);
+    }
+    if has_fees(l) {
+        columns.push(Column::Fees);
+    }
+    columns
+}
+
+fn has_subaccounts(l: &Ledger) -> bool {
+    l.accounts().any(|(_, a)| a.subaccounts().next().is_some())
+}
+
+// Whether any account ever put funds in escrow.
+fn has_escrow(l: &Ledger) -> bool {
+    let mut accounts = l
+        .accounts()
+        .flat_map(|(_, a)| a.subaccounts().map(|(_, a)| a).chain([a]));
+    accounts.any(|a| {
+        a.escrow() != Amount::ZERO
+            || a.operations().any(|(_, op)| {
+                matches!(
+                    op,
+                    OperationState::Escrow { .. } | OperationState::EscrowReleased
+                )
+            })
+    })
+}
+
+// Whether fees are charged at all, by the fee schedule or on credit line draws.
+fn has_fees(l: &Ledger) -> bool {
+    let config = l.config();
+    !config.fees.is_empty()
+        || config
+            .credit_lines
+            .iter()
+            .any(|c| c.draw_fee > Amount::ZERO)
+}
+
+impl Column {
+    fn csv_title(self) -> &'static str {
+        match self {
+            Column::Subaccount => "subaccount",
+            Column::Escrow => "escrow",
+            Column::CreditLimit => "credit_limit",
+            Column::CreditUtilization => "credit_utilization",
+            Column::Currencies => "currencies",
+            Column::ReportingTotal => "reporting_total",
+            Column::Fees => "fees",
+        }
+    }
+
+    fn table_title(self, l: &Ledger) -> String {
+        match self {
+            Column::Subaccount => "subaccount".to_string(),
+            Column::Escrow => "escrow".to_string(),
+            Column::CreditLimit => "credit limit".to_string(),
+            Column::CreditUtilization => "utilization".to_string(),
+            Column::Currencies => "currencies".to_string(),
+            Column::ReportingTotal => match l.config().reporting_currency {
+                Some(c) => format!("total {}", c),
+                None => "total".to_string(),
+            },
+            Column::Fees => "fees".to_string(),
+        }
+    }
+
+    // The cell of the column for the given row, empty when it does not apply to the account.
+    fn cell(self, r: &Row, table: bool, d: &Decimals) -> String {
+        let amount =
+            |column, v: Option<Amount>| v.map_or_else(String::new, |v| d.format(column, v));
+        match self {
+            // The row of the client rolling up its sub-accounts has an empty cell.
+            Column::Subaccount => r.subaccount.clone().unwrap_or_default(),
+            Column::Escrow => amount("escrow", Some(r.escrow)),
+            Column::CreditLimit => amount("credit_limit", r.credit.as_ref().map(|c| c.limit)),
+            Column::CreditUtilization if table => r
+                .credit
+                .as_ref()
+                .map_or_else(String::new, |c| format!("{:.1}%", c.utilization * 100.0)),
+            Column::CreditUtilization => r.credit.as_ref().map_or_else(String::new, |c| {
+                d.format("credit_utilization", c.utilization)
+            }),
+            Column::Currencies => currencies_cell(r, d),
+            Column::ReportingTotal => amount("reporting_total", r.reporting_total),
+            Column::Fees => amount("fees", Some(r.fees)),
+        }
+    }
+}
+
+// Rows sorted by client id, so that human-facing output is stable between runs.
+fn sorted_rows(l: &Ledger) -> Vec<Row> {
+    let mut rows: Vec<Row> = l.accounts().map(|(aid, a)| row(l, aid, a)).collect();
+    rows.sort_by_key(|r| r.client_id);
+    rows
+}
+
+pub fn write(
+    options: &Options,
+    color: bool,
+    l: &Ledger,
+    summary: &Summary,
+    rejections: &[Rejection],
+    metadata: &RunMetadata,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let d = &options.decimals;
+    match options.output_format {
+        OutputFormat::Csv => write_csv(
+            l,
+            options.csv_metadata.then_some(metadata),
+            d,
+            options.output_threads,
+            out,
+        ),
+        OutputFormat::Table => write_table(l, summary, color, d, options.locale, out),
+        OutputFormat::Json => write_json(l, summary, metadata, d, out),
+        OutputFormat::Yaml => write_yaml(l, summary, metadata, d, out),
+        OutputFormat::Xml => write_xml(l, summary, metadata, d, out),
+        OutputFormat::Xlsx => write_xlsx(l, summary, rejections, metadata, d, options.locale, out),
+    }
+}
+
+// Rows of the accounts, as written by write_csv.
+fn write_csv_rows<'a>(
+    l: &Ledger,
+    accounts: impl Iterator<Item = (u16, &'a Account)>,
+    columns: &[Column],
+    d: &Decimals,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for r in accounts.flat_map(|(aid, a)| flattened(row(l, aid, a))) {
+        write!(
+            out,
+            "{},{},{},{},{}",
+            r.client_id,
+            d.format("available", r.available),
+            d.format("held", r.held),
+            d.format("total", r.total),
+            r.locked
+        )?;
+        for c in columns {
+            write!(out, ",{}", c.cell(&r, false, d))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+// The CSV output optionally starts with the run metadata as "# key: value" comment lines. With
+// more than one thread (--output-threads), the rows are formatted in chunks of accounts on that
+// many threads, a round of chunks at a time, while the rows of the round before are written out,
+// in the same order.
+pub fn write_csv(
+    l: &Ledger,
+    metadata: Option<&RunMetadata>,
+    d: &Decimals,
+    threads: usize,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if let Some(m) = metadata {
+        writeln!(out, "# schema_version: {}", m.schema_version)?;
+        writeln!(out, "# tool_version: {}", m.tool_version)?;
+        writeln!(out, "# generated_at: {}", m.generated_at)?;
+        writeln!(out, "# input_sha256: {}", m.input_sha256)?;
+        writeln!(out, "# config_sha256: {}", m.config_sha256)?;
+    }
+    let columns = extra_columns(l);
+    write!(out, "client,available,held,total,locked")?;
+    for c in &columns {
+        write!(out, ",{}", c.csv_title())?;
+    }
+    writeln!(out)?;
+    if threads <= 1 {
+        return write_csv_rows(l, l.accounts(), &columns, d, out);
+    }
+    let mut accounts = l.accounts();
+    let mut formatted: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let round: Vec<Vec<(u16, &Account)>> = (0..threads)
+            .map(|_| accounts.by_ref().take(OUTPUT_CHUNK).collect::<Vec<_>>())
+            .take_while(|chunk| !chunk.is_empty())
+            .collect();
+        let columns = &columns;
+        let (next, written) = thread::scope(|s| {
+            let handles: Vec<_> = round
+                .into_iter()
+                .map(|chunk| {
+                    s.spawn(move || {
+                        let mut buf = Vec::new();
+                        write_csv_rows(l, chunk.into_iter(), columns, d, &mut buf).map(|_| buf)
+                    })
+                })
+                .collect();
+            let written = formatted.iter().try_for_each(|buf| out.write_all(buf));
+            let next: io::Result<Vec<Vec<u8>>> = handles
+                .into_iter()
+                .map(|h| {
+                    h.join()
+                        .unwrap_or_else(|_| Err(io::Error::other("output thread panicked")))
+                })
+                .collect();
+            (next, written)
+        });
+        written?;
+        formatted = next?;
+        if formatted.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+// Columns of the rows written by FinalizedRows: the accounts to come aren't known when the header
+// is written, so the columns of the features a ledger may use without configuring them are always
+// there, and those of the configured features as in extra_columns.
+fn finalized_columns(l: &Ledger) -> Vec<Column> {
+    let config = l.config();
+    let mut columns = vec![Column::Subaccount, Column::Escrow];
+    if !config.credit_lines.is_empty() {
+        columns.extend([Column::CreditLimit, Column::CreditUtilization]);
+    }
+    columns.push(Column::Currencies);
+    if config.reporting_currency.is_some() {
+        columns.push(Column::ReportingTotal);
+    }
+    if has_fees(l) {
+        columns.push(Column::Fees);
+    }
+    columns
+}
+
+// CSV output of --stream-finalized: the rows of an account are written (and flushed) as soon as
+// it is final, rather than at the end of the run, and the rows of the accounts which weren't
+// written by then are written by finish.
+pub struct FinalizedRows<W: Write> {
+    out: W,
+    columns: Vec<Column>,
+    decimals: Decimals,
+    current: Option<u16>, // Client of the records being read
+    closed: Vec<bool>,    // Whether the records of a client are over, by client id
+    written: Vec<bool>,   // Whether the rows of an account were written, by client id
+}
+
+impl<W: Write> FinalizedRows<W> {
+    pub fn create(l: &Ledger, decimals: Decimals, mut out: W) -> io::Result<FinalizedRows<W>> {
+        let columns = finalized_columns(l);
+        write!(out, "client,available,held,total,locked")?;
+        for c in &columns {
+            write!(out, ",{}", c.csv_title())?;
+        }
+        writeln!(out)?;
+        out.flush()?;
+        Ok(FinalizedRows {
+            out,
+            columns,
+            decimals,
+            current: None,
+            closed: vec![false; usize::from(u16::MAX) + 1],
+            written: vec![false; usize::from(u16::MAX) + 1],
+        })
+    }
+
+    // Takes note of a record of the client, before it is applied. A record of another client than
+    // the one before closes the records of that one, whose rows are written if the account is
+    // final (see Ledger::is_final), and left for finish otherwise. Returns false for a record of
+    // a client whose records were closed already, which can't be applied.
+    pub fn record(&mut self, l: &Ledger, client_id: u16) -> io::Result<bool> {
+        if self.current == Some(client_id) {
+            return Ok(true);
+        }
+        if self.closed[usize::from(client_id)] {
+            return Ok(false);
+        }
+        if let Some(previous) = self.current.replace(client_id) {
+            self.closed[usize::from(previous)] = true;
+            if l.is_final(previous) {
+                self.write(l, previous)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn write(&mut self, l: &Ledger, client_id: u16) -> io::Result<()> {
+        let Some(a) = l.account(client_id) else {
+            return Ok(());
+        };
+        self.written[usize::from(client_id)] = true;
+        let account = std::iter::once((client_id, a));
+        write_csv_rows(l, account, &self.columns, &self.decimals, &mut self.out)?;
+        self.out.flush()
+    }
+
+    // Writes the rows of the accounts not written yet, in the order of the accounts.
+    pub fn finish(mut self, l: &Ledger) -> io::Result<()> {
+        let written = &self.written;
+        let accounts = l.accounts().filter(|(c, _)| !written[usize::from(*c)]);
+        write_csv_rows(l, accounts, &self.columns, &self.decimals, &mut self.out)?;
+        self.out.flush()
+    }
+}
+
+fn write_table(
+    l: &Ledger,
+    summary: &Summary,
+    color: bool,
+    d: &Decimals,
+    locale: Option<Locale>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let localized = |cell: String| match locale {
+        Some(locale) => locale.localize(&cell),
+        None => cell,
+    };
+    let columns = extra_columns(l);
+    let mut header: Vec<String> = ["client", "available", "held", "total", "locked"]
+        .map(String::from)
+        .to_vec();
+    header.extend(columns.iter().map(|c| c.table_title(l)));
+    let cells: Vec<(Vec<String>, bool)> = sorted_rows(l)
+        .into_iter()
+        .flat_map(flattened)
+        .map(|r| {
+            let mut cells = vec![
+                r.client_id.to_string(),
+                localized(d.format("available", r.available)),
+                localized(d.format("held", r.held)),
+                localized(d.format("total", r.total)),
+                if r.locked { "yes" } else { "no" }.to_string(),
+            ];
+            cells.extend(columns.iter().map(|c| match c {
+                Column::Subaccount => c.cell(&r, true, d),
+                _ => localized(c.cell(&r, true, d)),
+            }));
+            (cells, r.locked)
+        })
+        .collect();
+
+    // Widths in characters, as padding counts them: locale separators may take several bytes.
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for (row, _) in &cells {
+        for (w, c) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(c.chars().count());
+        }
+    }
+
+    let (bold, red, reset) = if color {
+        (BOLD, RED, RESET)
+    } else {
+        ("", "", "")
+    };
+    let line: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+
+    let titles: Vec<String> = header
+        .iter()
+        .zip(&widths)
+        .map(|(h, w)| format!("{:>w$}", h, w = w))
+        .collect();
+    writeln!(out, "{}{}{}", bold, titles.join("  "), reset)?;
+    writeln!(out, "{}", line.join("  "))?;
+    for (row, locked) in &cells {
+        let padded: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:>w$}", c, w = w))
+            .collect();
+        if *locked {
+            writeln!(out, "{}{}{}", red, padded.join("  "), reset)?;
+        } else {
+            writeln!(out, "{}", padded.join("  "))?;
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{}Summary{}", bold, reset)?;
+    let entries = [
+        ("records read", summary.records),
+        ("applied", summary.applied),
+        ("rejected", summary.rejected),
+        ("accounts", l.len() as u64),
+    ];
+    let fees = (summary.fees > Amount::ZERO).then(|| localized(d.format("fees", summary.fees)));
+    let label_width = entries
+        .iter()
+        .map(|(k, _)| k.len())
+        .chain(summary.rejections.keys().map(|k| k.len() + 2))
+        .chain(fees.as_ref().map(|_| "fees charged".len()))
+        .max()
+        .unwrap_or(0);
+    for (label, value) in entries {
+        writeln!(out, "  {:<w$}  {}", label, value, w = label_width)?;
+        // The breakdown by reason is indented under the rejected count.
+        if label == "rejected" {
+            for (reason, count) in &summary.rejections {
+                writeln!(out, "    {:<w$}  {}", reason, count, w = label_width - 2)?;
+            }
+        }
+    }
+    if let Some(fees) = fees {
+        writeln!(out, "  {:<w$}  {}", "fees charged", fees, w = label_width)?;
+    }
+    Ok(())
+}
+
+// Rounds an amount to the four decimal places of the reports, so that JSON numbers don't expose
+// the float representation noise (e.g. 0.30000001). The balances outputs and the sinks use
+// Decimals instead.
+pub fn rounded(v: Amount) -> f64 {
+    (v.to_f64() * 10000.0).round() / 10000.0
+}
+
+#[derive(serde::Serialize)]
+struct JsonAccount {
+    client: u16,
+    available: f64,
+    held: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escrow: Option<f64>,
+    total: f64,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_utilization: Option<f64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Currency, f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reporting_total: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fees: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    subaccounts: Vec<JsonSubaccount>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonSubaccount {
+    name: String,
+    available: f64,
+    held: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escrow: Option<f64>,
+    total: f64,
+    locked: bool,
+}
+
+#[derive(serde::Serialize)]
+struct JsonSummary<'a> {
+    records: u64,
+    applied: u64,
+    rejected: u64,
+    rejections: &'a BTreeMap<&'static str, u64>,
+    accounts: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fees: Option<f64>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonDocument<'a> {
+    metadata: &'a RunMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reporting_currency: Option<Currency>,
+    accounts: Vec<JsonAccount>,
+    summary: JsonSummary<'a>,
+}
+
+fn write_json(
+    l: &Ledger,
+    summary: &Summary,
+    metadata: &RunMetadata,
+    d: &Decimals,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let fees = has_fees(l);
+    let escrow = has_escrow(l);
+    let doc = JsonDocument {
+        metadata,
+        reporting_currency: l.config().reporting_currency,
+        accounts: sorted_rows(l)
+            .into_iter()
+            .map(|r| JsonAccount {
+                client: r.client_id,
+                available: d.round("available", r.available),
+                held: d.round("held", r.held),
+                escrow: escrow.then(|| d.round("escrow", r.escrow)),
+                total: d.round("total", r.total),
+                locked: r.locked,
+                credit_limit: r.credit.as_ref().map(|c| d.round("credit_limit", c.limit)),
+                credit_utilization: r
+                    .credit
+                    .as_ref()
+                    .map(|c| d.round("credit_utilization", c.utilization)),
+                currencies: r
+                    .currencies
+                    .iter()
+                    .map(|(c, balance)| (*c, d.round("currencies", *balance)))
+                    .collect(),
+                reporting_total: r.reporting_total.map(|v| d.round("reporting_total", v)),
+                fees: fees.then(|| d.round("fees", r.fees)),
+                subaccounts: r
+                    .subaccounts
+                    .into_iter()
+                    .map(|s| JsonSubaccount {
+                        name: s.subaccount.unwrap_or_default(),
+                        available: d.round("available", s.available),
+                        held: d.round("held", s.held),
+                        escrow: escrow.then(|| d.round("escrow", s.escrow)),
+                        total: d.round("total", s.total),
+                        locked: s.locked,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        summary: JsonSummary {
+            records: summary.records,
+            applied: summary.applied,
+            rejected: summary.rejected,
+            rejections: &summary.rejections,
+            accounts: l.len(),
+            fees: fees.then(|| d.round("fees", summary.fees)),
+        },
+    };
+    serde_json::to_writer_pretty(&mut *out, &doc)?;
+    writeln!(out)
+}
+
+fn write_yaml(
+    l: &Ledger,
+    summary: &Summary,
+    metadata: &RunMetadata,
+    d: &Decimals,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "metadata:")?;
+    writeln!(out, "  schema_version: {}", metadata.schema_version)?;
+    writeln!(out, "  tool_version: \"{}\"", metadata.tool_version)?;
+    writeln!(out, "  generated_at: \"{}\"", metadata.generated_at)?;
+    writeln!(out, "  input_sha256: \"{}\"", metadata.input_sha256)?;
+    writeln!(out, "  config_sha256: \"{}\"", metadata.config_sha256)?;
+    if let Some(c) = l.config().reporting_currency {
+        writeln!(out, "reporting_currency: \"{}\"", c)?;
+    }
+    let fees = has_fees(l);
+    let escrow = has_escrow(l);
+    let rows = sorted_rows(l);
+    if rows.is_empty() {
+        writeln!(out, "accounts: []")?;
+    } else {
+        writeln!(out, "accounts:")?;
+    }
+    for r in rows {
+        writeln!(out, "  - client: {}", r.client_id)?;
+        writeln!(out, "    available: {}", d.format("available", r.available))?;
+        writeln!(out, "    held: {}", d.format("held", r.held))?;
+        if escrow {
+            writeln!(out, "    escrow: {}", d.format("escrow", r.escrow))?;
+        }
+        writeln!(out, "    total: {}", d.format("total", r.total))?;
+        writeln!(out, "    locked: {}", r.locked)?;
+        if let Some(c) = &r.credit {
+            writeln!(
+                out,
+                "    credit_limit: {}",
+                d.format("credit_limit", c.limit)
+            )?;
+            writeln!(
+                out,
+                "    credit_utilization: {}",
+                d.format("credit_utilization", c.utilization)
+            )?;
+        }
+        if !r.currencies.is_empty() {
+            writeln!(out, "    currencies:")?;
+        }
+        for (c, balance) in &r.currencies {
+            writeln!(out, "      {}: {}", c, d.format("currencies", *balance))?;
+        }
+        if let Some(total) = r.reporting_total {
+            writeln!(
+                out,
+                "    reporting_total: {}",
+                d.format("reporting_total", total)
+            )?;
+        }
+        if fees {
+            writeln!(out, "    fees: {}", d.format("fees", r.fees))?;
+        }
+        if !r.subaccounts.is_empty() {
+            writeln!(out, "    subaccounts:")?;
+        }
+        for s in &r.subaccounts {
+            writeln!(
+                out,
+                "      - name: \"{}\"",
+                s.subaccount.as_deref().unwrap_or_default()
+            )?;
+            writeln!(
+                out,
+                "        available: {}",
+                d.format("available", s.available)
+            )?;
+            writeln!(out, "        held: {}", d.format("held", s.held))?;
+            if escrow {
+                writeln!(out, "        escrow: {}", d.format("escrow", s.escrow))?;
+            }
+            writeln!(out, "        total: {}", d.format("total", s.total))?;
+            writeln!(out, "        locked: {}", s.locked)?;
+        }
+    }
+    writeln!(out, "summary:")?;
+    writeln!(out, "  records: {}", summary.records)?;
+    writeln!(out, "  applied: {}", summary.applied)?;
+    writeln!(out, "  rejected: {}", summary.rejected)?;
+    if summary.rejections.is_empty() {
+        writeln!(out, "  rejections: {{}}")?;
+    } else {
+        writeln!(out, "  rejections:")?;
+    }
+    for (reason, count) in &summary.rejections {
+        writeln!(out, "    {}: {}", reason, count)?;
+    }
+    writeln!(out, "  accounts: {}", l.len())?;
+    if fees {
+        writeln!(out, "  fees: {}", d.format("fees", summary.fees))?;
+    }
+    Ok(())
+}
+
+// The XML document has the following shape:
+//
+// <ledger schema="1">
+//   <metadata tool_version="0.1.0" generated_at="..." input_sha256="..." config_sha256="..."/>
+//   <account client="1" locked="false">
+//     <available>1.5000</available>
+//     <held>0.0000</held>
+//     <escrow>0.0000</escrow>                               (with escrow holds)
+//     <total>1.5000</total>
+//     <credit limit="100.0000" utilization="0.2500"/>  (only for clients with a credit line)
+//     <currency code="EUR">2.5000</currency>             (one per other currency held)
+//     <reporting_total currency="USD">4.2500</reporting_total>  (with a reporting currency)
+//     <fees>0.5000</fees>                                   (with a fee schedule)
+//     <subaccount name="savings" locked="false" available="1.0000" held="0.0000" total="1.0000"/>
+//                                                           (one per sub-account, after main)
+//   </account>
+//   <summary records="8" applied="4" rejected="4" accounts="2" fees="0.5000">
+//     <rejection reason="insufficient_funds" count="1"/>
+//   </summary>
+// </ledger>
+fn write_xml(
+    l: &Ledger,
+    summary: &Summary,
+    metadata: &RunMetadata,
+    d: &Decimals,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<ledger schema="{}">"#, metadata.schema_version)?;
+    writeln!(
+        out,
+        r#"  <metadata tool_version="{}" generated_at="{}" input_sha256="{}" config_sha256="{}"/>"#,
+        metadata.tool_version, metadata.generated_at, metadata.input_sha256, metadata.config_sha256
+    )?;
+    let fees = has_fees(l);
+    let escrow = has_escrow(l);
+    for r in sorted_rows(l) {
+        writeln!(
+            out,
+            r#"  <account client="{}" locked="{}">"#,
+            r.client_id, r.locked
+        )?;
+        writeln!(
+            out,
+            "    <available>{}</available>",
+            d.format("available", r.available)
+        )?;
+        writeln!(out, "    <held>{}</held>", d.format("held", r.held))?;
+        if escrow {
+            writeln!(out, "    <escrow>{}</escrow>", d.format("escrow", r.escrow))?;
+        }
+        writeln!(out, "    <total>{}</total>", d.format("total", r.total))?;
+        if let Some(c) = &r.credit {
+            writeln!(
+                out,
+                r#"    <credit limit="{}" utilization="{}"/>"#,
+                d.format("credit_limit", c.limit),
+                d.format("credit_utilization", c.utilization)
+            )?;
+        }
+        for (c, balance) in &r.currencies {
+            writeln!(
+                out,
+                r#"    <currency code="{}">{}</currency>"#,
+                c,
+                d.format("currencies", *balance)
+            )?;
+        }
+        if let (Some(total), Some(c)) = (r.reporting_total, l.config().reporting_currency) {
+            writeln!(
+                out,
+                r#"    <reporting_total currency="{}">{}</reporting_total>"#,
+                c,
+                d.format("reporting_total", total)
+            )?;
+        }
+        if fees {
+            writeln!(out, "    <fees>{}</fees>", d.format("fees", r.fees))?;
+        }
+        for s in &r.subaccounts {
+            let escrow_attribute = if escrow {
+                format!(r#" escrow="{}""#, d.format("escrow", s.escrow))
+            } else {
+                String::new()
+            };
+            writeln!(
+                out,
+                r#"    <subaccount name="{}" locked="{}" available="{}" held="{}"{} total="{}"/>"#,
+                s.subaccount.as_deref().unwrap_or_default(),
+                s.locked,
+                d.format("available", s.available),
+                d.format("held", s.held),
+                escrow_attribute,
+                d.format("total", s.total)
+            )?;
+        }
+        writeln!(out, "  </account>")?;
+    }
+    let fees_attribute = if fees {
+        format!(r#" fees="{}""#, d.format("fees", summary.fees))
+    } else {
+        String::new()
+    };
+    writeln!(
+        out,
+        r#"  <summary records="{}" applied="{}" rejected="{}" accounts="{}"{}>"#,
+        summary.records,
+        summary.applied,
+        summary.rejected,
+        l.len(),
+        fees_attribute
+    )?;
+    for (reason, count) in &summary.rejections {
+        writeln!(
+            out,
+            r#"    <rejection reason="{}" count="{}"/>"#,
+            reason, count
+        )?;
+    }
+    writeln!(out, "  </summary>")?;
+    writeln!(out, "</ledger>")?;
+    Ok(())
+}
+
+// The workbook has three sheets: Balances, with the columns of the CSV output; Summary, with the
+// run metadata and the counters of the summary as key and value rows; and Rejections, with the
+// rejected records as they appear in the rejects file.
+fn write_xlsx(
+    l: &Ledger,
+    summary: &Summary,
+    rejections: &[Rejection],
+    metadata: &RunMetadata,
+    d: &Decimals,
+    locale: Option<Locale>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut workbook = Workbook::new();
+    // With a locale, amounts have their thousands grouped.
+    let amount = |column, v: Amount| match locale {
+        Some(_) => Cell::grouped(&d.format(column, v)),
+        None => Cell::Number(d.round(column, v)),
+    };
+    let cell = |s: &str| match locale {
+        Some(_) => Cell::grouped(s),
+        None => Cell::parsed(s),
+    };
+
+    let columns = extra_columns(l);
+    let mut header: Vec<Cell> = ["client", "available", "held", "total", "locked"]
+        .into_iter()
+        .map(Cell::from)
+        .collect();
+    header.extend(columns.iter().map(|c| Cell::from(c.csv_title())));
+    let mut balances = vec![header];
+    for r in sorted_rows(l).into_iter().flat_map(flattened) {
+        let mut cells = vec![
+            Cell::from(r.client_id),
+            amount("available", r.available),
+            amount("held", r.held),
+            amount("total", r.total),
+            Cell::Bool(r.locked),
+        ];
+        cells.extend(columns.iter().map(|c| cell(&c.cell(&r, false, d))));
+        balances.push(cells);
+    }
+    workbook.sheet("Balances", balances);
+
+    let row = |key: &str, value: Cell| vec![Cell::from(key), value];
+    let mut rows = vec![
+        row("schema_version", Cell::from(metadata.schema_version)),
+        row("tool_version", Cell::from(metadata.tool_version)),
+        row("generated_at", Cell::from(metadata.generated_at.as_str())),
+        row("input_sha256", Cell::from(metadata.input_sha256.as_str())),
+        row("config_sha256", Cell::from(metadata.config_sha256.as_str())),
+        row("records", Cell::from(summary.records)),
+        row("applied", Cell::from(summary.applied)),
+        row("rejected", Cell::from(summary.rejected)),
+        row("accounts", Cell::from(l.len())),
+    ];
+    if summary.fees > Amount::ZERO {
+        rows.push(row("fees", amount("fees", summary.fees)));
+    }
+    for (reason, count) in &summary.rejections {
+        rows.push(row(&format!("rejected: {}", reason), Cell::from(*count)));
+    }
+    workbook.sheet("Summary", rows);
+
+    let mut rows = vec![[
+        "line", "byte", "tx", "client", "reason", "message", "record", "memo",
+    ]
+    .into_iter()
+    .map(Cell::from)
+    .collect()];
+    for r in rejections {
+        rows.push(vec![
+            Cell::from(r.line),
+            Cell::from(r.byte),
+            Cell::from(r.tx),
+            Cell::from(r.client),
+            Cell::from(r.reason),
+            Cell::from(r.message.as_str()),
+            Cell::from(r.record.as_str()),
+            Cell::from(r.memo.as_deref()),
+        ]);
+    }
+    workbook.sheet("Rejections", rows);
+
+    workbook.write(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn decimals_are_set_for_all_columns_or_some() {
+        let mut d = Decimals::default();
+        assert_eq!(d.format("available", amount("1.5")), "1.5000");
+        d.set("available=2, fees=full").unwrap();
+        assert_eq!(d.format("available", amount("1.5")), "1.50");
+        assert_eq!(d.format("fees", amount("1.5")), "1.5");
+        assert_eq!(d.format("held", amount("1.5")), "1.5000");
+        assert_eq!(d.round("available", amount("1.257")), 1.26);
+        // A number of places for all columns replaces those of the columns.
+        d.set("1").unwrap();
+        assert_eq!(d.format("fees", amount("1.26")), "1.3");
+        assert!(d.set("balance=2").is_err());
+        assert!(d.set("available=two").is_err());
+        assert!(d.set("-1").is_err());
+    }
+
+    #[test]
+    fn locales_set_the_separators_of_numbers() {
+        let localize = |locale: &str, cell: &str| locale.parse::<Locale>().unwrap().localize(cell);
+        assert_eq!(localize("de_DE.UTF-8", "-1234.5678"), "-1.234,5678");
+        assert_eq!(localize("en-US", "1234567.5"), "1,234,567.5");
+        assert_eq!(localize("de-CH", "1234.5"), "1'234.5");
+        assert_eq!(localize("fr", "1234.5"), "1\u{a0}234,5");
+        assert_eq!(localize("C", "1234.5"), "1234.5");
+        // Every number of the cell, and nothing else.
+        assert_eq!(
+            localize("de", "EUR:1234.5000 GBP:2.0000"),
+            "EUR:1.234,5000 GBP:2,0000"
+        );
+        assert_eq!(localize("de", "12.5%"), "12,5%");
+        assert!("xx_XX".parse::<Locale>().is_err());
+    }
+
+    fn deposit(client_id: u16, uid: u32, value: &str) -> ledger::TransactionEntry {
+        ledger::TransactionEntry {
+            t: "deposit".to_string(),
+            client_id,
+            uid,
+            amount: Some(amount(value)),
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            subaccount: None,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        }
+    }
+
+    fn csv(l: &Ledger, threads: usize) -> String {
+        let mut out = Vec::new();
+        write_csv(l, None, &Decimals::default(), threads, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn threads_write_the_rows_in_the_same_order() {
+        let mut l = Ledger::new();
+        // Several rounds of chunks on 3 threads, the last one short.
+        for client_id in (0..3 * OUTPUT_CHUNK as u16 + 10).rev() {
+            let uid = u32::from(client_id);
+            assert!(l.apply_transaction(deposit(client_id, uid, "1.5")).is_ok());
+        }
+        let expected = csv(&l, 1);
+        assert_eq!(expected.lines().count(), 3 * OUTPUT_CHUNK + 11);
+        let first = format!("{},1.5000,0.0000,1.5000,false", 3 * OUTPUT_CHUNK + 9);
+        assert_eq!(expected.lines().nth(1), Some(first.as_str()));
+        for threads in [2, 3, 8] {
+            assert!(csv(&l, threads) == expected, "{} threads", threads);
+        }
+        assert_eq!(
+            csv(&Ledger::new(), 3),
+            "client,available,held,total,locked\n"
+        );
+    }
+
+    #[test]
+    fn final_accounts_are_written_when_their_records_are_over() {
+        let mut l = ledger::LedgerBuilder::new().bonus_expiry_days(1).build();
+        let bonus = ledger::TransactionEntry {
+            t: "bonus".to_string(),
+            timestamp: Some("2024-03-01T00:00:00Z".parse().unwrap()),
+            ..deposit(1, 2, "5")
+        };
+        let mut out = Vec::new();
+        let mut rows = FinalizedRows::create(&l, Decimals::default(), &mut out).unwrap();
+        for t in [
+            deposit(1, 1, "10"),
+            bonus,
+            deposit(2, 3, "2"),
+            deposit(3, 4, "4"),
+        ] {
+            assert!(rows.record(&l, t.client_id).unwrap());
+            assert!(l.apply_transaction(t).is_ok());
+        }
+        // The records of client 1 are over.
+        assert!(!rows.record(&l, 1).unwrap());
+        rows.finish(&l).unwrap();
+        // Client 1 has a bonus left to expire, its rows wait for the end.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked,subaccount,escrow,currencies
+2,2.0000,0.0000,2.0000,false,,0.0000,
+1,15.0000,0.0000,15.0000,false,,0.0000,
+3,4.0000,0.0000,4.0000,false,,0.0000,
+"
+        );
+    }
+}
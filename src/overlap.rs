@@ -0,0 +1,87 @@
+use csv::StringRecord;
+use ledger::TransactionType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+// Row of the overlap report.
+#[derive(Debug, serde::Serialize)]
+struct OverlapRecord<'a> {
+    tx: u32,
+    file: &'a str,
+    line: u64,
+    first_file: &'a str, // Where the transaction id was first seen
+    first_line: u64,
+}
+
+// Opt-in analysis (--overlap-report) of transaction ids which appear in more than one of the input
+// files, as happens when a batch is delivered again: every such record is written to a CSV file
+// with the file and line it was first seen at. Disputes, resolves and chargebacks refer to earlier
+// transactions by id, which may well be in an earlier file, so only the records of the other
+// types are considered. The records are processed all the same, duplicates being rejected as
+// usual.
+pub struct Overlaps {
+    writer: csv::Writer<File>,
+    files: Vec<String>,
+    first: HashMap<u32, (usize, u64)>, // File index and line, by transaction id
+    found: u64,
+}
+
+impl Overlaps {
+    pub fn create(path: &str, files: &[String]) -> Result<Overlaps, csv::Error> {
+        Ok(Overlaps {
+            writer: csv::Writer::from_path(path)?,
+            files: files.to_vec(),
+            first: HashMap::new(),
+            found: 0,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        file: usize,
+        record: &StringRecord,
+        headers: &StringRecord,
+    ) -> Result<(), csv::Error> {
+        let field = |name| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .and_then(|i| record.get(i))
+        };
+        if matches!(
+            field("type").map(str::parse),
+            Some(Ok(TransactionType::Dispute
+                | TransactionType::Representment
+                | TransactionType::PreArbitration
+                | TransactionType::Arbitration
+                | TransactionType::ChargebackReversal
+                | TransactionType::Resolve
+                | TransactionType::Chargeback))
+        ) {
+            return Ok(());
+        }
+        let Some(tx) = field("tx").and_then(|tx| tx.parse::<u32>().ok()) else {
+            return Ok(());
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let (first_file, first_line) = *self.first.entry(tx).or_insert((file, line));
+        if first_file == file {
+            return Ok(()); // Duplicates within a file are not overlaps
+        }
+        self.found += 1;
+        self.writer.serialize(OverlapRecord {
+            tx,
+            file: &self.files[file],
+            line,
+            first_file: &self.files[first_file],
+            first_line,
+        })
+    }
+
+    // Flushes the file and returns the number of overlapping records found.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.found)
+    }
+}
@@ -0,0 +1,807 @@
+use crate::output::Decimals;
+use ledger::{Applied, Timestamp};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+
+// Events buffered per partition before they are written out as a file.
+const FILE_ROWS: usize = 100_000;
+// Clients per partition: 0-999, 1000-1999 and so on.
+const CLIENT_RANGE: u16 = 1000;
+// Partition of the events without a timestamp, as named by Hive and Spark.
+const NO_DATE: &str = "__HIVE_DEFAULT_PARTITION__";
+
+// Applied operation, as written to the Parquet files.
+#[derive(Debug)]
+struct Event {
+    tx: u32,
+    client: u16,
+    kind: &'static str,
+    amount: Option<f64>,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    timestamp: Option<Timestamp>,
+}
+
+// Writes the applied operations to Parquet files under the directory given with --events-out,
+// partitioned Hive-style by date and client range:
+//
+//     dir/date=2024-03-01/clients=0-999/part-00000.parquet
+//
+// Each file has a single row group with the columns tx, client, type, amount (null for
+// operations without an amount), available, held, total (the balances after the operation),
+// locked and timestamp (milliseconds since the epoch, null without a timestamp). Files are plain
+// (uncompressed) so that no codec is needed, and existing files of the same names are replaced.
+// With more than one thread (--output-threads), the files of the partitions left at the end are
+// encoded on that many threads, a round of partitions at a time.
+pub struct EventsOut {
+    dir: PathBuf,
+    partitions: HashMap<(Option<i64>, u16), Partition>, // By day and first client of the range
+    decimals: Decimals,
+    threads: usize,
+}
+
+#[derive(Debug, Default)]
+struct Partition {
+    events: Vec<Event>,
+    files: u32, // Files written so far
+}
+
+impl EventsOut {
+    pub fn create(dir: &str, decimals: Decimals, threads: usize) -> io::Result<EventsOut> {
+        fs::create_dir_all(dir)?;
+        Ok(EventsOut {
+            dir: PathBuf::from(dir),
+            partitions: HashMap::new(),
+            decimals,
+            threads,
+        })
+    }
+
+    pub fn record(&mut self, applied: &Applied) -> io::Result<()> {
+        let day = applied.timestamp.map(|t| t.0.div_euclid(86400));
+        let range = applied.client_id - applied.client_id % CLIENT_RANGE;
+        let partition = self.partitions.entry((day, range)).or_default();
+        let d = &self.decimals;
+        partition.events.push(Event {
+            tx: applied.tx,
+            client: applied.client_id,
+            kind: applied.kind.as_str(),
+            amount: applied.amount.map(|v| d.round("amount", v)),
+            available: d.round("available", applied.after.available),
+            held: d.round("held", applied.after.held),
+            total: d.round("total", applied.after.total),
+            locked: applied.after.locked,
+            timestamp: applied.timestamp,
+        });
+        if partition.events.len() >= FILE_ROWS {
+            let key = (day, range);
+            return self.write_partition(key);
+        }
+        Ok(())
+    }
+
+    fn write_partition(&mut self, key: (Option<i64>, u16)) -> io::Result<()> {
+        let Some(partition) = self.partitions.get(&key) else {
+            return Ok(());
+        };
+        let file = parquet_file(&partition.events);
+        self.write_file(key, &file)
+    }
+
+    // Writes the encoded events of the partition to its next file.
+    fn write_file(&mut self, key: (Option<i64>, u16), file: &[u8]) -> io::Result<()> {
+        let Some(partition) = self.partitions.get_mut(&key) else {
+            return Ok(());
+        };
+        let (day, range) = key;
+        let date = match day {
+            Some(day) => {
+                let (year, month, day) = Timestamp(day * 86400).date();
+                format!("{:04}-{:02}-{:02}", year, month, day)
+            }
+            None => NO_DATE.to_string(),
+        };
+        let last = u32::from(range) + u32::from(CLIENT_RANGE) - 1;
+        let dir = self.dir.join(format!("date={}", date)).join(format!(
+            "clients={}-{}",
+            range,
+            last.min(u32::from(u16::MAX))
+        ));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("part-{:05}.parquet", partition.files));
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(file)?;
+        out.flush()?;
+        partition.files += 1;
+        partition.events.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut keys: Vec<_> = self
+            .partitions
+            .iter()
+            .filter(|(_, p)| !p.events.is_empty())
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort();
+        if self.threads <= 1 {
+            for key in keys {
+                self.write_partition(key)?;
+            }
+            return Ok(());
+        }
+        for round in keys.chunks(self.threads) {
+            let partitions = &self.partitions;
+            let files = thread::scope(|s| {
+                let handles: Vec<_> = round
+                    .iter()
+                    .map(|key| s.spawn(move || parquet_file(&partitions[key].events)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join())
+                    .collect::<Result<Vec<Vec<u8>>, _>>()
+            })
+            .map_err(|_| io::Error::other("Parquet encoding thread panicked"))?;
+            for (key, file) in round.iter().zip(files) {
+                self.write_file(*key, &file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Physical types, repetitions, converted types and encodings of the Parquet format.
+const BOOLEAN: i32 = 0;
+const INT32: i32 = 1;
+const INT64: i32 = 2;
+const DOUBLE: i32 = 5;
+const BYTE_ARRAY: i32 = 6;
+const REQUIRED: i32 = 0;
+const OPTIONAL: i32 = 1;
+const UTF8: i32 = 0;
+const TIMESTAMP_MILLIS: i32 = 9;
+const UINT_16: i32 = 12;
+const UINT_32: i32 = 13;
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+
+// Column of a file: its schema and its values, PLAIN encoded, along with the definition levels of
+// optional columns.
+struct Column {
+    name: &'static str,
+    physical_type: i32,
+    converted_type: Option<i32>,
+    definitions: Option<Vec<bool>>, // Whether each row has a value, for optional columns
+    values: Vec<u8>,
+}
+
+fn required(
+    name: &'static str,
+    physical_type: i32,
+    converted_type: Option<i32>,
+    values: Vec<u8>,
+) -> Column {
+    Column {
+        name,
+        physical_type,
+        converted_type,
+        definitions: None,
+        values,
+    }
+}
+
+fn doubles(events: &[Event], value: impl Fn(&Event) -> f64) -> Vec<u8> {
+    events.iter().flat_map(|e| value(e).to_le_bytes()).collect()
+}
+
+fn columns(events: &[Event]) -> Vec<Column> {
+    let mut kinds = Vec::new();
+    for e in events {
+        kinds.extend_from_slice(&(e.kind.len() as u32).to_le_bytes());
+        kinds.extend_from_slice(e.kind.as_bytes());
+    }
+    let mut locked = vec![0u8; events.len().div_ceil(8)];
+    for (i, e) in events.iter().enumerate() {
+        locked[i / 8] |= u8::from(e.locked) << (i % 8);
+    }
+    vec![
+        // Unsigned values are stored as the signed INT32 of the same bits, as the format wants.
+        required(
+            "tx",
+            INT32,
+            Some(UINT_32),
+            events.iter().flat_map(|e| e.tx.to_le_bytes()).collect(),
+        ),
+        required(
+            "client",
+            INT32,
+            Some(UINT_16),
+            events
+                .iter()
+                .flat_map(|e| i32::from(e.client).to_le_bytes())
+                .collect(),
+        ),
+        required("type", BYTE_ARRAY, Some(UTF8), kinds),
+        Column {
+            name: "amount",
+            physical_type: DOUBLE,
+            converted_type: None,
+            definitions: Some(events.iter().map(|e| e.amount.is_some()).collect()),
+            values: events
+                .iter()
+                .filter_map(|e| e.amount)
+                .flat_map(f64::to_le_bytes)
+                .collect(),
+        },
+        required("available", DOUBLE, None, doubles(events, |e| e.available)),
+        required("held", DOUBLE, None, doubles(events, |e| e.held)),
+        required("total", DOUBLE, None, doubles(events, |e| e.total)),
+        required("locked", BOOLEAN, None, locked),
+        Column {
+            name: "timestamp",
+            physical_type: INT64,
+            converted_type: Some(TIMESTAMP_MILLIS),
+            definitions: Some(events.iter().map(|e| e.timestamp.is_some()).collect()),
+            values: events
+                .iter()
+                .filter_map(|e| e.timestamp)
+                .flat_map(|t| (t.0 * 1000).to_le_bytes())
+                .collect(),
+        },
+    ]
+}
+
+// Definition levels of an optional column (0 for nulls, 1 for values), RLE encoded with a bit
+// width of 1 and prefixed by their length, as they appear in a v1 data page.
+fn definition_levels(definitions: &[bool]) -> Vec<u8> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < definitions.len() {
+        let value = definitions[i];
+        let len = definitions[i..].iter().take_while(|d| **d == value).count();
+        varint(&mut runs, (len as u64) << 1);
+        runs.push(u8::from(value));
+        i += len;
+    }
+    let mut levels = (runs.len() as u32).to_le_bytes().to_vec();
+    levels.append(&mut runs);
+    levels
+}
+
+// A whole Parquet file holding the events in a single row group, each column in a single data
+// page.
+fn parquet_file(events: &[Event]) -> Vec<u8> {
+    let mut file = b"PAR1".to_vec();
+    let mut chunks = Vec::new();
+    let columns = columns(events);
+    for c in &columns {
+        let mut page = match &c.definitions {
+            Some(definitions) => definition_levels(definitions),
+            None => Vec::new(),
+        };
+        page.extend_from_slice(&c.values);
+        let mut header = Thrift::default();
+        header.i32(1, 0); // DATA_PAGE
+        header.i32(2, page.len() as i32);
+        header.i32(3, page.len() as i32);
+        header.begin_struct(5);
+        header.i32(1, events.len() as i32);
+        header.i32(2, PLAIN);
+        header.i32(3, RLE);
+        header.i32(4, RLE);
+        header.end_struct();
+        header.stop();
+        let offset = file.len() as i64;
+        let size = (header.out.len() + page.len()) as i64;
+        file.append(&mut header.out);
+        file.append(&mut page);
+        chunks.push((c, offset, size));
+    }
+
+    let mut meta = Thrift::default();
+    meta.i32(1, 1); // Version
+    meta.begin_list(2, STRUCT, columns.len() + 1);
+    meta.binary(4, b"schema");
+    meta.i32(5, columns.len() as i32);
+    meta.stop();
+    for c in &columns {
+        meta.i32(1, c.physical_type);
+        meta.i32(
+            3,
+            if c.definitions.is_some() {
+                OPTIONAL
+            } else {
+                REQUIRED
+            },
+        );
+        meta.binary(4, c.name.as_bytes());
+        if let Some(converted_type) = c.converted_type {
+            meta.i32(6, converted_type);
+        }
+        meta.stop();
+    }
+    meta.end_list();
+    meta.i64(3, events.len() as i64);
+    meta.begin_list(4, STRUCT, 1);
+    meta.begin_list(1, STRUCT, chunks.len());
+    for (c, offset, size) in &chunks {
+        meta.i64(2, *offset);
+        meta.begin_struct(3);
+        meta.i32(1, c.physical_type);
+        meta.begin_list(2, I32, 2);
+        meta.list_i32(PLAIN);
+        meta.list_i32(RLE);
+        meta.end_list();
+        meta.begin_list(3, BINARY, 1);
+        meta.list_binary(c.name.as_bytes());
+        meta.end_list();
+        meta.i32(4, 0); // UNCOMPRESSED
+        meta.i64(5, events.len() as i64);
+        meta.i64(6, *size);
+        meta.i64(7, *size);
+        meta.i64(9, *offset);
+        meta.end_struct();
+        meta.stop();
+    }
+    meta.end_list();
+    meta.i64(2, chunks.iter().map(|(_, _, size)| size).sum());
+    meta.i64(3, events.len() as i64);
+    meta.stop();
+    meta.end_list();
+    meta.binary(
+        6,
+        format!("ledger version {}", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    meta.stop();
+
+    let len = meta.out.len() as u32;
+    file.append(&mut meta.out);
+    file.extend_from_slice(&len.to_le_bytes());
+    file.extend_from_slice(b"PAR1");
+    file
+}
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+// Element types of the Thrift compact protocol.
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+// Writer of the Thrift compact protocol, in which the Parquet metadata is encoded. Fields are
+// written in increasing id order within each struct, and every struct (the outermost one
+// included) is terminated with stop. begin_struct and begin_list save the last field id of the
+// enclosing struct, end_struct and end_list restore it; list elements which are structs are
+// terminated with stop only.
+#[derive(Default)]
+struct Thrift {
+    out: Vec<u8>,
+    last: i16,           // Last field id of the current struct
+    enclosing: Vec<i16>, // Last field ids of the enclosing structs
+}
+
+impl Thrift {
+    fn field(&mut self, id: i16, kind: u8) {
+        let delta = id - self.last;
+        if (1..=15).contains(&delta) {
+            self.out.push((delta as u8) << 4 | kind);
+        } else {
+            self.out.push(kind);
+            varint(&mut self.out, zigzag(i64::from(id)));
+        }
+        self.last = id;
+    }
+
+    fn i32(&mut self, id: i16, v: i32) {
+        self.field(id, I32);
+        varint(&mut self.out, zigzag(i64::from(v)));
+    }
+
+    fn i64(&mut self, id: i16, v: i64) {
+        self.field(id, I64);
+        varint(&mut self.out, zigzag(v));
+    }
+
+    fn binary(&mut self, id: i16, v: &[u8]) {
+        self.field(id, BINARY);
+        self.list_binary(v);
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.enclosing.push(self.last);
+        self.last = 0;
+    }
+
+    fn end_struct(&mut self) {
+        self.out.push(0);
+        self.last = self.enclosing.pop().unwrap_or_default();
+    }
+
+    fn begin_list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, LIST);
+        if len < 15 {
+            self.out.push((len as u8) << 4 | kind);
+        } else {
+            self.out.push(0xf0 | kind);
+            varint(&mut self.out, len as u64);
+        }
+        self.enclosing.push(self.last);
+        self.last = 0;
+    }
+
+    fn end_list(&mut self) {
+        self.last = self.enclosing.pop().unwrap_or_default();
+    }
+
+    // Terminates a struct element of a list (or the outermost struct), resetting the field ids
+    // for the next element.
+    fn stop(&mut self) {
+        self.out.push(0);
+        self.last = 0;
+    }
+
+    fn list_i32(&mut self, v: i32) {
+        varint(&mut self.out, zigzag(i64::from(v)));
+    }
+
+    fn list_binary(&mut self, v: &[u8]) {
+        varint(&mut self.out, v.len() as u64);
+        self.out.extend_from_slice(v);
+    }
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Reader of the files, written from the Parquet and Thrift specifications rather than from the
+    // writer above: a generic compact protocol decoder, and the fields of parquet.thrift it needs.
+    #[derive(Clone, Debug, PartialEq)]
+    enum Value {
+        Bool(bool),
+        Int(i64),
+        Double(f64),
+        Binary(Vec<u8>),
+        List(Vec<Value>),
+        Struct(BTreeMap<i16, Value>),
+    }
+
+    impl Value {
+        fn field(&self, id: i16) -> Option<&Value> {
+            match self {
+                Value::Struct(fields) => fields.get(&id),
+                _ => panic!("{:?} is not a struct", self),
+            }
+        }
+
+        fn int(&self, id: i16) -> i64 {
+            match self.field(id) {
+                Some(Value::Int(v)) => *v,
+                v => panic!("field {} is {:?}", id, v),
+            }
+        }
+
+        fn string(&self, id: i16) -> String {
+            match self.field(id) {
+                Some(Value::Binary(v)) => String::from_utf8(v.clone()).unwrap(),
+                v => panic!("field {} is {:?}", id, v),
+            }
+        }
+
+        fn list(&self, id: i16) -> &[Value] {
+            match self.field(id) {
+                Some(Value::List(v)) => v,
+                v => panic!("field {} is {:?}", id, v),
+            }
+        }
+    }
+
+    struct Decoder<'a> {
+        data: &'a [u8],
+        at: usize,
+    }
+
+    impl Decoder<'_> {
+        fn byte(&mut self) -> u8 {
+            self.at += 1;
+            self.data[self.at - 1]
+        }
+
+        fn varint(&mut self) -> u64 {
+            let (mut v, mut shift) = (0, 0);
+            loop {
+                let b = self.byte();
+                v |= u64::from(b & 0x7f) << shift;
+                if b & 0x80 == 0 {
+                    return v;
+                }
+                shift += 7;
+            }
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let z = self.varint();
+            ((z >> 1) as i64) ^ -((z & 1) as i64)
+        }
+
+        fn bytes(&mut self, len: usize) -> &[u8] {
+            self.at += len;
+            &self.data[self.at - len..self.at]
+        }
+
+        fn value(&mut self, kind: u8) -> Value {
+            match kind {
+                1 | 2 => Value::Bool(self.byte() == 1),
+                3 => Value::Int(self.byte() as i8 as i64),
+                4..=6 => Value::Int(self.zigzag()),
+                7 => Value::Double(f64::from_le_bytes(self.bytes(8).try_into().unwrap())),
+                8 => {
+                    let len = self.varint() as usize;
+                    Value::Binary(self.bytes(len).to_vec())
+                }
+                9 | 10 => {
+                    let header = self.byte();
+                    let len = match header >> 4 {
+                        15 => self.varint() as usize,
+                        len => len as usize,
+                    };
+                    Value::List((0..len).map(|_| self.value(header & 0x0f)).collect())
+                }
+                12 => self.structure(),
+                _ => panic!("unexpected compact type {}", kind),
+            }
+        }
+
+        fn structure(&mut self) -> Value {
+            let mut fields = BTreeMap::new();
+            let mut last = 0;
+            loop {
+                let header = self.byte();
+                if header == 0 {
+                    return Value::Struct(fields);
+                }
+                let id = match header >> 4 {
+                    0 => self.zigzag() as i16,
+                    delta => last + i16::from(delta),
+                };
+                let value = match header & 0x0f {
+                    1 => Value::Bool(true),
+                    2 => Value::Bool(false),
+                    kind => self.value(kind),
+                };
+                fields.insert(id, value);
+                last = id;
+            }
+        }
+    }
+
+    // Decodes count values of the RLE / bit-packing hybrid encoding.
+    fn hybrid(data: &[u8], bit_width: usize, count: usize) -> Vec<u64> {
+        let mut d = Decoder { data, at: 0 };
+        let mut values = Vec::new();
+        while values.len() < count {
+            let header = d.varint();
+            if header & 1 == 1 {
+                let bytes = d.bytes((header >> 1) as usize * bit_width);
+                for i in 0..bytes.len() * 8 / bit_width {
+                    let v = (0..bit_width)
+                        .map(|b| i * bit_width + b)
+                        .map(|bit| u64::from(bytes[bit / 8] >> (bit % 8) & 1))
+                        .enumerate()
+                        .fold(0, |v, (b, bit)| v | bit << b);
+                    values.push(v);
+                }
+            } else {
+                let v = d.bytes(bit_width.div_ceil(8));
+                let v = v.iter().rev().fold(0, |v, b| v << 8 | u64::from(*b));
+                values.extend((0..header >> 1).map(|_| v));
+            }
+        }
+        values.truncate(count);
+        values
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Cell {
+        Int(i64),
+        Double(f64),
+        Bool(bool),
+        Text(String),
+    }
+
+    // The schema (name, physical type, repetition, converted type) and the columns of a file.
+    #[allow(clippy::type_complexity)]
+    fn read(file: &[u8]) -> (Vec<(String, i64, i64, Option<i64>)>, Vec<Vec<Option<Cell>>>) {
+        assert_eq!(&file[..4], b"PAR1");
+        assert_eq!(&file[file.len() - 4..], b"PAR1");
+        let len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        let start = file.len() - 8 - len as usize;
+        let mut d = Decoder {
+            data: &file[start..file.len() - 8],
+            at: 0,
+        };
+        let meta = d.structure();
+        assert_eq!(d.at, len as usize);
+        let rows = meta.int(3) as usize;
+        let schema = meta.list(2);
+        assert_eq!(schema[0].int(5) as usize, schema.len() - 1);
+        let schema: Vec<_> = schema[1..]
+            .iter()
+            .map(|e| {
+                let converted = e.field(6).map(|_| e.int(6));
+                (e.string(4), e.int(1), e.int(3), converted)
+            })
+            .collect();
+        let groups = meta.list(4);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].int(3) as usize, rows);
+        let chunks = groups[0].list(1);
+        assert_eq!(chunks.len(), schema.len());
+        let mut columns = Vec::new();
+        for (chunk, (name, physical_type, repetition, _)) in chunks.iter().zip(&schema) {
+            let m = chunk.field(3).unwrap();
+            assert_eq!(m.int(1), *physical_type);
+            assert_eq!(m.list(3), [Value::Binary(name.as_bytes().to_vec())]);
+            assert_eq!(m.int(4), 0); // UNCOMPRESSED
+            assert_eq!(m.int(5) as usize, rows);
+            let offset = m.int(9) as usize;
+            let mut d = Decoder {
+                data: &file[offset..],
+                at: 0,
+            };
+            let header = d.structure();
+            assert_eq!(header.int(1), 0); // DATA_PAGE
+            let size = header.int(3) as usize;
+            assert_eq!(m.int(7) as usize, d.at + size);
+            let page_header = header.field(5).unwrap();
+            assert_eq!(page_header.int(1) as usize, rows);
+            assert_eq!(page_header.int(2), 0); // PLAIN
+            let page = d.bytes(size);
+            let (defined, mut values) = if *repetition == 1 {
+                assert_eq!(page_header.int(3), 3); // RLE
+                let len = u32::from_le_bytes(page[..4].try_into().unwrap()) as usize;
+                let levels = hybrid(&page[4..4 + len], 1, rows);
+                (levels, &page[4 + len..])
+            } else {
+                (vec![1; rows], page)
+            };
+            let mut booleans = 0;
+            let mut column = Vec::new();
+            for level in defined {
+                if level == 0 {
+                    column.push(None);
+                    continue;
+                }
+                let (cell, rest) = match *physical_type {
+                    0 => {
+                        let bit = values[booleans / 8] >> (booleans % 8) & 1;
+                        booleans += 1;
+                        (Cell::Bool(bit == 1), values)
+                    }
+                    1 => {
+                        let v = i32::from_le_bytes(values[..4].try_into().unwrap());
+                        (Cell::Int(i64::from(v)), &values[4..])
+                    }
+                    2 => {
+                        let v = i64::from_le_bytes(values[..8].try_into().unwrap());
+                        (Cell::Int(v), &values[8..])
+                    }
+                    5 => {
+                        let v = f64::from_le_bytes(values[..8].try_into().unwrap());
+                        (Cell::Double(v), &values[8..])
+                    }
+                    6 => {
+                        let len = u32::from_le_bytes(values[..4].try_into().unwrap()) as usize;
+                        let text = String::from_utf8(values[4..4 + len].to_vec()).unwrap();
+                        (Cell::Text(text), &values[4 + len..])
+                    }
+                    t => panic!("unexpected physical type {}", t),
+                };
+                values = rest;
+                column.push(Some(cell));
+            }
+            if *physical_type == 0 {
+                assert_eq!(values.len(), booleans.div_ceil(8));
+            } else {
+                assert!(values.is_empty(), "{} bytes left in {}", values.len(), name);
+            }
+            columns.push(column);
+        }
+        (schema, columns)
+    }
+
+    fn event(i: u32) -> Event {
+        Event {
+            tx: u32::MAX - i,
+            client: i as u16 * 1000,
+            kind: ["deposit", "withdrawal", "dispute"][i as usize % 3],
+            amount: (i % 3 != 2).then_some(f64::from(i) + 0.25),
+            available: f64::from(i),
+            held: -f64::from(i),
+            total: 0.5,
+            locked: i.is_multiple_of(5),
+            timestamp: (i >= 7).then_some(Timestamp(1_700_000_000 + i64::from(i))),
+        }
+    }
+
+    #[test]
+    fn files_are_read_back_by_a_parquet_reader() {
+        let events: Vec<_> = (0..20).map(event).collect();
+        let (schema, columns) = read(&parquet_file(&events));
+        // Physical types: 0 BOOLEAN, 1 INT32, 2 INT64, 5 DOUBLE, 6 BYTE_ARRAY. Repetitions: 0
+        // REQUIRED, 1 OPTIONAL. Converted types: 0 UTF8, 9 TIMESTAMP_MILLIS, 12 UINT_16, 13 UINT_32.
+        let expected_schema = [
+            ("tx", 1, 0, Some(13)),
+            ("client", 1, 0, Some(12)),
+            ("type", 6, 0, Some(0)),
+            ("amount", 5, 1, None),
+            ("available", 5, 0, None),
+            ("held", 5, 0, None),
+            ("total", 5, 0, None),
+            ("locked", 0, 0, None),
+            ("timestamp", 2, 1, Some(9)),
+        ];
+        let expected_schema: Vec<_> = expected_schema
+            .iter()
+            .map(|(name, t, r, c)| (name.to_string(), *t, *r, *c))
+            .collect();
+        assert_eq!(schema, expected_schema);
+        for (i, e) in events.iter().enumerate() {
+            let row: Vec<_> = columns.iter().map(|c| c[i].as_ref()).collect();
+            let expected = [
+                Some(Cell::Int(i64::from(e.tx as i32))),
+                Some(Cell::Int(i64::from(e.client))),
+                Some(Cell::Text(e.kind.to_string())),
+                e.amount.map(Cell::Double),
+                Some(Cell::Double(e.available)),
+                Some(Cell::Double(e.held)),
+                Some(Cell::Double(e.total)),
+                Some(Cell::Bool(e.locked)),
+                e.timestamp.map(|t| Cell::Int(t.0 * 1000)),
+            ];
+            let expected: Vec<_> = expected.iter().map(Option::as_ref).collect();
+            assert_eq!(row, expected, "row {}", i);
+        }
+    }
+
+    #[test]
+    fn files_without_events_have_no_rows() {
+        let (schema, columns) = read(&parquet_file(&[]));
+        assert_eq!(schema.len(), 9);
+        assert!(columns.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn hybrid_decoding_handles_bit_packed_runs() {
+        // Bit-packed run of 8 values with a bit width of 3, from the Parquet encodings document.
+        assert_eq!(
+            hybrid(&[0x03, 0x88, 0xc6, 0xfa], 3, 8),
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+        assert_eq!(
+            hybrid(&[0x0a, 0x01, 0x04, 0x00], 1, 7),
+            [1, 1, 1, 1, 1, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const PERIOD: Duration = Duration::from_millis(1);
+
+// Phase of a run the main thread is in, which the profiler samples. The phases of the records
+// (reading, processing, recording the outputs of the applied operations, checkpointing) are
+// entered for every record, the others once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Setup,
+    Read,
+    Process,
+    Record,
+    Checkpoint,
+    Finish,
+    Output,
+}
+
+pub const PHASES: [Phase; 7] = [
+    Phase::Setup,
+    Phase::Read,
+    Phase::Process,
+    Phase::Record,
+    Phase::Checkpoint,
+    Phase::Finish,
+    Phase::Output,
+];
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Setup => "setup",
+            Phase::Read => "read",
+            Phase::Process => "process",
+            Phase::Record => "record",
+            Phase::Checkpoint => "checkpoint",
+            Phase::Finish => "finish",
+            Phase::Output => "output",
+        }
+    }
+
+    // Where the code of the phase is, for the profile.
+    fn filename(self) -> &'static str {
+        match self {
+            Phase::Read => "src/input.rs",
+            Phase::Process => "src/lib.rs",
+            Phase::Output => "src/output.rs",
+            _ => "src/main.rs",
+        }
+    }
+}
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+// Marks the main thread as being in the phase, until the next one. This is a single relaxed
+// store, cheap enough to be done whether the run is profiled or not.
+pub fn enter(phase: Phase) {
+    CURRENT.store(phase as usize, Ordering::Relaxed);
+}
+
+// Phase the main thread is in, as an index into PHASES.
+pub fn current() -> usize {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+// Iterator entering the phase before every item of the inner one is produced.
+pub struct Phased<I> {
+    inner: I,
+    phase: Phase,
+}
+
+impl<I: Iterator> Iterator for Phased<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        enter(self.phase);
+        self.inner.next()
+    }
+}
+
+pub fn phased<I: Iterator>(inner: I, phase: Phase) -> Phased<I> {
+    Phased { inner, phase }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Svg,   // Flame graph
+    Pprof, // Uncompressed profile.proto, which `go tool pprof` reads
+}
+
+impl Format {
+    // Format of the profile file, by its extension.
+    pub fn of(path: &str) -> Result<Format> {
+        if path.ends_with(".svg") {
+            Ok(Format::Svg)
+        } else if path.ends_with(".pb") {
+            Ok(Format::Pprof)
+        } else {
+            Err(anyhow! {"invalid --profile {} (expected a .svg or .pb file)", path})
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Samples {
+    count: u64,
+    nanos: u64, // Wall time between the sample and the one before
+}
+
+// Sampling profiler of the phases of a run: a thread looks at the phase the main thread is in
+// every millisecond, and the profile is written when the profiler is dropped, at the end of the
+// run. The samples are of the wall time, waiting on the input or a sink included.
+pub struct Profiler {
+    path: String,
+    format: Format,
+    started: SystemTime,
+    stop: Arc<AtomicBool>,
+    sampler: Option<JoinHandle<Vec<Samples>>>,
+}
+
+impl Profiler {
+    pub fn start(path: &str) -> Result<Profiler> {
+        let format = Format::of(path)?;
+        enter(Phase::Setup);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let sampler = thread::spawn(move || {
+            let mut samples = vec![Samples::default(); PHASES.len()];
+            let mut last = Instant::now();
+            while !stopped.load(Ordering::Relaxed) {
+                thread::sleep(PERIOD);
+                let now = Instant::now();
+                let s = &mut samples[current()];
+                s.count += 1;
+                s.nanos += (now - last).as_nanos() as u64;
+                last = now;
+            }
+            samples
+        });
+        Ok(Profiler {
+            path: path.to_string(),
+            format,
+            started: SystemTime::now(),
+            stop,
+            sampler: Some(sampler),
+        })
+    }
+
+    fn write(&mut self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let samples = self
+            .sampler
+            .take()
+            .map(|s| s.join())
+            .transpose()
+            .map_err(|_| anyhow! {"the profiler panicked"})?
+            .unwrap_or_default();
+        let contents = match self.format {
+            Format::Svg => flame_graph(&samples).into_bytes(),
+            Format::Pprof => pprof(&samples, self.started),
+        };
+        fs::write(&self.path, contents)
+            .map_err(|e| anyhow! {"cannot write profile {}: {}", self.path, e})
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            eprintln!("Error occurred while writing profile: {}", e);
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Flame graph of the samples: the whole run at the bottom, its phases above, as wide as the share
+// of the samples they got.
+fn flame_graph(samples: &[Samples]) -> String {
+    const WIDTH: f64 = 1200.0;
+    const FRAME: f64 = 16.0;
+    let total: u64 = samples.iter().map(|s| s.count).sum();
+    let mut svg = String::new();
+    let height = 3.0 * FRAME + 24.0;
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="Verdana" font-size="12">"#,
+        WIDTH, height
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="16" text-anchor="middle" font-size="15">ledger profile, {} samples every {}ms</text>"#,
+        WIDTH / 2.0,
+        total,
+        PERIOD.as_millis()
+    );
+    let mut frame = |name: &str, count: u64, x: f64, depth: f64, color: &str| {
+        let width = if total == 0 {
+            0.0
+        } else {
+            WIDTH * count as f64 / total as f64
+        };
+        let y = height - (depth + 1.0) * FRAME;
+        let percent = if total == 0 {
+            0.0
+        } else {
+            100.0 * count as f64 / total as f64
+        };
+        let _ = writeln!(
+            svg,
+            r#"<g><title>{} ({} samples, {:.2}%)</title><rect x="{:.1}" y="{}" width="{:.1}" height="{}" fill="{}" rx="2"/>"#,
+            escape(name),
+            count,
+            percent,
+            x,
+            y,
+            width,
+            FRAME - 1.0,
+            color
+        );
+        // Names only fit in frames wide enough, about 7 pixels a character.
+        if width > 7.0 * name.len() as f64 + 6.0 {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{}">{}</text>"#,
+                x + 3.0,
+                y + FRAME - 4.0,
+                escape(name)
+            );
+        }
+        let _ = writeln!(svg, "</g>");
+        width
+    };
+    frame("ledger", total, 0.0, 0.0, "rgb(230,110,60)");
+    let mut x = 0.0;
+    for (i, phase) in PHASES.iter().enumerate() {
+        let count = samples[i].count;
+        if count > 0 {
+            let color = format!("rgb(240,{},50)", 120 + (i * 19) % 110);
+            x += frame(phase.as_str(), count, x, 1.0, &color);
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// Protocol buffers encoding of the fields of the profile.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn int(&mut self, field: u64, v: u64) {
+        self.varint(field << 3);
+        self.varint(v);
+    }
+
+    fn bytes(&mut self, field: u64, v: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+
+    fn packed(&mut self, field: u64, values: &[u64]) {
+        let mut m = Message::default();
+        for &v in values {
+            m.varint(v);
+        }
+        self.bytes(field, &m.0);
+    }
+}
+
+// Profile in the profile.proto format of pprof, with a sample count and a wall time for every
+// stack of frames (the run, then its phase).
+fn pprof(samples: &[Samples], started: SystemTime) -> Vec<u8> {
+    let mut strings = vec![""];
+    let mut string = |s: &'static str| match strings.iter().position(|t| *t == s) {
+        Some(i) => i as u64,
+        None => {
+            strings.push(s);
+            strings.len() as u64 - 1
+        }
+    };
+    let mut p = Message::default();
+    // Message fields of Profile: sample_type = 1, sample = 2, location = 4, function = 5,
+    // string_table = 6, time_nanos = 9, duration_nanos = 10, period_type = 11, period = 12.
+    let value_type = |kind: u64, unit: u64| {
+        let mut v = Message::default();
+        v.int(1, kind);
+        v.int(2, unit);
+        v.0
+    };
+    let (samples_type, count_unit) = (string("samples"), string("count"));
+    let (wall_type, nanos_unit) = (string("wall"), string("nanoseconds"));
+    p.bytes(1, &value_type(samples_type, count_unit));
+    p.bytes(1, &value_type(wall_type, nanos_unit));
+    // Location and function 1 are those of the run, i + 2 those of the phase i.
+    let frames = [("ledger", "src/main.rs")]
+        .into_iter()
+        .chain(PHASES.iter().map(|ph| (ph.as_str(), ph.filename())));
+    for (id, (name, filename)) in (1u64..).zip(frames) {
+        let mut line = Message::default();
+        line.int(1, id);
+        let mut location = Message::default();
+        location.int(1, id);
+        location.bytes(4, &line.0);
+        p.bytes(4, &location.0);
+        let mut function = Message::default();
+        function.int(1, id);
+        function.int(2, string(name));
+        function.int(3, string(name));
+        function.int(4, string(filename));
+        p.bytes(5, &function.0);
+    }
+    let mut duration = 0;
+    for (i, s) in samples.iter().enumerate().filter(|(_, s)| s.count > 0) {
+        let mut sample = Message::default();
+        sample.packed(1, &[i as u64 + 2, 1]); // Leaf first
+        sample.packed(2, &[s.count, s.nanos]);
+        p.bytes(2, &sample.0);
+        duration += s.nanos;
+    }
+    for s in &strings {
+        p.bytes(6, s.as_bytes());
+    }
+    let since_epoch = started
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    p.int(9, since_epoch);
+    p.int(10, duration);
+    p.bytes(11, &value_type(wall_type, nanos_unit));
+    p.int(12, PERIOD.as_nanos() as u64);
+    p.0
+}
@@ -0,0 +1,47 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+
+// `ledger prune --snapshot s.json [--snapshot-out o.json] [--transactions N] [--days N]`: applies
+// a retention to the oplogs of the ledger of the snapshot (see Ledger::prune_through), that of
+// the configuration the snapshot was taken with unless --transactions or --days are given, and
+// writes the ledger back like `ledger undo`, through a temporary file. Days can only be counted
+// in snapshots taken with --retention-days, which keep the marks of the days.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut snapshot, mut snapshot_out, mut transactions, mut days) = (None, None, None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--snapshot-out" => snapshot_out = Some(value()?.clone()),
+            "--transactions" => transactions = Some(value()?.parse::<u32>()?),
+            "--days" => days = Some(value()?.parse::<u32>()?),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let path = snapshot.ok_or_else(|| anyhow! {"prune requires the --snapshot to prune"})?;
+    let mut l = crate::load_snapshot(&path)?;
+    if days.is_some() && l.config().retention_days.is_none() {
+        return Err(
+            anyhow! {"snapshot {} was taken without --retention-days, --days can't be counted", path},
+        );
+    }
+    let pruned = if transactions.is_some() || days.is_some() {
+        l.prune_older_than(transactions, days)
+    } else if l.config().retention_transactions.is_some() || l.config().retention_days.is_some() {
+        l.prune()
+    } else {
+        return Err(
+            anyhow! {"prune requires --transactions or --days, snapshot {} has no retention", path},
+        );
+    };
+    let out = snapshot_out.unwrap_or_else(|| path.clone());
+    let tmp = format!("{}.tmp", out);
+    crate::write_snapshot(&tmp, &l)?;
+    fs::rename(&tmp, &out)?;
+    println!("Pruned {} oplog entries", pruned);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Error, Result};
+use csv::{ReaderBuilder, Trim};
+use ledger::{Currency, ExchangeRateProvider, FixedRate, Ledger, RateTable, Timestamp};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+// Where the exchange rates come from: a rates file (--rates), a single fixed rate (--fixed-rate)
+// or an HTTP feed queried as rates are needed (--rates-url).
+#[derive(Debug)]
+pub enum RatesSource {
+    File(String),
+    Fixed(FixedRate),
+    Http(String),
+}
+
+impl RatesSource {
+    pub fn install(&self, l: &mut Ledger) -> Result<()> {
+        match self {
+            RatesSource::File(path) => l.set_rates(read_rates(path)?),
+            RatesSource::Fixed(rate) => l.set_rates(*rate),
+            RatesSource::Http(url) => l.set_rates(HttpRateFeed::new(url)?),
+        }
+        Ok(())
+    }
+}
+
+// Parses a fixed rate given as FROM/TO=RATE, e.g. USD/EUR=0.9.
+pub fn parse_fixed_rate(s: &str) -> Result<FixedRate> {
+    let invalid = || anyhow! {"invalid fixed rate {} (expected e.g. USD/EUR=0.9)", s};
+    let (pair, rate) = s.split_once('=').ok_or_else(invalid)?;
+    let (from, to) = pair.split_once('/').ok_or_else(invalid)?;
+    Ok(FixedRate {
+        from: from.parse().map_err(Error::msg)?,
+        to: to.parse().map_err(Error::msg)?,
+        rate: rate.parse().map_err(|_| invalid())?,
+    })
+}
+
+// Exchange rate, as read from the rates file. Rates without an effective time apply to any time.
+#[derive(Debug, serde::Deserialize)]
+struct RateRecord {
+    from: Currency,
+    to: Currency,
+    rate: f32,
+    #[serde(default)]
+    effective: Option<Timestamp>,
+}
+
+// Reads the rates file: a CSV file with from, to, rate and (optionally) effective columns.
+fn read_rates(path: &str) -> Result<RateTable> {
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_path(path)
+        .map_err(|e| anyhow! {"cannot read rates file {}: {}", path, e})?;
+    let mut rates = RateTable::new();
+    for record in rdr.deserialize() {
+        let r: RateRecord = record.map_err(|e| anyhow! {"invalid rates file {}: {}", path, e})?;
+        rates.insert(r.from, r.to, r.effective, r.rate);
+    }
+    Ok(rates)
+}
+
+// Rate feed served over plain HTTP. The rate of a pair is fetched on first use with
+// GET <url>?from=USD&to=EUR[&at=<RFC 3339 time>], and the response body is the bare rate. Rates
+// (and failures, which are reported once on stderr) are cached for the rest of the run.
+#[derive(Debug)]
+struct HttpRateFeed {
+    host: String, // host[:port], as given in the URL
+    path: String,
+    cache: Mutex<HashMap<RateKey, Option<f32>>>,
+}
+
+type RateKey = (Currency, Currency, Option<Timestamp>);
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl HttpRateFeed {
+    fn new(url: &str) -> Result<HttpRateFeed> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow! {"unsupported rates URL {} (expected http://...)", url})?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        Ok(HttpRateFeed {
+            host: host.to_string(),
+            path: path.to_string(),
+            cache: Mutex::default(),
+        })
+    }
+
+    fn fetch(&self, from: Currency, to: Currency, at: Option<Timestamp>) -> Result<f32> {
+        let separator = if self.path.contains('?') { '&' } else { '?' };
+        let mut target = format!("{}{}from={}&to={}", self.path, separator, from, to);
+        if let Some(at) = at {
+            target.push_str(&format!("&at={}", at));
+        }
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:80", self.host)
+        };
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            target, self.host
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow! {"malformed HTTP response"})?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(anyhow! {"{}", status});
+        }
+        f32::from_str(body.trim()).map_err(|_| anyhow! {"invalid rate {}", body.trim()})
+    }
+}
+
+impl ExchangeRateProvider for HttpRateFeed {
+    fn rate(&self, from: Currency, to: Currency, at: Option<Timestamp>) -> Option<f32> {
+        if from == to {
+            return Some(1.0);
+        }
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        *cache.entry((from, to, at)).or_insert_with(|| {
+            self.fetch(from, to, at)
+                .map_err(|e| eprintln!("Error occurred while fetching rate {}/{}: {}", from, to, e))
+                .ok()
+        })
+    }
+}
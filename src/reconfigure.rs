@@ -0,0 +1,124 @@
+use crate::{duplicate_filter, Account, Config, Ledger, OperationState};
+use std::fmt;
+
+// Change of configuration Ledger::reconfigure refuses, as the state of the ledger was built with
+// the setting it would replace.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Incompatibility {
+    // The main balances of the accounts are in the base currency.
+    BaseCurrency { accounts: usize },
+    // The pending bonuses expire on the days bonus_expiry_days and the calendar gave them.
+    BonusExpiry { pending: usize },
+    // Likewise for the pending authorizations and authorization_expiry_days.
+    AuthorizationExpiry { pending: usize },
+    // The open disputes are in stages of the dispute lifecycle.
+    DisputeLifecycle { open: usize },
+}
+
+impl Incompatibility {
+    // Stable identifier of the incompatibility, like LedgerError::code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Incompatibility::BaseCurrency { .. } => "base_currency",
+            Incompatibility::BonusExpiry { .. } => "bonus_expiry",
+            Incompatibility::AuthorizationExpiry { .. } => "authorization_expiry",
+            Incompatibility::DisputeLifecycle { .. } => "dispute_lifecycle",
+        }
+    }
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Incompatibility::BaseCurrency { accounts } => {
+                write!(f, "base-currency can't change with {} accounts", accounts)
+            }
+            Incompatibility::BonusExpiry { pending } => write!(
+                f,
+                "bonus-expiry-days and calendar can't change with {} bonuses pending expiry",
+                pending
+            ),
+            Incompatibility::AuthorizationExpiry { pending } => write!(
+                f,
+                "authorization-expiry-days and calendar can't change with {} authorizations \
+                 pending expiry",
+                pending
+            ),
+            Incompatibility::DisputeLifecycle { open } => write!(
+                f,
+                "dispute-lifecycle can't change with {} disputes open",
+                open
+            ),
+        }
+    }
+}
+
+pub(crate) fn open_disputes(a: &Account) -> usize {
+    a.operations()
+        .filter(|(_, op)| {
+            matches!(
+                op,
+                OperationState::DisputedDeposit { .. }
+                    | OperationState::RepresentedDeposit { .. }
+                    | OperationState::PreArbitrationDeposit { .. }
+                    | OperationState::ArbitrationDeposit { .. }
+            )
+        })
+        .count()
+}
+
+impl Ledger {
+    // Changes of the configuration which the state of the ledger is incompatible with, see
+    // Incompatibility. Limits, fees, tiers, credit lines, policies and flags only apply to the
+    // transactions to come, they may always change.
+    pub fn incompatibilities(&self, config: &Config) -> Vec<Incompatibility> {
+        let old = &self.config;
+        let mut found = Vec::new();
+        let accounts = self.accounts().count();
+        if config.base_currency != old.base_currency && accounts > 0 {
+            found.push(Incompatibility::BaseCurrency { accounts });
+        }
+        let calendar = config.calendar != old.calendar;
+        let pending = self.bonus_expiries.len();
+        if (calendar || config.bonus_expiry_days != old.bonus_expiry_days) && pending > 0 {
+            found.push(Incompatibility::BonusExpiry { pending });
+        }
+        let pending = self.authorization_expiries.len();
+        if (calendar || config.authorization_expiry_days != old.authorization_expiry_days)
+            && pending > 0
+        {
+            found.push(Incompatibility::AuthorizationExpiry { pending });
+        }
+        if config.dispute_lifecycle != old.dispute_lifecycle {
+            let open: usize = self
+                .accounts()
+                .flat_map(|(_, a)| a.subaccounts().map(|(_, a)| a).chain([a]))
+                .map(open_disputes)
+                .sum();
+            if open > 0 {
+                found.push(Incompatibility::DisputeLifecycle { open });
+            }
+        }
+        found
+    }
+
+    // Replaces the configuration of a running ledger, unless the state of the ledger is
+    // incompatible with the change (see Ledger::incompatibilities), in which case the ledger is
+    // left as it was. The duplicate filter is rebuilt for the new capacity and the undo journal
+    // shortened to the new depth.
+    pub fn reconfigure(&mut self, config: Config) -> Result<(), Vec<Incompatibility>> {
+        let found = self.incompatibilities(&config);
+        if !found.is_empty() {
+            return Err(found);
+        }
+        if config.duplicate_filter_capacity != self.config.duplicate_filter_capacity {
+            self.duplicate_filter = duplicate_filter(&config, &self.accounts);
+        }
+        while self.undo.len() > config.undo_depth {
+            self.undo.pop_front();
+        }
+        self.config = config;
+        Ok(())
+    }
+}
@@ -0,0 +1,349 @@
+use crate::memory::{self, MemoryStats};
+use crate::Summary;
+use anyhow::{anyhow, Error};
+use csv::StringRecord;
+use ledger::{Amount, LedgerError, Money};
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+
+// Format of the rejection messages written to stderr. Text is meant for humans, Json writes one
+// object per line (NDJSON) for jq or log collectors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorsFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorsFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorsFormat::Text),
+            "json" => Ok(ErrorsFormat::Json),
+            _ => Err(anyhow! {"unknown errors format {} (expected text or json)", s}),
+        }
+    }
+}
+
+// Reason code of records which could not be parsed at all.
+const PARSE_ERROR: &str = "parse_error";
+
+// Ways an amount breaks the strict amount syntax, which only accepts plain decimals (an optional
+// minus sign, digits and at most MAX_DECIMALS decimal places) rather than whatever the float
+// parser happens to accept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AmountViolation {
+    ScientificNotation,
+    NotFinite, // NaN, inf, infinity
+    TooManyDecimals,
+    LeadingPlus,
+    Malformed, // Anything else the float parser accepts or not, e.g. "1." or "1,5"
+}
+
+pub const MAX_DECIMALS: usize = 4;
+
+impl AmountViolation {
+    // The violation of the amount, if any.
+    pub fn of(amount: &str) -> Option<AmountViolation> {
+        let lower = amount.to_ascii_lowercase();
+        let unsigned = lower.strip_prefix(['-', '+']).unwrap_or(&lower);
+        if matches!(unsigned, "nan" | "inf" | "infinity") {
+            return Some(AmountViolation::NotFinite);
+        }
+        if amount.starts_with('+') {
+            return Some(AmountViolation::LeadingPlus);
+        }
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if let Some((mantissa, exponent)) = unsigned.split_once('e') {
+            let exponent = exponent.strip_prefix(['-', '+']).unwrap_or(exponent);
+            if digits(exponent) && mantissa.split('.').all(digits) {
+                return Some(AmountViolation::ScientificNotation);
+            }
+        }
+        match unsigned.split_once('.') {
+            None if digits(unsigned) => None,
+            Some((integer, decimals)) if digits(integer) && digits(decimals) => {
+                (decimals.len() > MAX_DECIMALS).then_some(AmountViolation::TooManyDecimals)
+            }
+            _ => Some(AmountViolation::Malformed),
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            AmountViolation::ScientificNotation => "amount_scientific_notation",
+            AmountViolation::NotFinite => "amount_not_finite",
+            AmountViolation::TooManyDecimals => "amount_too_many_decimals",
+            AmountViolation::LeadingPlus => "amount_leading_plus",
+            AmountViolation::Malformed => "amount_malformed",
+        }
+    }
+}
+
+impl std::fmt::Display for AmountViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AmountViolation::ScientificNotation => write!(f, "Amount in scientific notation"),
+            AmountViolation::NotFinite => write!(f, "Amount is not a finite number"),
+            AmountViolation::TooManyDecimals => {
+                write!(f, "Amount has more than {} decimal places", MAX_DECIMALS)
+            }
+            AmountViolation::LeadingPlus => write!(f, "Amount has a leading +"),
+            AmountViolation::Malformed => write!(f, "Amount is not a plain decimal number"),
+        }
+    }
+}
+
+// A record of the input which was not applied to the ledger, along with its position in the
+// input. Transaction and client ids are missing when they could not be parsed from the record.
+// The memo is the one of the record, if the input has a memo column.
+#[derive(Debug, serde::Serialize)]
+pub struct Rejection {
+    pub line: Option<u64>,
+    pub byte: Option<u64>,
+    pub tx: Option<u32>,
+    pub client: Option<u16>,
+    pub reason: &'static str,
+    pub message: String,
+    pub record: String, // The raw record, with fields joined by commas
+    pub memo: Option<String>,
+}
+
+fn raw(record: &StringRecord) -> String {
+    record.iter().collect::<Vec<_>>().join(",")
+}
+
+impl Rejection {
+    pub fn new(record: &StringRecord, tx: u32, client: u16, e: LedgerError) -> Rejection {
+        let position = record.position();
+        Rejection {
+            line: position.map(|p| p.line()),
+            byte: position.map(|p| p.byte()),
+            tx: Some(tx),
+            client: Some(client),
+            reason: e.code(),
+            message: e.to_string(),
+            record: raw(record),
+            memo: None,
+        }
+    }
+
+    // Rejection for a record which failed to parse. Whatever ids can still be read from the raw
+    // record are kept, to help tracking the record down.
+    pub fn parse_error(record: Option<&StringRecord>, e: &csv::Error) -> Rejection {
+        let field = |i| record.and_then(|r| r.get(i));
+        let position = e.position().or_else(|| record.and_then(|r| r.position()));
+        // The position is reported separately, so it is left out of the message.
+        let message = match e.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => format!("CSV deserialize error: {}", err),
+            _ => e.to_string(),
+        };
+        Rejection {
+            line: position.map(|p| p.line()),
+            byte: position.map(|p| p.byte()),
+            tx: field(2).and_then(|f| f.parse().ok()),
+            client: field(1).and_then(|f| f.parse().ok()),
+            reason: PARSE_ERROR,
+            message,
+            record: record.map(raw).unwrap_or_default(),
+            memo: None,
+        }
+    }
+
+    // Rejection for a record whose timestamp can't be read in the zone of --assume-tz, though it
+    // parsed in UTC.
+    pub fn invalid_timestamp(record: &StringRecord, message: String) -> Rejection {
+        let position = record.position();
+        Rejection {
+            line: position.map(|p| p.line()),
+            byte: position.map(|p| p.byte()),
+            tx: record.get(2).and_then(|f| f.parse().ok()),
+            client: record.get(1).and_then(|f| f.parse().ok()),
+            reason: PARSE_ERROR,
+            message,
+            record: raw(record),
+            memo: None,
+        }
+    }
+
+    // Whether the record is the retry of an applied one (LedgerError::AlreadyApplied), which is
+    // acknowledged rather than rejected.
+    pub fn is_acknowledged(&self) -> bool {
+        self.reason == "already_applied"
+    }
+
+    // Rejection for a record whose amount breaks the strict amount syntax (--strict-amounts), with
+    // the reason code of the violation.
+    pub fn invalid_amount(record: &StringRecord, violation: AmountViolation) -> Rejection {
+        let position = record.position();
+        Rejection {
+            line: position.map(|p| p.line()),
+            byte: position.map(|p| p.byte()),
+            tx: record.get(2).and_then(|f| f.parse().ok()),
+            client: record.get(1).and_then(|f| f.parse().ok()),
+            reason: violation.code(),
+            message: violation.to_string(),
+            record: raw(record),
+            memo: None,
+        }
+    }
+
+    // Rejection for a record of a client whose account was written out already, as final, with
+    // --stream-finalized.
+    pub fn finalized(record: &StringRecord, client: u16) -> Rejection {
+        let position = record.position();
+        Rejection {
+            line: position.map(|p| p.line()),
+            byte: position.map(|p| p.byte()),
+            tx: record.get(2).and_then(|f| f.parse().ok()),
+            client: Some(client),
+            reason: "client_finalized",
+            message: format!(
+                "Account of client {} already written out, its records must be contiguous",
+                client
+            ),
+            record: raw(record),
+            memo: None,
+        }
+    }
+
+    // The same rejection, with the memo of the rejected record.
+    pub fn with_memo(self, memo: Option<&str>) -> Rejection {
+        Rejection {
+            memo: memo.filter(|m| !m.is_empty()).map(String::from),
+            ..self
+        }
+    }
+}
+
+pub fn report(format: ErrorsFormat, r: &Rejection) {
+    match format {
+        ErrorsFormat::Text => match (r.line, r.byte) {
+            (Some(line), Some(byte)) => {
+                eprintln!(
+                    "Error occurred: line {}, byte {}: {}",
+                    line, byte, r.message
+                )
+            }
+            _ => eprintln!("Error occurred: {}", r.message),
+        },
+        ErrorsFormat::Json => match serde_json::to_string(r) {
+            Ok(json) => eprintln!("{}", json),
+            Err(e) => eprintln!("Error occurred while serializing rejection: {}", e),
+        },
+    }
+}
+
+// Writes every rejection as a row of a CSV file, so that rejected records can be inspected and
+// re-submitted after the run.
+pub struct RejectsWriter {
+    writer: csv::Writer<File>,
+}
+
+impl RejectsWriter {
+    pub fn create(path: &str) -> Result<RejectsWriter, csv::Error> {
+        Ok(RejectsWriter {
+            writer: csv::Writer::from_path(path)?,
+        })
+    }
+
+    // Carries on the file of an interrupted run (see checkpoint::reopen), which has the header
+    // unless it is empty.
+    pub fn resume(file: File) -> io::Result<RejectsWriter> {
+        let empty = file.metadata()?.len() == 0;
+        Ok(RejectsWriter {
+            writer: csv::WriterBuilder::new()
+                .has_headers(empty)
+                .from_writer(file),
+        })
+    }
+
+    // Flushes the file and returns its length, for the checkpoints.
+    pub fn len(&mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+
+    pub fn write(&mut self, r: &Rejection) -> Result<(), csv::Error> {
+        self.writer.serialize(r)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// The end-of-run summary goes to stderr next to the rejections, so it follows the same format.
+// Memory statistics of the summary, in text, see --mem-stats.
+pub fn report_memory(stats: &MemoryStats) {
+    eprint!("Memory: ");
+    if let Some(rss) = stats.peak_rss {
+        eprint!("{} peak RSS, ", memory::mib(rss));
+    }
+    eprintln!(
+        "{} peak heap, {} allocations",
+        memory::mib(stats.peak_heap),
+        stats.allocations
+    );
+    for p in &stats.phases {
+        eprintln!(
+            "  {}: {} peak heap, {} allocations",
+            p.phase,
+            memory::mib(p.peak_heap),
+            p.allocations
+        );
+    }
+}
+
+pub fn report_summary(format: ErrorsFormat, summary: &Summary) {
+    match format {
+        ErrorsFormat::Text => {
+            let reasons: Vec<String> = summary
+                .rejections
+                .iter()
+                .map(|(reason, count)| format!("{}: {}", reason, count))
+                .collect();
+            eprint!(
+                "Processed {} records: {} applied, {} rejected",
+                summary.records, summary.applied, summary.rejected
+            );
+            if !reasons.is_empty() {
+                eprint!(" ({})", reasons.join(", "));
+            }
+            if summary.fees > Amount::ZERO {
+                eprint!(", {:.4} in fees charged", summary.fees);
+            }
+            if summary.acknowledged > 0 {
+                eprint!(", {} acknowledged as already applied", summary.acknowledged);
+            }
+            if summary.queued > 0 {
+                eprint!(", {} chargebacks queued for review", summary.queued);
+            }
+            if summary.skipped > 0 {
+                eprint!(
+                    ", {} skipped as in the base snapshot or checkpoint",
+                    summary.skipped
+                );
+            }
+            if summary.unsampled > 0 {
+                eprint!(", {} of clients out of the sample", summary.unsampled);
+            }
+            if summary.pruned > 0 {
+                eprint!(", {} oplog entries pruned", summary.pruned);
+            }
+            eprintln!();
+            for sniffed in &summary.dialects {
+                eprintln!("Detected dialect of {}: {}", sniffed.input, sniffed.dialect);
+            }
+            if let Some(stats) = &summary.memory {
+                report_memory(stats);
+            }
+        }
+        ErrorsFormat::Json => match serde_json::to_string(summary) {
+            Ok(json) => eprintln!(r#"{{"summary":{}}}"#, json),
+            Err(e) => eprintln!("Error occurred while serializing summary: {}", e),
+        },
+    }
+}
@@ -0,0 +1,130 @@
+use crate::slab::Accounts;
+use crate::AccountState::{Locked, Open};
+use crate::{authorization_expiries, bonus_expiries, Account, Ledger};
+use std::collections::HashMap;
+use std::mem;
+
+// Conflict found while remapping client ids, see Ledger::remap_clients.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemapConflict {
+    pub client_id: u16, // New id of the merged account
+    pub into: u16,      // Old id of the account merged into
+    pub merged: u16,    // Old id of the account merged into it
+    pub kind: RemapConflictKind,
+}
+
+// New kinds may come in any release, see as_str for their stable identifiers.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum RemapConflictKind {
+    // Both clients had an account, the accounts were merged.
+    Merged,
+    // Both accounts logged the transaction, the one of the account merged into was kept.
+    DuplicateTransaction(u32),
+    // One of the accounts was locked, the merged account is.
+    Locked,
+    // The tags of the transaction didn't fit into the MAX_TAGS of the merged account, they were
+    // dropped.
+    TagsDropped(u32),
+}
+
+impl RemapConflictKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RemapConflictKind::Merged => "merged",
+            RemapConflictKind::DuplicateTransaction(_) => "duplicate_transaction",
+            RemapConflictKind::Locked => "locked",
+            RemapConflictKind::TagsDropped(_) => "tags_dropped",
+        }
+    }
+}
+
+impl Account {
+    // Merges another account of the same client into this one: the balances add up, the logs of
+    // transactions, bonuses, sweeps, tags and memos are joined, and sub-accounts of the same name are
+    // merged in turn. Returns the conflicts, as kinds.
+    pub(crate) fn merge(&mut self, other: Account) -> Vec<RemapConflictKind> {
+        let mut conflicts = Vec::new();
+        if self.is_locked() != other.is_locked() {
+            conflicts.push(RemapConflictKind::Locked);
+        }
+        let (available, held) = (
+            self.available() + other.available(),
+            self.held() + other.held(),
+        );
+        self.state = if self.is_locked() || other.is_locked() {
+            Locked { available, held }
+        } else {
+            Open { available, held }
+        };
+        for (tx_id, op) in other.oplog.iter() {
+            if self.oplog.contains_key(tx_id) {
+                conflicts.push(RemapConflictKind::DuplicateTransaction(tx_id));
+                continue;
+            }
+            self.oplog.insert(tx_id, op);
+            let tags: Vec<&str> = other.tags(tx_id).collect();
+            if self.check_tags(&tags).is_ok() {
+                self.add_tags(tx_id, &tags);
+            } else {
+                conflicts.push(RemapConflictKind::TagsDropped(tx_id));
+            }
+            if let Some(memos) = other.memos.get(&tx_id) {
+                self.memos.insert(tx_id, memos.clone());
+            }
+        }
+        for (currency, balance) in other.currencies {
+            *self.currencies.entry(currency).or_default() += balance;
+        }
+        self.fees += other.fees;
+        self.escrow += other.escrow;
+        self.bonuses.extend(other.bonuses);
+        self.sweeps.extend(other.sweeps);
+        for (name, subaccount) in other.subaccounts {
+            match self.subaccounts.get_mut(&name) {
+                Some(a) => conflicts.extend(a.merge(subaccount)),
+                None => {
+                    self.subaccounts.insert(name, subaccount);
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+impl Ledger {
+    // Renames clients according to the mapping (old id -> new id), clients not in it keeping
+    // their id. When several accounts end up with the same id, whether mapped to the same id or
+    // mapped to the id of an existing client, they are merged (in the order they were opened) and
+    // the conflicts are returned. The undo journal refers to the old ids, so it is cleared.
+    pub fn remap_clients(&mut self, mapping: &HashMap<u16, u16>) -> Vec<RemapConflict> {
+        let mut conflicts = Vec::new();
+        let mut accounts = Accounts::with_capacity(self.accounts.len());
+        let mut old_ids = HashMap::new(); // Of the accounts merged into, by new id
+        for (client_id, mut account) in mem::take(&mut self.accounts) {
+            let new_id = mapping.get(&client_id).copied().unwrap_or(client_id);
+            // Sweeps name the depositors, which are renamed too.
+            for sweep in &mut account.sweeps {
+                sweep.client = mapping.get(&sweep.client).copied().unwrap_or(sweep.client);
+            }
+            let Some(a) = accounts.get_mut(new_id) else {
+                accounts.insert(new_id, account);
+                old_ids.insert(new_id, client_id);
+                continue;
+            };
+            let conflict = |kind| RemapConflict {
+                client_id: new_id,
+                into: old_ids[&new_id],
+                merged: client_id,
+                kind,
+            };
+            conflicts.push(conflict(RemapConflictKind::Merged));
+            conflicts.extend(a.merge(account).into_iter().map(conflict));
+        }
+        self.bonus_expiries = bonus_expiries(&accounts);
+        self.authorization_expiries = authorization_expiries(&accounts);
+        self.accounts = accounts;
+        self.undo.clear();
+        conflicts
+    }
+}
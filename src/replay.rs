@@ -0,0 +1,464 @@
+use anyhow::{anyhow, Result};
+use ledger::{Account, Amount, Applied, Balance, Config, Ledger, Timestamp, TransactionEntry};
+
+// Record of the audit log, as replayed: the transaction with the amount it actually moved, and
+// the balances it left, which the replayed transaction has to leave as well.
+#[derive(Debug, serde::Deserialize)]
+struct LoggedRecord {
+    line: u64,
+    client: u16,
+    subaccount: Option<String>,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: String,
+    amount: Option<Amount>,
+    available: Amount,
+    held: Amount,
+    #[serde(default)]
+    escrow: Amount,
+    locked: bool,
+    timestamp: Option<Timestamp>,
+    #[serde(default)]
+    expired: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+    memo: Option<String>,
+    reason: Option<String>,
+}
+
+// Tombstone of a forgotten client, in place of its records (see audit::forget): the balances they
+// left, over all its sub-accounts, which the snapshot has to have.
+#[derive(Debug, serde::Deserialize)]
+struct ForgottenRecord {
+    line: u64,
+    client: u16,
+    available: Amount,
+    held: Amount,
+    #[serde(default)]
+    escrow: Amount,
+    locked: bool,
+}
+
+// Differences reported in full; past that, only counted.
+const SHOWN_DIFFERENCES: usize = 10;
+
+// `ledger replay <log> [--through-line N] [--snapshot s.json] [--config c.toml]`: rebuilds the
+// ledger from the audit log alone, by applying its records again as transactions (with the amounts
+// they moved), up to the records of input line N. Every replayed record has to leave the balances
+// the log recorded, and with a snapshot, the rebuilt ledger has to match it account by account,
+// which shows that the log is enough to recover the state. The ledger rules come from the
+// snapshot, or else from the config file: replaying under other rules (fees, precision) would
+// diverge.
+//
+// The records keep their timestamps, so bonuses and authorizations expire during the replay as
+// they did during the run: before the record whose timestamp is past their expiry, and each
+// expiry has to be in the log there, with the balances it left. Compacted logs can't be replayed,
+// their checkpoint only keeps balances. Forgotten clients (see `ledger forget`) aren't replayed
+// either, their tombstones are compared with the snapshot.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut path, mut through_line, mut snapshot, mut config) = (None, None, None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--through-line" => through_line = Some(value()?.parse::<u64>()?),
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--config" => config = Some(value()?.clone()),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let path = path.ok_or_else(|| anyhow! {"replay requires the audit log to replay"})?;
+    let snapshot = snapshot.as_deref().map(crate::load_snapshot).transpose()?;
+    let config = match (&snapshot, config) {
+        (Some(s), None) => s.config().clone(),
+        (Some(_), Some(_)) => return Err(anyhow! {"--config can't be given with --snapshot"}),
+        (None, Some(path)) => crate::read_config(&path)?,
+        (None, None) => Config::default(),
+    };
+    let mut l = Ledger::with_config(config);
+    let contents = crate::encryption::read_log(&path)
+        .map_err(|e| anyhow! {"cannot read audit log {}: {}", path, e})?;
+    let mut differences = Differences::default();
+    let mut replayed = 0;
+    let mut forgotten = Vec::new();
+    // Expiries of the replay which the log has yet to record.
+    let mut expiries: Vec<Applied> = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        if line.starts_with(r#"{"checkpoint":"#) {
+            return Err(anyhow! {"audit log {} was compacted, replay needs the whole log", path});
+        }
+        let invalid = |e| anyhow! {"invalid audit log {}: line {}: {}", path, n + 1, e};
+        if line.starts_with(r#"{"forgotten":"#) {
+            let f: ForgottenRecord = serde_json::from_str(line).map_err(invalid)?;
+            if through_line.is_some_and(|through| f.line > through) {
+                break;
+            }
+            forgotten.push(f);
+            continue;
+        }
+        let r: LoggedRecord = serde_json::from_str(line).map_err(invalid)?;
+        if through_line.is_some_and(|through| r.line > through) {
+            break;
+        }
+        replayed += 1;
+        if let Some(t) = r.timestamp {
+            expiries.extend(l.expire_bonuses(t));
+            expiries.extend(l.expire_authorizations(t));
+        }
+        if r.expired {
+            match expiries
+                .iter()
+                .position(|e| e.client_id == r.client && e.tx == r.tx)
+            {
+                Some(i) => differences.check(&r, &expiries.remove(i).after),
+                None => differences.add(format!(
+                    "line {}: {} tx {} of client {} expired in the log but not in the replay",
+                    r.line, r.kind, r.tx, r.client
+                )),
+            }
+            continue;
+        }
+        // The expiries due by the time of the record were logged before it.
+        for e in expiries.drain(..) {
+            differences.add(format!(
+                "line {}: {} tx {} of client {} expired in the replay but not in the log",
+                r.line,
+                e.kind.as_str(),
+                e.tx,
+                e.client_id
+            ));
+        }
+        let entry = TransactionEntry {
+            t: r.kind.clone(),
+            client_id: r.client,
+            uid: r.tx,
+            amount: r.amount,
+            currency: None,
+            to_currency: None,
+            timestamp: r.timestamp,
+            subaccount: r.subaccount.clone(),
+            tags: (!r.tags.is_empty()).then(|| r.tags.join(";")),
+            memo: r.memo.clone(),
+            idempotency_key: None, // Retries were acknowledged, they aren't in the log
+            reason: r.reason.clone(),
+        };
+        match l.apply_transaction(entry) {
+            Ok(applied) => differences.check(&r, &applied.after),
+            Err(e) => differences.add(format!(
+                "line {}: {} tx {} of client {} failed: {}",
+                r.line, r.kind, r.tx, r.client, e
+            )),
+        }
+    }
+    eprintln!("Replayed {} records of {}", replayed, path);
+    if let Some(snapshot) = &snapshot {
+        compare(&l, snapshot, &forgotten, &mut differences);
+    }
+    match differences.count {
+        0 if snapshot.is_some() => {
+            eprintln!("The replayed ledger matches the log and the snapshot")
+        }
+        0 => eprintln!("The replayed ledger matches the log"),
+        n => {
+            if n > SHOWN_DIFFERENCES {
+                eprintln!("... and {} more differences", n - SHOWN_DIFFERENCES);
+            }
+            return Err(anyhow! {"the replayed ledger differs in {} places", n});
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Differences {
+    count: usize,
+}
+
+impl Differences {
+    fn add(&mut self, difference: String) {
+        self.count += 1;
+        if self.count <= SHOWN_DIFFERENCES {
+            eprintln!("Difference: {}", difference);
+        }
+    }
+
+    // Checks that the replayed record left the balances the log recorded.
+    fn check(&mut self, r: &LoggedRecord, after: &Balance) {
+        let expected = (r.available, r.held, r.escrow, r.locked);
+        if (after.available, after.held, after.escrow, after.locked) != expected {
+            self.add(format!(
+                "line {}: {} tx {} of client {} left {} instead of {}",
+                r.line,
+                r.kind,
+                r.tx,
+                r.client,
+                balances(after.available, after.held, after.escrow, after.locked),
+                balances(r.available, r.held, r.escrow, r.locked)
+            ));
+        }
+    }
+}
+
+// Balances as reported in differences, e.g. "available 1.5, held 0, escrow 0, locked".
+fn balances(available: Amount, held: Amount, escrow: Amount, locked: bool) -> String {
+    format!(
+        "available {}, held {}, escrow {}, {}",
+        available,
+        held,
+        escrow,
+        if locked { "locked" } else { "open" }
+    )
+}
+
+fn balance(b: &Balance) -> String {
+    balances(b.available, b.held, b.escrow, b.locked)
+}
+
+// Compares the accounts of the replayed ledger with those of the snapshot: their balances and
+// the states of their transactions, sub-accounts included. The accounts of forgotten clients are
+// compared with their tombstones instead.
+fn compare(
+    replayed: &Ledger,
+    snapshot: &Ledger,
+    forgotten: &[ForgottenRecord],
+    differences: &mut Differences,
+) {
+    for f in forgotten {
+        // Compared as amounts, not as text: the tombstone and the snapshot may write the same
+        // amount with other decimal places.
+        let expected = (f.available, f.held, f.escrow, f.locked);
+        match snapshot.account(f.client).map(Account::rollup) {
+            Some(b) if (b.available, b.held, b.escrow, b.locked) == expected => {}
+            Some(b) => differences.add(format!(
+                "forgotten client {} has {} in the snapshot instead of {}",
+                f.client,
+                balance(&b),
+                balances(f.available, f.held, f.escrow, f.locked)
+            )),
+            None => differences.add(format!(
+                "forgotten client {} is not in the snapshot, which should have {}",
+                f.client,
+                balances(f.available, f.held, f.escrow, f.locked)
+            )),
+        }
+    }
+    let mut clients: Vec<u16> = replayed
+        .accounts()
+        .chain(snapshot.accounts())
+        .map(|(client_id, _)| client_id)
+        .filter(|&client_id| forgotten.iter().all(|f| f.client != client_id))
+        .collect();
+    clients.sort_unstable();
+    clients.dedup();
+    for client_id in clients {
+        match (replayed.account(client_id), snapshot.account(client_id)) {
+            (Some(a), Some(b)) => {
+                compare_accounts(client_id, None, a, b, differences);
+                let mut names: Vec<&str> = a
+                    .subaccounts()
+                    .chain(b.subaccounts())
+                    .map(|(n, _)| n)
+                    .collect();
+                names.sort_unstable();
+                names.dedup();
+                for name in names {
+                    match (a.subaccount(name), b.subaccount(name)) {
+                        (Some(a), Some(b)) => compare_accounts(client_id, Some(name), a, b, differences),
+                        _ => differences.add(format!(
+                            "sub-account {} of client {} is only in one of the replayed ledger and the snapshot",
+                            name, client_id
+                        )),
+                    }
+                }
+            }
+            (a, _) => differences.add(format!(
+                "client {} is only in the {}",
+                client_id,
+                if a.is_some() {
+                    "replayed ledger"
+                } else {
+                    "snapshot"
+                }
+            )),
+        }
+    }
+}
+
+fn compare_accounts(
+    client_id: u16,
+    subaccount: Option<&str>,
+    a: &Account,
+    b: &Account,
+    differences: &mut Differences,
+) {
+    let name = match subaccount {
+        Some(name) => format!("sub-account {} of client {}", name, client_id),
+        None => format!("client {}", client_id),
+    };
+    if a.balance() != b.balance() {
+        differences.add(format!(
+            "{} has {} instead of {}",
+            name,
+            balance(&a.balance()),
+            balance(&b.balance())
+        ));
+    }
+    let mut txs: Vec<u32> = a
+        .operations()
+        .chain(b.operations())
+        .map(|(tx, _)| tx)
+        .collect();
+    txs.sort_unstable();
+    txs.dedup();
+    // States are shown as in snapshots, e.g. {"state":"regular_deposit","amount":10.0}.
+    let state = |op| match op {
+        Some(op) => serde_json::to_string(&op).unwrap_or_default(),
+        None => "not logged".to_string(),
+    };
+    for tx in txs {
+        let (op, expected) = (state(a.operation(tx)), state(b.operation(tx)));
+        if op != expected {
+            differences.add(format!(
+                "tx {} of {} is {} instead of {}",
+                tx, name, op, expected
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(kind: &str, client_id: u16, uid: u32, amount: Option<&str>) -> TransactionEntry {
+        TransactionEntry {
+            t: kind.to_string(),
+            client_id,
+            uid,
+            amount: amount.map(|a| a.parse().unwrap()),
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            subaccount: None,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        }
+    }
+
+    fn ledger(txs: Vec<TransactionEntry>) -> Ledger {
+        let mut l = Ledger::new();
+        for t in txs {
+            assert!(l.apply_transaction(t).is_ok());
+        }
+        l
+    }
+
+    fn differences(replayed: &Ledger, snapshot: &Ledger, forgotten: &[ForgottenRecord]) -> usize {
+        let mut differences = Differences::default();
+        compare(replayed, snapshot, forgotten, &mut differences);
+        differences.count
+    }
+
+    #[test]
+    fn balances_are_described_in_plain_text() {
+        let (available, held) = ("1.5".parse().unwrap(), "2".parse().unwrap());
+        assert_eq!(
+            balances(available, held, Amount::default(), true),
+            format!("available {}, held {}, escrow 0, locked", available, held)
+        );
+        assert!(balances(available, held, Amount::default(), false).ends_with(", open"));
+    }
+
+    #[test]
+    fn differences_past_the_shown_ones_are_counted() {
+        let mut differences = Differences::default();
+        for n in 0..SHOWN_DIFFERENCES + 3 {
+            differences.add(format!("difference {}", n));
+        }
+        assert_eq!(differences.count, SHOWN_DIFFERENCES + 3);
+    }
+
+    #[test]
+    fn the_same_ledgers_have_no_differences() {
+        let txs = || {
+            vec![
+                tx("deposit", 1, 1, Some("10")),
+                tx("deposit", 2, 2, Some("5")),
+                tx("dispute", 2, 2, None),
+            ]
+        };
+        assert_eq!(differences(&ledger(txs()), &ledger(txs()), &[]), 0);
+    }
+
+    #[test]
+    fn balances_states_and_clients_are_compared() {
+        let replayed = ledger(vec![
+            tx("deposit", 1, 1, Some("10")),
+            tx("deposit", 2, 2, Some("5")),
+        ]);
+        // Client 1 has another balance, client 2 the deposit under dispute.
+        let snapshot = ledger(vec![
+            tx("deposit", 1, 1, Some("10")),
+            tx("deposit", 1, 3, Some("1")),
+            tx("deposit", 2, 2, Some("5")),
+            tx("dispute", 2, 2, None),
+        ]);
+        // The balance and tx 3 of client 1, the balance and tx 2 of client 2.
+        assert_eq!(differences(&replayed, &snapshot, &[]), 4);
+        let more = ledger(vec![
+            tx("deposit", 1, 1, Some("10")),
+            tx("deposit", 2, 2, Some("5")),
+            tx("deposit", 3, 4, Some("1")),
+        ]);
+        assert_eq!(differences(&more, &replayed, &[]), 1);
+        assert_eq!(differences(&replayed, &more, &[]), 1);
+    }
+
+    #[test]
+    fn sub_accounts_are_compared() {
+        let of = |subaccount: &str| TransactionEntry {
+            subaccount: Some(subaccount.to_string()),
+            ..tx("deposit", 1, 2, Some("3"))
+        };
+        let replayed = ledger(vec![tx("deposit", 1, 1, Some("10")), of("savings")]);
+        assert_eq!(
+            differences(
+                &replayed,
+                &ledger(vec![tx("deposit", 1, 1, Some("10")), of("savings")]),
+                &[]
+            ),
+            0
+        );
+        // Each sub-account is only in one of them.
+        let snapshot = ledger(vec![tx("deposit", 1, 1, Some("10")), of("escrow")]);
+        assert_eq!(differences(&replayed, &snapshot, &[]), 2);
+    }
+
+    #[test]
+    fn forgotten_clients_are_compared_with_their_tombstones() {
+        let replayed = ledger(vec![tx("deposit", 1, 1, Some("10"))]);
+        // The forgotten client 2 isn't replayed, the snapshot keeps its balances.
+        let snapshot = ledger(vec![
+            tx("deposit", 1, 1, Some("10")),
+            tx("deposit", 2, 2, Some("4")),
+        ]);
+        let tombstone = |client, available: &str| ForgottenRecord {
+            line: 3,
+            client,
+            available: available.parse().unwrap(),
+            held: Amount::default(),
+            escrow: Amount::default(),
+            locked: false,
+        };
+        assert_eq!(differences(&replayed, &snapshot, &[tombstone(2, "4")]), 0);
+        assert_eq!(differences(&replayed, &snapshot, &[tombstone(2, "3")]), 1);
+        // Not in the snapshot at all.
+        assert_eq!(differences(&replayed, &snapshot, &[tombstone(3, "0")]), 2);
+    }
+}
@@ -0,0 +1,1005 @@
+use crate::output::{self, OutputFormat};
+use anyhow::{anyhow, Result};
+use csv::{ReaderBuilder, Trim};
+use ledger::{Amount, Applied, Balance, Ledger, Money, Timestamp, TransactionType};
+use serde::Serializer;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+// Name of the group and business unit of clients missing from the hierarchy file.
+const UNASSIGNED: &str = "unassigned";
+
+// Row of the hierarchy file.
+#[derive(Debug, serde::Deserialize)]
+struct HierarchyRecord {
+    client: u16,
+    group: String,
+    business_unit: String,
+}
+
+// Account hierarchy read from the file given with --hierarchy: every client belongs to a group,
+// every group to a business unit.
+#[derive(Debug, Default)]
+pub struct Hierarchy {
+    groups: HashMap<u16, String>,   // Group of each client
+    units: HashMap<String, String>, // Business unit of each group
+}
+
+// Reads the hierarchy file: a CSV file with client, group and business_unit columns.
+pub fn read_hierarchy(path: &str) -> Result<Hierarchy> {
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_path(path)
+        .map_err(|e| anyhow! {"cannot read hierarchy file {}: {}", path, e})?;
+    let mut hierarchy = Hierarchy::default();
+    for record in rdr.deserialize() {
+        let r: HierarchyRecord =
+            record.map_err(|e| anyhow! {"invalid hierarchy file {}: {}", path, e})?;
+        if let Some(unit) = hierarchy.units.get(&r.group) {
+            if *unit != r.business_unit {
+                return Err(anyhow! {
+                    "invalid hierarchy file {}: group {} belongs to both {} and {}",
+                    path, r.group, unit, r.business_unit
+                });
+            }
+        }
+        if hierarchy.groups.insert(r.client, r.group.clone()).is_some() {
+            return Err(
+                anyhow! {"invalid hierarchy file {}: client {} listed twice", path, r.client},
+            );
+        }
+        hierarchy.units.insert(r.group, r.business_unit);
+    }
+    Ok(hierarchy)
+}
+
+// Balances rolled up over a set of clients (along with their sub-accounts).
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+struct Rollup {
+    accounts: u64,
+    #[serde(serialize_with = "rounded")]
+    available: Amount,
+    #[serde(serialize_with = "rounded")]
+    held: Amount,
+    #[serde(serialize_with = "rounded")]
+    total: Amount,
+}
+
+fn rounded<S: Serializer>(v: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(output::rounded(*v))
+}
+
+impl Rollup {
+    fn add(&mut self, b: &Rollup) {
+        self.accounts += b.accounts;
+        self.available += b.available;
+        self.held += b.held;
+        self.total += b.total;
+    }
+}
+
+impl From<Balance> for Rollup {
+    fn from(b: Balance) -> Rollup {
+        Rollup {
+            accounts: 1,
+            available: b.available,
+            held: b.held,
+            total: b.total,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    rollup: Rollup,
+    clients: BTreeMap<u16, Rollup>,
+}
+
+#[derive(Debug, Default)]
+struct Unit {
+    rollup: Rollup,
+    groups: BTreeMap<String, Group>,
+}
+
+// Rolls the balances of the ledger up the hierarchy, returning the business units (sorted by
+// name) and the grand total.
+fn rollups(l: &Ledger, hierarchy: &Hierarchy) -> (BTreeMap<String, Unit>, Rollup) {
+    let mut units: BTreeMap<String, Unit> = BTreeMap::new();
+    let mut total = Rollup::default();
+    for (client_id, a) in l.accounts() {
+        let group = hierarchy.groups.get(&client_id).map_or(UNASSIGNED, |g| g);
+        let unit_name = hierarchy.units.get(group).map_or(UNASSIGNED, |u| u);
+        let rollup = Rollup::from(a.rollup());
+        let unit = units.entry(unit_name.to_string()).or_default();
+        unit.rollup.add(&rollup);
+        let group = unit.groups.entry(group.to_string()).or_default();
+        group.rollup.add(&rollup);
+        group.clients.insert(client_id, rollup);
+        total.add(&rollup);
+    }
+    (units, total)
+}
+
+// Writes the balances rolled up at each level of the hierarchy, in CSV:
+//
+//     level,name,parent,accounts,available,held,total
+//     business_unit,retail,,2,...
+//     group,eu,retail,2,...
+//     client,1,eu,1,...
+//     ...
+//     total,,,5,...
+//
+// or in JSON, as the same tree nested by business unit and group.
+pub fn write(
+    l: &Ledger,
+    hierarchy: &Hierarchy,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let (units, total) = rollups(l, hierarchy);
+    if format == OutputFormat::Json {
+        return write_json(&units, &total, out);
+    }
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "level",
+        "name",
+        "parent",
+        "accounts",
+        "available",
+        "held",
+        "total",
+    ])?;
+    let mut line = |level: &str, name: &str, parent: &str, r: &Rollup| {
+        writer.write_record([
+            level,
+            name,
+            parent,
+            &r.accounts.to_string(),
+            &format!("{:.4}", r.available),
+            &format!("{:.4}", r.held),
+            &format!("{:.4}", r.total),
+        ])
+    };
+    for (unit_name, unit) in &units {
+        line("business_unit", unit_name, "", &unit.rollup)?;
+        for (group_name, group) in &unit.groups {
+            line("group", group_name, unit_name, &group.rollup)?;
+            for (client_id, r) in &group.clients {
+                line("client", &client_id.to_string(), group_name, r)?;
+            }
+        }
+    }
+    line("total", "", "", &total)?;
+    writer.flush()
+}
+
+#[derive(serde::Serialize)]
+struct JsonUnit<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    rollup: Rollup,
+    groups: Vec<JsonGroup<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonGroup<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    rollup: Rollup,
+    clients: Vec<JsonClient>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonClient {
+    client: u16,
+    #[serde(flatten)]
+    rollup: Rollup,
+}
+
+#[derive(serde::Serialize)]
+struct JsonReport<'a> {
+    business_units: Vec<JsonUnit<'a>>,
+    total: Rollup,
+}
+
+fn json_group<'a>(name: &'a str, group: &Group) -> JsonGroup<'a> {
+    JsonGroup {
+        name,
+        rollup: group.rollup,
+        clients: group
+            .clients
+            .iter()
+            .map(|(client, rollup)| JsonClient {
+                client: *client,
+                rollup: *rollup,
+            })
+            .collect(),
+    }
+}
+
+fn write_json(
+    units: &BTreeMap<String, Unit>,
+    total: &Rollup,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let report = JsonReport {
+        business_units: units
+            .iter()
+            .map(|(name, unit)| JsonUnit {
+                name,
+                rollup: unit.rollup,
+                groups: unit
+                    .groups
+                    .iter()
+                    .map(|(name, group)| json_group(name, group))
+                    .collect(),
+            })
+            .collect(),
+        total: *total,
+    };
+    serde_json::to_writer_pretty(&mut *out, &report)?;
+    writeln!(out)
+}
+
+// Number and total amount of the applied transactions of each tag, by transaction type.
+#[derive(Debug, Default)]
+pub struct TagTotals {
+    totals: BTreeMap<(String, TransactionType), TagTotal>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct TagTotal {
+    transactions: u64,
+    amount: Amount,
+}
+
+impl TagTotals {
+    pub fn record(&mut self, applied: &Applied) {
+        for tag in &applied.tags {
+            let total = self.totals.entry((tag.clone(), applied.kind)).or_default();
+            total.transactions += 1;
+            total.amount += applied.amount.unwrap_or(Amount::ZERO);
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TagRecord<'a> {
+    tag: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    transactions: u64,
+    #[serde(serialize_with = "rounded")]
+    amount: Amount,
+}
+
+// Writes the totals per tag and transaction type, sorted by tag, in CSV (with the tag, type,
+// transactions and amount columns) or in JSON (as an array of objects with the same fields). A
+// transaction with several tags counts towards each of them.
+pub fn write_tags(
+    totals: &TagTotals,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let records = totals.totals.iter().map(|((tag, kind), t)| TagRecord {
+        tag,
+        kind: kind.as_str(),
+        transactions: t.transactions,
+        amount: t.amount,
+    });
+    if format == OutputFormat::Json {
+        let records: Vec<TagRecord> = records.collect();
+        serde_json::to_writer_pretty(&mut *out, &records)?;
+        return writeln!(out);
+    }
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["tag", "type", "transactions", "amount"])?;
+    for r in records {
+        writer.write_record([
+            r.tag,
+            r.kind,
+            &r.transactions.to_string(),
+            &format!("{:.4}", r.amount),
+        ])?;
+    }
+    writer.flush()
+}
+
+// Length of the periods of the cash-flow report. Weeks start on Monday.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl FromStr for Period {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(anyhow! {"unknown period {} (expected day, week or month)", s}),
+        }
+    }
+}
+
+impl Period {
+    // The day (in days since 1970-01-01) the period containing the timestamp starts on.
+    fn start(self, t: Timestamp) -> i64 {
+        let days = t.0.div_euclid(86400);
+        match self {
+            Period::Day => days,
+            // 1970-01-01 was a Thursday.
+            Period::Week => days - (days + 3).rem_euclid(7),
+            Period::Month => days - i64::from(t.date().2 - 1),
+        }
+    }
+}
+
+// Amounts of the movements of a period, by transaction type.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+struct Flows {
+    #[serde(serialize_with = "rounded")]
+    deposits: Amount,
+    #[serde(serialize_with = "rounded")]
+    withdrawals: Amount,
+    #[serde(serialize_with = "rounded")]
+    disputes: Amount,
+    #[serde(serialize_with = "rounded")]
+    chargebacks: Amount,
+}
+
+impl Flows {
+    fn add(&mut self, kind: TransactionType, amount: Amount) {
+        match kind {
+            TransactionType::Deposit => self.deposits += amount,
+            TransactionType::Withdrawal => self.withdrawals += amount,
+            TransactionType::Dispute => self.disputes += amount,
+            TransactionType::Chargeback => self.chargebacks += amount,
+            _ => {}
+        }
+    }
+}
+
+// Deposits, withdrawals, disputes and chargebacks per period, overall and per client.
+// Transactions without a timestamp can't be put in a period and are left out.
+#[derive(Debug)]
+pub struct CashFlow {
+    period: Period,
+    flows: BTreeMap<(i64, Option<u16>), Flows>, // By start of the period and client (None overall)
+}
+
+impl CashFlow {
+    pub fn new(period: Period) -> CashFlow {
+        CashFlow {
+            period,
+            flows: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, applied: &Applied) {
+        let (Some(t), Some(amount)) = (applied.timestamp, applied.amount) else {
+            return;
+        };
+        let start = self.period.start(t);
+        for client in [None, Some(applied.client_id)] {
+            let flows = self.flows.entry((start, client)).or_default();
+            flows.add(applied.kind, amount);
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CashFlowRecord {
+    period: String,
+    client: Option<u16>,
+    #[serde(flatten)]
+    flows: Flows,
+}
+
+// Writes the cash-flow report, sorted by period, in CSV:
+//
+//     period,client,deposits,withdrawals,disputes,chargebacks
+//     2024-03-01,,...
+//     2024-03-01,1,...
+//     ...
+//
+// where the row without a client is the total of the period, or in JSON, as an array of objects
+// with the same fields (and a null client for the totals). Periods are given by their first day.
+pub fn write_cash_flow(
+    cash_flow: &CashFlow,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let records = cash_flow.flows.iter().map(|((start, client), flows)| {
+        let (year, month, day) = Timestamp(start * 86400).date();
+        CashFlowRecord {
+            period: format!("{:04}-{:02}-{:02}", year, month, day),
+            client: *client,
+            flows: *flows,
+        }
+    });
+    if format == OutputFormat::Json {
+        let records: Vec<CashFlowRecord> = records.collect();
+        serde_json::to_writer_pretty(&mut *out, &records)?;
+        return writeln!(out);
+    }
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "period",
+        "client",
+        "deposits",
+        "withdrawals",
+        "disputes",
+        "chargebacks",
+    ])?;
+    for r in records {
+        writer.write_record([
+            r.period,
+            r.client.map(|c| c.to_string()).unwrap_or_default(),
+            format!("{:.4}", r.flows.deposits),
+            format!("{:.4}", r.flows.withdrawals),
+            format!("{:.4}", r.flows.disputes),
+            format!("{:.4}", r.flows.chargebacks),
+        ])?;
+    }
+    writer.flush()
+}
+
+// Step of the lifecycle of a dispute: the transaction, by its input line, with its timestamp if
+// the input has one.
+#[derive(Clone, Copy, Debug)]
+struct Step {
+    kind: TransactionType,
+    line: u64,
+    timestamp: Option<Timestamp>,
+}
+
+// A dispute of a deposit, from the dispute to the resolve or chargeback closing it, through the
+// representment and arbitration stages, and the chargeback reversal which may follow.
+#[derive(Debug)]
+struct Lifecycle {
+    client: u16,
+    tx: u32,
+    amount: Amount, // Disputed
+    steps: Vec<Step>,
+}
+
+impl Lifecycle {
+    fn closed(&self) -> Option<&Step> {
+        self.steps.iter().find(|s| {
+            matches!(
+                s.kind,
+                TransactionType::Resolve | TransactionType::Chargeback
+            )
+        })
+    }
+
+    fn outcome(&self) -> &'static str {
+        match self.steps.last().map(|s| s.kind) {
+            Some(TransactionType::Resolve) => "resolved",
+            Some(TransactionType::Chargeback) => "charged_back",
+            Some(TransactionType::ChargebackReversal) => "reversed",
+            _ => "open",
+        }
+    }
+
+    // Seconds between the dispute and the step closing it, when both have a timestamp.
+    fn open_seconds(&self) -> Option<i64> {
+        let opened = self.steps[0].timestamp?;
+        let closed = self.closed()?.timestamp?;
+        Some(closed.0 - opened.0)
+    }
+}
+
+// Every dispute applied during the run, in the order they were opened. A deposit disputed again
+// after a resolve has a lifecycle per dispute. Disputes opened before the run (in the base
+// snapshot, or the records a checkpoint covers) are left out, as their start wasn't observed.
+#[derive(Debug, Default)]
+pub struct Disputes {
+    lifecycles: Vec<Lifecycle>,
+    latest: HashMap<(u16, u32), usize>, // Index of the latest lifecycle of each deposit
+}
+
+impl Disputes {
+    pub fn record(&mut self, line: u64, applied: &Applied) {
+        let step = Step {
+            kind: applied.kind,
+            line,
+            timestamp: applied.timestamp,
+        };
+        let key = (applied.client_id, applied.tx);
+        match applied.kind {
+            TransactionType::Dispute => {
+                self.latest.insert(key, self.lifecycles.len());
+                self.lifecycles.push(Lifecycle {
+                    client: applied.client_id,
+                    tx: applied.tx,
+                    amount: applied.amount.unwrap_or(Amount::ZERO),
+                    steps: vec![step],
+                });
+            }
+            TransactionType::Representment
+            | TransactionType::PreArbitration
+            | TransactionType::Arbitration
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::ChargebackReversal => {
+                if let Some(&i) = self.latest.get(&key) {
+                    self.lifecycles[i].steps.push(step);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct StepRecord {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    line: u64,
+    timestamp: Option<Timestamp>,
+}
+
+#[derive(serde::Serialize)]
+struct DisputeRecord {
+    client: u16,
+    tx: u32,
+    #[serde(serialize_with = "rounded")]
+    amount: Amount,
+    steps: Vec<StepRecord>,
+    open_seconds: Option<i64>,
+    outcome: &'static str,
+}
+
+// Writes the disputes, sorted by client and then in the order they were opened, in CSV:
+//
+//     client,tx,amount,opened_line,opened_at,steps,closed_line,closed_at,open_seconds,outcome
+//     1,3,2.0000,5,2024-03-01T10:00:00Z,dispute:5;representment:9;chargeback:12,12,...
+//
+// where steps lists the type and input line of every step, and the closing step is the resolve or
+// chargeback (so that a chargeback reversed later stays closed at the chargeback); open disputes
+// have no closing step. In JSON, as an array of objects with the client, tx, amount, steps (with
+// their type, line and timestamp), open_seconds and outcome (resolved, charged_back, reversed or
+// open).
+pub fn write_disputes(
+    disputes: &Disputes,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut lifecycles: Vec<&Lifecycle> = disputes.lifecycles.iter().collect();
+    lifecycles.sort_by_key(|l| l.client);
+    if format == OutputFormat::Json {
+        let records: Vec<DisputeRecord> = lifecycles
+            .iter()
+            .map(|l| DisputeRecord {
+                client: l.client,
+                tx: l.tx,
+                amount: l.amount,
+                steps: l
+                    .steps
+                    .iter()
+                    .map(|s| StepRecord {
+                        kind: s.kind.as_str(),
+                        line: s.line,
+                        timestamp: s.timestamp,
+                    })
+                    .collect(),
+                open_seconds: l.open_seconds(),
+                outcome: l.outcome(),
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut *out, &records)?;
+        return writeln!(out);
+    }
+    let timestamp = |s: Option<&Step>| {
+        s.and_then(|s| s.timestamp)
+            .map(|t| t.to_string())
+            .unwrap_or_default()
+    };
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "client",
+        "tx",
+        "amount",
+        "opened_line",
+        "opened_at",
+        "steps",
+        "closed_line",
+        "closed_at",
+        "open_seconds",
+        "outcome",
+    ])?;
+    for l in lifecycles {
+        let steps: Vec<String> = l
+            .steps
+            .iter()
+            .map(|s| format!("{}:{}", s.kind.as_str(), s.line))
+            .collect();
+        let closed = l.closed();
+        writer.write_record([
+            l.client.to_string(),
+            l.tx.to_string(),
+            format!("{:.4}", l.amount),
+            l.steps[0].line.to_string(),
+            timestamp(l.steps.first()),
+            steps.join(";"),
+            closed.map(|s| s.line.to_string()).unwrap_or_default(),
+            timestamp(closed),
+            l.open_seconds().map(|s| s.to_string()).unwrap_or_default(),
+            l.outcome().to_string(),
+        ])?;
+    }
+    writer.flush()
+}
+
+// Threshold of a card scheme (or any program monitoring chargebacks) on the chargeback ratios of
+// a client in a period: the ratio of the number of chargebacks to that of deposits, or of their
+// amounts. Clients with fewer chargebacks than min_chargebacks in the period are never flagged.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct SchemeThreshold {
+    scheme: String,
+    count_ratio: Option<f64>,
+    amount_ratio: Option<f64>,
+    min_chargebacks: Option<u64>,
+}
+
+// Reads the thresholds file given with --chargeback-thresholds: a CSV file with scheme,
+// count_ratio, amount_ratio and min_chargebacks columns, ratios as fractions (0.009 for 0.9%),
+// either of them and min_chargebacks possibly empty.
+pub fn read_thresholds(path: &str) -> Result<Vec<SchemeThreshold>> {
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_path(path)
+        .map_err(|e| anyhow! {"cannot read thresholds file {}: {}", path, e})?;
+    let mut thresholds = Vec::new();
+    for record in rdr.deserialize() {
+        let t: SchemeThreshold =
+            record.map_err(|e| anyhow! {"invalid thresholds file {}: {}", path, e})?;
+        if t.count_ratio.is_none() && t.amount_ratio.is_none() {
+            return Err(
+                anyhow! {"invalid thresholds file {}: scheme {} has no ratio", path, t.scheme},
+            );
+        }
+        thresholds.push(t);
+    }
+    Ok(thresholds)
+}
+
+// Deposits and chargebacks of a period, by count and amount.
+#[derive(Clone, Copy, Debug, Default)]
+struct Chargebacks {
+    deposits: u64,
+    deposit_amount: Amount,
+    chargebacks: u64,
+    chargeback_amount: Amount,
+}
+
+impl Chargebacks {
+    // None without deposits in the period, where the ratios aren't defined.
+    fn count_ratio(&self) -> Option<f64> {
+        (self.deposits > 0).then(|| self.chargebacks as f64 / self.deposits as f64)
+    }
+
+    fn amount_ratio(&self) -> Option<f64> {
+        (self.deposit_amount > Amount::ZERO)
+            .then(|| self.chargeback_amount.to_f64() / self.deposit_amount.to_f64())
+    }
+
+    // Schemes whose thresholds the ratios exceed.
+    fn exceeds<'a>(&self, thresholds: &'a [SchemeThreshold]) -> Vec<&'a str> {
+        let above = |ratio: Option<f64>, threshold: Option<f64>| {
+            ratio.zip(threshold).is_some_and(|(r, t)| r > t)
+        };
+        thresholds
+            .iter()
+            .filter(|t| {
+                self.chargebacks >= t.min_chargebacks.unwrap_or(0)
+                    && (above(self.count_ratio(), t.count_ratio)
+                        || above(self.amount_ratio(), t.amount_ratio))
+            })
+            .map(|t| t.scheme.as_str())
+            .collect()
+    }
+}
+
+// Chargeback ratios per period, overall and per client, with the clients exceeding the scheme
+// thresholds flagged. Chargebacks count in the period they happened, against the deposits of that
+// period, as schemes count them; those reversed later still count. Transactions without a
+// timestamp can't be put in a period and are left out.
+#[derive(Debug)]
+pub struct ChargebackRatios {
+    period: Period,
+    thresholds: Vec<SchemeThreshold>,
+    periods: BTreeMap<(i64, Option<u16>), Chargebacks>, // By start of the period and client
+}
+
+impl ChargebackRatios {
+    pub fn new(period: Period, thresholds: Vec<SchemeThreshold>) -> ChargebackRatios {
+        ChargebackRatios {
+            period,
+            thresholds,
+            periods: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, applied: &Applied) {
+        let (Some(t), Some(amount)) = (applied.timestamp, applied.amount) else {
+            return;
+        };
+        let start = self.period.start(t);
+        for client in [None, Some(applied.client_id)] {
+            let c = self.periods.entry((start, client)).or_default();
+            match applied.kind {
+                TransactionType::Deposit => {
+                    c.deposits += 1;
+                    c.deposit_amount += amount;
+                }
+                TransactionType::Chargeback => {
+                    c.chargebacks += 1;
+                    c.chargeback_amount += amount;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ChargebackRatioRecord<'a> {
+    period: String,
+    client: Option<u16>,
+    deposits: u64,
+    #[serde(serialize_with = "rounded")]
+    deposit_amount: Amount,
+    chargebacks: u64,
+    #[serde(serialize_with = "rounded")]
+    chargeback_amount: Amount,
+    count_ratio: Option<f64>,
+    amount_ratio: Option<f64>,
+    exceeds: Vec<&'a str>,
+}
+
+// Writes the chargeback ratios, sorted by period, in CSV:
+//
+//     period,client,deposits,deposit_amount,chargebacks,chargeback_amount,count_ratio,amount_ratio,exceeds
+//     2024-03-01,,...
+//     2024-03-01,1,120,...,0.012500,0.008100,visa;mastercard
+//     ...
+//
+// where the row without a client is the total of the period, exceeds lists the schemes whose
+// thresholds the ratios of a client exceed, and the ratios are empty without deposits, or in
+// JSON, as an array of objects with the same fields (and a null client for the totals). Periods
+// are given by their first day.
+pub fn write_chargeback_ratios(
+    ratios: &ChargebackRatios,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let records = ratios.periods.iter().map(|((start, client), c)| {
+        let (year, month, day) = Timestamp(start * 86400).date();
+        ChargebackRatioRecord {
+            period: format!("{:04}-{:02}-{:02}", year, month, day),
+            client: *client,
+            deposits: c.deposits,
+            deposit_amount: c.deposit_amount,
+            chargebacks: c.chargebacks,
+            chargeback_amount: c.chargeback_amount,
+            count_ratio: c.count_ratio(),
+            amount_ratio: c.amount_ratio(),
+            exceeds: if client.is_some() {
+                c.exceeds(&ratios.thresholds)
+            } else {
+                Vec::new()
+            },
+        }
+    });
+    if format == OutputFormat::Json {
+        let records: Vec<ChargebackRatioRecord> = records.collect();
+        serde_json::to_writer_pretty(&mut *out, &records)?;
+        return writeln!(out);
+    }
+    let ratio = |r: Option<f64>| r.map(|r| format!("{:.6}", r)).unwrap_or_default();
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "period",
+        "client",
+        "deposits",
+        "deposit_amount",
+        "chargebacks",
+        "chargeback_amount",
+        "count_ratio",
+        "amount_ratio",
+        "exceeds",
+    ])?;
+    for r in records {
+        writer.write_record([
+            r.period,
+            r.client.map(|c| c.to_string()).unwrap_or_default(),
+            r.deposits.to_string(),
+            format!("{:.4}", r.deposit_amount),
+            r.chargebacks.to_string(),
+            format!("{:.4}", r.chargeback_amount),
+            ratio(r.count_ratio),
+            ratio(r.amount_ratio),
+            r.exceeds.join(";"),
+        ])?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ledger::TransactionEntry;
+
+    fn tx(
+        kind: &str,
+        client_id: u16,
+        uid: u32,
+        amount: Option<&str>,
+        at: &str,
+    ) -> TransactionEntry {
+        TransactionEntry {
+            t: kind.to_string(),
+            client_id,
+            uid,
+            amount: amount.map(|a| a.parse().unwrap()),
+            currency: None,
+            to_currency: None,
+            timestamp: (!at.is_empty()).then(|| at.parse().unwrap()),
+            subaccount: None,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        }
+    }
+
+    fn apply(l: &mut Ledger, t: TransactionEntry) -> Applied {
+        l.apply_transaction(t).unwrap()
+    }
+
+    fn csv(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn balances_roll_up_the_hierarchy() {
+        let mut l = Ledger::new();
+        for (client, amount) in [(1, "10"), (2, "5"), (3, "1")] {
+            apply(
+                &mut l,
+                tx("deposit", client, u32::from(client), Some(amount), ""),
+            );
+        }
+        apply(&mut l, tx("dispute", 2, 2, None, ""));
+        let hierarchy = Hierarchy {
+            groups: HashMap::from([(1, "eu".to_string()), (2, "eu".to_string())]),
+            units: HashMap::from([("eu".to_string(), "retail".to_string())]),
+        };
+        // Client 3 isn't in the hierarchy.
+        assert_eq!(
+            csv(|out| write(&l, &hierarchy, OutputFormat::Csv, out)),
+            "level,name,parent,accounts,available,held,total
+business_unit,retail,,2,10.0000,5.0000,15.0000
+group,eu,retail,2,10.0000,5.0000,15.0000
+client,1,eu,1,10.0000,0.0000,10.0000
+client,2,eu,1,0.0000,5.0000,5.0000
+business_unit,unassigned,,1,1.0000,0.0000,1.0000
+group,unassigned,unassigned,1,1.0000,0.0000,1.0000
+client,3,unassigned,1,1.0000,0.0000,1.0000
+total,,,3,11.0000,5.0000,16.0000
+"
+        );
+    }
+
+    #[test]
+    fn periods_start_on_their_first_day() {
+        let start = |period: &str, at: &str| {
+            let start = period.parse::<Period>().unwrap().start(at.parse().unwrap());
+            Timestamp(start * 86400).date()
+        };
+        assert_eq!(start("day", "2024-03-06T23:59:59Z"), (2024, 3, 6));
+        // A Wednesday, and a Sunday of the week starting on Monday 26 February.
+        assert_eq!(start("week", "2024-03-06T12:00:00Z"), (2024, 3, 4));
+        assert_eq!(start("week", "2024-03-03T12:00:00Z"), (2024, 2, 26));
+        assert_eq!(start("month", "2024-02-29T12:00:00Z"), (2024, 2, 1));
+        assert!("year".parse::<Period>().is_err());
+    }
+
+    #[test]
+    fn cash_flows_are_totalled_per_period_and_client() {
+        let mut l = Ledger::new();
+        let mut cash_flow = CashFlow::new(Period::Month);
+        for t in [
+            tx("deposit", 1, 1, Some("10"), "2024-03-01T00:00:00Z"),
+            tx("deposit", 2, 2, Some("4"), "2024-03-31T23:59:59Z"),
+            tx("withdrawal", 1, 3, Some("3"), "2024-04-02T10:00:00Z"),
+            tx("deposit", 1, 4, Some("100"), ""),
+        ] {
+            cash_flow.record(&apply(&mut l, t));
+        }
+        assert_eq!(
+            csv(|out| write_cash_flow(&cash_flow, OutputFormat::Csv, out)),
+            "period,client,deposits,withdrawals,disputes,chargebacks
+2024-03-01,,14.0000,0.0000,0.0000,0.0000
+2024-03-01,1,10.0000,0.0000,0.0000,0.0000
+2024-03-01,2,4.0000,0.0000,0.0000,0.0000
+2024-04-01,,0.0000,3.0000,0.0000,0.0000
+2024-04-01,1,0.0000,3.0000,0.0000,0.0000
+"
+        );
+    }
+
+    #[test]
+    fn disputes_have_a_lifecycle_each() {
+        let mut l = Ledger::new();
+        let mut disputes = Disputes::default();
+        for (line, t) in [
+            tx("deposit", 1, 1, Some("10"), "2024-03-01T10:00:00Z"),
+            tx("dispute", 1, 1, None, "2024-03-02T10:00:00Z"),
+            tx("resolve", 1, 1, None, "2024-03-02T11:00:00Z"),
+            tx("dispute", 1, 1, None, "2024-03-05T10:00:00Z"),
+            tx("representment", 1, 1, None, "2024-03-06T10:00:00Z"),
+            tx("chargeback", 1, 1, None, ""),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            disputes.record(line as u64 + 2, &apply(&mut l, t));
+        }
+        let [resolved, charged_back] = &disputes.lifecycles[..] else {
+            panic!("{:?}", disputes.lifecycles);
+        };
+        assert_eq!(resolved.outcome(), "resolved");
+        assert_eq!(resolved.open_seconds(), Some(3600));
+        assert_eq!(charged_back.outcome(), "charged_back");
+        assert_eq!(charged_back.closed().map(|s| s.line), Some(7));
+        // The chargeback has no timestamp.
+        assert_eq!(charged_back.open_seconds(), None);
+        let report = csv(|out| write_disputes(&disputes, OutputFormat::Csv, out));
+        assert_eq!(
+            report.lines().nth(2),
+            Some("1,1,10.0000,5,2024-03-05T10:00:00Z,dispute:5;representment:6;chargeback:7,7,,,charged_back")
+        );
+    }
+
+    #[test]
+    fn clients_above_the_ratios_of_a_scheme_are_flagged() {
+        let threshold =
+            |scheme: &str, count_ratio, amount_ratio, min_chargebacks| SchemeThreshold {
+                scheme: scheme.to_string(),
+                count_ratio,
+                amount_ratio,
+                min_chargebacks,
+            };
+        let thresholds = [
+            threshold("count", Some(0.4), None, None),
+            threshold("amount", None, Some(0.05), None),
+            threshold("many", Some(0.01), None, Some(2)),
+        ];
+        // 1 chargeback of 2 deposits, by amount 1 of 101.
+        let c = Chargebacks {
+            deposits: 2,
+            deposit_amount: "101".parse().unwrap(),
+            chargebacks: 1,
+            chargeback_amount: "1".parse().unwrap(),
+        };
+        assert_eq!(c.exceeds(&thresholds), vec!["count"]);
+        let none = Chargebacks {
+            chargebacks: 1,
+            ..Chargebacks::default()
+        };
+        assert_eq!(none.count_ratio(), None);
+        assert!(none.exceeds(&thresholds).is_empty());
+    }
+}
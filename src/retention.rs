@@ -0,0 +1,141 @@
+use crate::{Account, Ledger, OperationState, Timestamp};
+use std::collections::VecDeque;
+
+// Notes that the transaction was applied at t, for Config::retention_days: the marks of the days
+// within the window are kept, those of earlier days folded into the first one.
+pub(crate) fn mark_day(marks: &mut VecDeque<(i64, u32)>, days: u32, t: Timestamp, tx_id: u32) {
+    let day = t.0.div_euclid(86400);
+    match marks.back_mut() {
+        // Transactions out of order count as of the latest day.
+        Some((last, highest)) if *last >= day => *highest = (*highest).max(tx_id),
+        _ => marks.push_back((day, tx_id)),
+    }
+    let latest = marks.back().map_or(day, |&(day, _)| day);
+    while marks.len() > 1 && marks[1].0 < latest - i64::from(days) {
+        let (_, highest) = marks.pop_front().expect("at least two marks");
+        marks[0].1 = marks[0].1.max(highest);
+    }
+}
+
+impl OperationState {
+    // Whether the entry still holds funds which later transactions release: open disputes,
+    // escrows and authorizations. The other entries only serve later disputes, chargeback
+    // reversals and duplicate detection.
+    fn holds_funds(&self) -> bool {
+        matches!(
+            self,
+            OperationState::DisputedDeposit { .. }
+                | OperationState::RepresentedDeposit { .. }
+                | OperationState::PreArbitrationDeposit { .. }
+                | OperationState::ArbitrationDeposit { .. }
+                | OperationState::Escrow { .. }
+                | OperationState::Authorization { .. }
+        )
+    }
+}
+
+impl Account {
+    // Drops the oplog entries (with their tags and memos) of the transactions up to the horizon,
+    // but those holding funds, the bonuses which may still expire and the held transactions, in
+    // the sub-accounts as well. Returns the number of entries dropped.
+    fn prune(&mut self, horizon: u32, held: &[u32]) -> u64 {
+        let pruned: Vec<u32> = self
+            .oplog
+            .iter()
+            .filter(|&(tx_id, op)| {
+                tx_id <= horizon
+                    && !op.holds_funds()
+                    && self.bonuses.iter().all(|b| b.tx != tx_id)
+                    && !held.contains(&tx_id)
+            })
+            .map(|(tx_id, _)| tx_id)
+            .collect();
+        for tx_id in &pruned {
+            self.oplog.remove(*tx_id);
+            self.tags.remove(tx_id);
+            self.memos.remove(tx_id);
+        }
+        let subaccounts: u64 = self
+            .subaccounts
+            .values_mut()
+            .map(|s| s.prune(horizon, held))
+            .sum();
+        pruned.len() as u64 + subaccounts
+    }
+}
+
+impl Ledger {
+    // Highest transaction id whose oplog entries are outside the retention windows of the
+    // configuration (see Config::retention_transactions and retention_days), if any is. Transaction
+    // ids are taken to increase with time, as with --since-tx. The day window needs timestamps:
+    // until transactions of enough days have been applied, nothing is outside it.
+    pub fn retention_horizon(&self) -> Option<u32> {
+        self.horizon(
+            self.config.retention_transactions,
+            self.config.retention_days,
+        )
+    }
+
+    pub(crate) fn horizon(&self, transactions: Option<u32>, days: Option<u32>) -> Option<u32> {
+        let by_transactions = transactions.map(|n| {
+            let latest = self
+                .accounts
+                .iter()
+                .flat_map(|(_, a)| a.subaccounts().map(|(_, s)| s).chain([a]))
+                .filter_map(|a| a.operations().map(|(tx_id, _)| tx_id).max())
+                .max();
+            latest.and_then(|latest| latest.checked_sub(n))
+        });
+        let by_days = days.map(|n| {
+            let latest = self.day_marks.back()?.0;
+            let outside = self
+                .day_marks
+                .iter()
+                .take_while(|&&(day, _)| day < latest - i64::from(n));
+            outside.map(|&(_, highest)| highest).max()
+        });
+        match (by_transactions, by_days) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).flatten(),
+        }
+    }
+
+    // Applies the retention of the configuration to the oplogs, see Ledger::prune_through.
+    pub fn prune(&mut self) -> u64 {
+        self.retention_horizon()
+            .map_or(0, |horizon| self.prune_through(horizon))
+    }
+
+    // Applies a retention of the given number of transactions and/or days rather than that of the
+    // configuration. The days are counted with the marks the ledger keeps with
+    // Config::retention_days only, so without it there is no day window to apply.
+    pub fn prune_older_than(&mut self, transactions: Option<u32>, days: Option<u32>) -> u64 {
+        self.horizon(transactions, days)
+            .map_or(0, |horizon| self.prune_through(horizon))
+    }
+
+    // Drops the oplog entries of the transactions up to the horizon, unless they still hold funds
+    // (open disputes, escrows, authorizations) or are of bonuses which may still expire, and
+    // returns how many were dropped. Those under a legal hold are kept too, all the entries of a
+    // client held as a whole. Deposits dropped can't be disputed anymore (disputes are
+    // rejected as transaction_not_found), so the retention has to be at least the dispute window;
+    // and their ids are only detected as duplicates by the duplicate filter, until the ledger is
+    // reloaded.
+    pub fn prune_through(&mut self, horizon: u32) -> u64 {
+        let mut pruned = 0;
+        for client_id in self.accounts().map(|(c, _)| c).collect::<Vec<u16>>() {
+            let holds: Vec<_> = self
+                .legal_holds()
+                .filter(|h| h.client == client_id)
+                .collect();
+            if holds.iter().any(|h| h.tx.is_none()) {
+                continue;
+            }
+            let held: Vec<u32> = holds.iter().filter_map(|h| h.tx).collect();
+            if let Some(a) = self.accounts.get_mut(client_id) {
+                pruned += a.prune(horizon, &held);
+            }
+        }
+        pruned
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use csv::StringRecord;
+use ledger::{Amount, TransactionEntry};
+use std::fs::{self, File};
+use std::io;
+
+// Chargeback held for review, as exported to the review file. The reviewer fills in the decision,
+// approve or deny, and hands the file back to `ledger apply-decisions`.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct PendingChargeback {
+    line: u64,
+    client: u16,
+    subaccount: Option<String>,
+    tx: u32,
+    amount: Option<Amount>,
+    decision: String,
+}
+
+// With --review-chargebacks, chargebacks are not applied but queued in the review file, one row
+// per chargeback. The disputed funds stay held in the meantime.
+pub struct ReviewQueue {
+    writer: csv::Writer<File>,
+}
+
+impl ReviewQueue {
+    pub fn create(path: &str) -> Result<ReviewQueue, csv::Error> {
+        Ok(ReviewQueue {
+            writer: csv::Writer::from_path(path)?,
+        })
+    }
+
+    // Queues the record if it is a chargeback, returning whether it was. Chargebacks which don't
+    // parse are left to the ledger, which rejects them as usual.
+    pub fn queue(&mut self, record: &StringRecord, headers: &StringRecord) -> Result<bool> {
+        let field = |name| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .and_then(|i| record.get(i))
+                .map(str::trim)
+        };
+        if field("type") != Some("chargeback") {
+            return Ok(false);
+        }
+        let amount = match field("amount").filter(|a| !a.is_empty()) {
+            Some(amount) => match amount.parse() {
+                Ok(amount) => Some(amount),
+                Err(_) => return Ok(false),
+            },
+            None => None,
+        };
+        let (Some(Ok(client)), Some(Ok(tx))) =
+            (field("client").map(str::parse), field("tx").map(str::parse))
+        else {
+            return Ok(false);
+        };
+        self.writer.serialize(PendingChargeback {
+            line: record.position().map_or(0, |p| p.line()),
+            client,
+            subaccount: field("subaccount")
+                .filter(|s| !s.is_empty())
+                .map(String::from),
+            tx,
+            amount,
+            decision: String::new(),
+        })?;
+        Ok(true)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// `ledger apply-decisions <decisions.csv> --snapshot s.json [--snapshot-out o.json]`: applies the
+// approved chargebacks of a review file (see ReviewQueue) to the ledger of the snapshot taken at
+// the end of the run which queued them, and discards the denied ones. Rows without a decision yet
+// are left pending, to be decided in a later run. The snapshot is written back like with
+// `ledger undo`, through a temporary file.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut decisions, mut snapshot, mut snapshot_out) = (None, None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--snapshot-out" => snapshot_out = Some(value()?.clone()),
+            _ if decisions.is_none() && !arg.starts_with("--") => decisions = Some(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let decisions =
+        decisions.ok_or_else(|| anyhow! {"apply-decisions requires the decisions file"})?;
+    let path = snapshot.ok_or_else(|| anyhow! {"apply-decisions requires the --snapshot"})?;
+    let mut l = crate::load_snapshot(&path)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(&decisions)
+        .map_err(|e| anyhow! {"cannot read decisions {}: {}", decisions, e})?;
+    let (mut approved, mut denied, mut pending) = (0, 0, 0);
+    for row in reader.deserialize() {
+        let row: PendingChargeback =
+            row.map_err(|e| anyhow! {"invalid decisions {}: {}", decisions, e})?;
+        match row.decision.to_ascii_lowercase().as_str() {
+            "approve" | "approved" => {}
+            "deny" | "denied" => {
+                denied += 1;
+                println!(
+                    "Discarded chargeback of transaction {} of client {}",
+                    row.tx, row.client
+                );
+                continue;
+            }
+            "" => {
+                pending += 1;
+                continue;
+            }
+            other => {
+                return Err(anyhow! {
+                    "invalid decisions {}: unknown decision {} for line {} (expected approve or deny)",
+                    decisions,
+                    other,
+                    row.line
+                })
+            }
+        }
+        let entry = TransactionEntry {
+            t: "chargeback".to_string(),
+            client_id: row.client,
+            uid: row.tx,
+            amount: row.amount,
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            subaccount: row.subaccount,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        };
+        match l.apply_transaction(entry) {
+            Ok(_) => {
+                approved += 1;
+                println!(
+                    "Applied chargeback of transaction {} of client {}",
+                    row.tx, row.client
+                );
+            }
+            Err(e) => eprintln!(
+                "Error occurred: chargeback of transaction {} of client {} (line {}): {}",
+                row.tx, row.client, row.line, e
+            ),
+        }
+    }
+    let out = snapshot_out.unwrap_or_else(|| path.clone());
+    let tmp = format!("{}.tmp", out);
+    crate::write_snapshot(&tmp, &l)?;
+    fs::rename(&tmp, &out)?;
+    eprintln!(
+        "Applied {} chargebacks, discarded {}, {} still pending",
+        approved, denied, pending
+    );
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use ledger::{Amount, Applied, TransactionType};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+
+// Defaults of the rules, when --review-queue is given without them.
+#[cfg(not(feature = "minor-units"))]
+const AMOUNT_THRESHOLD: Amount = 10000.0;
+#[cfg(feature = "minor-units")]
+const AMOUNT_THRESHOLD: Amount = ledger::MinorUnits::new(10000, 0);
+const VELOCITY: Velocity = Velocity {
+    transactions: 10,
+    seconds: 3600,
+};
+const REPEATED_DISPUTES: u32 = 3;
+
+// More than the given number of transactions of a client within the given number of seconds,
+// given as N/SECONDS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Velocity {
+    pub transactions: usize,
+    pub seconds: i64,
+}
+
+impl FromStr for Velocity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow! {"invalid velocity {} (expected N/SECONDS)", s};
+        let (transactions, seconds) = s.split_once('/').ok_or_else(invalid)?;
+        let velocity = Velocity {
+            transactions: transactions.parse().map_err(|_| invalid())?,
+            seconds: seconds.parse().map_err(|_| invalid())?,
+        };
+        if velocity.transactions == 0 || velocity.seconds <= 0 {
+            return Err(invalid());
+        }
+        Ok(velocity)
+    }
+}
+
+// Risk rules of the review queue, see RiskReview.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RiskRules {
+    pub amount_threshold: Amount, // Transactions moving at least this amount
+    pub velocity: Velocity,
+    pub repeated_disputes: u32, // Disputes of a client from the given number on
+}
+
+impl Default for RiskRules {
+    fn default() -> RiskRules {
+        RiskRules {
+            amount_threshold: AMOUNT_THRESHOLD,
+            velocity: VELOCITY,
+            repeated_disputes: REPEATED_DISPUTES,
+        }
+    }
+}
+
+// Row of the review queue: the operation, the rule it matched and the balances of the account
+// before and after it.
+#[derive(Debug, serde::Serialize)]
+struct ReviewRecord<'a> {
+    line: u64,
+    client: u16,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<Amount>,
+    rule: &'static str,
+    detail: String,
+    available_before: Amount,
+    held_before: Amount,
+    total_before: Amount,
+    locked_before: bool,
+    available_after: Amount,
+    held_after: Amount,
+    total_after: Amount,
+    locked_after: bool,
+    memo: Option<&'a str>,
+}
+
+// History of a client, as far as the rules need it.
+#[derive(Debug, Default)]
+struct History {
+    recent: VecDeque<i64>, // Timestamps of the transactions within the velocity window
+    disputes: u32,
+}
+
+// Matches the operations applied against the risk rules: those moving at least the amount
+// threshold (--risk-amount), those beyond the velocity of their client (--risk-velocity, among
+// the transactions with a timestamp) and the disputes of clients which disputed repeatedly
+// (--risk-disputes).
+#[derive(Debug)]
+pub struct RiskMonitor {
+    rules: RiskRules,
+    clients: HashMap<u16, History>,
+}
+
+impl RiskMonitor {
+    pub fn new(rules: RiskRules) -> RiskMonitor {
+        RiskMonitor {
+            rules,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &RiskRules {
+        &self.rules
+    }
+
+    // The rules the operation matches, with a description of how it matched each.
+    pub fn matches(&mut self, applied: &Applied) -> Vec<(&'static str, String)> {
+        let rules = &self.rules;
+        let history = self.clients.entry(applied.client_id).or_default();
+        let mut matched = Vec::new();
+        if let Some(amount) = applied
+            .amount
+            .filter(|amount| amount.abs() >= rules.amount_threshold)
+        {
+            matched.push((
+                "amount_threshold",
+                format!(
+                    "amount of {} at or above the threshold of {}",
+                    amount, rules.amount_threshold
+                ),
+            ));
+        }
+        if let Some(t) = applied.timestamp {
+            let window = rules.velocity.seconds;
+            while history.recent.front().is_some_and(|&s| s <= t.0 - window) {
+                history.recent.pop_front();
+            }
+            history.recent.push_back(t.0);
+            if history.recent.len() > rules.velocity.transactions {
+                matched.push((
+                    "velocity",
+                    format!(
+                        "{} transactions within {} seconds, more than {}",
+                        history.recent.len(),
+                        window,
+                        rules.velocity.transactions
+                    ),
+                ));
+            }
+        }
+        if applied.kind == TransactionType::Dispute {
+            history.disputes += 1;
+            if history.disputes >= rules.repeated_disputes {
+                matched.push((
+                    "repeated_disputes",
+                    format!("dispute number {} of the client", history.disputes),
+                ));
+            }
+        }
+        matched
+    }
+}
+
+// Operations which matched a risk rule but were applied anyway (--review-queue), for manual
+// investigation, see RiskMonitor. An operation matching several rules is listed once per rule.
+pub struct RiskReview {
+    writer: csv::Writer<File>,
+    monitor: RiskMonitor,
+    queued: u64,
+}
+
+impl RiskReview {
+    pub fn create(path: &str, rules: RiskRules) -> Result<RiskReview, csv::Error> {
+        Ok(RiskReview {
+            writer: csv::Writer::from_path(path)?,
+            monitor: RiskMonitor::new(rules),
+            queued: 0,
+        })
+    }
+
+    pub fn record(&mut self, line: u64, applied: &Applied) -> Result<(), csv::Error> {
+        for (rule, detail) in self.monitor.matches(applied) {
+            self.queued += 1;
+            self.writer.serialize(ReviewRecord {
+                line,
+                client: applied.client_id,
+                tx: applied.tx,
+                kind: applied.kind.as_str(),
+                amount: applied.amount,
+                rule,
+                detail,
+                available_before: applied.before.available,
+                held_before: applied.before.held,
+                total_before: applied.before.total,
+                locked_before: applied.before.locked,
+                available_after: applied.after.available,
+                held_after: applied.after.held,
+                total_after: applied.after.total,
+                locked_after: applied.after.locked,
+                memo: applied.memo.as_deref(),
+            })?;
+        }
+        Ok(())
+    }
+
+    // Flushes the file and returns the number of operations queued for review.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        Ok(self.queued)
+    }
+}
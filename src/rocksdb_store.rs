@@ -0,0 +1,97 @@
+use crate::kv_store::{account_key, decode_account, encode_account, oplog_changes, storage_error};
+use crate::{Account, AsyncLedgerStore, LedgerError};
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+const ACCOUNTS: &str = "accounts";
+const OPLOGS: &str = "oplogs";
+
+// AsyncLedgerStore on a RocksDB database (with the rocksdb feature), for ledgers whose accounts
+// and oplogs don't fit in memory: only the accounts being updated are. The accounts are in the
+// accounts column family, the oplog entries in the oplogs one, see kv_store for the layout. Each
+// update is written as one batch, so that a crash never leaves an account out of step with its
+// oplog. The database calls block, as RocksDB has no async interface, but they are short.
+pub struct RocksDbStore {
+    db: DB,
+    // Held over the read, change and write of an update, so that updates don't interleave.
+    updates: Mutex<()>,
+}
+
+impl RocksDbStore {
+    // Opens the database at the path, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<RocksDbStore, LedgerError> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let db = DB::open_cf(&options, path, [ACCOUNTS, OPLOGS]).map_err(storage_error)?;
+        Ok(RocksDbStore {
+            db,
+            updates: Mutex::new(()),
+        })
+    }
+
+    fn column_family(&self, name: &str) -> Result<&ColumnFamily, LedgerError> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| storage_error(format!("missing column family {}", name)))
+    }
+
+    fn read(&self, client_id: u16) -> Result<Option<Account>, LedgerError> {
+        let key = account_key(client_id);
+        let Some(record) = self
+            .db
+            .get_cf(self.column_family(ACCOUNTS)?, key)
+            .map_err(storage_error)?
+        else {
+            return Ok(None);
+        };
+        let entries = self
+            .db
+            .iterator_cf(
+                self.column_family(OPLOGS)?,
+                IteratorMode::From(&key, Direction::Forward),
+            )
+            .map(|entry| entry.map_err(storage_error))
+            .take_while(|entry| !matches!(entry, Ok((k, _)) if !k.starts_with(&key)));
+        decode_account(&record, entries).map(Some)
+    }
+
+    // Syncs the write-ahead log to disk. Updates survive a crash of the process without it, but
+    // not necessarily one of the machine.
+    pub fn sync(&self) -> Result<(), LedgerError> {
+        self.db.flush_wal(true).map_err(storage_error)
+    }
+}
+
+impl AsyncLedgerStore for RocksDbStore {
+    async fn load(&self, client_id: u16) -> Result<Option<Account>, LedgerError> {
+        self.read(client_id)
+    }
+
+    async fn update<R: Send>(
+        &self,
+        client_id: u16,
+        change: impl FnOnce(&mut Account) -> R + Send,
+    ) -> Result<R, LedgerError> {
+        let _update = self.updates.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut account = self.read(client_id)?.unwrap_or_default();
+        let before = account.oplog.clone();
+        let result = change(&mut account);
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            self.column_family(ACCOUNTS)?,
+            account_key(client_id),
+            encode_account(&mut account)?,
+        );
+        let oplogs = self.column_family(OPLOGS)?;
+        for (key, value) in oplog_changes(client_id, &before, &account.oplog)? {
+            match value {
+                Some(value) => batch.put_cf(oplogs, key, value),
+                None => batch.delete_cf(oplogs, key),
+            }
+        }
+        self.db.write(batch).map_err(storage_error)?;
+        Ok(result)
+    }
+}
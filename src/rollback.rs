@@ -0,0 +1,46 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+
+// `ledger undo --last N --snapshot s.json [--snapshot-out o.json]`: reverts the last N
+// transactions applied to the ledger of the snapshot, which has to have been taken with
+// --undo-depth (see Ledger::undo_last), and writes the ledger back, to the snapshot itself unless
+// --snapshot-out is given. The snapshot is replaced as a whole, through a temporary file, so that
+// an interrupted undo leaves it as it was.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut last, mut snapshot, mut snapshot_out) = (None, None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--last" => last = Some(value()?.parse::<usize>()?),
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            "--snapshot-out" => snapshot_out = Some(value()?.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let last = last.ok_or_else(|| anyhow! {"undo requires --last N"})?;
+    let path = snapshot.ok_or_else(|| anyhow! {"undo requires the --snapshot to revert"})?;
+    let mut l = crate::load_snapshot(&path)?;
+    if l.config().undo_depth == 0 {
+        return Err(anyhow! {"snapshot {} was taken without --undo-depth", path});
+    }
+    if l.undoable() < last {
+        return Err(anyhow! {
+            "only the last {} transactions of snapshot {} can be undone",
+            l.undoable(),
+            path
+        });
+    }
+    let reverted = l.undo_last(last);
+    let out = snapshot_out.unwrap_or_else(|| path.clone());
+    let tmp = format!("{}.tmp", out);
+    crate::write_snapshot(&tmp, &l)?;
+    fs::rename(&tmp, &out)?;
+    for (client, tx) in &reverted {
+        println!("Reverted transaction {} of client {}", tx, client);
+    }
+    Ok(())
+}
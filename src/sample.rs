@@ -0,0 +1,33 @@
+use csv::StringRecord;
+use sha2::{Digest, Sha256};
+
+// Sample of the clients (--sample FRACTION --seed SEED): a client is in it when the hash of its id
+// and the seed falls within the fraction, so that the same seed picks the same clients whatever
+// the input, and a larger fraction picks the clients of a smaller one and more. All the records
+// of a sampled client are processed, those of other clients (and those without a valid client)
+// are left out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub fraction: f64,
+    pub seed: u64,
+}
+
+impl Sample {
+    pub fn contains(&self, client: u16) -> bool {
+        let digest = Sha256::new()
+            .chain_update(self.seed.to_be_bytes())
+            .chain_update(client.to_be_bytes())
+            .finalize();
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+        (hash as f64) < self.fraction * (u64::MAX as f64)
+    }
+
+    pub fn includes(&self, record: &StringRecord, headers: &StringRecord) -> bool {
+        headers
+            .iter()
+            .position(|h| h == "client")
+            .and_then(|i| record.get(i))
+            .and_then(|client| client.parse().ok())
+            .is_some_and(|client| self.contains(client))
+    }
+}
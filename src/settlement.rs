@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Result};
+use ledger::{Amount, Applied, Calendar, Money, Timestamp};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::str::FromStr;
+
+// Point at which a settlement batch is closed: every day at the given time of day (in seconds
+// since midnight UTC), given as HH:MM[:SS], or once at the given timestamp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cutoff {
+    Daily(i64),
+    At(Timestamp),
+}
+
+impl FromStr for Cutoff {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow! {"invalid cut-off {} (expected HH:MM[:SS] or a timestamp)", s};
+        if s.contains('-') || !s.contains(':') {
+            return s.parse().map(Cutoff::At).map_err(|_| invalid());
+        }
+        let fields: Option<Vec<i64>> = s.split(':').map(|f| f.parse().ok()).collect();
+        let (h, m, sec) = match fields.as_deref() {
+            Some(&[h, m]) => (h, m, 0),
+            Some(&[h, m, sec]) => (h, m, sec),
+            _ => return Err(invalid()),
+        };
+        if !(0..24).contains(&h) || !(0..60).contains(&m) || !(0..60).contains(&sec) {
+            return Err(invalid());
+        }
+        Ok(Cutoff::Daily(h * 3600 + m * 60 + sec))
+    }
+}
+
+impl Cutoff {
+    // The first occurrence of the cut-off strictly after the given time. With a calendar, daily
+    // cut-offs only fall on business days.
+    fn next_after(self, t: Timestamp, calendar: Option<&Calendar>) -> Option<Timestamp> {
+        match self {
+            Cutoff::Daily(time_of_day) => {
+                if calendar.is_some_and(|c| !c.has_business_days()) {
+                    return None;
+                }
+                let mut day = t.0.div_euclid(86400);
+                while day * 86400 + time_of_day <= t.0
+                    || calendar.is_some_and(|c| !c.is_business_day(day))
+                {
+                    day += 1;
+                }
+                Some(Timestamp(day * 86400 + time_of_day))
+            }
+            Cutoff::At(at) => (at > t).then_some(at),
+        }
+    }
+}
+
+// Net movement of a client within a batch.
+#[derive(Debug, Default)]
+struct Movement {
+    transactions: u64,
+    net: Amount,
+}
+
+// Row of the settlement file.
+#[derive(Debug, serde::Serialize)]
+struct BatchRecord {
+    batch: u64,
+    cutoff: Option<Timestamp>, // Empty for the batch still open at the end of the input
+    client: u16,
+    transactions: u64,
+    net: String,
+}
+
+// Nets the movements of each client into settlement batches, closed at the cut-offs. A batch is
+// closed when a transaction timestamped at or after the next cut-off comes in, and the
+// transaction goes into the next batch; transactions without a timestamp go into the open batch.
+// The batch still open at the end of the input is written out too, without a cut-off.
+pub struct Settlement {
+    cutoffs: Vec<Cutoff>,
+    calendar: Option<Calendar>,
+    writer: csv::Writer<File>,
+    batch: u64,
+    closes_at: Option<Timestamp>,
+    movements: BTreeMap<u16, Movement>,
+}
+
+impl Settlement {
+    pub fn create(
+        path: &str,
+        cutoffs: Vec<Cutoff>,
+        calendar: Option<Calendar>,
+    ) -> Result<Settlement, csv::Error> {
+        Ok(Settlement {
+            cutoffs,
+            calendar,
+            writer: csv::Writer::from_path(path)?,
+            batch: 1,
+            closes_at: None,
+            movements: BTreeMap::new(),
+        })
+    }
+
+    fn next_cutoff(&self, t: Timestamp) -> Option<Timestamp> {
+        self.cutoffs
+            .iter()
+            .filter_map(|c| c.next_after(t, self.calendar.as_ref()))
+            .min()
+    }
+
+    // Adds an applied transaction to the open batch, closing the batch first if the transaction
+    // is past its cut-off. Returns the cut-off of the batch it closed, if any.
+    pub fn record(&mut self, applied: &Applied) -> Result<Option<Timestamp>, csv::Error> {
+        let mut closed = None;
+        if let Some(t) = applied.timestamp {
+            match self.closes_at {
+                Some(cutoff) if t >= cutoff => {
+                    self.close(Some(cutoff))?;
+                    closed = Some(cutoff);
+                    self.closes_at = self.next_cutoff(t);
+                }
+                None => self.closes_at = self.next_cutoff(t),
+                _ => {}
+            }
+        }
+        let movement = self.movements.entry(applied.client_id).or_default();
+        movement.transactions += 1;
+        movement.net += applied.after.total - applied.before.total;
+        if let Some((overflow_account, excess)) = applied.swept {
+            let movement = self.movements.entry(overflow_account).or_default();
+            movement.transactions += 1;
+            movement.net += excess;
+        }
+        Ok(closed)
+    }
+
+    fn close(&mut self, cutoff: Option<Timestamp>) -> Result<(), csv::Error> {
+        for (client, m) in std::mem::take(&mut self.movements) {
+            self.writer.serialize(BatchRecord {
+                batch: self.batch,
+                cutoff,
+                client,
+                transactions: m.transactions,
+                net: format!("{:.4}", m.net),
+            })?;
+        }
+        self.batch += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), csv::Error> {
+        if !self.movements.is_empty() {
+            self.close(None)?;
+        }
+        self.writer.flush().map_err(csv::Error::from)
+    }
+}
+
+// Columns of the settlement export.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportColumn {
+    Client,
+    Credits,
+    Debits,
+    Net,
+    Transactions,
+    PeriodStart,
+    PeriodEnd,
+}
+
+impl ExportColumn {
+    fn name(self) -> &'static str {
+        match self {
+            ExportColumn::Client => "client",
+            ExportColumn::Credits => "credits",
+            ExportColumn::Debits => "debits",
+            ExportColumn::Net => "net",
+            ExportColumn::Transactions => "transactions",
+            ExportColumn::PeriodStart => "period_start",
+            ExportColumn::PeriodEnd => "period_end",
+        }
+    }
+}
+
+// Layout of the settlement export, read from the file given with --settlement-layout:
+//
+//     columns = ["client", "credits", "debits", "net"]
+//     delimiter = ";"
+//     header = true
+//     decimal-places = 2
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExportLayout {
+    pub columns: Vec<ExportColumn>,
+    pub delimiter: char,
+    pub header: bool,
+    pub decimal_places: usize,
+}
+
+impl Default for ExportLayout {
+    fn default() -> ExportLayout {
+        ExportLayout {
+            columns: vec![
+                ExportColumn::Client,
+                ExportColumn::Debits,
+                ExportColumn::Credits,
+                ExportColumn::Net,
+            ],
+            delimiter: ',',
+            header: true,
+            decimal_places: 4,
+        }
+    }
+}
+
+pub fn read_layout(path: &str) -> Result<ExportLayout> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow! {"cannot read settlement layout {}: {}", path, e})?;
+    let layout: ExportLayout = toml::from_str(&contents)
+        .map_err(|e| anyhow! {"invalid settlement layout {}: {}", path, e})?;
+    if !layout.delimiter.is_ascii() {
+        return Err(anyhow! {"invalid settlement layout {}: the delimiter must be ASCII", path});
+    }
+    Ok(layout)
+}
+
+// Credits and debits of a client over the whole processed period.
+#[derive(Debug, Default)]
+struct Totals {
+    credits: Amount,
+    debits: Amount,
+    transactions: u64,
+}
+
+// Per-client net movements (debits, credits and net) over everything processed, written once at
+// the end of the run for the payment processor. The period is given by the earliest and latest
+// timestamps seen.
+pub struct SettlementExport {
+    path: String,
+    layout: ExportLayout,
+    totals: BTreeMap<u16, Totals>,
+    period: Option<(Timestamp, Timestamp)>,
+}
+
+impl SettlementExport {
+    pub fn new(path: &str, layout: ExportLayout) -> SettlementExport {
+        SettlementExport {
+            path: path.to_string(),
+            layout,
+            totals: BTreeMap::new(),
+            period: None,
+        }
+    }
+
+    fn add(&mut self, client: u16, movement: Amount) {
+        let totals = self.totals.entry(client).or_default();
+        totals.transactions += 1;
+        if movement >= Amount::ZERO {
+            totals.credits += movement;
+        } else {
+            totals.debits -= movement;
+        }
+    }
+
+    pub fn record(&mut self, applied: &Applied) {
+        if let Some(t) = applied.timestamp {
+            self.period = Some(match self.period {
+                Some((start, end)) => (start.min(t), end.max(t)),
+                None => (t, t),
+            });
+        }
+        self.add(
+            applied.client_id,
+            applied.after.total - applied.before.total,
+        );
+        if let Some((overflow_account, excess)) = applied.swept {
+            self.add(overflow_account, excess);
+        }
+    }
+
+    pub fn write(self) -> Result<(), csv::Error> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.layout.delimiter as u8)
+            .from_path(&self.path)?;
+        if self.layout.header {
+            writer.write_record(self.layout.columns.iter().map(|c| c.name()))?;
+        }
+        let amount = |v: Amount| format!("{:.*}", self.layout.decimal_places, v);
+        let time = |t: Option<Timestamp>| t.map(|t| t.to_string()).unwrap_or_default();
+        for (client, t) in &self.totals {
+            let cells = self.layout.columns.iter().map(|c| match c {
+                ExportColumn::Client => client.to_string(),
+                ExportColumn::Credits => amount(t.credits),
+                ExportColumn::Debits => amount(t.debits),
+                ExportColumn::Net => amount(t.credits - t.debits),
+                ExportColumn::Transactions => t.transactions.to_string(),
+                ExportColumn::PeriodStart => time(self.period.map(|p| p.0)),
+                ExportColumn::PeriodEnd => time(self.period.map(|p| p.1)),
+            });
+            writer.write_record(cells)?;
+        }
+        writer.flush().map_err(csv::Error::from)
+    }
+}
@@ -0,0 +1,81 @@
+use crate::idempotency::{self, IdempotencyKeys, Keyed};
+use crate::{
+    credit_overflow, process_transaction, sweep_target, Account, Applied, Balance, Ledger,
+    LedgerError, TransactionEntry,
+};
+use std::collections::BTreeMap;
+
+// Account touched by a simulation, with its balances (over its sub-accounts) before and after
+// the hypothetical transactions. It had no balances before if the client had no account yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulatedAccount {
+    pub client_id: u16,
+    pub before: Option<Balance>,
+    pub after: Balance,
+}
+
+// Outcome of Ledger::simulate: the result of every hypothetical transaction, in order, and the
+// accounts they touched, by client id.
+#[derive(Debug)]
+pub struct SimulationReport {
+    pub results: Vec<Result<Applied, LedgerError>>,
+    pub accounts: Vec<SimulatedAccount>,
+}
+
+impl Ledger {
+    // The copy of the account of the client, made on first use.
+    fn copy<'a>(&self, copies: &'a mut BTreeMap<u16, Account>, client_id: u16) -> &'a mut Account {
+        copies
+            .entry(client_id)
+            .or_insert_with(|| self.account(client_id).cloned().unwrap_or_default())
+    }
+
+    // Applies hypothetical transactions to a copy of the accounts they touch, as
+    // apply_transaction would, and reports the outcome without changing the ledger. Only the
+    // touched accounts are copied, on their first transaction. Bonuses and authorizations don't
+    // expire during a simulation.
+    pub fn simulate(&self, transactions: &[TransactionEntry]) -> SimulationReport {
+        let mut copies: BTreeMap<u16, Account> = BTreeMap::new();
+        let mut keys = IdempotencyKeys::new(); // Those of the simulated transactions
+        let mut results = Vec::with_capacity(transactions.len());
+        for tx in transactions {
+            let keyed = idempotency::lookup(&self.idempotency_keys, tx)
+                .or_else(|| idempotency::lookup(&keys, tx));
+            if let Some(keyed) = keyed {
+                results.push(Err(keyed.check(tx)));
+                continue;
+            }
+            let key = tx.idempotency_key.clone().zip(Keyed::of(tx));
+            let overflow = sweep_target(tx, &self.config)
+                .and_then(|o| copies.get(&o).or_else(|| self.account(o)))
+                .map(Account::rollup);
+            let a = self.copy(&mut copies, tx.client_id);
+            let result =
+                process_transaction(tx.clone(), a, &self.config, &*self.rates.0, None, overflow)
+                    .and_then(|applied| {
+                        if let Some((overflow_account, excess)) = applied.swept {
+                            let overflow = self.copy(&mut copies, overflow_account);
+                            credit_overflow(applied.client_id, applied.tx, excess, overflow)?;
+                        }
+                        Ok(applied)
+                    });
+            if let Ok(applied) = &result {
+                if let Some((key, keyed)) = key {
+                    keys.entry(applied.client_id)
+                        .or_default()
+                        .insert(key, keyed);
+                }
+            }
+            results.push(result);
+        }
+        let accounts = copies
+            .iter()
+            .map(|(&client_id, a)| SimulatedAccount {
+                client_id,
+                before: self.account(client_id).map(Account::rollup),
+                after: a.rollup(),
+            })
+            .collect();
+        SimulationReport { results, accounts }
+    }
+}
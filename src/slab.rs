@@ -0,0 +1,122 @@
+use crate::Account;
+use std::ops::Index;
+
+// Marks a client without an account in the index.
+const VACANT: u32 = u32::MAX;
+
+// Accounts of a ledger, stored next to each other in a slab in the order they were opened, with a
+// direct index from client id to slot (client ids are only 16 bits, so the index is a flat table
+// of 256 KiB, allocated with the first account). Compared to a map of boxed entries this saves a
+// hash and a few pointer chases per transaction, and keeps runs over millions of transactions
+// from fragmenting the heap. Accounts are only removed by undoing the transactions which opened
+// them, the last opened first.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Accounts {
+    index: Vec<u32>,
+    slots: Vec<(u16, Account)>,
+}
+
+impl Accounts {
+    pub(crate) fn with_capacity(capacity: usize) -> Accounts {
+        Accounts {
+            index: Vec::new(),
+            slots: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn slot(&self, client_id: u16) -> Option<usize> {
+        match self.index.get(usize::from(client_id)) {
+            Some(&slot) if slot != VACANT => Some(slot as usize),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get(&self, client_id: u16) -> Option<&Account> {
+        self.slot(client_id).map(|slot| &self.slots[slot].1)
+    }
+
+    pub(crate) fn get_mut(&mut self, client_id: u16) -> Option<&mut Account> {
+        self.slot(client_id).map(|slot| &mut self.slots[slot].1)
+    }
+
+    // The account of the client, opened if it doesn't exist yet.
+    pub(crate) fn get_or_default(&mut self, client_id: u16) -> &mut Account {
+        let slot = match self.slot(client_id) {
+            Some(slot) => slot,
+            None => self.open(client_id, Account::new()),
+        };
+        &mut self.slots[slot].1
+    }
+
+    // Adds the account of a client, returning false if the client already has one.
+    pub(crate) fn insert(&mut self, client_id: u16, account: Account) -> bool {
+        if self.slot(client_id).is_some() {
+            return false;
+        }
+        self.open(client_id, account);
+        true
+    }
+
+    fn open(&mut self, client_id: u16, account: Account) -> usize {
+        if self.index.is_empty() {
+            self.index = vec![VACANT; usize::from(u16::MAX) + 1];
+        }
+        let slot = self.slots.len();
+        self.index[usize::from(client_id)] = slot as u32;
+        self.slots.push((client_id, account));
+        slot
+    }
+
+    // Removes the account of the client if it is the last one opened, when undoing the
+    // transaction which opened it.
+    pub(crate) fn remove_last(&mut self, client_id: u16) -> bool {
+        if self.slots.last().is_none_or(|(c, _)| *c != client_id) {
+            return false;
+        }
+        self.slots.pop();
+        self.index[usize::from(client_id)] = VACANT;
+        true
+    }
+
+    // Iterates over the accounts, as (client id, account) pairs in the order they were opened.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u16, &Account)> {
+        self.slots.iter().map(|(client_id, a)| (*client_id, a))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+impl Index<u16> for Accounts {
+    type Output = Account;
+
+    fn index(&self, client_id: u16) -> &Account {
+        self.get(client_id).expect("no account for client")
+    }
+}
+
+impl IntoIterator for Accounts {
+    type Item = (u16, Account);
+    type IntoIter = std::vec::IntoIter<(u16, Account)>;
+
+    // Consumes the accounts, in the order they were opened.
+    fn into_iter(self) -> Self::IntoIter {
+        self.slots.into_iter()
+    }
+}
+
+impl FromIterator<(u16, Account)> for Accounts {
+    // Keeps the first account of a client given more than once.
+    fn from_iter<I: IntoIterator<Item = (u16, Account)>>(iter: I) -> Accounts {
+        let mut accounts = Accounts::default();
+        for (client_id, account) in iter {
+            accounts.insert(client_id, account);
+        }
+        accounts
+    }
+}
@@ -0,0 +1,96 @@
+use crate::kv_store::{account_key, decode_account, encode_account, oplog_changes, storage_error};
+use crate::{Account, AsyncLedgerStore, LedgerError};
+use sled::transaction::{ConflictableTransactionError, TransactionResult};
+use sled::{Db, Transactional, Tree};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+// AsyncLedgerStore on a sled database (with the sled feature), the pure-Rust alternative to
+// RocksDbStore: the accounts are in the accounts tree and the oplog entries in the oplogs one,
+// with the layout of kv_store. Each update is one transaction over both trees, flushed to disk
+// before the update completes, so that after a crash the database has every completed update and
+// no partial one. The flush is synchronous, like the transaction: the asynchronous one of sled
+// runs on its thread pool, whose handle of the database may outlive the update and keep the
+// database locked after the store is dropped.
+pub struct SledStore {
+    db: Db,
+    accounts: Tree,
+    oplogs: Tree,
+    // Held over the read, change and write of an update, so that updates don't interleave.
+    updates: Mutex<()>,
+}
+
+impl SledStore {
+    // Opens the database at the path, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<SledStore, LedgerError> {
+        // Updates flush themselves, so there is no need for the background flusher (whose thread
+        // would also keep the database locked for a while after the store is dropped).
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(None)
+            .open()
+            .map_err(storage_error)?;
+        Ok(SledStore {
+            accounts: db.open_tree("accounts").map_err(storage_error)?,
+            oplogs: db.open_tree("oplogs").map_err(storage_error)?,
+            db,
+            updates: Mutex::new(()),
+        })
+    }
+
+    fn read(&self, client_id: u16) -> Result<Option<Account>, LedgerError> {
+        let key = account_key(client_id);
+        let Some(record) = self.accounts.get(key).map_err(storage_error)? else {
+            return Ok(None);
+        };
+        let entries = self
+            .oplogs
+            .scan_prefix(key)
+            .map(|entry| entry.map_err(storage_error));
+        decode_account(&record, entries).map(Some)
+    }
+
+    // Reads the account, applies the change and writes the result in a transaction.
+    fn change<R>(
+        &self,
+        client_id: u16,
+        change: impl FnOnce(&mut Account) -> R,
+    ) -> Result<R, LedgerError> {
+        let _update = self.updates.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut account = self.read(client_id)?.unwrap_or_default();
+        let before = account.oplog.clone();
+        let result = change(&mut account);
+        let record = encode_account(&mut account)?;
+        let changes = oplog_changes(client_id, &before, &account.oplog)?;
+        // The closure may run more than once, when the transaction conflicts with another one.
+        let written: TransactionResult<(), sled::Error> = (&self.accounts, &self.oplogs)
+            .transaction(|(accounts, oplogs)| {
+                accounts.insert(&account_key(client_id), record.as_slice())?;
+                for (key, value) in &changes {
+                    match value {
+                        Some(value) => oplogs.insert(key, value.as_slice())?,
+                        None => oplogs.remove(key)?,
+                    };
+                }
+                Ok::<(), ConflictableTransactionError<sled::Error>>(())
+            });
+        written.map_err(storage_error)?;
+        Ok(result)
+    }
+}
+
+impl AsyncLedgerStore for SledStore {
+    async fn load(&self, client_id: u16) -> Result<Option<Account>, LedgerError> {
+        self.read(client_id)
+    }
+
+    async fn update<R: Send>(
+        &self,
+        client_id: u16,
+        change: impl FnOnce(&mut Account) -> R + Send,
+    ) -> Result<R, LedgerError> {
+        let result = self.change(client_id, change)?;
+        self.db.flush().map_err(storage_error)?;
+        Ok(result)
+    }
+}
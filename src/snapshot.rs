@@ -0,0 +1,232 @@
+use crate::idempotency::IdempotencyKeys;
+use crate::oplog::Oplog;
+use crate::slab::Accounts;
+use crate::undo::Undo;
+use crate::{
+    authorization_expiries, bonus_expiries, duplicate_filter, Account, AccountState, Amount, Bonus,
+    Config, Currency, Ledger, LegalHold, Money, Rates, Sweep,
+};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+// Version of the serialized representation of a Ledger. Snapshots with a different version are
+// refused on deserialization rather than misread, so any incompatible change to the serialized
+// types (field or variant names, their meaning) has to bump it.
+pub const SNAPSHOT_VERSION: u32 = 10;
+
+// A serialized Ledger looks like this (in JSON):
+//
+// {
+//   "version": 10,
+//   "config": { "precision": null, "allow-overdraft": 0.0, "locked-policy": "reject-all" },
+//   "accounts": [
+//     {
+//       "client": 1,
+//       "state": { "status": "open", "available": 1.5, "held": 0.0 },
+//       "oplog": { "1": { "state": "regular_deposit", "amount": 1.0 }, "4": { "state": "after_withdrawal" } },
+//       "currencies": { "EUR": 2.5 }
+//     }
+//   ]
+// }
+//
+// Currencies are left out for accounts without balances in other currencies than the base one,
+// fees and escrow when they are zero, bonuses for accounts without pending bonuses and subaccounts
+// for clients which never used any, and the tags and memos for accounts without any. Accounts of
+// forgotten clients are tombstones, with "forgotten": true.
+// Sub-accounts are serialized like accounts, by name.
+// Accounts are sorted by client and oplogs by transaction id, so that serializing the same state
+// always produces the same output. With Config::undo_depth, the undo journal follows the accounts,
+// the latest transaction last, then the transactions by client and idempotency key if any were
+// used (see idempotency::Keyed), and
+// with Config::retention_days, the day marks of the retention window as [day, transaction id],
+// and the legal holds placed on the ledger (those of the configuration are in the config).
+#[derive(Serialize)]
+struct LedgerRef<'a> {
+    version: u32,
+    config: &'a Config,
+    accounts: Vec<AccountRef<'a>>,
+    #[serde(skip_serializing_if = "VecDeque::is_empty")]
+    undo: &'a VecDeque<Undo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    idempotency_keys: &'a IdempotencyKeys,
+    #[serde(skip_serializing_if = "VecDeque::is_empty")]
+    day_marks: &'a VecDeque<(i64, u32)>,
+    #[serde(skip_serializing_if = "<[LegalHold]>::is_empty")]
+    legal_holds: &'a [LegalHold],
+}
+
+#[derive(Serialize)]
+struct AccountRef<'a> {
+    client: u16,
+    state: &'a AccountState,
+    oplog: &'a Oplog,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: &'a BTreeMap<Currency, Amount>,
+    #[serde(skip_serializing_if = "is_zero")]
+    fees: Amount,
+    #[serde(skip_serializing_if = "is_zero")]
+    escrow: Amount,
+    #[serde(skip_serializing_if = "<[Bonus]>::is_empty")]
+    bonuses: &'a [Bonus],
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    subaccounts: &'a BTreeMap<String, Account>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tag_names: &'a [String],
+    #[serde(
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted"
+    )]
+    tags: &'a HashMap<u32, u64>,
+    #[serde(
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted"
+    )]
+    memos: &'a HashMap<u32, Vec<String>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    forgotten: bool,
+    #[serde(skip_serializing_if = "<[Sweep]>::is_empty")]
+    sweeps: &'a [Sweep],
+}
+
+pub(crate) fn is_zero(v: &Amount) -> bool {
+    *v == Amount::ZERO
+}
+
+#[derive(Deserialize)]
+struct LedgerRepr {
+    version: u32,
+    config: Config,
+    accounts: Vec<AccountRepr>,
+    #[serde(default)]
+    undo: VecDeque<Undo>,
+    #[serde(default)]
+    idempotency_keys: IdempotencyKeys,
+    #[serde(default)]
+    day_marks: VecDeque<(i64, u32)>,
+    #[serde(default)]
+    legal_holds: Vec<LegalHold>,
+}
+
+#[derive(Deserialize)]
+struct AccountRepr {
+    client: u16,
+    state: AccountState,
+    oplog: Oplog,
+    #[serde(default)]
+    currencies: BTreeMap<Currency, Amount>,
+    #[serde(default)]
+    fees: Amount,
+    #[serde(default)]
+    escrow: Amount,
+    #[serde(default)]
+    bonuses: Vec<Bonus>,
+    #[serde(default)]
+    subaccounts: BTreeMap<String, Account>,
+    #[serde(default)]
+    tag_names: Vec<String>,
+    #[serde(default)]
+    tags: HashMap<u32, u64>,
+    #[serde(default)]
+    memos: HashMap<u32, Vec<String>>,
+    #[serde(default)]
+    forgotten: bool,
+    #[serde(default)]
+    sweeps: Vec<Sweep>,
+}
+
+impl Serialize for Ledger {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut accounts: Vec<AccountRef> = self
+            .accounts
+            .iter()
+            .map(|(client, account)| AccountRef {
+                client,
+                state: &account.state,
+                oplog: &account.oplog,
+                currencies: &account.currencies,
+                fees: account.fees,
+                escrow: account.escrow,
+                bonuses: &account.bonuses,
+                subaccounts: &account.subaccounts,
+                tag_names: &account.tag_names,
+                tags: &account.tags,
+                memos: &account.memos,
+                forgotten: account.forgotten,
+                sweeps: &account.sweeps,
+            })
+            .collect();
+        accounts.sort_by_key(|a| a.client);
+        LedgerRef {
+            version: SNAPSHOT_VERSION,
+            config: &self.config,
+            accounts,
+            undo: &self.undo,
+            idempotency_keys: &self.idempotency_keys,
+            day_marks: &self.day_marks,
+            legal_holds: &self.legal_holds,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ledger {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Ledger, D::Error> {
+        let repr = LedgerRepr::deserialize(deserializer)?;
+        if repr.version != SNAPSHOT_VERSION {
+            return Err(D::Error::custom(format!(
+                "unsupported ledger snapshot version {} (expected {})",
+                repr.version, SNAPSHOT_VERSION
+            )));
+        }
+        let mut accounts = Accounts::with_capacity(repr.accounts.len());
+        for a in repr.accounts {
+            let account = Account {
+                state: a.state,
+                oplog: a.oplog,
+                currencies: a.currencies,
+                fees: a.fees,
+                escrow: a.escrow,
+                bonuses: a.bonuses,
+                subaccounts: a.subaccounts,
+                tag_names: a.tag_names,
+                tags: a.tags,
+                memos: a.memos,
+                forgotten: a.forgotten,
+                sweeps: a.sweeps,
+            };
+            // The ledger keeps the rollups in range, see Account::rollup.
+            if account.checked_rollup().is_none() {
+                return Err(D::Error::custom(format!(
+                    "balances of client {} out of range",
+                    a.client
+                )));
+            }
+            if !accounts.insert(a.client, account) {
+                return Err(D::Error::custom(format!("duplicate client {}", a.client)));
+            }
+        }
+        Ok(Ledger {
+            bonus_expiries: bonus_expiries(&accounts),
+            authorization_expiries: authorization_expiries(&accounts),
+            duplicate_filter: duplicate_filter(&repr.config, &accounts),
+            accounts,
+            config: repr.config,
+            // Rates are input data rather than ledger state, they are not part of snapshots.
+            rates: Rates::default(),
+            undo: repr.undo,
+            idempotency_keys: repr.idempotency_keys,
+            day_marks: repr.day_marks,
+            legal_holds: repr.legal_holds,
+        })
+    }
+}
+
+// Serializes a map by transaction id (the oplog, the tags) sorted by transaction id.
+pub(crate) fn serialize_sorted<T: Serialize, S: Serializer>(
+    map: &HashMap<u32, T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let sorted: BTreeMap<&u32, &T> = map.iter().collect();
+    sorted.serialize(serializer)
+}
@@ -0,0 +1,340 @@
+use crate::memory;
+use anyhow::{anyhow, Result};
+use ledger::{Amount, Config, Ledger, TransactionEntry};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RATE: f64 = 10_000.0; // Transactions per second
+const DURATION: Duration = Duration::from_secs(10);
+const CLIENTS: u16 = 10_000;
+// Transactions generated ahead of the engine, before the generator waits for it.
+const QUEUE: usize = 65_536;
+// Deposits (and disputes) the generator remembers, to dispute (and resolve or charge back).
+const RECENT: usize = 4096;
+
+// Parses a rate of transactions per second, optionally with a k or m suffix and a /s unit, e.g.
+// 100k/s.
+fn parse_rate(s: &str) -> Option<f64> {
+    let s = s.strip_suffix("/s").unwrap_or(s);
+    let (digits, factor) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 1e3),
+        b'M' | b'm' => (&s[..s.len() - 1], 1e6),
+        _ => (s, 1.0),
+    };
+    Some(digits.parse::<f64>().ok()? * factor).filter(|r| r.is_finite() && *r > 0.0)
+}
+
+// Parses a duration in seconds, optionally with an s, m or h unit, e.g. 60s.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (digits, factor) = match s.as_bytes().last()? {
+        b's' => (&s[..s.len() - 1], 1),
+        b'm' => (&s[..s.len() - 1], 60),
+        b'h' => (&s[..s.len() - 1], 3600),
+        _ => (s, 1),
+    };
+    Some(Duration::from_secs(
+        digits.parse::<u64>().ok()?.checked_mul(factor)?,
+    ))
+}
+
+// Deterministic synthetic traffic: deposits and withdrawals of random amounts for random clients,
+// with disputes of recent deposits which are then resolved or charged back. The same seed gives
+// the same transactions.
+struct Generator {
+    state: u64,
+    clients: u16,
+    tx: u32,
+    deposits: Vec<(u16, u32)>,
+    disputes: Vec<(u16, u32)>,
+}
+
+impl Generator {
+    fn new(seed: u64, clients: u16) -> Generator {
+        Generator {
+            state: seed,
+            clients,
+            tx: 0,
+            deposits: Vec::with_capacity(RECENT),
+            disputes: Vec::with_capacity(RECENT),
+        }
+    }
+
+    // SplitMix64.
+    fn random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Amount of up to max, with 4 decimals.
+    fn amount(&mut self, max: u64) -> Amount {
+        let units = 1 + self.random() % (max * 10_000);
+        #[cfg(not(feature = "minor-units"))]
+        let amount = units as f32 / 10_000.0;
+        #[cfg(feature = "minor-units")]
+        let amount = ledger::MinorUnits::new(units as i64, 4);
+        amount
+    }
+
+    // Remembers a transaction, replacing a random older one once there are enough.
+    fn remember(&mut self, disputed: bool, entry: (u16, u32)) {
+        let slot = self.random() as usize % RECENT;
+        let recent = if disputed {
+            &mut self.disputes
+        } else {
+            &mut self.deposits
+        };
+        if recent.len() < RECENT {
+            recent.push(entry);
+        } else {
+            recent[slot] = entry;
+        }
+    }
+
+    // Takes a random remembered transaction out.
+    fn take(&mut self, disputed: bool) -> Option<(u16, u32)> {
+        let slot = self.random() as usize;
+        let recent = if disputed {
+            &mut self.disputes
+        } else {
+            &mut self.deposits
+        };
+        (!recent.is_empty()).then(|| recent.swap_remove(slot % recent.len()))
+    }
+
+    fn next(&mut self) -> TransactionEntry {
+        self.tx = self.tx.wrapping_add(1);
+        let mut client = (self.random() % u64::from(self.clients)) as u16 + 1;
+        let mut uid = self.tx;
+        // Chargebacks lock the account, they are rare for the traffic not to end up rejected.
+        let roll = self.random() % 1000;
+        let (t, amount) = if roll < 60 {
+            match self.take(false) {
+                Some((c, tx)) => {
+                    (client, uid) = (c, tx);
+                    self.remember(true, (c, tx));
+                    ("dispute", None)
+                }
+                None => ("deposit", Some(self.amount(1000))),
+            }
+        } else if roll < 110 {
+            match self.take(true) {
+                Some((c, tx)) => {
+                    (client, uid) = (c, tx);
+                    let t = if roll < 109 { "resolve" } else { "chargeback" };
+                    (t, None)
+                }
+                None => ("deposit", Some(self.amount(1000))),
+            }
+        } else if roll < 600 {
+            ("deposit", Some(self.amount(1000)))
+        } else {
+            ("withdrawal", Some(self.amount(500)))
+        };
+        if t == "deposit" {
+            self.remember(false, (client, uid));
+        }
+        TransactionEntry {
+            t: t.to_string(),
+            client_id: client,
+            uid,
+            amount,
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            subaccount: None,
+            tags: None,
+            memo: None,
+            idempotency_key: None,
+            reason: None,
+        }
+    }
+}
+
+// Latencies, in nanoseconds, counted in buckets of 16 per power of two, which keeps percentiles
+// within about 6% of the actual values whatever the number of transactions.
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; 64 * 16],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    fn bucket(v: u64) -> usize {
+        if v < 16 {
+            return v as usize;
+        }
+        let exponent = 63 - v.leading_zeros() as usize; // At least 4
+        let mantissa = (v >> (exponent - 4)) as usize & 15;
+        (exponent - 3) * 16 + mantissa
+    }
+
+    // Largest value of the bucket.
+    fn upper(bucket: usize) -> u64 {
+        if bucket < 16 {
+            return bucket as u64;
+        }
+        let (exponent, mantissa) = (bucket / 16 + 3, bucket as u64 % 16);
+        ((16 + mantissa + 1) << (exponent - 4)) - 1
+    }
+
+    fn record(&mut self, v: u64) {
+        self.counts[Histogram::bucket(v)] += 1;
+        self.total += 1;
+        self.max = self.max.max(v);
+    }
+
+    fn percentile(&self, p: f64) -> u64 {
+        let rank = ((p / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Histogram::upper(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn format_latency(ns: u64) -> String {
+    if ns < 1_000_000 {
+        format!("{:.1}us", ns as f64 / 1e3)
+    } else {
+        format!("{:.2}ms", ns as f64 / 1e6)
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    format!("{:.1}MiB", bytes as f64 / (1 << 20) as f64)
+}
+
+// `ledger stress [--rate 100k/s] [--duration 60s] [--clients N] [--seed S] [--config c.toml]`:
+// drives the engine with synthetic traffic (see Generator) at a steady rate for the duration, and
+// reports the sustained throughput, the percentiles of the latencies and the memory of the
+// ledger, for capacity planning. The traffic is generated on a thread of its own, each
+// transaction due at its time in the schedule, and its latency runs from then until the engine
+// has applied (or rejected) it: when the engine falls behind, the latencies include the time
+// spent queued. The memory is that allocated on the heap during the run, by the ledger and by the
+// transactions queued for it, reported with the memory-accounting feature only.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut rate, mut duration, mut clients, mut seed) = (RATE, DURATION, CLIENTS, 0);
+    let mut config = Config::default();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--rate" => {
+                let v = value()?;
+                rate = parse_rate(v).ok_or_else(|| anyhow! {"invalid rate {}", v})?;
+            }
+            "--duration" => {
+                let v = value()?;
+                duration = parse_duration(v).ok_or_else(|| anyhow! {"invalid duration {}", v})?;
+            }
+            "--clients" => {
+                let v = value()?;
+                clients = v
+                    .parse()
+                    .ok()
+                    .filter(|&c| c > 0)
+                    .ok_or_else(|| anyhow! {"invalid number of clients {}", v})?;
+            }
+            "--seed" => {
+                let v = value()?;
+                seed = v.parse().map_err(|_| anyhow! {"invalid seed {}", v})?;
+            }
+            "--config" => config = crate::read_config(value()?)?,
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let offered = (rate * duration.as_secs_f64()) as u64;
+    let (sender, receiver) = mpsc::sync_channel::<(Instant, TransactionEntry)>(QUEUE);
+    let baseline = memory::allocated();
+    let start = Instant::now();
+    let generator = thread::spawn(move || {
+        let mut g = Generator::new(seed, clients);
+        for n in 0..offered {
+            let due = start + Duration::from_secs_f64(n as f64 / rate);
+            let now = Instant::now();
+            if due > now + Duration::from_millis(1) {
+                thread::sleep(due - now);
+            }
+            // A transaction handed over ahead of time (by less than the granularity of the
+            // sleeps) arrives when it is handed over, one handed over late when it was due.
+            let arrival = Instant::now().min(due);
+            if sender.send((arrival, g.next())).is_err() {
+                break;
+            }
+        }
+    });
+    let mut l = Ledger::with_config(config);
+    let mut latencies = Histogram::new();
+    let (mut applied, mut rejected, mut peak) = (0u64, 0u64, 0);
+    for (arrival, entry) in receiver.iter() {
+        if l.apply_transaction(entry).is_ok() {
+            applied += 1;
+        } else {
+            rejected += 1;
+        }
+        let latency = Instant::now().saturating_duration_since(arrival);
+        latencies.record(latency.as_nanos() as u64);
+        if latencies.total.is_multiple_of(1024) {
+            peak = peak.max(memory::allocated());
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let end = memory::allocated();
+    peak = peak.max(end);
+    generator
+        .join()
+        .map_err(|_| anyhow! {"the traffic generator panicked"})?;
+    let processed = applied + rejected;
+    println!(
+        "Offered {} transactions at {:.0}/s for {}s to {} clients",
+        offered,
+        rate,
+        duration.as_secs(),
+        clients
+    );
+    println!(
+        "Sustained throughput: {:.0}/s over {:.2}s ({} applied, {} rejected)",
+        processed as f64 / elapsed,
+        elapsed,
+        applied,
+        rejected
+    );
+    if processed > 0 {
+        println!(
+            "Latency: p50 {}, p90 {}, p99 {}, p99.9 {}, max {}",
+            format_latency(latencies.percentile(50.0)),
+            format_latency(latencies.percentile(90.0)),
+            format_latency(latencies.percentile(99.0)),
+            format_latency(latencies.percentile(99.9)),
+            format_latency(latencies.max)
+        );
+    }
+    if memory::COUNTED {
+        println!(
+            "Memory: {} peak, {} at the end, for {} accounts",
+            format_bytes(peak.saturating_sub(baseline)),
+            format_bytes(end.saturating_sub(baseline)),
+            l.accounts().count()
+        );
+    }
+    Ok(())
+}
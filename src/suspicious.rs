@@ -0,0 +1,164 @@
+use crate::metadata::rfc3339;
+use crate::output;
+use crate::risk::{RiskMonitor, RiskRules};
+use crate::template::Template;
+use anyhow::{anyhow, Result};
+use ledger::{Account, Applied, Ledger};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Write;
+use std::time::SystemTime;
+
+// Operation which matched a risk rule, as the template sees it.
+#[derive(Clone, Debug, serde::Serialize)]
+struct FlagRecord {
+    line: u64,
+    client: u16,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<f64>,
+    timestamp: Option<String>,
+    memo: Option<String>,
+    rule: &'static str,
+    detail: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct VelocityRecord {
+    transactions: usize,
+    seconds: i64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct RulesRecord {
+    amount_threshold: f64,
+    velocity: VelocityRecord,
+    repeated_disputes: u32,
+}
+
+// Flagged client, with its balances at the end of the run (over its sub-accounts).
+#[derive(Debug, serde::Serialize)]
+struct ClientRecord {
+    client: u16,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    rules: BTreeSet<&'static str>, // Matched by any of its operations
+    flags: Vec<FlagRecord>,
+}
+
+// The context of the template.
+#[derive(Debug, serde::Serialize)]
+struct Context {
+    generated_at: String,
+    rules: RulesRecord,
+    flags: Vec<FlagRecord>,
+    clients: Vec<ClientRecord>,
+}
+
+// Suspicious-activity export (--suspicious-activity with --suspicious-activity-out): the operations
+// matching the risk rules (see RiskMonitor, and --risk-amount, --risk-velocity and
+// --risk-disputes) and the clients they belong to, rendered at the end of the run through a
+// template (see Template) into the format the regulator requires. The template gets:
+//
+// - generated_at, the time of the export;
+// - rules, with amount_threshold, velocity.transactions, velocity.seconds and repeated_disputes;
+// - flags, the operations in the order they were applied, with their line, client, tx, type,
+//   amount, timestamp, memo, and the rule they matched with its detail (once per rule matched);
+// - clients, the flagged clients by id, with their available, held, total and locked balances,
+//   the rules they matched and their flags.
+//
+// e.g. for an XML filing:
+//
+//     <report generated="{{ generated_at }}">
+//     {%- for c in clients %}
+//       <subject id="{{ c.client }}" total="{{ c.total }}">
+//       {%- for f in c.flags %}
+//         <activity tx="{{ f.tx }}" rule="{{ f.rule }}">{{ f.detail | xml }}</activity>
+//       {%- endfor %}
+//       </subject>
+//     {%- endfor %}
+//     </report>
+pub struct SuspiciousActivity {
+    template_path: String,
+    template: Template,
+    file: File,
+    monitor: RiskMonitor,
+    flags: Vec<FlagRecord>,
+}
+
+impl SuspiciousActivity {
+    // Reads the template and creates the output file, so that neither fails only at the end.
+    pub fn create(template: &str, path: &str, rules: RiskRules) -> Result<SuspiciousActivity> {
+        Ok(SuspiciousActivity {
+            template_path: template.to_string(),
+            template: Template::read(template)?,
+            file: File::create(path)?,
+            monitor: RiskMonitor::new(rules),
+            flags: Vec::new(),
+        })
+    }
+
+    pub fn record(&mut self, line: u64, applied: &Applied) {
+        for (rule, detail) in self.monitor.matches(applied) {
+            self.flags.push(FlagRecord {
+                line,
+                client: applied.client_id,
+                tx: applied.tx,
+                kind: applied.kind.as_str(),
+                amount: applied.amount.map(output::rounded),
+                timestamp: applied.timestamp.map(|t| t.to_string()),
+                memo: applied.memo.clone(),
+                rule,
+                detail,
+            });
+        }
+    }
+
+    // Renders the export and returns the number of operations flagged.
+    pub fn finish(mut self, l: &Ledger) -> Result<u64> {
+        let mut clients: BTreeMap<u16, ClientRecord> = BTreeMap::new();
+        for f in &self.flags {
+            let client = clients.entry(f.client).or_insert_with(|| {
+                let b = l
+                    .account(f.client)
+                    .map_or_else(|| Account::new().balance(), Account::rollup);
+                ClientRecord {
+                    client: f.client,
+                    available: output::rounded(b.available),
+                    held: output::rounded(b.held),
+                    total: output::rounded(b.total),
+                    locked: b.locked,
+                    rules: BTreeSet::new(),
+                    flags: Vec::new(),
+                }
+            });
+            client.rules.insert(f.rule);
+            client.flags.push(f.clone());
+        }
+        let rules = self.monitor.rules();
+        let flagged = self.flags.len() as u64;
+        let context = Context {
+            generated_at: rfc3339(SystemTime::now()),
+            rules: RulesRecord {
+                amount_threshold: output::rounded(rules.amount_threshold),
+                velocity: VelocityRecord {
+                    transactions: rules.velocity.transactions,
+                    seconds: rules.velocity.seconds,
+                },
+                repeated_disputes: rules.repeated_disputes,
+            },
+            flags: self.flags,
+            clients: clients.into_values().collect(),
+        };
+        let rendered = self
+            .template
+            .render(&serde_json::to_value(&context)?)
+            .map_err(|e| anyhow! {"cannot render template {}: {}", self.template_path, e})?;
+        self.file.write_all(rendered.as_bytes())?;
+        self.file.sync_all()?;
+        Ok(flagged)
+    }
+}
@@ -0,0 +1,472 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+// Templates of the exports whose format is up to the user, with a small subset of the Jinja
+// syntax:
+//
+// - `{{ path }}` writes a value of the context, `{{ path | filter | ... }}` after the filters:
+//   xml (escapes it for XML), csv (quotes it as a CSV field if needed), json (writes it as JSON),
+//   upper, lower and length (of a list or text);
+// - `{% for name in path %}...{% endfor %}` repeats its body for every item of a list, with
+//   loop.index (from 1), loop.first and loop.last;
+// - `{% if [not] path [== or != literal] %}...{% else %}...{% endif %}`, the literal in JSON
+//   (`"velocity"`, `3`, `true`), without one on whether the value is set, non-zero and non-empty;
+// - `{# ... #}` is a comment.
+//
+// Paths are names separated by dots, list items by their index. A `-` inside the delimiters
+// (`{%-`, `-%}`) trims the whitespace before or after them. Values which aren't in the context are
+// errors rather than empty, so that a misspelled field doesn't go unnoticed in a filed report.
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Text(&'a str),
+    Expression(&'a str, usize), // With its line
+    Tag(&'a str, usize),
+}
+
+#[derive(Debug)]
+struct Expression {
+    path: Vec<String>,
+    filters: Vec<String>,
+    line: usize,
+}
+
+#[derive(Debug)]
+struct Condition {
+    negated: bool,
+    value: Expression,
+    comparison: Option<(bool, Value)>, // Whether equal to, and the literal
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Expression(Expression),
+    For {
+        name: String,
+        list: Expression,
+        body: Vec<Node>,
+    },
+    If {
+        condition: Condition,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+const FILTERS: [&str; 6] = ["xml", "csv", "json", "upper", "lower", "length"];
+
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let (mut rest, mut line, mut trim) = (source, 1, false);
+    loop {
+        let start = ["{{", "{%", "{#"].iter().filter_map(|d| rest.find(d)).min();
+        let Some(start) = start else {
+            let text = if trim { rest.trim_start() } else { rest };
+            if !text.is_empty() {
+                tokens.push(Token::Text(text));
+            }
+            return Ok(tokens);
+        };
+        let mut text = &rest[..start];
+        if trim {
+            text = text.trim_start();
+        }
+        let line_of_tag = line + rest[..start].matches('\n').count();
+        let open = &rest[start..start + 2];
+        let close = match open {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let end = rest[start + 2..]
+            .find(close)
+            .ok_or_else(|| anyhow! {"line {}: {} is never closed", line_of_tag, open})?
+            + start
+            + 2;
+        let mut inner = &rest[start + 2..end];
+        if let Some(s) = inner.strip_prefix('-') {
+            text = text.trim_end();
+            inner = s;
+        }
+        trim = false;
+        if let Some(s) = inner.strip_suffix('-') {
+            trim = true;
+            inner = s;
+        }
+        if !text.is_empty() {
+            tokens.push(Token::Text(text));
+        }
+        match open {
+            "{{" => tokens.push(Token::Expression(inner.trim(), line_of_tag)),
+            "{%" => tokens.push(Token::Tag(inner.trim(), line_of_tag)),
+            _ => {}
+        }
+        line = line_of_tag + rest[start..end].matches('\n').count();
+        rest = &rest[end + 2..];
+    }
+}
+
+fn path(s: &str, line: usize) -> Result<Vec<String>> {
+    let segments: Vec<String> = s.split('.').map(|s| s.trim().to_string()).collect();
+    let valid =
+        |s: &String| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !segments.iter().all(valid) {
+        return Err(anyhow! {"line {}: invalid path {:?}", line, s});
+    }
+    Ok(segments)
+}
+
+fn expression(s: &str, line: usize) -> Result<Expression> {
+    let mut parts = s.split('|');
+    let path = path(parts.next().unwrap_or_default(), line)?;
+    let mut filters = Vec::new();
+    for filter in parts.map(str::trim) {
+        if !FILTERS.contains(&filter) {
+            return Err(anyhow! {"line {}: unknown filter {:?}", line, filter});
+        }
+        filters.push(filter.to_string());
+    }
+    Ok(Expression {
+        path,
+        filters,
+        line,
+    })
+}
+
+fn condition(s: &str, line: usize) -> Result<Condition> {
+    let (negated, s) = match s.strip_prefix("not ") {
+        Some(s) => (true, s.trim()),
+        None => (false, s),
+    };
+    let (value, comparison) = match s
+        .split_once("==")
+        .map(|(a, b)| (a, true, b))
+        .or_else(|| s.split_once("!=").map(|(a, b)| (a, false, b)))
+    {
+        Some((value, equal, literal)) => {
+            let literal: Value = serde_json::from_str(literal.trim())
+                .map_err(|_| anyhow! {"line {}: invalid literal {:?}", line, literal.trim()})?;
+            (value, Some((equal, literal)))
+        }
+        None => (s, None),
+    };
+    Ok(Condition {
+        negated,
+        value: expression(value, line)?,
+        comparison,
+    })
+}
+
+// Parses the nodes up to one of the tags ending the block, and returns them with that tag.
+fn parse<'a>(
+    tokens: &mut std::vec::IntoIter<Token<'a>>,
+    ends: &[&str],
+) -> Result<(Vec<Node>, Option<&'a str>)> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.next() {
+        let (tag, line) = match token {
+            Token::Text(text) => {
+                nodes.push(Node::Text(text.to_string()));
+                continue;
+            }
+            Token::Expression(s, line) => {
+                nodes.push(Node::Expression(expression(s, line)?));
+                continue;
+            }
+            Token::Tag(tag, line) => (tag, line),
+        };
+        let (keyword, rest) = tag.split_once(' ').unwrap_or((tag, ""));
+        let rest = rest.trim();
+        if ends.contains(&keyword) {
+            return Ok((nodes, Some(keyword)));
+        }
+        match keyword {
+            "for" => {
+                let (name, list) = rest
+                    .split_once(" in ")
+                    .ok_or_else(|| anyhow! {"line {}: expected for <name> in <path>", line})?;
+                let name = path(name, line)?;
+                if name.len() != 1 {
+                    return Err(anyhow! {"line {}: invalid loop variable {:?}", line, rest});
+                }
+                let list = expression(list, line)?;
+                let (body, end) = parse(tokens, &["endfor"])?;
+                if end.is_none() {
+                    return Err(anyhow! {"line {}: {{% for %}} without {{% endfor %}}", line});
+                }
+                nodes.push(Node::For {
+                    name: name.into_iter().next().unwrap_or_default(),
+                    list,
+                    body,
+                });
+            }
+            "if" => {
+                let condition = condition(rest, line)?;
+                let (then, end) = parse(tokens, &["else", "endif"])?;
+                let otherwise = match end {
+                    Some("else") => match parse(tokens, &["endif"])? {
+                        (otherwise, Some(_)) => otherwise,
+                        (_, None) => {
+                            return Err(anyhow! {"line {}: {{% if %}} without {{% endif %}}", line})
+                        }
+                    },
+                    Some(_) => Vec::new(),
+                    None => {
+                        return Err(anyhow! {"line {}: {{% if %}} without {{% endif %}}", line})
+                    }
+                };
+                nodes.push(Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                });
+            }
+            _ => return Err(anyhow! {"line {}: unexpected {{% {} %}}", line, tag}),
+        }
+    }
+    Ok((nodes, None))
+}
+
+// Text of a value as written to the output: strings as they are, null as nothing and the rest
+// as JSON.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+fn filter(value: Value, filter: &str) -> Value {
+    match filter {
+        "xml" => Value::String(
+            text(&value)
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&apos;"),
+        ),
+        "csv" => {
+            let s = text(&value);
+            if s.contains([',', '"', '\n', '\r']) {
+                Value::String(format!("\"{}\"", s.replace('"', "\"\"")))
+            } else {
+                Value::String(s)
+            }
+        }
+        "json" => Value::String(value.to_string()),
+        "upper" => Value::String(text(&value).to_uppercase()),
+        "lower" => Value::String(text(&value).to_lowercase()),
+        _ => Value::from(match &value {
+            Value::Array(a) => a.len(),
+            Value::Object(o) => o.len(),
+            v => text(v).chars().count(),
+        }),
+    }
+}
+
+// Values in scope while rendering: the loop variables, innermost last, then the context.
+struct Scope<'a> {
+    context: &'a Value,
+    variables: Vec<(&'a str, Value)>,
+}
+
+impl<'a> Scope<'a> {
+    fn evaluate(&self, e: &Expression) -> Result<Value> {
+        let undefined = || anyhow! {"line {}: {} is not defined", e.line, e.path.join(".")};
+        let (first, rest) = e.path.split_first().ok_or_else(undefined)?;
+        let mut value = match self.variables.iter().rev().find(|(name, _)| name == first) {
+            Some((_, v)) => v,
+            None => self.context.get(first).ok_or_else(undefined)?,
+        };
+        for segment in rest {
+            value = match value {
+                Value::Array(a) => segment.parse::<usize>().ok().and_then(|i| a.get(i)),
+                v => v.get(segment),
+            }
+            .ok_or_else(undefined)?;
+        }
+        Ok(e.filters.iter().fold(value.clone(), |v, f| filter(v, f)))
+    }
+
+    fn render(&mut self, nodes: &'a [Node], out: &mut String) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(s) => out.push_str(s),
+                Node::Expression(e) => out.push_str(&text(&self.evaluate(e)?)),
+                Node::For { name, list, body } => {
+                    let items = match self.evaluate(list)? {
+                        Value::Array(items) => items,
+                        Value::Null => Vec::new(),
+                        _ => {
+                            return Err(
+                                anyhow! {"line {}: {} is not a list", list.line, list.path.join(".")},
+                            )
+                        }
+                    };
+                    let length = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let info = serde_json::json!({
+                            "index": i + 1,
+                            "first": i == 0,
+                            "last": i + 1 == length,
+                            "length": length,
+                        });
+                        self.variables.push(("loop", info));
+                        self.variables.push((name, item));
+                        let rendered = self.render(body, out);
+                        self.variables.truncate(self.variables.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let value = self.evaluate(&condition.value)?;
+                    let holds = match &condition.comparison {
+                        Some((equal, literal)) => (value == *literal) == *equal,
+                        None => truthy(&value),
+                    };
+                    if holds != condition.negated {
+                        self.render(then, out)?;
+                    } else {
+                        self.render(otherwise, out)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template> {
+        let mut tokens = tokenize(source)?.into_iter();
+        let (nodes, end) = parse(&mut tokens, &[])?;
+        debug_assert!(end.is_none());
+        Ok(Template { nodes })
+    }
+
+    pub fn read(path: &str) -> Result<Template> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| anyhow! {"cannot read template {}: {}", path, e})?;
+        Template::parse(&source).map_err(|e| anyhow! {"invalid template {}: {}", path, e})
+    }
+
+    pub fn render(&self, context: &Value) -> Result<String> {
+        let mut out = String::new();
+        let mut scope = Scope {
+            context,
+            variables: Vec::new(),
+        };
+        scope.render(&self.nodes, &mut out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, context: &Value) -> String {
+        Template::parse(source).unwrap().render(context).unwrap()
+    }
+
+    fn error(source: &str, context: &Value) -> String {
+        match Template::parse(source) {
+            Ok(template) => template.render(context).unwrap_err().to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn writes_values_through_their_filters() {
+        let context = json!({
+            "client": {"id": 7, "name": "Tom & \"Jerry\"", "tags": ["a", "b"]},
+            "memo": "one, two",
+            "none": null,
+        });
+        assert_eq!(render("{{ client.id }}/{{ none }}", &context), "7/");
+        assert_eq!(
+            render("{{ client.name | xml }}", &context),
+            "Tom &amp; &quot;Jerry&quot;"
+        );
+        assert_eq!(
+            render("{{ memo | csv }},{{ client.id | csv }}", &context),
+            "\"one, two\",7"
+        );
+        assert_eq!(render("{{ client.tags | json }}", &context), r#"["a","b"]"#);
+        assert_eq!(render("{{ client.tags.1 | upper }}", &context), "B");
+        assert_eq!(
+            render("{{ client.tags | length }} {{ memo | length }}", &context),
+            "2 8"
+        );
+        assert_eq!(render("{{ memo | upper | lower }}", &context), "one, two");
+    }
+
+    #[test]
+    fn repeats_loops_and_picks_branches() {
+        let context = json!({"rows": [{"kind": "velocity"}, {"kind": "amount"}], "empty": []});
+        let source = "{% for row in rows -%}
+            {{ loop.index }}:{% if row.kind == \"velocity\" %}v{% else %}{{ row.kind }}{% endif %}
+            {%- if not loop.last %},{% endif %}
+        {%- endfor %}{# done #}{% if empty %}!{% endif %}{% for row in empty %}?{% endfor %}";
+        assert_eq!(render(source, &context), "1:v,2:amount");
+        let counts = json!({"n": 0, "m": 3});
+        assert_eq!(
+            render(
+                "{% if n %}a{% endif %}{% if m != 3 %}b{% else %}c{% endif %}",
+                &counts
+            ),
+            "c"
+        );
+    }
+
+    #[test]
+    fn reports_mistakes_with_their_line() {
+        let context = json!({"rows": 1});
+        for (source, message) in [
+            ("\n{{ missing }}", "line 2: missing is not defined"),
+            ("{{ rows | bold }}", "line 1: unknown filter \"bold\""),
+            ("a\n\n{{ rows", "line 3: {{ is never closed"),
+            (
+                "{% for row in rows %}x",
+                "line 1: {% for %} without {% endfor %}",
+            ),
+            (
+                "{% for row in rows %}{% endfor %}",
+                "line 1: rows is not a list",
+            ),
+            (
+                "{% if rows %}\n{% endfor %}",
+                "line 2: unexpected {% endfor %}",
+            ),
+            (
+                "{% if rows == velocity %}{% endif %}",
+                "line 1: invalid literal \"velocity\"",
+            ),
+        ] {
+            assert_eq!(error(source, &context), message, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Point in time of a transaction, in seconds since 1970-01-01T00:00:00Z. Parsed either from a
+// number of seconds or from an RFC 3339 date ("2024-03-01") or date-time, with an offset
+// ("2024-03-01T12:00:00Z", "2024-03-01t12:00:00.250z", "2024-03-01 12:00:00+02:00") or a named
+// zone after it, in brackets or after a space ("2024-03-01T12:00:00[Europe/Paris]"). Dates and
+// date-times without either are in UTC, or in the zone given to parse_in. Fractional seconds are
+// dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Timestamp(pub i64);
+
+// Time zone: a fixed offset from UTC ("+02:00", "-0500", "UTC") or a named zone ("Europe/Paris"),
+// of which only the current standard offset and daylight saving time rule are known: they are
+// applied from the year they took effect (see ZONES), and assumed to hold in later years. The
+// hour repeated when daylight saving time ends is read in standard time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Zone {
+    offset: i32, // Standard offset, in seconds east of UTC
+    dst: Dst,
+    since: i64, // First year of the offset and rule
+}
+
+// Daylight saving time rules, each moving the clocks an hour forward.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Dst {
+    #[default]
+    None,
+    Europe,    // From the last Sunday of March to the last Sunday of October, at 01:00 UTC
+    America,   // From the second Sunday of March to the first Sunday of November, at 02:00
+    Australia, // From the first Sunday of October to the first Sunday of April, at 02:00
+}
+
+// Named zones, with their standard offset and daylight saving time rule, and the first year of
+// both: timestamps of earlier years are rejected in the zone, rather than read with rules it
+// didn't have then.
+const ZONES: [(&str, i32, Dst, i64); 41] = [
+    ("UTC", 0, Dst::None, i64::MIN),
+    ("GMT", 0, Dst::None, i64::MIN),
+    ("Etc/UTC", 0, Dst::None, i64::MIN),
+    ("Europe/London", 0, Dst::Europe, 1996),
+    ("Europe/Dublin", 0, Dst::Europe, 1996),
+    ("Europe/Lisbon", 0, Dst::Europe, 1997),
+    ("Europe/Paris", 3600, Dst::Europe, 1996),
+    ("Europe/Berlin", 3600, Dst::Europe, 1996),
+    ("Europe/Madrid", 3600, Dst::Europe, 1996),
+    ("Europe/Rome", 3600, Dst::Europe, 1996),
+    ("Europe/Amsterdam", 3600, Dst::Europe, 1996),
+    ("Europe/Brussels", 3600, Dst::Europe, 1996),
+    ("Europe/Luxembourg", 3600, Dst::Europe, 1996),
+    ("Europe/Vienna", 3600, Dst::Europe, 1996),
+    ("Europe/Zurich", 3600, Dst::Europe, 1996),
+    ("Europe/Stockholm", 3600, Dst::Europe, 1996),
+    ("Europe/Oslo", 3600, Dst::Europe, 1996),
+    ("Europe/Copenhagen", 3600, Dst::Europe, 1996),
+    ("Europe/Warsaw", 3600, Dst::Europe, 1996),
+    ("Europe/Prague", 3600, Dst::Europe, 1996),
+    ("Europe/Athens", 7200, Dst::Europe, 1996),
+    ("Europe/Helsinki", 7200, Dst::Europe, 1996),
+    ("Europe/Kyiv", 7200, Dst::Europe, 1996),
+    ("Europe/Istanbul", 10800, Dst::None, 2017),
+    ("Europe/Moscow", 10800, Dst::None, 2015),
+    ("America/New_York", -18000, Dst::America, 2007),
+    ("America/Toronto", -18000, Dst::America, 2007),
+    ("America/Chicago", -21600, Dst::America, 2007),
+    ("America/Denver", -25200, Dst::America, 2007),
+    ("America/Phoenix", -25200, Dst::None, 1968),
+    ("America/Los_Angeles", -28800, Dst::America, 2007),
+    ("America/Sao_Paulo", -10800, Dst::None, 2020),
+    ("Asia/Dubai", 14400, Dst::None, 1920),
+    ("Asia/Kolkata", 19800, Dst::None, 1946),
+    ("Asia/Singapore", 28800, Dst::None, 1982),
+    ("Asia/Hong_Kong", 28800, Dst::None, 1980),
+    ("Asia/Shanghai", 28800, Dst::None, 1992),
+    ("Asia/Tokyo", 32400, Dst::None, 1952),
+    ("Australia/Perth", 28800, Dst::None, 2010),
+    ("Australia/Brisbane", 36000, Dst::None, 1993),
+    ("Australia/Sydney", 36000, Dst::Australia, 2008),
+];
+
+impl Zone {
+    pub const UTC: Zone = Zone {
+        offset: 0,
+        dst: Dst::None,
+        since: i64::MIN,
+    };
+
+    // Offset from UTC, in seconds, of the given local time (in seconds since 1970-01-01T00:00:00
+    // local time).
+    fn offset(self, local: i64) -> i64 {
+        let standard = i64::from(self.offset);
+        let (year, _, _) = Timestamp(local).date();
+        // Day of the nth Sunday of the month, counting from its end when n is negative
+        // (1970-01-01 was a Thursday).
+        let sunday = |month: u32, n: i64| {
+            let first = days_from_civil(year, month, 1);
+            if n > 0 {
+                first + (3 - first).rem_euclid(7) + 7 * (n - 1)
+            } else {
+                let last = first + month_days(year, month) - 1;
+                last - (last + 4).rem_euclid(7) + 7 * (n + 1)
+            }
+        };
+        let dst = match self.dst {
+            Dst::None => false,
+            Dst::Europe => (sunday(3, -1) * 86400 + 3600 + standard
+                ..sunday(10, -1) * 86400 + 3600 + standard)
+                .contains(&local),
+            Dst::America => {
+                (sunday(3, 2) * 86400 + 7200..sunday(11, 1) * 86400 + 3600).contains(&local)
+            }
+            Dst::Australia => {
+                !(sunday(4, 1) * 86400 + 7200..sunday(10, 1) * 86400 + 7200).contains(&local)
+            }
+        };
+        if dst {
+            standard + 3600
+        } else {
+            standard
+        }
+    }
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(&(_, offset, dst, since)) = ZONES.iter().find(|(name, ..)| *name == s) {
+            return Ok(Zone { offset, dst, since });
+        }
+        let invalid = || format!("invalid time zone {}", s);
+        let (sign, rest) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = match rest.split_once(':') {
+            Some((hours, minutes)) => (hours, minutes),
+            None if rest.len() == 4 => rest.split_at(2),
+            None => (rest, "00"),
+        };
+        let number = |s: &str, max| {
+            Some(s)
+                .filter(|s| s.len() == 2 && s.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|s| s.parse::<i32>().ok())
+                .filter(|n| *n <= max)
+        };
+        match (number(hours, 14), number(minutes, 59)) {
+            (Some(hours), Some(minutes)) => Ok(Zone {
+                offset: sign * (hours * 3600 + minutes * 60),
+                ..Zone::UTC
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Timestamp {
+    // The (year, month, day) date of the timestamp, in UTC.
+    pub fn date(self) -> (i64, u32, u32) {
+        civil_from_days(self.0.div_euclid(86400))
+    }
+
+    // Parses a timestamp, those without an offset or zone being in the given zone.
+    pub fn parse_in(s: &str, zone: Zone) -> Result<Timestamp, String> {
+        let invalid = || format!("invalid timestamp {}", s);
+        if let Ok(seconds) = s.parse() {
+            return Ok(Timestamp(seconds));
+        }
+        // A space before a time separates it from the date, as RFC 3339 allows, rather than a
+        // zone from the date-time.
+        let named = s
+            .strip_suffix(']')
+            .and_then(|s| s.split_once('['))
+            .or_else(|| {
+                s.rsplit_once(' ')
+                    .filter(|(_, name)| !name.starts_with(|c: char| c.is_ascii_digit()))
+            });
+        let (s, zone) = match named {
+            Some((s, name)) => (s.trim_end(), name.parse().map_err(|_| invalid())?),
+            None => (s, zone),
+        };
+        let (date, time) = match s.split_once(['T', 't', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+        // Offset after the time, Z being UTC.
+        let (time, offset) = match time.map(|time| (time, time.find(['Z', 'z', '+', '-']))) {
+            Some((time, Some(i))) => {
+                let offset: Zone = match &time[i..] {
+                    "Z" | "z" => Zone::UTC,
+                    offset => offset.parse().map_err(|_| invalid())?,
+                };
+                (Some(&time[..i]), Some(i64::from(offset.offset)))
+            }
+            Some((time, None)) => (Some(time), None),
+            None => (None, None),
+        };
+        // Fractional seconds, dropped.
+        let time = match time.map(|time| time.split_once('.')) {
+            Some(Some((time, fraction)))
+                if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                Some(time)
+            }
+            Some(Some(_)) => return Err(invalid()),
+            _ => time,
+        };
+
+        // Three numbers separated by the given character.
+        let fields = |s: &str, separator| -> Option<[u32; 3]> {
+            let fields: Option<Vec<u32>> = s.split(separator).map(|f| f.parse().ok()).collect();
+            fields?.try_into().ok()
+        };
+        let [year, month, day] = fields(date, '-').ok_or_else(invalid)?;
+        let [hour, minute, second] = match time {
+            Some(time) => fields(time, ':').ok_or_else(invalid)?,
+            None => [0, 0, 0],
+        };
+        let year = i64::from(year);
+        if !(1..=12).contains(&month)
+            || day < 1
+            || i64::from(day) > month_days(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return Err(invalid());
+        }
+        if offset.is_none() && year < zone.since {
+            return Err(format!(
+                "{}: the rules of its time zone are only known from {}",
+                invalid(),
+                zone.since
+            ));
+        }
+        let days = days_from_civil(year, month, day);
+        let local = days * 86400 + i64::from(hour * 3600 + minute * 60 + second);
+        Ok(Timestamp(
+            local - offset.unwrap_or_else(|| zone.offset(local)),
+        ))
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Timestamp::parse_in(s, Zone::UTC)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    // Formats the timestamp as an RFC 3339 date-time in UTC.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (days, seconds) = (self.0.div_euclid(86400), self.0.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Converts a (year, month, day) triple in the proleptic Gregorian calendar to a number of days
+// since 1970-01-01 (Howard Hinnant's days_from_civil algorithm).
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Number of days of the month of the year.
+fn month_days(year: i64, month: u32) -> i64 {
+    days_from_civil(year + i64::from(month / 12), month % 12 + 1, 1)
+        - days_from_civil(year, month, 1)
+}
+
+// The inverse of days_from_civil.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (
+        if month <= 2 {
+            yoe + era * 400 + 1
+        } else {
+            yoe + era * 400
+        },
+        month,
+        day,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> Timestamp {
+        s.parse().unwrap()
+    }
+
+    fn zone(s: &str) -> Zone {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rfc_3339_date_times() {
+        let noon = utc("2024-03-01T12:00:00Z");
+        assert_eq!(noon, Timestamp(1709294400));
+        for s in [
+            "2024-03-01t12:00:00z",
+            "2024-03-01 12:00:00Z",
+            "2024-03-01 12:00:00",
+            "2024-03-01T12:00:00.123Z",
+            "2024-03-01T12:00:00.999999",
+            "2024-03-01T14:00:00+02:00",
+            "2024-03-01T07:00:00-0500",
+            "1709294400",
+        ] {
+            assert_eq!(utc(s), noon, "{}", s);
+        }
+        assert_eq!(utc("2024-03-01"), Timestamp(1709251200));
+        assert_eq!(noon.to_string(), "2024-03-01T12:00:00Z");
+        for s in [
+            "2024-03-01T12:00:00.Z",
+            "2024-03-01T12:00:00.1x",
+            "2024-03-01T12:00",
+            "2024-03-01T",
+            "2024-03-01T24:00:00Z",
+            "2024-03-01T12:00:00+15:00",
+            "2024-03-01 12:00:00 Mars/Olympus",
+        ] {
+            assert!(s.parse::<Timestamp>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn validates_days_against_months() {
+        assert_eq!(utc("2024-02-29").0, utc("2024-03-01").0 - 86400);
+        for s in [
+            "2024-02-30",
+            "2023-02-29",
+            "2024-02-31",
+            "2024-04-31",
+            "2024-13-01",
+        ] {
+            assert!(s.parse::<Timestamp>().is_err(), "{}", s);
+        }
+        assert!("2000-02-29".parse::<Timestamp>().is_ok());
+        assert!("1900-02-29".parse::<Timestamp>().is_err());
+        assert!("2024-12-31T23:59:60Z".parse::<Timestamp>().is_ok());
+    }
+
+    #[test]
+    fn reads_named_zones_with_daylight_saving_time() {
+        let paris = |s| Timestamp::parse_in(s, zone("Europe/Paris")).unwrap();
+        assert_eq!(paris("2024-01-15T13:00:00"), utc("2024-01-15T12:00:00Z"));
+        assert_eq!(paris("2024-07-15T14:00:00"), utc("2024-07-15T12:00:00Z"));
+        // Clocks go forward at 01:00 UTC on the last Sunday of March.
+        assert_eq!(paris("2024-03-31T01:59:59"), utc("2024-03-31T00:59:59Z"));
+        assert_eq!(paris("2024-03-31T03:00:00"), utc("2024-03-31T01:00:00Z"));
+        // The hour repeated on the last Sunday of October is read in standard time.
+        assert_eq!(paris("2024-10-27T02:30:00"), utc("2024-10-27T01:30:00Z"));
+        // An offset or zone of the timestamp wins over the given zone.
+        assert_eq!(paris("2024-07-15T12:00:00Z"), utc("2024-07-15T12:00:00Z"));
+        assert_eq!(
+            utc("2024-07-15T08:00:00[America/New_York]"),
+            utc("2024-07-15T12:00:00Z")
+        );
+        assert_eq!(
+            utc("2024-07-15 08:00:00 America/New_York"),
+            utc("2024-07-15T12:00:00Z")
+        );
+        assert_eq!(
+            utc("2024-01-15T23:00:00[Australia/Sydney]"),
+            utc("2024-01-15T12:00:00Z")
+        );
+        assert_eq!(
+            utc("2024-07-15T22:00:00[Australia/Sydney]"),
+            utc("2024-07-15T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn rejects_years_before_the_rules_of_the_zone() {
+        let moscow = zone("Europe/Moscow");
+        assert!(Timestamp::parse_in("2015-01-01T00:00:00", moscow).is_ok());
+        let e = Timestamp::parse_in("2012-01-01T00:00:00", moscow).unwrap_err();
+        assert!(e.ends_with("only known from 2015"), "{}", e);
+        assert!("1990-07-01T12:00:00[Europe/Paris]"
+            .parse::<Timestamp>()
+            .is_err());
+        // Offsets and UTC hold for every year.
+        assert!(Timestamp::parse_in("1990-07-01T12:00:00+03:00", moscow).is_ok());
+        assert!(Timestamp::parse_in("1900-07-01T12:00:00", zone("+03:00")).is_ok());
+        assert_eq!(zone("UTC"), Zone::UTC);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::output::OutputFormat;
+#[cfg(feature = "minor-units")]
+use ledger::Money;
+use ledger::{Amount, Applied, Ledger, TransactionType};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+// Accounts of the books. Client funds are a single control account, whose balance the accounts
+// of the ledger break down by client. The other ones are the operator side of the transactions:
+// cash for funds entering and leaving through deposits, withdrawals and chargebacks, and the
+// income and expense accounts of fees, interest, bonuses and base currency conversions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum BookAccount {
+    Cash,
+    ClientFunds,
+    FeeIncome,
+    InterestIncome,
+    Promotions,
+    CurrencyExchange,
+}
+
+impl BookAccount {
+    fn name(self) -> &'static str {
+        match self {
+            BookAccount::Cash => "cash",
+            BookAccount::ClientFunds => "client_funds",
+            BookAccount::FeeIncome => "fee_income",
+            BookAccount::InterestIncome => "interest_income",
+            BookAccount::Promotions => "promotions",
+            BookAccount::CurrencyExchange => "currency_exchange",
+        }
+    }
+
+    // Operator account on the other side of the movement of client funds of a transaction.
+    fn counterpart(kind: TransactionType) -> BookAccount {
+        match kind {
+            TransactionType::Bonus => BookAccount::Promotions,
+            TransactionType::Convert => BookAccount::CurrencyExchange,
+            _ => BookAccount::Cash,
+        }
+    }
+}
+
+// Double-entry journal of a run, kept as the debit and credit totals of every account. Every
+// applied transaction is posted as balanced entries: the change of the base balance of the client
+// against client funds, the fee against fee income and the rest against the counterpart of the
+// transaction. Disputes and resolves only move funds between available and held, and
+// transactions in other currencies than the base currency don't touch the books.
+#[derive(Debug, Default)]
+pub struct Journal {
+    totals: BTreeMap<BookAccount, (Total, Total)>, // (debits, credits)
+}
+
+// Totals of the journal: f64 sums of the f32 amounts, which balance up to the rounding of the
+// outputs, or with minor units the amounts themselves, which balance exactly.
+#[cfg(not(feature = "minor-units"))]
+type Total = f64;
+#[cfg(feature = "minor-units")]
+type Total = Amount;
+
+#[cfg(not(feature = "minor-units"))]
+const NOTHING: Total = 0.0;
+#[cfg(feature = "minor-units")]
+const NOTHING: Total = Amount::ZERO;
+
+#[cfg(not(feature = "minor-units"))]
+fn total(amount: Amount) -> Total {
+    f64::from(amount)
+}
+
+#[cfg(feature = "minor-units")]
+fn total(amount: Amount) -> Total {
+    amount
+}
+
+// Rounds to the four decimal places of the outputs, so that float noise doesn't show.
+#[cfg(not(feature = "minor-units"))]
+fn rounded(v: Total) -> Total {
+    (v * 10000.0).round() / 10000.0
+}
+
+#[cfg(feature = "minor-units")]
+fn rounded(v: Total) -> Total {
+    v
+}
+
+#[cfg(not(feature = "minor-units"))]
+fn number(v: Total) -> f64 {
+    v
+}
+
+#[cfg(feature = "minor-units")]
+fn number(v: Total) -> f64 {
+    v.to_f64()
+}
+
+impl Journal {
+    // Credits the amount to the first account and debits it to the second one.
+    fn post(&mut self, credit: BookAccount, debit: BookAccount, amount: Total) {
+        if amount == NOTHING {
+            return;
+        }
+        self.totals.entry(credit).or_default().1 += amount;
+        self.totals.entry(debit).or_default().0 += amount;
+    }
+
+    pub fn record(&mut self, applied: &Applied) {
+        let fee = total(applied.fee);
+        // What the overflow account received belongs to client funds all the same.
+        let swept = applied.swept.map_or(NOTHING, |(_, excess)| total(excess));
+        let funds = total(applied.after.total - applied.before.total) + swept;
+        let counterpart = BookAccount::counterpart(applied.kind);
+        let movement = funds + fee;
+        if movement >= NOTHING {
+            self.post(BookAccount::ClientFunds, counterpart, movement);
+        } else {
+            self.post(counterpart, BookAccount::ClientFunds, -movement);
+        }
+        self.post(BookAccount::FeeIncome, BookAccount::ClientFunds, fee);
+    }
+
+    pub fn record_interest(&mut self, interest: Amount) {
+        self.post(
+            BookAccount::InterestIncome,
+            BookAccount::ClientFunds,
+            total(interest),
+        );
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Line {
+    account: &'static str,
+    debit: f64,
+    credit: f64,
+}
+
+// Writes the trial balance: the balance of every account of the journal, in the debit or credit
+// column, and the totals of both columns, which are equal when the books balance. Reports on
+// stderr when the books don't balance, or when client funds don't match the total of the
+// balances of the ledger accounts.
+pub fn write(
+    journal: &Journal,
+    l: &Ledger,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let balances: Vec<(BookAccount, Total)> = journal
+        .totals
+        .iter()
+        .map(|(account, (debits, credits))| (*account, rounded(*debits - *credits)))
+        .collect();
+    let debit: Total = balances.iter().map(|&(_, b)| b.max(NOTHING)).sum();
+    let credit: Total = balances.iter().map(|&(_, b)| (-b).max(NOTHING)).sum();
+    if rounded(debit - credit) != NOTHING {
+        eprintln!(
+            "Trial balance does not balance: debits {:.4}, credits {:.4}",
+            debit, credit
+        );
+    }
+    let client_funds = journal
+        .totals
+        .get(&BookAccount::ClientFunds)
+        .map_or(NOTHING, |(debits, credits)| *credits - *debits);
+    let accounts: Total = l.accounts().map(|(_, a)| total(a.rollup().total)).sum();
+    if rounded(client_funds - accounts) != NOTHING {
+        eprintln!(
+            "Trial balance does not reconcile: client funds {:.4}, account balances {:.4}",
+            client_funds, accounts
+        );
+    }
+    let mut lines: Vec<Line> = balances
+        .iter()
+        .map(|&(account, balance)| Line {
+            account: account.name(),
+            debit: number(balance.max(NOTHING)),
+            credit: number((-balance).max(NOTHING)),
+        })
+        .collect();
+    lines.push(Line {
+        account: "total",
+        debit: number(rounded(debit)),
+        credit: number(rounded(credit)),
+    });
+    if format == OutputFormat::Json {
+        serde_json::to_writer_pretty(&mut *out, &lines)?;
+        return writeln!(out);
+    }
+    writeln!(out, "account,debit,credit")?;
+    for line in &lines {
+        writeln!(out, "{},{:.4},{:.4}", line.account, line.debit, line.credit)?;
+    }
+    Ok(())
+}
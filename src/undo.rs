@@ -0,0 +1,180 @@
+use crate::idempotency;
+use crate::{Account, AccountState, Amount, Bonus, Currency, Ledger, OperationState, Timestamp};
+use std::collections::BTreeMap;
+
+// What a transaction may change of an account (or sub-account), as it was before: restoring it
+// is the inverse of the transaction.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Prior {
+    state: AccountState,
+    op: Option<OperationState>, // Of the transaction
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Currency, Amount>,
+    fees: Amount,
+    escrow: Amount,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bonuses: Vec<Bonus>,
+    tag_names: usize, // Known tag names, new ones are appended
+    tags: Option<u64>,
+    memos: usize,
+}
+
+impl Prior {
+    pub(crate) fn of(a: &Account, tx_id: u32) -> Prior {
+        Prior {
+            state: a.state.clone(),
+            op: a.oplog.get(tx_id),
+            currencies: a.currencies.clone(),
+            fees: a.fees,
+            escrow: a.escrow,
+            bonuses: a.bonuses.clone(),
+            tag_names: a.tag_names.len(),
+            tags: a.tags.get(&tx_id).copied(),
+            memos: a.memos(tx_id).len(),
+        }
+    }
+
+    fn restore(self, a: &mut Account, tx_id: u32) {
+        a.state = self.state;
+        match self.op {
+            Some(op) => a.oplog.insert(tx_id, op),
+            None => a.oplog.remove(tx_id),
+        }
+        a.currencies = self.currencies;
+        a.fees = self.fees;
+        a.escrow = self.escrow;
+        a.bonuses = self.bonuses;
+        a.tag_names.truncate(self.tag_names);
+        match self.tags {
+            Some(set) => a.tags.insert(tx_id, set),
+            None => a.tags.remove(&tx_id),
+        };
+        match self.memos {
+            0 => {
+                a.memos.remove(&tx_id);
+            }
+            n => {
+                if let Some(memos) = a.memos.get_mut(&tx_id) {
+                    memos.truncate(n);
+                }
+            }
+        }
+    }
+}
+
+// Inverse of an applied transaction: the prior state of the (sub-)account it was applied to and,
+// for swept deposits, of the overflow account, also noting which accounts it opened.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Undo {
+    client: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subaccount: Option<String>,
+    tx: u32,
+    opened: bool, // The account was opened by the transaction
+    #[serde(default)]
+    opened_subaccount: bool,
+    prior: Prior,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    overflow: Option<(u16, bool, Prior)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bonus_expiry: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    authorization_expiry: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+}
+
+impl Undo {
+    // Whether the inverse restores an account of the client.
+    pub(crate) fn touches(&self, client_id: u16) -> bool {
+        self.client == client_id || self.overflow.as_ref().is_some_and(|o| o.0 == client_id)
+    }
+
+    // Records the state of the account of the client before the transaction.
+    pub(crate) fn before(l: &Ledger, client_id: u16, subaccount: Option<&str>, tx_id: u32) -> Undo {
+        let a = l.accounts.get(client_id);
+        let sub = subaccount.and_then(|name| a?.subaccounts.get(name));
+        let prior = match (subaccount, a, sub) {
+            (Some(_), _, Some(sub)) => Prior::of(sub, tx_id),
+            (Some(_), _, None) | (None, None, _) => Prior::of(&Account::new(), tx_id),
+            (None, Some(a), _) => Prior::of(a, tx_id),
+        };
+        Undo {
+            client: client_id,
+            subaccount: subaccount.map(String::from),
+            tx: tx_id,
+            opened: a.is_none(),
+            opened_subaccount: subaccount.is_some() && sub.is_none(),
+            prior,
+            overflow: None,
+            bonus_expiry: None,
+            authorization_expiry: None,
+            idempotency_key: None,
+        }
+    }
+
+    // Records the state of the overflow account before the swept part of the deposit is credited.
+    pub(crate) fn before_sweep(&mut self, l: &Ledger, overflow_account: u16) {
+        let a = l.accounts.get(overflow_account);
+        let prior = Prior::of(a.unwrap_or(&Account::new()), self.tx);
+        self.overflow = Some((overflow_account, a.is_none(), prior));
+    }
+
+    pub(crate) fn set_bonus_expiry(&mut self, expires_at: Timestamp) {
+        self.bonus_expiry = Some(expires_at);
+    }
+
+    pub(crate) fn set_authorization_expiry(&mut self, expires_at: Timestamp) {
+        self.authorization_expiry = Some(expires_at);
+    }
+
+    pub(crate) fn set_idempotency_key(&mut self, key: String) {
+        self.idempotency_key = Some(key);
+    }
+
+    // Reverts the transaction, which has to be the last one applied to the accounts it touched.
+    // Accounts it opened are removed, unless other accounts were opened since (by rejected
+    // transactions), in which case they are left empty.
+    pub(crate) fn revert(self, l: &mut Ledger) {
+        if let Some((client_id, opened, prior)) = self.overflow {
+            if !(opened && l.accounts.remove_last(client_id)) {
+                if let Some(a) = l.accounts.get_mut(client_id) {
+                    a.state = prior.state;
+                    a.sweeps
+                        .retain(|s| (s.client, s.tx) != (self.client, self.tx));
+                }
+            }
+        }
+        if let Some(expires_at) = self.bonus_expiry {
+            l.bonus_expiries.remove(&(expires_at, self.client, self.tx));
+        }
+        if let Some(expires_at) = self.authorization_expiry {
+            l.authorization_expiries
+                .remove(&(expires_at, self.client, self.tx));
+        }
+        if let Some(key) = &self.idempotency_key {
+            idempotency::remove(&mut l.idempotency_keys, self.client, key);
+        }
+        if self.opened && l.accounts.remove_last(self.client) {
+            return;
+        }
+        let Some(a) = l.accounts.get_mut(self.client) else {
+            return;
+        };
+        match &self.subaccount {
+            Some(name) if self.opened_subaccount => {
+                a.subaccounts.remove(name);
+            }
+            Some(name) => {
+                if let Some(sub) = a.subaccounts.get_mut(name) {
+                    self.prior.restore(sub, self.tx);
+                }
+            }
+            None => self.prior.restore(a, self.tx),
+        }
+    }
+
+    pub(crate) fn applied(&self) -> (u16, u32) {
+        (self.client, self.tx)
+    }
+}
@@ -0,0 +1,100 @@
+use crate::output::Decimals;
+use anyhow::{anyhow, Result};
+use ledger::{Balance, TransactionEntry};
+use std::io;
+
+// Row of the simulation output: an account touched by the hypothetical transactions, with its
+// balances before and after them.
+#[derive(Debug, serde::Serialize)]
+struct SimulatedRecord {
+    client: u16,
+    available_before: Option<String>,
+    held_before: Option<String>,
+    total_before: Option<String>,
+    locked_before: Option<bool>,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+// `ledger simulate <hypothetical.csv> --snapshot s.json`: applies the transactions of the file to
+// the ledger of the snapshot as a what-if (see Ledger::simulate), and writes the accounts they
+// touch to stdout, with their balances before and after. The transactions which would be
+// rejected are reported on stderr. The snapshot is left as it is.
+pub fn command(args: &[String]) -> Result<()> {
+    let mut it = args.iter();
+    let (mut input, mut snapshot) = (None, None);
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow! {"{} requires a value", arg})
+        };
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(value()?.clone()),
+            _ if input.is_none() && !arg.starts_with("--") => input = Some(arg.clone()),
+            _ => return Err(anyhow! {"unknown option {}", arg}),
+        }
+    }
+    let input = input.ok_or_else(|| anyhow! {"simulate requires the transaction file"})?;
+    let path = snapshot.ok_or_else(|| anyhow! {"simulate requires the --snapshot"})?;
+    let l = crate::load_snapshot(&path)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(&input)
+        .map_err(|e| anyhow! {"cannot read {}: {}", input, e})?;
+    let headers = reader.headers()?.clone();
+    let mut lines = Vec::new();
+    let mut transactions: Vec<TransactionEntry> = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        match crate::deserialize_transaction_entry(&record, &headers) {
+            Ok(tx) => {
+                lines.push(line);
+                transactions.push(tx);
+            }
+            Err(e) => eprintln!("Line {}: invalid transaction: {}", line, e),
+        }
+    }
+    let report = l.simulate(&transactions);
+    for (line, result) in lines.iter().zip(&report.results) {
+        if let Err(e) = result {
+            eprintln!("Line {}: would be rejected: {}", line, e);
+        }
+    }
+    let d = Decimals::default();
+    let format = |b: Option<Balance>| {
+        (
+            b.map(|b| d.format("available", b.available)),
+            b.map(|b| d.format("held", b.held)),
+            b.map(|b| d.format("total", b.total)),
+            b.map(|b| b.locked),
+        )
+    };
+    let mut w = csv::Writer::from_writer(io::stdout());
+    for a in &report.accounts {
+        let (available_before, held_before, total_before, locked_before) = format(a.before);
+        w.serialize(SimulatedRecord {
+            client: a.client_id,
+            available_before,
+            held_before,
+            total_before,
+            locked_before,
+            available: d.format("available", a.after.available),
+            held: d.format("held", a.after.held),
+            total: d.format("total", a.after.total),
+            locked: a.after.locked,
+        })?;
+    }
+    w.flush()?;
+    let applied = report.results.iter().filter(|r| r.is_ok()).count();
+    eprintln!(
+        "Simulated {} transactions: {} would be applied, {} rejected",
+        report.results.len(),
+        applied,
+        report.results.len() - applied
+    );
+    Ok(())
+}
@@ -0,0 +1,702 @@
+use ledger::Amount;
+use std::io::{self, Write};
+
+// Minimal writer of Excel workbooks (Office Open XML), enough for the xlsx output: sheets of
+// plain text, number and boolean cells, the only styles being the number formats of grouped
+// numbers. The package is a ZIP archive whose entries are stored uncompressed, which every
+// spreadsheet application reads.
+#[derive(Debug, Default)]
+pub struct Workbook {
+    sheets: Vec<(String, Vec<Vec<Cell>>)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Empty,
+    Text(String),
+    Number(f64),
+    // Number shown with thousands separators and the given decimal places, the separators being
+    // those of the locale of the spreadsheet application.
+    Grouped(f64, usize),
+    Bool(bool),
+}
+
+impl Cell {
+    // Cell for a value formatted for the CSV output: a number when it parses as one.
+    pub fn parsed(s: &str) -> Cell {
+        match s.parse() {
+            _ if s.is_empty() => Cell::Empty,
+            Ok(v) => Cell::Number(v),
+            Err(_) => Cell::Text(s.to_string()),
+        }
+    }
+
+    // Grouped cell for a value formatted for the CSV output, keeping its decimal places.
+    pub fn grouped(s: &str) -> Cell {
+        match Cell::parsed(s) {
+            Cell::Number(v) => {
+                let places = s.split_once('.').map_or(0, |(_, decimals)| decimals.len());
+                Cell::Grouped(v, places)
+            }
+            cell => cell,
+        }
+    }
+
+    // Number format of a grouped cell, e.g. #,##0.00 for two decimal places.
+    fn number_format(&self) -> Option<String> {
+        match self {
+            Cell::Grouped(_, 0) => Some("#,##0".to_string()),
+            Cell::Grouped(_, places) => Some(format!("#,##0.{}", "0".repeat(*places))),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(s: &str) -> Cell {
+        Cell::Text(s.to_string())
+    }
+}
+
+impl From<Amount> for Cell {
+    fn from(v: Amount) -> Cell {
+        Cell::Number(crate::output::rounded(v))
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(v: Option<T>) -> Cell {
+        v.map_or(Cell::Empty, Into::into)
+    }
+}
+
+macro_rules! number_cell {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Cell {
+            fn from(v: $t) -> Cell {
+                Cell::Number(v as f64)
+            }
+        })*
+    };
+}
+
+number_cell!(u16, u32, u64, usize);
+
+impl Workbook {
+    pub fn new() -> Workbook {
+        Workbook::default()
+    }
+
+    // Adds a sheet, whose first row is usually the header.
+    pub fn sheet(&mut self, name: &str, rows: Vec<Vec<Cell>>) {
+        self.sheets.push((name.to_string(), rows));
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut zip = Zip::default();
+        let mut overrides = String::new();
+        let mut sheets = String::new();
+        let mut relationships = String::new();
+        // Number formats of the grouped cells, whose style is their index here plus one.
+        let mut formats: Vec<String> = Vec::new();
+        let cells = self
+            .sheets
+            .iter()
+            .flat_map(|(_, rows)| rows.iter().flatten());
+        for format in cells.filter_map(Cell::number_format) {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+        if !formats.is_empty() {
+            overrides.push_str(r#"<Override PartName="/xl/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.styles+xml"/>"#);
+            relationships.push_str(r#"<Relationship Id="rIdStyles" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles" Target="styles.xml"/>"#);
+            zip.add("xl/styles.xml", &styles(&formats));
+        }
+        for (i, (name, _)) in self.sheets.iter().enumerate() {
+            let n = i + 1;
+            overrides.push_str(&format!(
+                r#"<Override PartName="/xl/worksheets/sheet{}.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#,
+                n
+            ));
+            sheets.push_str(&format!(
+                r#"<sheet name="{}" sheetId="{}" r:id="rId{}"/>"#,
+                escape(name),
+                n,
+                n
+            ));
+            relationships.push_str(&format!(
+                r#"<Relationship Id="rId{}" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet{}.xml"/>"#,
+                n, n
+            ));
+        }
+        zip.add(
+            "[Content_Types].xml",
+            &format!(
+                r#"{}<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>{}</Types>"#,
+                XML_DECLARATION, overrides
+            ),
+        );
+        zip.add(
+            "_rels/.rels",
+            &format!(
+                r#"{}<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#,
+                XML_DECLARATION
+            ),
+        );
+        zip.add(
+            "xl/workbook.xml",
+            &format!(
+                r#"{}<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets>{}</sheets></workbook>"#,
+                XML_DECLARATION, sheets
+            ),
+        );
+        zip.add(
+            "xl/_rels/workbook.xml.rels",
+            &format!(
+                r#"{}<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">{}</Relationships>"#,
+                XML_DECLARATION, relationships
+            ),
+        );
+        for (i, (_, rows)) in self.sheets.iter().enumerate() {
+            zip.add(
+                &format!("xl/worksheets/sheet{}.xml", i + 1),
+                &worksheet(rows, &formats),
+            );
+        }
+        out.write_all(&zip.finish())
+    }
+}
+
+const XML_DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#;
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // Control characters other than tabs and line breaks aren't allowed in XML.
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Name of the column of the given (zero-based) index: A to Z, then AA, AB and so on.
+fn column_name(mut i: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (i % 26) as u8);
+        if i < 26 {
+            break;
+        }
+        i = i / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap_or_default()
+}
+
+// Style sheet with a cell style per number format, after the default style. Custom number
+// formats have ids from 164 on.
+fn styles(formats: &[String]) -> String {
+    let number_formats: String = formats
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            format!(
+                r#"<numFmt numFmtId="{}" formatCode="{}"/>"#,
+                164 + i,
+                escape(f)
+            )
+        })
+        .collect();
+    let cell_formats: String = (0..formats.len())
+        .map(|i| format!(r#"<xf numFmtId="{}" applyNumberFormat="1"/>"#, 164 + i))
+        .collect();
+    format!(
+        r#"{}<styleSheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><numFmts count="{}">{}</numFmts><fonts count="1"><font/></fonts><fills count="2"><fill><patternFill patternType="none"/></fill><fill><patternFill patternType="gray125"/></fill></fills><borders count="1"><border/></borders><cellStyleXfs count="1"><xf/></cellStyleXfs><cellXfs count="{}"><xf/>{}</cellXfs></styleSheet>"#,
+        XML_DECLARATION,
+        formats.len(),
+        number_formats,
+        formats.len() + 1,
+        cell_formats
+    )
+}
+
+fn worksheet(rows: &[Vec<Cell>], formats: &[String]) -> String {
+    let mut xml = format!(
+        r#"{}<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>"#,
+        XML_DECLARATION
+    );
+    for (r, row) in rows.iter().enumerate() {
+        xml.push_str(&format!(r#"<row r="{}">"#, r + 1));
+        for (c, cell) in row.iter().enumerate() {
+            let reference = format!("{}{}", column_name(c), r + 1);
+            match cell {
+                Cell::Empty => {}
+                Cell::Text(s) => xml.push_str(&format!(
+                    r#"<c r="{}" t="inlineStr"><is><t xml:space="preserve">{}</t></is></c>"#,
+                    reference,
+                    escape(s)
+                )),
+                Cell::Number(v) if v.is_finite() => {
+                    xml.push_str(&format!(r#"<c r="{}"><v>{}</v></c>"#, reference, v))
+                }
+                Cell::Number(_) => {}
+                Cell::Grouped(v, _) if v.is_finite() => {
+                    let style = cell
+                        .number_format()
+                        .and_then(|f| formats.iter().position(|g| *g == f))
+                        .map_or(0, |i| i + 1);
+                    xml.push_str(&format!(
+                        r#"<c r="{}" s="{}"><v>{}</v></c>"#,
+                        reference, style, v
+                    ))
+                }
+                Cell::Grouped(..) => {}
+                Cell::Bool(b) => xml.push_str(&format!(
+                    r#"<c r="{}" t="b"><v>{}</v></c>"#,
+                    reference,
+                    u8::from(*b)
+                )),
+            }
+        }
+        xml.push_str("</row>");
+    }
+    xml.push_str("</sheetData></worksheet>");
+    xml
+}
+
+// CRC-32 (IEEE) of the data, as used by ZIP.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// ZIP archive built in memory, with stored (uncompressed) entries dated 1980-01-01.
+#[derive(Debug, Default)]
+struct Zip {
+    data: Vec<u8>,
+    directory: Vec<u8>,
+    entries: u16,
+}
+
+const DOS_DATE: u16 = 0x21; // 1980-01-01
+
+impl Zip {
+    fn add(&mut self, name: &str, contents: &str) {
+        let contents = contents.as_bytes();
+        let offset = self.data.len() as u32;
+        let crc = crc32(contents);
+        let size = contents.len() as u32;
+        let name = name.as_bytes();
+        // Fields shared by the local header and the central directory record: version needed,
+        // flags, method, time, date, CRC, compressed and uncompressed sizes, name length.
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&DOS_DATE.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        self.data.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        self.data.extend_from_slice(&common);
+        self.data.extend_from_slice(&0u16.to_le_bytes()); // Extra field length
+        self.data.extend_from_slice(name);
+        self.data.extend_from_slice(contents);
+
+        self.directory
+            .extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        self.directory.extend_from_slice(&20u16.to_le_bytes()); // Version made by
+        self.directory.extend_from_slice(&common);
+        // Extra field and comment lengths, disk number, internal and external attributes.
+        self.directory.extend_from_slice(&[0; 12]);
+        self.directory.extend_from_slice(&offset.to_le_bytes());
+        self.directory.extend_from_slice(name);
+        self.entries += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let offset = self.data.len() as u32;
+        let size = self.directory.len() as u32;
+        self.data.append(&mut self.directory);
+        self.data.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        self.data.extend_from_slice(&[0; 4]); // Disk numbers
+        self.data.extend_from_slice(&self.entries.to_le_bytes());
+        self.data.extend_from_slice(&self.entries.to_le_bytes());
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data.extend_from_slice(&offset.to_le_bytes());
+        self.data.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Entries (name and contents) of a ZIP archive, read from its central directory, each checked
+    // against its local header and its CRC.
+    fn unzip(data: &[u8]) -> Vec<(String, String)> {
+        let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        let end = data.len() - 22;
+        assert_eq!(u32_at(end), 0x0605_4b50, "no end of central directory");
+        assert_eq!(u16_at(end + 20), 0); // Comment length
+        let entries = u16_at(end + 10);
+        assert_eq!(u16_at(end + 8), entries);
+        let (size, mut at) = (u32_at(end + 12), u32_at(end + 16));
+        assert_eq!(at + size, end);
+        let mut files = Vec::new();
+        for _ in 0..entries {
+            assert_eq!(u32_at(at), 0x0201_4b50);
+            assert_eq!(u16_at(at + 10), 0); // Stored
+            let (crc, compressed, size) = (u32_at(at + 16), u32_at(at + 20), u32_at(at + 24));
+            assert_eq!(compressed, size);
+            let name_len = u16_at(at + 28);
+            let skipped = u16_at(at + 30) + u16_at(at + 32);
+            let offset = u32_at(at + 42);
+            let name = &data[at + 46..at + 46 + name_len];
+            at += 46 + name_len + skipped;
+
+            assert_eq!(u32_at(offset), 0x0403_4b50);
+            assert_eq!(u32_at(offset + 14), crc);
+            assert_eq!(u32_at(offset + 22), size);
+            assert_eq!(&data[offset + 30..offset + 30 + u16_at(offset + 26)], name);
+            let start = offset + 30 + u16_at(offset + 26) + u16_at(offset + 28);
+            let contents = &data[start..start + size];
+            assert_eq!(crc32(contents) as usize, crc);
+            let name = String::from_utf8(name.to_vec()).unwrap();
+            files.push((name, String::from_utf8(contents.to_vec()).unwrap()));
+        }
+        assert_eq!(at, end);
+        files
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Node {
+        Element(Element),
+        Text(String),
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Element {
+        name: String,
+        attributes: Vec<(String, String)>,
+        children: Vec<Node>,
+    }
+
+    impl Element {
+        fn attribute(&self, name: &str) -> Option<&str> {
+            self.attributes
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        }
+
+        fn elements<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+            self.children.iter().filter_map(move |c| match c {
+                Node::Element(e) if e.name == name => Some(e),
+                _ => None,
+            })
+        }
+
+        fn element<'a>(&'a self, name: &'a str) -> &'a Element {
+            self.elements(name).next().unwrap()
+        }
+
+        fn text(&self) -> String {
+            self.children
+                .iter()
+                .map(|c| match c {
+                    Node::Text(t) => t.clone(),
+                    Node::Element(e) => e.text(),
+                })
+                .collect()
+        }
+    }
+
+    // Parser of the XML subset the workbook parts are written in (a declaration, then elements,
+    // attributes, text and character references), rejecting what isn't well-formed.
+    struct Parser<'a> {
+        s: &'a str,
+    }
+
+    impl Parser<'_> {
+        fn expect(&mut self, prefix: &str) {
+            self.s = self
+                .s
+                .strip_prefix(prefix)
+                .unwrap_or_else(|| panic!("expected {:?} at {:?}", prefix, self.s));
+        }
+
+        fn name(&mut self) -> String {
+            let len = self
+                .s
+                .find(|c: char| !c.is_alphanumeric() && !matches!(c, ':' | '_' | '-' | '.'))
+                .unwrap_or(self.s.len());
+            assert!(len > 0, "expected a name at {:?}", self.s);
+            let (name, rest) = self.s.split_at(len);
+            self.s = rest;
+            name.to_string()
+        }
+
+        fn unescaped(s: &str) -> String {
+            let mut out = String::new();
+            let mut parts = s.split('&');
+            out.push_str(parts.next().unwrap());
+            for part in parts {
+                let (entity, rest) = part.split_once(';').expect("unterminated reference");
+                let c = match entity {
+                    "amp" => '&',
+                    "lt" => '<',
+                    "gt" => '>',
+                    "quot" => '"',
+                    "apos" => '\'',
+                    _ => match entity.strip_prefix("#x") {
+                        Some(hex) => char::from_u32(u32::from_str_radix(hex, 16).unwrap()).unwrap(),
+                        None => char::from_u32(entity[1..].parse().unwrap()).unwrap(),
+                    },
+                };
+                out.push(c);
+                out.push_str(rest);
+            }
+            assert!(
+                !out.chars()
+                    .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r')),
+                "control character in {:?}",
+                s
+            );
+            out
+        }
+
+        fn element(&mut self) -> Element {
+            self.expect("<");
+            let name = self.name();
+            let mut attributes: Vec<(String, String)> = Vec::new();
+            loop {
+                self.s = self.s.trim_start();
+                if let Some(rest) = self.s.strip_prefix("/>") {
+                    self.s = rest;
+                    return Element {
+                        name,
+                        attributes,
+                        children: Vec::new(),
+                    };
+                }
+                if let Some(rest) = self.s.strip_prefix('>') {
+                    self.s = rest;
+                    break;
+                }
+                let attribute = self.name();
+                self.expect("=\"");
+                let (value, rest) = self.s.split_once('"').expect("unterminated attribute");
+                assert!(!value.contains('<'), "< in attribute {}", attribute);
+                self.s = rest;
+                assert!(
+                    attributes.iter().all(|(a, _)| *a != attribute),
+                    "duplicate attribute {}",
+                    attribute
+                );
+                attributes.push((attribute, Parser::unescaped(value)));
+            }
+            let mut children = Vec::new();
+            loop {
+                if let Some(rest) = self.s.strip_prefix("</") {
+                    self.s = rest;
+                    assert_eq!(self.name(), name, "mismatched end tag");
+                    self.expect(">");
+                    return Element {
+                        name,
+                        attributes,
+                        children,
+                    };
+                }
+                if self.s.starts_with('<') {
+                    children.push(Node::Element(self.element()));
+                } else {
+                    let len = self.s.find('<').expect("unterminated element");
+                    let (text, rest) = self.s.split_at(len);
+                    assert!(!text.contains('>'), "> in text {:?}", text);
+                    children.push(Node::Text(Parser::unescaped(text)));
+                    self.s = rest;
+                }
+            }
+        }
+    }
+
+    fn parse(xml: &str) -> Element {
+        let mut p = Parser { s: xml };
+        p.expect(r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#);
+        let root = p.element();
+        assert_eq!(p.s, "", "content after the root element");
+        root
+    }
+
+    fn part(files: &[(String, String)], name: &str) -> Element {
+        let (_, xml) = files
+            .iter()
+            .find(|(n, _)| n == name)
+            .unwrap_or_else(|| panic!("no {} in the package", name));
+        parse(xml)
+    }
+
+    // The CRC catalogue's check value.
+    #[test]
+    fn crc32_matches_the_reference_vector() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn columns_are_named_like_spreadsheets_do() {
+        let names: Vec<_> = [0, 25, 26, 27, 51, 52, 701, 702]
+            .into_iter()
+            .map(column_name)
+            .collect();
+        assert_eq!(names, ["A", "Z", "AA", "AB", "AZ", "BA", "ZZ", "AAA"]);
+    }
+
+    #[test]
+    fn workbooks_are_well_formed_packages() {
+        let mut w = Workbook::new();
+        w.sheet(
+            "Accounts & <balances>",
+            vec![
+                vec!["client".into(), "total".into(), "locked".into()],
+                vec![
+                    Cell::Number(1.0),
+                    Cell::grouped("1234.50"),
+                    Cell::Bool(true),
+                ],
+                vec![Cell::Empty, Cell::Number(f64::NAN), Cell::Bool(false)],
+            ],
+        );
+        w.sheet(
+            "Rejections",
+            vec![vec![Cell::Text("\"quoted\"\u{1} <tx> & more ".to_string())]],
+        );
+        let mut out = Vec::new();
+        w.write(&mut out).unwrap();
+        let files = unzip(&out);
+        let names: Vec<_> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "xl/styles.xml",
+                "[Content_Types].xml",
+                "_rels/.rels",
+                "xl/workbook.xml",
+                "xl/_rels/workbook.xml.rels",
+                "xl/worksheets/sheet1.xml",
+                "xl/worksheets/sheet2.xml",
+            ]
+        );
+
+        // Every part but the relationships and the content types themselves has its content type,
+        // and every target exists.
+        let types = part(&files, "[Content_Types].xml");
+        let overrides: Vec<_> = types
+            .elements("Override")
+            .map(|o| o.attribute("PartName").unwrap())
+            .collect();
+        for name in names
+            .iter()
+            .filter(|n| !n.ends_with(".rels") && **n != "[Content_Types].xml")
+        {
+            assert!(
+                overrides.contains(&format!("/{}", name).as_str()),
+                "{}",
+                name
+            );
+        }
+        let root = part(&files, "_rels/.rels");
+        assert_eq!(
+            root.element("Relationship").attribute("Target"),
+            Some("xl/workbook.xml")
+        );
+        let relationships = part(&files, "xl/_rels/workbook.xml.rels");
+        for r in relationships.elements("Relationship") {
+            let target = format!("xl/{}", r.attribute("Target").unwrap());
+            assert!(names.contains(&target.as_str()), "{}", target);
+        }
+
+        let workbook = part(&files, "xl/workbook.xml");
+        let sheets: Vec<_> = workbook
+            .element("sheets")
+            .elements("sheet")
+            .map(|s| (s.attribute("name").unwrap(), s.attribute("r:id").unwrap()))
+            .collect();
+        assert_eq!(
+            sheets,
+            [("Accounts & <balances>", "rId1"), ("Rejections", "rId2")]
+        );
+
+        let styles = part(&files, "xl/styles.xml");
+        let format = styles.element("numFmts").element("numFmt");
+        assert_eq!(format.attribute("numFmtId"), Some("164"));
+        assert_eq!(format.attribute("formatCode"), Some("#,##0.00"));
+        let cell_formats: Vec<_> = styles.element("cellXfs").elements("xf").collect();
+        assert_eq!(cell_formats[1].attribute("numFmtId"), Some("164"));
+
+        // Cells as (reference, type, style, value).
+        let cells = |sheet: &Element| -> Vec<(String, Option<String>, Option<String>, String)> {
+            let data = sheet.element("sheetData");
+            data.elements("row")
+                .flat_map(|r| r.elements("c"))
+                .map(|c| {
+                    let attribute = |name| c.attribute(name).map(str::to_string);
+                    (
+                        attribute("r").unwrap(),
+                        attribute("t"),
+                        attribute("s"),
+                        c.text(),
+                    )
+                })
+                .collect()
+        };
+        let cell = |r: &str, t: Option<&str>, s: Option<&str>, v: &str| {
+            (
+                r.to_string(),
+                t.map(str::to_string),
+                s.map(str::to_string),
+                v.to_string(),
+            )
+        };
+        let text = Some("inlineStr");
+        assert_eq!(
+            cells(&part(&files, "xl/worksheets/sheet1.xml")),
+            [
+                cell("A1", text, None, "client"),
+                cell("B1", text, None, "total"),
+                cell("C1", text, None, "locked"),
+                cell("A2", None, None, "1"),
+                cell("B2", None, Some("1"), "1234.5"),
+                cell("C2", Some("b"), None, "1"),
+                cell("C3", Some("b"), None, "0"),
+            ]
+        );
+        assert_eq!(
+            cells(&part(&files, "xl/worksheets/sheet2.xml")),
+            [cell("A1", text, None, "\"quoted\" <tx> & more ")]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::tx;
+use ledger::Config;
+#[cfg(feature = "minor-units")]
+use {
+    common::amount,
+    ledger::{ConcurrentLedger, Ledger, LedgerError, MaxBalance},
+};
+
+#[test]
+fn precisions_beyond_the_amounts_are_rejected() {
+    assert!(toml::from_str::<Config>("precision = 18").is_ok());
+    let e = toml::from_str::<Config>("precision = 25").unwrap_err();
+    assert!(e
+        .to_string()
+        .contains("precision 25 above the maximum of 18"));
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn currency_exponents_beyond_the_amounts_are_rejected() {
+    assert!(toml::from_str::<Config>("[currency-exponents]\nUSD = 18").is_ok());
+    let e = toml::from_str::<Config>("[currency-exponents]\nUSD = 19").unwrap_err();
+    assert!(e
+        .to_string()
+        .contains("exponent 19 of USD above the maximum of 18"));
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn balances_out_of_range_reject_the_transaction() {
+    let mut l = Ledger::new();
+    let max = "922337203685477.5807"; // i64::MAX units of the default exponent
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some(max))).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("deposit", 1, 2, Some("0.0001"))),
+        Err(LedgerError::AmountOutOfRange)
+    ));
+    assert!(l
+        .apply_transaction(tx("withdrawal", 1, 3, Some("0.0001")))
+        .is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.held(), amount(max));
+    assert_eq!(a.total(), amount("922337203685477.5806"));
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn rollups_out_of_range_reject_the_transaction() {
+    let mut l = Ledger::new();
+    let max = "922337203685477.5807";
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some(max))).is_ok());
+    let mut savings = tx("deposit", 1, 2, Some(max));
+    savings.subaccount = Some("savings".to_string());
+    assert!(matches!(
+        l.apply_transaction(savings.clone()),
+        Err(LedgerError::AmountOutOfRange)
+    ));
+    assert!(l
+        .apply_transaction(tx("withdrawal", 1, 3, Some(max)))
+        .is_ok());
+    assert!(l.apply_transaction(savings).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("deposit", 1, 4, Some("0.0001"))),
+        Err(LedgerError::AmountOutOfRange)
+    ));
+    let rollup = l.account(1).unwrap().rollup();
+    assert_eq!(rollup.available, amount(max));
+    assert_eq!(rollup.total, amount(max));
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn sweeps_out_of_range_reject_the_deposit() {
+    let config = Config {
+        max_balance: Some(MaxBalance {
+            limit: amount("1"),
+            overflow_account: Some(0),
+        }),
+        ..Config::default()
+    };
+    let max = "922337203685477.5807";
+    let mut l = Ledger::with_config(config.clone());
+    let concurrent = ConcurrentLedger::with_config(config);
+    // The deposits of the overflow account itself are never swept.
+    assert!(l.apply_transaction(tx("deposit", 0, 1, Some(max))).is_ok());
+    assert!(concurrent
+        .apply_transaction(tx("deposit", 0, 1, Some(max)))
+        .is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("deposit", 1, 2, Some("3"))),
+        Err(LedgerError::AmountOutOfRange)
+    ));
+    assert!(matches!(
+        concurrent.apply_transaction(tx("deposit", 1, 2, Some("3"))),
+        Err(LedgerError::AmountOutOfRange)
+    ));
+    // Nothing is swept from deposits within the limit.
+    assert!(l.apply_transaction(tx("deposit", 1, 3, Some("1"))).is_ok());
+    for l in [l, concurrent.into_ledger()] {
+        assert_eq!(l.account(0).unwrap().total(), amount(max));
+        assert!(l.account(0).unwrap().sweeps().is_empty());
+    }
+}
+
+#[cfg(feature = "minor-units")]
+#[test]
+fn amounts_out_of_range_of_their_exponent_are_rejected() {
+    let mut l = Ledger::builder().precision(18).build();
+    assert!(matches!(
+        l.apply_transaction(tx("deposit", 1, 1, Some("9000000000000"))),
+        Err(LedgerError::AmountOutOfRange)
+    ));
+    assert!(l.apply_transaction(tx("deposit", 1, 2, Some("9"))).is_ok());
+    assert_eq!(l.account(1).unwrap().total(), amount("9"));
+}
+
+#[cfg(not(feature = "minor-units"))]
+#[test]
+fn amounts_are_parsed_as_floats() {
+    assert_eq!(tx("deposit", 1, 1, Some("1.5")).amount, Some(1.5));
+}
@@ -0,0 +1,63 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use common::{amount, tx};
+use ledger::{AsyncLedger, Config, MaxBalance, MemoryStore};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+// The futures of MemoryStore never wait, so they complete on their first poll.
+fn block_on<F: Future>(f: F) -> F::Output {
+    match pin!(f).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("MemoryStore futures do not wait"),
+    }
+}
+
+#[test]
+fn rejected_first_transactions_create_the_account() {
+    let l = AsyncLedger::new(MemoryStore::new());
+    assert!(block_on(l.apply_transaction(tx("withdrawal", 1, 1, Some("5")))).is_err());
+    let account = block_on(l.account(1)).unwrap().unwrap();
+    assert_eq!(account.total(), amount("0"));
+    assert!(block_on(l.apply_transaction(tx("deposit", 1, 2, Some("5")))).is_ok());
+    assert_eq!(
+        block_on(l.account(1)).unwrap().unwrap().total(),
+        amount("5")
+    );
+}
+
+#[test]
+fn concurrent_sweeps_are_all_credited_to_the_overflow_account() {
+    let config = Config {
+        max_balance: Some(MaxBalance {
+            limit: amount("1"),
+            overflow_account: Some(0),
+        }),
+        ..Config::default()
+    };
+    let l = Arc::new(AsyncLedger::with_config(MemoryStore::new(), config));
+    let threads: Vec<_> = (1..=8u16)
+        .map(|client| {
+            let l = Arc::clone(&l);
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let uid = u32::from(client) * 1000 + i;
+                    let deposit = tx("deposit", client, uid, Some("3"));
+                    assert!(block_on(l.apply_transaction(deposit)).is_ok());
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let overflow = block_on(l.account(0)).unwrap().unwrap();
+    // The first deposit of each client keeps 1, all the rest is swept.
+    assert_eq!(overflow.available(), amount("1192"));
+    assert_eq!(overflow.sweeps().len(), 8 * 50);
+}
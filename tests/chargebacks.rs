@@ -0,0 +1,101 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Config, Ledger, LedgerError, LockedPolicy};
+
+fn reversals_unlock() -> Ledger {
+    Ledger::with_config(Config {
+        chargeback_reversal_unlocks: true,
+        ..Config::default()
+    })
+}
+
+// Partial chargebacks lock the account too, the rest of the deposit can only be disputed and
+// charged back when locked accounts take transactions.
+fn partial_chargebacks() -> Ledger {
+    Ledger::with_config(Config {
+        chargeback_reversal_unlocks: true,
+        locked_policy: LockedPolicy::AllowAll,
+        ..Config::default()
+    })
+}
+
+#[test]
+fn chargebacks_take_the_disputed_funds_and_lock_the_account() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.total(), amount("0"));
+    assert!(a.is_locked());
+    assert!(matches!(
+        l.apply_transaction(tx("deposit", 1, 2, Some("1"))),
+        Err(LedgerError::AccountLocked)
+    ));
+}
+
+#[test]
+fn chargebacks_need_a_dispute() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_err());
+    assert!(l.apply_transaction(tx("chargeback", 1, 2, None)).is_err());
+    assert!(!l.account(1).unwrap().is_locked());
+}
+
+#[test]
+fn reversals_restore_what_the_chargeback_took() {
+    let mut l = reversals_unlock();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    assert!(l
+        .apply_transaction(tx("chargeback_reversal", 1, 1, None))
+        .is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.available(), amount("10"));
+    assert!(!a.is_locked());
+    // There is nothing left to reverse.
+    assert!(l
+        .apply_transaction(tx("chargeback_reversal", 1, 1, None))
+        .is_err());
+}
+
+#[test]
+fn reversals_restore_every_partial_chargeback() {
+    let mut l = partial_chargebacks();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l
+        .apply_transaction(tx("chargeback", 1, 1, Some("4")))
+        .is_ok());
+    assert!(l
+        .apply_transaction(tx("chargeback", 1, 1, Some("6")))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().total(), amount("0"));
+    assert!(l
+        .apply_transaction(tx("chargeback_reversal", 1, 1, None))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().available(), amount("10"));
+}
+
+#[test]
+fn reversals_restore_chargebacks_of_earlier_disputes() {
+    let mut l = partial_chargebacks();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, Some("3"))).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    // Charged back in part only, the deposit can't be reversed yet.
+    assert!(l
+        .apply_transaction(tx("chargeback_reversal", 1, 1, None))
+        .is_err());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    assert!(l
+        .apply_transaction(tx("chargeback_reversal", 1, 1, None))
+        .is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.available(), amount("10"));
+    assert_eq!(a.held(), amount("0"));
+}
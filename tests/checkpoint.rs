@@ -0,0 +1,108 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn ledger(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ledger"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Path of a file of the test, in the target directory.
+fn path(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    path.to_string_lossy().into_owned()
+}
+
+// Rejections before and after the last checkpoint (taken after the 4th record).
+const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,2,2,5
+deposit,2,3,4
+withdrawal,1,4,3
+withdrawal,2,5,9
+";
+
+const OUTPUTS: [(&str, &str); 4] = [
+    ("--audit-log", "audit.log"),
+    ("--rejects", "rejects.csv"),
+    ("--cdc-out", "cdc.csv"),
+    ("--events-out", "events.ndjson"),
+];
+
+// Runs the input with the outputs of the name, and the further arguments.
+fn run(name: &str, input: &str, args: &[&str]) -> Output {
+    let outputs: Vec<_> = OUTPUTS
+        .iter()
+        .map(|(option, file)| (*option, path(&format!("{}_{}", name, file))))
+        .collect();
+    let mut all = vec![input];
+    for (option, path) in &outputs {
+        all.extend([*option, path.as_str()]);
+    }
+    all.extend(args);
+    ledger(&all)
+}
+
+fn contents(name: &str, file: &str) -> String {
+    fs::read_to_string(path(&format!("{}_{}", name, file))).unwrap()
+}
+
+#[test]
+fn resumed_runs_carry_on_their_outputs() {
+    let input = path("checkpoint.csv");
+    fs::write(&input, INPUT).unwrap();
+    let uninterrupted = run("checkpoint_whole", &input, &[]);
+    assert!(uninterrupted.status.success());
+
+    let dir = path("checkpoint_dir");
+    let _ = fs::remove_dir_all(&dir);
+    let checkpointed = ["--checkpoint-every", "2", "--checkpoint-dir", &dir];
+    assert!(run("checkpoint_resumed", &input, &checkpointed)
+        .status
+        .success());
+    // What the interrupted run wrote after its last checkpoint, which the resumed run drops.
+    for (_, file) in OUTPUTS {
+        let path = path(&format!("checkpoint_resumed_{}", file));
+        let mut f = OpenOptions::new().append(true).open(path).unwrap();
+        f.write_all(b"{\"line\":6,").unwrap();
+    }
+    let resume = [&checkpointed[..], &["--resume-from-checkpoint"]].concat();
+    let resumed = run("checkpoint_resumed", &input, &resume);
+    assert!(
+        resumed.status.success(),
+        "{}",
+        String::from_utf8_lossy(&resumed.stderr)
+    );
+    assert_eq!(resumed.stdout, uninterrupted.stdout);
+    for (_, file) in OUTPUTS {
+        let whole = contents("checkpoint_whole", file);
+        assert!(!whole.is_empty());
+        assert_eq!(contents("checkpoint_resumed", file), whole, "{}", file);
+    }
+    let log = contents("checkpoint_resumed", "audit.log");
+    assert_eq!(log.lines().count(), 3);
+    assert!(contents("checkpoint_resumed", "rejects.csv")
+        .lines()
+        .nth(2)
+        .is_some_and(|l| l.contains(",5,")));
+}
+
+#[test]
+fn outputs_missing_from_the_checkpoint_are_not_overwritten() {
+    let input = path("checkpoint_missing.csv");
+    fs::write(&input, INPUT).unwrap();
+    let dir = path("checkpoint_missing_dir");
+    let _ = fs::remove_dir_all(&dir);
+    let checkpointed = ["--checkpoint-every", "2", "--checkpoint-dir", &dir];
+    // The run of the checkpoint has none of the outputs.
+    assert!(ledger(&[&[input.as_str()][..], &checkpointed].concat())
+        .status
+        .success());
+    let resume = [&checkpointed[..], &["--resume-from-checkpoint"]].concat();
+    fs::write(path("checkpoint_missing_audit.log"), "kept\n").unwrap();
+    assert!(!run("checkpoint_missing", &input, &resume).status.success());
+    assert_eq!(contents("checkpoint_missing", "audit.log"), "kept\n");
+}
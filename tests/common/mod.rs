@@ -0,0 +1,24 @@
+use ledger::{Amount, TransactionEntry};
+
+// Helpers shared by the integration tests. Amounts are written as strings, so that the tests run
+// the same with f32 amounts and with the minor-units feature.
+pub fn amount(s: &str) -> Amount {
+    s.parse().unwrap()
+}
+
+pub fn tx(kind: &str, client: u16, uid: u32, value: Option<&str>) -> TransactionEntry {
+    TransactionEntry {
+        t: kind.to_string(),
+        client_id: client,
+        uid,
+        amount: value.map(amount),
+        currency: None,
+        to_currency: None,
+        timestamp: None,
+        subaccount: None,
+        tags: None,
+        memo: None,
+        idempotency_key: None,
+        reason: None,
+    }
+}
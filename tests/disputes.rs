@@ -0,0 +1,226 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Config, DisputeLifecycle, Ledger, LedgerError, OperationState, TransactionType};
+
+// A deposit of 10 under representment, next to 5 available.
+fn represented() -> Ledger {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("deposit", 1, 2, Some("5"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("representment", 1, 1, None)).is_ok());
+    l
+}
+
+#[test]
+fn representments_keep_the_disputed_funds_held() {
+    let mut l = represented();
+    let a = l.account(1).unwrap();
+    assert_eq!(a.held(), amount("10"));
+    assert_eq!(a.available(), amount("5"));
+    assert!(matches!(
+        a.operation(1),
+        Some(OperationState::RepresentedDeposit { .. })
+    ));
+    assert!(matches!(
+        l.apply_transaction(tx("withdrawal", 1, 3, Some("6"))),
+        Err(LedgerError::InsufficientFunds)
+    ));
+    assert_eq!(l.account(1).unwrap().held(), amount("10"));
+}
+
+#[test]
+fn representments_are_resolved_in_favor_of_the_merchant() {
+    let mut l = represented();
+    assert!(l.apply_transaction(tx("resolve", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.available(), amount("15"));
+    assert_eq!(a.held(), amount("0"));
+    assert!(!a.is_locked());
+    // The deposit can be disputed again.
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+}
+
+#[test]
+fn representments_are_charged_back_in_favor_of_the_cardholder() {
+    let mut l = represented();
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.total(), amount("5"));
+    assert_eq!(a.held(), amount("0"));
+    assert!(a.is_locked());
+}
+
+#[test]
+fn partial_resolutions_keep_the_rest_under_representment() {
+    let mut l = represented();
+    assert!(l.apply_transaction(tx("resolve", 1, 1, Some("4"))).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.held(), amount("6"));
+    assert_eq!(a.available(), amount("9"));
+    assert!(matches!(
+        a.operation(1),
+        Some(OperationState::RepresentedDeposit { .. })
+    ));
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    assert_eq!(l.account(1).unwrap().total(), amount("9"));
+}
+
+#[test]
+fn representments_need_an_open_dispute() {
+    let mut l = represented();
+    // Represented already.
+    assert!(matches!(
+        l.apply_transaction(tx("representment", 1, 1, None)),
+        Err(LedgerError::IllegalStateTransition)
+    ));
+    // Not disputed.
+    assert!(matches!(
+        l.apply_transaction(tx("representment", 1, 2, None)),
+        Err(LedgerError::IllegalStateTransition)
+    ));
+    assert!(matches!(
+        l.apply_transaction(tx("representment", 1, 9, None)),
+        Err(LedgerError::TransactionNotFound)
+    ));
+    assert!(l.apply_transaction(tx("resolve", 1, 1, None)).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("representment", 1, 1, None)),
+        Err(LedgerError::IllegalStateTransition)
+    ));
+}
+
+#[test]
+fn lifecycles_may_leave_out_representment() {
+    let config = Config {
+        dispute_lifecycle: Some(DisputeLifecycle {
+            disputed: vec![TransactionType::Resolve, TransactionType::Chargeback],
+            ..DisputeLifecycle::default()
+        }),
+        ..Config::default()
+    };
+    let mut l = Ledger::with_config(config);
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("representment", 1, 1, None)),
+        Err(LedgerError::IllegalStateTransition)
+    ));
+    assert!(l.apply_transaction(tx("resolve", 1, 1, None)).is_ok());
+}
+
+// A deposit of 10 escalated to arbitration, next to 5 available.
+fn arbitrated() -> Ledger {
+    let mut l = represented();
+    assert!(l
+        .apply_transaction(tx("pre_arbitration", 1, 1, None))
+        .is_ok());
+    assert!(l.apply_transaction(tx("arbitration", 1, 1, None)).is_ok());
+    l
+}
+
+#[test]
+fn disputes_escalate_one_stage_at_a_time() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    for skipped in ["pre_arbitration", "arbitration"] {
+        assert!(matches!(
+            l.apply_transaction(tx(skipped, 1, 1, None)),
+            Err(LedgerError::IllegalStateTransition)
+        ));
+    }
+    assert!(l.apply_transaction(tx("representment", 1, 1, None)).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("arbitration", 1, 1, None)),
+        Err(LedgerError::IllegalStateTransition)
+    ));
+    assert!(l
+        .apply_transaction(tx("pre_arbitration", 1, 1, None))
+        .is_ok());
+    assert!(matches!(
+        l.account(1).unwrap().operation(1),
+        Some(OperationState::PreArbitrationDeposit { .. })
+    ));
+    assert!(l.apply_transaction(tx("arbitration", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert!(matches!(
+        a.operation(1),
+        Some(OperationState::ArbitrationDeposit { .. })
+    ));
+    assert_eq!(a.held(), amount("10"));
+    // Arbitration is the last stage.
+    for again in ["representment", "pre_arbitration", "arbitration"] {
+        assert!(matches!(
+            l.apply_transaction(tx(again, 1, 1, None)),
+            Err(LedgerError::IllegalStateTransition)
+        ));
+    }
+}
+
+#[test]
+fn arbitrations_end_in_a_resolve_or_a_chargeback() {
+    let mut l = arbitrated();
+    assert!(l.apply_transaction(tx("resolve", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.available(), amount("15"));
+    assert_eq!(a.held(), amount("0"));
+    assert!(matches!(
+        a.operation(1),
+        Some(OperationState::RegularDeposit { disputes: 1, .. })
+    ));
+
+    let mut l = arbitrated();
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    let a = l.account(1).unwrap();
+    assert_eq!(a.total(), amount("5"));
+    assert!(a.is_locked());
+    assert!(matches!(
+        a.operation(1),
+        Some(OperationState::FinalDeposit { .. })
+    ));
+}
+
+#[test]
+fn deposits_may_be_disputed_again_until_charged_back() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    for _ in 0..2 {
+        assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+        assert!(l.apply_transaction(tx("resolve", 1, 1, None)).is_ok());
+    }
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    assert!(matches!(
+        l.account(1).unwrap().operation(1),
+        Some(OperationState::FinalDeposit { disputes: 3, .. })
+    ));
+    for after in ["dispute", "resolve", "chargeback", "representment"] {
+        assert!(
+            l.apply_transaction(tx(after, 1, 1, None)).is_err(),
+            "{}",
+            after
+        );
+    }
+}
+
+#[test]
+fn configured_lifecycles_may_skip_stages() {
+    // The network without pre-arbitration of the DisputeLifecycle example.
+    let config: Config = toml::from_str(
+        "[dispute-lifecycle]\nrepresentment = [\"arbitration\", \"resolve\", \"chargeback\"]\n",
+    )
+    .unwrap();
+    let mut l = Ledger::with_config(config);
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("representment", 1, 1, None)).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("pre_arbitration", 1, 1, None)),
+        Err(LedgerError::IllegalStateTransition)
+    ));
+    assert!(l.apply_transaction(tx("arbitration", 1, 1, None)).is_ok());
+    assert!(l.apply_transaction(tx("chargeback", 1, 1, None)).is_ok());
+    assert!(l.account(1).unwrap().is_locked());
+}
@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn ledger(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ledger"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Input file of the test, written to the target directory.
+fn input(name: &str, contents: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn successful_runs_exit_with_success() {
+    let path = input("exit_ok.csv", "type,client,tx,amount\ndeposit,1,1,1.5\n");
+    let output = ledger(&[&path]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1.5"));
+}
+
+#[test]
+fn missing_inputs_exit_with_failure() {
+    let output = ledger(&["/nonexistent/transactions.csv"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn invalid_options_exit_with_failure() {
+    let path = input("exit_invalid.csv", "type,client,tx,amount\n");
+    assert!(!ledger(&[&path, "--no-such-option"]).status.success());
+}
+
+#[cfg(feature = "memory-accounting")]
+#[test]
+fn exceeding_the_memory_budget_exits_with_failure() {
+    let path = input("exit_memory.csv", "type,client,tx,amount\ndeposit,1,1,1\n");
+    let output = ledger(&[&path, "--max-memory", "1"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("memory budget"));
+    assert!(output.stdout.is_empty());
+}
+
+#[cfg(not(feature = "memory-accounting"))]
+#[test]
+fn memory_budgets_require_memory_accounting() {
+    let path = input("exit_no_memory.csv", "type,client,tx,amount\n");
+    let output = ledger(&[&path, "--max-memory", "1G"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("memory-accounting"));
+}
+
+#[test]
+fn failing_subcommands_exit_with_failure() {
+    assert!(!ledger(&["replay", "/nonexistent/audit.log"])
+        .status
+        .success());
+}
+
+#[test]
+fn assert_state_exits_with_the_status_of_the_check() {
+    let path = input(
+        "exit_assert.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.5\n",
+    );
+    let header = "client,available,held,total,locked\n";
+    let matching = input(
+        "exit_assert_ok.csv",
+        &format!("{}1,1.5,0,1.5,false\n", header),
+    );
+    let differing = input("exit_assert_ko.csv", &format!("{}1,2,0,2,false\n", header));
+    let status = |expected: &str, profile: &str| {
+        let profile = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(profile);
+        let _ = std::fs::remove_file(&profile);
+        let output = ledger(&[
+            "assert-state",
+            expected,
+            &path,
+            "--profile",
+            &profile.to_string_lossy(),
+        ]);
+        // The run ends as usual, writing the profile, whatever the outcome of the check.
+        assert!(profile.exists(), "{}", expected);
+        output.status.code()
+    };
+    assert_eq!(status(&matching, "exit_assert_ok.svg"), Some(0));
+    assert_eq!(status(&differing, "exit_assert_ko.svg"), Some(1));
+    assert_eq!(
+        status("/nonexistent/expected.csv", "exit_assert_none.svg"),
+        Some(2)
+    );
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{
+    Behavior, Config, FeeRule, Flag, Flags, Ledger, LedgerError, Timestamp, TransactionEntry,
+    TransactionType,
+};
+
+fn at(mut tx: TransactionEntry, t: i64) -> TransactionEntry {
+    tx.timestamp = Some(Timestamp(t));
+    tx
+}
+
+fn flag(behavior: Behavior, enabled: bool, from: i64) -> Flag {
+    Flag {
+        behavior,
+        enabled,
+        effective_from: Some(Timestamp(from)),
+    }
+}
+
+#[test]
+fn flags_switch_behaviors_from_their_effective_time() {
+    let config = Config {
+        allow_admin_ops: true,
+        flags: vec![
+            flag(Behavior::AdminOps, false, 100),
+            flag(Behavior::Fees, false, 100),
+            flag(Behavior::Fees, true, 200),
+        ],
+        ..Config::default()
+    };
+    let defaults = Flags {
+        overdraft: true,
+        fees: true,
+        credit_lines: true,
+        chargeback_reversal_unlocks: false,
+        admin_ops: true,
+    };
+    assert_eq!(config.flags_at(Some(Timestamp(99))), defaults);
+    let disabled = Flags {
+        fees: false,
+        admin_ops: false,
+        ..defaults
+    };
+    assert_eq!(config.flags_at(Some(Timestamp(150))), disabled);
+    // Without a timestamp, the flags in force last.
+    let last = Flags {
+        admin_ops: false,
+        ..defaults
+    };
+    assert_eq!(config.flags_at(Some(Timestamp(200))), last);
+    assert_eq!(config.flags_at(None), last);
+}
+
+#[test]
+fn disabled_overdraft_rejects_withdrawals_below_zero() {
+    let mut l = Ledger::with_config(Config {
+        allow_overdraft: amount("50"),
+        flags: vec![flag(Behavior::Overdraft, false, 100)],
+        ..Config::default()
+    });
+    let withdrawal = |uid, t| at(tx("withdrawal", 1, uid, Some("20")), t);
+    assert!(l.apply_transaction(withdrawal(1, 50)).is_ok());
+    assert!(matches!(
+        l.apply_transaction(withdrawal(2, 150)),
+        Err(LedgerError::InsufficientFunds)
+    ));
+    assert_eq!(l.account(1).unwrap().available(), amount("-20"));
+}
+
+#[test]
+fn disabled_fees_are_not_charged() {
+    let mut l = Ledger::with_config(Config {
+        fees: vec![FeeRule {
+            kind: TransactionType::Deposit,
+            tier: None,
+            min_amount: None,
+            max_amount: None,
+            fixed: amount("1"),
+            percent: 0.0,
+        }],
+        flags: vec![flag(Behavior::Fees, false, 100)],
+        ..Config::default()
+    });
+    assert!(l
+        .apply_transaction(at(tx("deposit", 1, 1, Some("10")), 50))
+        .is_ok());
+    assert!(l
+        .apply_transaction(at(tx("deposit", 1, 2, Some("10")), 150))
+        .is_ok());
+    let account = l.account(1).unwrap();
+    assert_eq!(account.fees(), amount("1"));
+    assert_eq!(account.available(), amount("19"));
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Ledger, LedgerBuilder, LedgerError, TransactionEntry};
+
+fn keyed(kind: &str, client: u16, uid: u32, value: Option<&str>, key: &str) -> TransactionEntry {
+    TransactionEntry {
+        idempotency_key: Some(key.to_string()),
+        ..tx(kind, client, uid, value)
+    }
+}
+
+#[test]
+fn keys_are_those_of_each_client() {
+    let mut l = Ledger::new();
+    assert!(l
+        .apply_transaction(keyed("deposit", 1, 1, Some("10"), "k"))
+        .is_ok());
+    assert!(l
+        .apply_transaction(keyed("deposit", 2, 2, Some("10"), "k"))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().available(), amount("10"));
+    assert_eq!(l.account(2).unwrap().available(), amount("10"));
+}
+
+#[test]
+fn retries_with_the_same_payload_are_acknowledged() {
+    let mut l = Ledger::new();
+    assert!(l
+        .apply_transaction(keyed("deposit", 1, 1, Some("10"), "k"))
+        .is_ok());
+    // Under another transaction id, as upstream systems may retry with a new one.
+    assert!(matches!(
+        l.apply_transaction(keyed("deposit", 1, 7, Some("10"), "k")),
+        Err(LedgerError::AlreadyApplied(1))
+    ));
+    assert_eq!(l.account(1).unwrap().available(), amount("10"));
+}
+
+#[test]
+fn keys_reused_with_another_payload_are_rejected() {
+    let mut l = Ledger::new();
+    assert!(l
+        .apply_transaction(keyed("deposit", 1, 1, Some("10"), "k"))
+        .is_ok());
+    for other in [
+        keyed("deposit", 1, 2, Some("11"), "k"),
+        keyed("withdrawal", 1, 2, Some("10"), "k"),
+    ] {
+        let result = l.apply_transaction(other);
+        assert!(matches!(result, Err(LedgerError::IdempotencyKeyReused(1))));
+        assert_eq!(result.unwrap_err().code(), "idempotency_key_reused");
+    }
+    assert_eq!(l.account(1).unwrap().available(), amount("10"));
+}
+
+#[test]
+fn undone_transactions_free_their_key() {
+    let mut l = LedgerBuilder::new().undo_depth(10).build();
+    assert!(l
+        .apply_transaction(keyed("deposit", 1, 1, Some("10"), "k"))
+        .is_ok());
+    assert_eq!(l.undo_last(1), vec![(1, 1)]);
+    assert!(l
+        .apply_transaction(keyed("deposit", 1, 1, Some("12"), "k"))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().available(), amount("12"));
+}
+
+#[test]
+fn snapshots_keep_the_keys() {
+    let mut l = Ledger::new();
+    assert!(l
+        .apply_transaction(keyed("deposit", 1, 1, Some("10"), "k"))
+        .is_ok());
+    assert!(l
+        .apply_transaction(keyed("deposit", 2, 2, Some("5"), "k"))
+        .is_ok());
+    let json = serde_json::to_string(&l).unwrap();
+    let mut restored: Ledger = serde_json::from_str(&json).unwrap();
+    assert!(matches!(
+        restored.apply_transaction(keyed("deposit", 2, 3, Some("5"), "k")),
+        Err(LedgerError::AlreadyApplied(2))
+    ));
+    assert!(matches!(
+        restored.apply_transaction(keyed("deposit", 1, 3, Some("5"), "k")),
+        Err(LedgerError::IdempotencyKeyReused(1))
+    ));
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Config, Ledger, LedgerBuilder, LedgerError, MaxBalance, Sweep};
+
+// Balances are capped at 100, the excess of deposits going to client 9.
+fn capped_config() -> Config {
+    Config {
+        max_balance: Some(MaxBalance {
+            limit: amount("100"),
+            overflow_account: Some(9),
+        }),
+        ..Config::default()
+    }
+}
+
+fn capped() -> Ledger {
+    Ledger::with_config(capped_config())
+}
+
+#[test]
+fn excess_is_swept_into_the_overflow_account() {
+    let mut l = capped();
+    assert!(l
+        .apply_transaction(tx("deposit", 1, 1, Some("150")))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().total(), amount("100"));
+    let overflow = l.account(9).unwrap();
+    assert_eq!(overflow.available(), amount("50"));
+    let sweep = Sweep {
+        client: 1,
+        tx: 1,
+        amount: amount("50"),
+    };
+    assert_eq!(overflow.sweeps(), &[sweep]);
+    assert!(overflow.operation(1).is_none());
+}
+
+#[test]
+fn deposits_over_the_limit_are_rejected_without_overflow_account() {
+    let mut l = Ledger::with_config(Config {
+        max_balance: Some(MaxBalance {
+            limit: amount("100"),
+            overflow_account: None,
+        }),
+        ..Config::default()
+    });
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("60"))).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("deposit", 1, 2, Some("60"))),
+        Err(LedgerError::MaxBalanceExceeded)
+    ));
+    assert_eq!(l.account(1).unwrap().total(), amount("60"));
+}
+
+#[test]
+fn sweeps_can_not_be_disputed_by_the_overflow_client() {
+    let mut l = capped();
+    assert!(l
+        .apply_transaction(tx("deposit", 1, 1, Some("150")))
+        .is_ok());
+    assert!(l.apply_transaction(tx("dispute", 9, 1, None)).is_err());
+    assert!(l.apply_transaction(tx("chargeback", 9, 1, None)).is_err());
+    let overflow = l.account(9).unwrap();
+    assert_eq!(overflow.available(), amount("50"));
+    assert_eq!(overflow.held(), amount("0"));
+    assert!(!overflow.is_locked());
+}
+
+#[test]
+fn sweeps_leave_transactions_of_the_overflow_account_alone() {
+    let mut l = capped();
+    assert!(l.apply_transaction(tx("deposit", 9, 1, Some("20"))).is_ok());
+    assert!(l
+        .apply_transaction(tx("deposit", 1, 1, Some("150")))
+        .is_ok());
+    assert_eq!(l.account(9).unwrap().total(), amount("70"));
+    // The deposit of the overflow client is still the one logged under its id.
+    assert!(l.apply_transaction(tx("dispute", 9, 1, None)).is_ok());
+    let overflow = l.account(9).unwrap();
+    assert_eq!(overflow.held(), amount("20"));
+    assert_eq!(overflow.available(), amount("50"));
+}
+
+#[test]
+fn undoing_a_swept_deposit_drops_the_sweep() {
+    let mut l = LedgerBuilder::from_config(capped_config())
+        .undo_depth(10)
+        .build();
+    assert!(l.apply_transaction(tx("deposit", 9, 1, Some("20"))).is_ok());
+    assert!(l
+        .apply_transaction(tx("deposit", 1, 1, Some("150")))
+        .is_ok());
+    assert_eq!(l.undo_last(1), vec![(1, 1)]);
+    let overflow = l.account(9).unwrap();
+    assert_eq!(overflow.total(), amount("20"));
+    assert!(overflow.sweeps().is_empty());
+    assert!(overflow.operation(1).is_some());
+}
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn ledger(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ledger"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Path of a file of the test, in the target directory.
+fn path(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    path.to_string_lossy().into_owned()
+}
+
+// A bonus which partly expires a day later, before the deposit of the 5th, then an authorization.
+const INPUT: &str = "type,client,tx,amount,timestamp
+deposit,1,1,10,2024-03-01T00:00:00Z
+bonus,1,2,5,2024-03-01T01:00:00Z
+withdrawal,1,3,2,2024-03-01T02:00:00Z
+deposit,1,4,1,2024-03-05T00:00:00Z
+authorize,1,5,1,2024-03-05T00:00:00Z
+";
+
+// Runs the input with bonus expiry, writing the audit log and snapshot of the given name.
+fn run(name: &str) -> (String, String) {
+    let (input, log, snapshot) = (
+        path(&format!("{}.csv", name)),
+        path(&format!("{}.log", name)),
+        path(&format!("{}.json", name)),
+    );
+    std::fs::write(&input, INPUT).unwrap();
+    let output = ledger(&[
+        &input,
+        "--bonus-expiry-days",
+        "1",
+        "--audit-log",
+        &log,
+        "--snapshot-out",
+        &snapshot,
+    ]);
+    assert!(output.status.success());
+    (log, snapshot)
+}
+
+#[test]
+fn logs_with_bonus_expiries_replay_to_the_snapshot() {
+    let (log, snapshot) = run("replay_expiries");
+    let records = std::fs::read_to_string(&log).unwrap();
+    assert!(records.lines().any(|l| l.contains(r#""expired":true"#)));
+    assert!(records
+        .lines()
+        .all(|l| l.contains(r#""timestamp":"2024-03-0"#)));
+    let output = ledger(&["replay", &log, "--snapshot", &snapshot]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("matches the log and the snapshot"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn differences_are_described_in_plain_text() {
+    let (log, snapshot) = run("replay_differences");
+    let records = std::fs::read_to_string(&log).unwrap();
+    // Amounts are numbers, or strings with minor-units.
+    let tampered = records
+        .replacen(r#""available":15.0"#, r#""available":99.0"#, 1)
+        .replacen(r#""available":"15.0000""#, r#""available":"99.0000""#, 1);
+    assert_ne!(tampered, records);
+    std::fs::write(&log, tampered).unwrap();
+    let output = ledger(&["replay", &log, "--snapshot", &snapshot]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    let difference = stderr
+        .lines()
+        .find(|l| l.contains("line 3: bonus tx 2 of client 1 left available 15"))
+        .unwrap_or_else(|| panic!("{}", stderr));
+    assert!(difference.contains("instead of available 99"), "{}", stderr);
+    assert!(difference.ends_with(", open"), "{}", stderr);
+    assert!(!stderr.contains("Balance {"), "{}", stderr);
+}
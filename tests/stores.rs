@@ -0,0 +1,134 @@
+#![cfg(any(feature = "rocksdb", feature = "sled"))]
+
+mod common;
+
+use common::{amount, tx};
+use ledger::{AsyncLedger, AsyncLedgerStore, Config, LedgerError, MaxBalance};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+// Waker unparking the thread blocked on the future.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Polls the future until it completes, parking the thread while it waits.
+fn block_on<F: Future>(f: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut f = pin!(f);
+    loop {
+        if let Poll::Ready(output) = f.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+// Empty directory of the test for a database, in the target directory.
+fn database(name: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+// Runs a dispute lifecycle over reopenings of the store, which must keep the accounts and their
+// oplogs as the transactions left them.
+fn lifecycle<S: AsyncLedgerStore>(open: impl Fn() -> S) {
+    let l = AsyncLedger::new(open());
+    assert!(block_on(l.apply_transaction(tx("deposit", 1, 1, Some("10")))).is_ok());
+    assert!(block_on(l.apply_transaction(tx("deposit", 1, 2, Some("5")))).is_ok());
+    assert!(block_on(l.apply_transaction(tx("withdrawal", 2, 3, Some("1")))).is_err());
+    drop(l);
+
+    let l = AsyncLedger::new(open());
+    assert!(block_on(l.apply_transaction(tx("dispute", 1, 1, None))).is_ok());
+    assert!(matches!(
+        block_on(l.apply_transaction(tx("deposit", 1, 2, Some("5")))),
+        Err(LedgerError::DuplicateTransaction)
+    ));
+    let account = block_on(l.account(1)).unwrap().unwrap();
+    assert_eq!(account.held(), amount("10"));
+    assert_eq!(account.available(), amount("5"));
+    // The rejected first transaction of client 2 created its account.
+    assert_eq!(
+        block_on(l.account(2)).unwrap().unwrap().total(),
+        amount("0")
+    );
+    assert!(block_on(l.account(3)).unwrap().is_none());
+    drop(l);
+
+    let l = AsyncLedger::new(open());
+    assert!(block_on(l.apply_transaction(tx("chargeback", 1, 1, None))).is_ok());
+    drop(l);
+    let l = AsyncLedger::new(open());
+    let account = block_on(l.account(1)).unwrap().unwrap();
+    assert!(account.is_locked());
+    assert_eq!(account.total(), amount("5"));
+    assert!(block_on(l.apply_transaction(tx("resolve", 1, 1, None))).is_err());
+}
+
+// Sweeps of concurrent clients into the same overflow account, which the store must not lose.
+fn concurrent_sweeps<S: AsyncLedgerStore + Send + Sync + 'static>(store: S) {
+    let config = Config {
+        max_balance: Some(MaxBalance {
+            limit: amount("1"),
+            overflow_account: Some(0),
+        }),
+        ..Config::default()
+    };
+    let l = Arc::new(AsyncLedger::with_config(store, config));
+    let threads: Vec<_> = (1..=4u16)
+        .map(|client| {
+            let l = Arc::clone(&l);
+            thread::spawn(move || {
+                for i in 0..20 {
+                    let uid = u32::from(client) * 1000 + i;
+                    let deposit = tx("deposit", client, uid, Some("3"));
+                    assert!(block_on(l.apply_transaction(deposit)).is_ok());
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let overflow = block_on(l.account(0)).unwrap().unwrap();
+    // The first deposit of each client keeps 1, all the rest is swept.
+    assert_eq!(overflow.available(), amount("236"));
+    assert_eq!(overflow.sweeps().len(), 4 * 20);
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_keeps_accounts_and_oplogs() {
+    let path = database("sled_lifecycle");
+    lifecycle(|| ledger::SledStore::open(&path).unwrap());
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn sled_keeps_concurrent_sweeps() {
+    concurrent_sweeps(ledger::SledStore::open(database("sled_sweeps")).unwrap());
+}
+
+#[cfg(feature = "rocksdb")]
+#[test]
+fn rocksdb_keeps_accounts_and_oplogs() {
+    let path = database("rocksdb_lifecycle");
+    lifecycle(|| ledger::RocksDbStore::open(&path).unwrap());
+}
+
+#[cfg(feature = "rocksdb")]
+#[test]
+fn rocksdb_keeps_concurrent_sweeps() {
+    concurrent_sweeps(ledger::RocksDbStore::open(database("rocksdb_sweeps")).unwrap());
+}
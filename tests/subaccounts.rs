@@ -0,0 +1,55 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Ledger, LedgerError, TransactionEntry};
+
+fn in_subaccount(mut t: TransactionEntry, name: &str) -> TransactionEntry {
+    t.subaccount = Some(name.to_string());
+    t
+}
+
+#[test]
+fn transaction_ids_are_unique_across_subaccounts() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    for name in ["savings", "fees"] {
+        let t = in_subaccount(tx("deposit", 1, 1, Some("10")), name);
+        assert!(matches!(
+            l.apply_transaction(t),
+            Err(LedgerError::DuplicateTransaction)
+        ));
+    }
+    assert_eq!(l.account(1).unwrap().rollup().total, amount("10"));
+}
+
+#[test]
+fn main_balance_rejects_ids_of_subaccounts() {
+    let mut l = Ledger::new();
+    let t = in_subaccount(tx("deposit", 1, 7, Some("5")), "savings");
+    assert!(l.apply_transaction(t).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("withdrawal", 1, 7, Some("1"))),
+        Err(LedgerError::DuplicateTransaction)
+    ));
+    assert_eq!(l.account(1).unwrap().rollup().total, amount("5"));
+}
+
+#[test]
+fn disputes_apply_in_the_subaccount_of_the_deposit() {
+    let mut l = Ledger::new();
+    let deposit = in_subaccount(tx("deposit", 1, 1, Some("10")), "savings");
+    assert!(l.apply_transaction(deposit).is_ok());
+    assert!(l.apply_transaction(tx("dispute", 1, 1, None)).is_err());
+    let dispute = in_subaccount(tx("dispute", 1, 1, None), "savings");
+    assert!(l.apply_transaction(dispute).is_ok());
+    let savings = l.account(1).unwrap().subaccount("savings").unwrap();
+    assert_eq!(savings.held(), amount("10"));
+}
+
+#[test]
+fn other_clients_may_reuse_ids() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    let t = in_subaccount(tx("deposit", 2, 1, Some("10")), "savings");
+    assert!(l.apply_transaction(t).is_ok());
+}
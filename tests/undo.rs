@@ -0,0 +1,109 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Ledger, LedgerBuilder, TransactionEntry};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn ledger(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ledger"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Path of a file of the test, in the target directory.
+fn path(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    path.to_string_lossy().into_owned()
+}
+
+fn snapshot(l: &Ledger) -> serde_json::Value {
+    serde_json::to_value(l).unwrap()
+}
+
+// Transactions of every kind undo reverts, on two clients.
+fn later() -> Vec<TransactionEntry> {
+    vec![
+        tx("deposit", 1, 10, Some("7")),
+        tx("withdrawal", 2, 11, Some("1")),
+        tx("dispute", 1, 1, None),
+        tx("representment", 1, 1, None),
+        tx("chargeback", 1, 1, None),
+        tx("escrow_hold", 2, 12, Some("2")),
+        tx("authorize", 2, 13, Some("1")),
+        tx("capture", 2, 13, None),
+        TransactionEntry {
+            idempotency_key: Some("retry".to_string()),
+            ..tx("deposit", 2, 14, Some("3"))
+        },
+        tx("bonus", 2, 15, Some("0.5")),
+    ]
+}
+
+#[test]
+fn undoing_the_later_transactions_restores_the_ledger_before_them() {
+    let mut l = LedgerBuilder::new().undo_depth(20).build();
+    for t in [
+        tx("deposit", 1, 1, Some("10")),
+        tx("deposit", 2, 2, Some("20")),
+        tx("dispute", 2, 2, None),
+        tx("resolve", 2, 2, None),
+    ] {
+        assert!(l.apply_transaction(t).is_ok());
+    }
+    let before = snapshot(&l);
+    let later = later();
+    for t in later.clone() {
+        let (tx, kind) = (t.uid, t.t.clone());
+        assert!(l.apply_transaction(t).is_ok(), "{} {}", kind, tx);
+    }
+    assert_ne!(snapshot(&l), before);
+    let reverted = l.undo_last(later.len());
+    let expected: Vec<(u16, u32)> = later.iter().rev().map(|t| (t.client_id, t.uid)).collect();
+    assert_eq!(reverted, expected);
+    assert_eq!(snapshot(&l), before);
+    // The undone transactions, and the idempotency key, can be applied again.
+    for t in later {
+        assert!(l.apply_transaction(t).is_ok());
+    }
+}
+
+#[test]
+fn undoing_stops_at_the_depth_of_the_journal() {
+    let mut l = LedgerBuilder::new().undo_depth(2).build();
+    for uid in 1..=3 {
+        assert!(l
+            .apply_transaction(tx("deposit", 1, uid, Some("1")))
+            .is_ok());
+    }
+    assert_eq!(l.undoable(), 2);
+    assert_eq!(l.undo_last(5), vec![(1, 3), (1, 2)]);
+    assert_eq!(l.account(1).unwrap().total(), amount("1"));
+    assert!(l.undo_last(1).is_empty());
+}
+
+#[test]
+fn undo_command_writes_the_snapshot_before_the_last_records() {
+    let records = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\n";
+    let bad = "withdrawal,1,3,4\ndispute,2,2,\n";
+    let (first, whole) = (path("undo_first.csv"), path("undo_whole.csv"));
+    std::fs::write(&first, records).unwrap();
+    std::fs::write(&whole, format!("{}{}", records, bad)).unwrap();
+    let (expected, undone) = (path("undo_first.json"), path("undo_whole.json"));
+    for (input, snapshot) in [(&first, &expected), (&whole, &undone)] {
+        let output = ledger(&[input, "--undo-depth", "5", "--snapshot-out", snapshot]);
+        assert!(output.status.success());
+    }
+    let output = ledger(&["undo", "--last", "2", "--snapshot", &undone]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    let read = |path: &str| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+    assert_eq!(read(&undone), read(&expected));
+    // Nothing is left to undo of the whole input but the first records.
+    assert!(!ledger(&["undo", "--last", "3", "--snapshot", &undone])
+        .status
+        .success());
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{amount, tx};
+use ledger::{Config, CreditLine, Ledger, LedgerError, Tier};
+
+#[test]
+fn withdrawals_beyond_the_available_funds_are_rejected() {
+    let mut l = Ledger::new();
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    assert!(matches!(
+        l.apply_transaction(tx("withdrawal", 1, 2, Some("10.5"))),
+        Err(LedgerError::InsufficientFunds)
+    ));
+    assert!(l
+        .apply_transaction(tx("withdrawal", 1, 3, Some("10")))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().available(), amount("0"));
+}
+
+// Client 1 may draw up to 100 on its credit line, at a fee of 5 per draw.
+fn with_credit_line(tiers: Vec<Tier>) -> Ledger {
+    Ledger::with_config(Config {
+        credit_lines: vec![CreditLine {
+            client: 1,
+            limit: amount("100"),
+            interest_rate: 0.0,
+            draw_fee: amount("5"),
+        }],
+        tiers,
+        ..Config::default()
+    })
+}
+
+#[test]
+fn draws_on_the_credit_line_are_charged_the_fee() {
+    let mut l = with_credit_line(Vec::new());
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    let applied = l
+        .apply_transaction(tx("withdrawal", 1, 2, Some("50")))
+        .unwrap();
+    assert_eq!(applied.fee, amount("5"));
+    assert_eq!(l.account(1).unwrap().available(), amount("-45"));
+    // Withdrawals within the available funds don't draw on the line.
+    let mut l = with_credit_line(Vec::new());
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    let applied = l
+        .apply_transaction(tx("withdrawal", 1, 2, Some("10")))
+        .unwrap();
+    assert_eq!(applied.fee, amount("0"));
+}
+
+#[test]
+fn draw_fees_count_against_the_credit_limit() {
+    let mut l = with_credit_line(Vec::new());
+    assert!(l.apply_transaction(tx("deposit", 1, 1, Some("10"))).is_ok());
+    // 110 would take the funds to the limit, but not with the fee on top.
+    assert!(matches!(
+        l.apply_transaction(tx("withdrawal", 1, 2, Some("110"))),
+        Err(LedgerError::InsufficientFunds)
+    ));
+    assert!(l
+        .apply_transaction(tx("withdrawal", 1, 3, Some("105")))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().available(), amount("-100"));
+}
+
+#[test]
+fn draw_fees_count_against_the_minimum_balance() {
+    let tier = Tier {
+        name: "standard".to_string(),
+        min_balance: amount("-50"),
+        clients: vec![1],
+    };
+    let mut l = with_credit_line(vec![tier]);
+    assert!(matches!(
+        l.apply_transaction(tx("withdrawal", 1, 1, Some("50"))),
+        Err(LedgerError::BelowMinimumBalance)
+    ));
+    assert!(l
+        .apply_transaction(tx("withdrawal", 1, 2, Some("45")))
+        .is_ok());
+    assert_eq!(l.account(1).unwrap().available(), amount("-50"));
+}